@@ -0,0 +1,194 @@
+use crate::index::Index;
+use crate::objects::{ObjectId, Tree};
+use crate::{promisor, worktree};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::Path;
+
+// What the working tree is being updated for, as the messages about local changes put it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Operation {
+    Checkout,
+    Merge,
+}
+
+impl Operation {
+    fn name(self) -> &'static str {
+        match self {
+            Operation::Checkout => "checkout",
+            Operation::Merge => "merge",
+        }
+    }
+
+    fn action(self) -> &'static str {
+        match self {
+            Operation::Checkout => "switch branches",
+            Operation::Merge => "merge",
+        }
+    }
+}
+
+// Move the index and working tree from the tree `from` (None for an unborn branch) to the
+// tree `to`, like git's two-way merge on checkout: files that are the same in both trees
+// are left alone, so local changes to them carry over. Files that differ are updated, which
+// is refused if that would throw away local changes (staged or not) or overwrite untracked
+// files, unless `force` is set. With `force`, every tracked file is reset to `to`.
+pub(crate) fn switch_trees(
+    index: &mut Index,
+    from: Option<&str>,
+    to: &str,
+    force: bool,
+    operation: Operation,
+) -> anyhow::Result<()> {
+    let old = match from {
+        Some(from) => Tree::flatten(from)?,
+        None => BTreeMap::new(),
+    };
+    let new = Tree::flatten(to)?;
+
+    // The paths to bring up to date
+    let mut paths: BTreeSet<&str> = old
+        .keys()
+        .chain(new.keys())
+        .filter(|path| old.get(*path) != new.get(*path))
+        .map(String::as_str)
+        .collect();
+    if force {
+        paths.extend(index.entries.iter().map(|entry| entry.path.as_str()));
+        paths.extend(new.keys().map(String::as_str));
+    } else {
+        check_clobbering(index, &old, &new, &paths, operation)?;
+    }
+    check_directories(index, &new, &paths)?;
+    let paths: Vec<String> = paths.into_iter().map(str::to_string).collect();
+
+    // A partial clone gets the blobs it lacks in one fetch rather than one by one, before
+    // anything on disk changes
+    let blobs = paths.iter().filter_map(|path| new.get(path)).filter(|(mode, _)| *mode != 0o160000);
+    promisor::prefetch(blobs.map(|(_, hash)| hash.to_string()))?;
+
+    // Removals first, so files and directories can swap places
+    for path in paths.iter().rev() {
+        // Untracked files (like one whose deletion is staged) stay on disk
+        if !new.contains_key(path) && index.remove(path) {
+            worktree::remove_file(path)?;
+        }
+    }
+
+    for path in &paths {
+        let Some(&(mode, hash)) = new.get(path) else { continue };
+        // Files that already have the wanted content keep their index entry (and stat data)
+        if let Some(entry) = index.get(path) {
+            if entry.mode == mode && entry.hash == hash && !worktree::is_modified(entry, index.mtime)? {
+                continue;
+            }
+        }
+        index.add(worktree::checkout_file(path, mode, hash)?);
+    }
+    Ok(())
+}
+
+fn check_clobbering(
+    index: &Index,
+    old: &BTreeMap<String, (u32, ObjectId)>,
+    new: &BTreeMap<String, (u32, ObjectId)>,
+    paths: &BTreeSet<&str>,
+    operation: Operation,
+) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        index.entries.iter().all(|entry| entry.stage() == 0),
+        "you need to resolve your current index first"
+    );
+
+    let mut changed = Vec::new();
+    let mut untracked = Vec::new();
+    for &path in paths {
+        let wanted = new.get(path);
+        match index.get(path) {
+            Some(entry) => {
+                let staged = Some(&(entry.mode, entry.hash));
+                // Already staged exactly as the target has it: nothing to lose
+                if staged == wanted && !worktree::is_modified(entry, index.mtime)? {
+                    continue;
+                }
+                if staged != old.get(path) || worktree::is_modified(entry, index.mtime)? {
+                    changed.push(path);
+                }
+            }
+            None => {
+                // A file the target would create, which exists but isn't tracked
+                if wanted.is_some() && fs::symlink_metadata(path).is_ok_and(|meta| !meta.is_dir()) {
+                    untracked.push(path);
+                } else if old.contains_key(path) && wanted.is_some() {
+                    // Deleted from the index but kept in the target: the deletion would be lost
+                    changed.push(path);
+                }
+            }
+        }
+    }
+
+    // A file (or symlink) where the target needs a directory for the paths it creates, unless
+    // it is a tracked file on its way out
+    let mut blockers = BTreeSet::new();
+    for &path in paths.iter().filter(|&&path| new.contains_key(path)) {
+        let mut parents = path.match_indices('/').map(|(end, _)| &path[..end]);
+        if let Some(parent) = parents.find(|parent| fs::symlink_metadata(parent).is_ok_and(|meta| !meta.is_dir())) {
+            blockers.insert(parent);
+        }
+    }
+    for parent in blockers {
+        match index.get(parent) {
+            Some(_) if paths.contains(parent) && !new.contains_key(parent) => {}
+            Some(_) => changed.push(parent),
+            None => untracked.push(parent),
+        }
+    }
+
+    if !changed.is_empty() {
+        anyhow::bail!(
+            "Your local changes to the following files would be overwritten by {}:\n\t{}\n\
+             Please commit your changes or stash them before you {}.\nAborting",
+            operation.name(),
+            changed.join("\n\t"),
+            operation.action()
+        );
+    }
+    if !untracked.is_empty() {
+        anyhow::bail!(
+            "The following untracked working tree files would be overwritten by {}:\n\t{}\n\
+             Please move or remove them before you {}.\nAborting",
+            operation.name(),
+            untracked.join("\n\t"),
+            operation.action()
+        );
+    }
+    Ok(())
+}
+
+// A directory standing where the target has a file can only be replaced if everything in it
+// is about to be removed anyway.
+fn check_directories(
+    index: &Index,
+    new: &BTreeMap<String, (u32, ObjectId)>,
+    paths: &BTreeSet<&str>,
+) -> anyhow::Result<()> {
+    let mut lost = Vec::new();
+    for &path in paths {
+        if !new.get(path).is_some_and(|&(mode, _)| mode != 0o160000) {
+            continue;
+        }
+        if !fs::symlink_metadata(path).is_ok_and(|meta| meta.is_dir()) {
+            continue;
+        }
+        let removed = |file: &String| index.get(file).is_some() && paths.contains(file.as_str()) && !new.contains_key(file);
+        if !worktree::files(Path::new(path), path, None)?.iter().all(removed) {
+            lost.push(path);
+        }
+    }
+    anyhow::ensure!(
+        lost.is_empty(),
+        "Updating the following directories would lose untracked files in them:\n\t{}\nAborting",
+        lost.join("\n\t")
+    );
+    Ok(())
+}
@@ -0,0 +1,78 @@
+use crate::ignore::IgnoreRules;
+use crate::index::{Index, IndexEntry};
+use crate::worktree;
+use anyhow::Context;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub fn invoke(paths: Vec<PathBuf>, force: bool) -> anyhow::Result<()> {
+    let mut index = Index::load()?;
+    let mut ignore = IgnoreRules::load()?;
+
+    for path in paths {
+        let repo_path = worktree::repo_path(&path)?;
+        let disk_path = if repo_path.is_empty() { Path::new(".") } else { Path::new(&repo_path) };
+
+        match fs::symlink_metadata(disk_path) {
+            Ok(meta) if meta.is_dir() => {
+                // Stage every file below the directory that isn't ignored...
+                let files = worktree::files(disk_path, &repo_path, (!force).then_some(&mut ignore))?;
+                for file in &files {
+                    add_file(&mut index, file)?;
+                }
+                // ...tracked files, which ignore rules don't apply to...
+                let tracked: Vec<String> = index
+                    .matching(&repo_path)
+                    .map(|entry| entry.path.clone())
+                    .filter(|tracked| files.binary_search(tracked).is_err())
+                    .collect();
+                for tracked in tracked {
+                    if fs::symlink_metadata(&tracked).is_ok() {
+                        add_file(&mut index, &tracked)?;
+                    } else {
+                        // ...and the removal of tracked files that are gone from it
+                        index.remove(&tracked);
+                    }
+                }
+            }
+            Ok(meta) => {
+                // Naming an ignored file explicitly is most likely a mistake
+                if !force && index.get(&repo_path).is_none() && ignore.is_ignored(&repo_path, meta.is_dir())? {
+                    anyhow::bail!(
+                        "the path '{}' is ignored by one of your .gitignore files\nuse -f if you really want to add it",
+                        path.display()
+                    );
+                }
+                add_file(&mut index, &repo_path)?
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                // A deleted file that is still tracked: stage its removal
+                let gone: Vec<String> = index.matching(&repo_path).map(|entry| entry.path.clone()).collect();
+                anyhow::ensure!(!gone.is_empty(), "pathspec '{}' did not match any files", path.display());
+                for tracked in gone {
+                    index.remove(&tracked);
+                }
+            }
+            Err(e) => return Err(e).with_context(|| format!("stat {}", path.display())),
+        }
+    }
+
+    index.write()
+}
+
+fn add_file(index: &mut Index, path: &str) -> anyhow::Result<()> {
+    let meta = fs::symlink_metadata(path).with_context(|| format!("stat {path}"))?;
+
+    // Files whose stat data hasn't changed since they were staged don't need rehashing
+    if index
+        .get(path)
+        .is_some_and(|entry| entry.matches_stat(&meta, index.mtime))
+    {
+        return Ok(());
+    }
+
+    let hash = worktree::hash_file(Path::new(path), &meta, true)
+        .with_context(|| format!("add {path} to .git/objects"))?;
+    index.add(IndexEntry::from_metadata(path.to_string(), &meta, hash));
+    Ok(())
+}
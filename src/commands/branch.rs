@@ -0,0 +1,124 @@
+use super::switch::create_branch;
+use crate::{refs, repository, revision};
+
+#[derive(Debug, clap::Args)]
+pub struct Args {
+    /// Branch to create, delete or rename
+    name: Option<String>,
+
+    /// Commit to start a new branch at (default: HEAD), or the new name with -m/-M
+    start: Option<String>,
+
+    /// Delete a branch that is merged into HEAD
+    #[clap(short = 'd', long)]
+    delete: bool,
+
+    /// Delete a branch even if it is not merged
+    #[clap(short = 'D')]
+    force_delete: bool,
+
+    /// Rename a branch (the current one if only the new name is given)
+    #[clap(short = 'm', long = "move")]
+    rename: bool,
+
+    /// Rename a branch even if the new name already exists
+    #[clap(short = 'M')]
+    force_rename: bool,
+
+    /// Reset an existing branch to the start commit
+    #[clap(short = 'f', long)]
+    force: bool,
+
+    #[clap(short = 'l', long)]
+    list: bool,
+}
+
+pub fn invoke(args: Args) -> anyhow::Result<()> {
+    anyhow::ensure!(repository::git_dir().is_dir(), "not in a git directory");
+    let Args { name, start, delete, force_delete, rename, force_rename, force, list } = args;
+    let current = refs::symbolic_target("HEAD")?;
+    let current = current.as_deref().and_then(|current| current.strip_prefix("refs/heads/"));
+
+    if delete || force_delete {
+        let name = name.ok_or_else(|| anyhow::anyhow!("branch name required"))?;
+        return delete_branch(&name, current, force_delete);
+    }
+    if rename || force_rename {
+        // "branch -m <new>" renames the current branch
+        let (old, new) = match (name, start) {
+            (Some(old), Some(new)) => (old, new),
+            (Some(new), None) => (
+                current
+                    .ok_or_else(|| anyhow::anyhow!("cannot rename the current branch while not on any"))?
+                    .to_string(),
+                new,
+            ),
+            _ => anyhow::bail!("branch name required"),
+        };
+        return rename_branch(&old, &new, current, force_rename);
+    }
+
+    match name.filter(|_| !list) {
+        Some(name) => create_branch(&name, start.as_deref().unwrap_or("HEAD"), force),
+        None => list_branches(current),
+    }
+}
+
+fn list_branches(current: Option<&str>) -> anyhow::Result<()> {
+    if current.is_none() {
+        if let Some(head) = refs::resolve("HEAD")? {
+            println!("* (HEAD detached at {})", revision::abbreviate(&head, 7)?);
+        }
+    }
+    for (name, _) in refs::list("refs/heads/")? {
+        let name = name.strip_prefix("refs/heads/").unwrap_or(&name);
+        let marker = if Some(name) == current { '*' } else { ' ' };
+        println!("{marker} {name}");
+    }
+    Ok(())
+}
+
+fn delete_branch(name: &str, current: Option<&str>, force: bool) -> anyhow::Result<()> {
+    let full_name = format!("refs/heads/{name}");
+    let hash = refs::resolve(&full_name)?.ok_or_else(|| anyhow::anyhow!("branch '{name}' not found."))?;
+    anyhow::ensure!(current != Some(name), "Cannot delete branch '{name}' checked out");
+
+    // Without -D, only branches whose commits are all reachable from HEAD can go
+    if !force {
+        let merged = match refs::resolve("HEAD")? {
+            Some(head) => revision::is_ancestor(&hash, &head)?,
+            None => false,
+        };
+        anyhow::ensure!(
+            merged,
+            "The branch '{name}' is not fully merged.\n\
+             If you are sure you want to delete it, run 'branch -D {name}'."
+        );
+    }
+
+    refs::delete(&full_name, Some(&hash))?;
+    println!("Deleted branch {name} (was {}).", revision::abbreviate(&hash, 7)?);
+    Ok(())
+}
+
+fn rename_branch(old: &str, new: &str, current: Option<&str>, force: bool) -> anyhow::Result<()> {
+    anyhow::ensure!(refs::valid_name(new), "'{new}' is not a valid branch name");
+    let old_ref = format!("refs/heads/{old}");
+    let new_ref = format!("refs/heads/{new}");
+    anyhow::ensure!(refs::resolve(&old_ref)?.is_some(), "branch '{old}' not found.");
+    if old == new {
+        return Ok(());
+    }
+    if let Some(existing) = refs::resolve(&new_ref)? {
+        anyhow::ensure!(force, "a branch named '{new}' already exists");
+        anyhow::ensure!(current != Some(new), "cannot force update the current branch");
+        refs::delete(&new_ref, Some(&existing))?;
+    }
+
+    refs::rename(&old_ref, &new_ref, &format!("Branch: renamed {old_ref} to {new_ref}"))?;
+    // HEAD follows its branch
+    if current == Some(old) {
+        refs::set_symbolic("HEAD", &new_ref, None)?;
+    }
+    Ok(())
+}
@@ -1,18 +1,85 @@
 use anyhow::Context;
-use crate::objects::{Kind, Object};
+use crate::objects::{self, Kind, Object, Tree};
+use crate::revision;
+use std::io::{BufRead, Read, Write};
 
-pub fn invoke(pretty_print: bool, object_hash: String) -> anyhow::Result<()> {
-    // Ensure the "-p" flag is provided
-    anyhow::ensure!(pretty_print, "the -p flag is required to use this command");
+#[derive(Debug, clap::Args)]
+#[command(group(
+    clap::ArgGroup::new("mode")
+        .required(true)
+        .args(["pretty_print", "show_type", "show_size", "exists", "batch", "batch_check"])
+))]
+#[command(group(clap::ArgGroup::new("batch_mode").args(["batch", "batch_check"])))]
+pub struct Args {
+    #[clap(short = 'p')]
+    pretty_print: bool,
+
+    /// Show the object type
+    #[clap(short = 't')]
+    show_type: bool,
+
+    /// Show the object size
+    #[clap(short = 's')]
+    show_size: bool,
+
+    /// Exit with zero status if the object exists, non-zero otherwise
+    #[clap(short = 'e')]
+    exists: bool,
+
+    /// Print type, size and contents of every object named on stdin
+    #[clap(long)]
+    batch: bool,
+
+    /// Print type and size of every object named on stdin
+    #[clap(long)]
+    batch_check: bool,
+
+    /// With --batch or --batch-check, go through every object in the repository instead of stdin
+    #[clap(long, requires = "batch_mode")]
+    batch_all_objects: bool,
+
+    #[clap(required_unless_present_any = ["batch", "batch_check"])]
+    object_hash: Option<String>,
+}
+
+pub fn invoke(args: Args) -> anyhow::Result<()> {
+    if args.batch || args.batch_check {
+        return batch(args.batch, args.batch_all_objects);
+    }
+    let object_hash = args.object_hash.expect("clap requires a hash outside of batch mode");
+
+    // With -e, only the exit status tells whether the object exists (and is readable)
+    if args.exists {
+        let found = revision::resolve(&object_hash).and_then(|hash| Object::read(&hash).map(|_| ()));
+        if found.is_err() {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+    let object_hash = revision::resolve(&object_hash)?;
 
     // Read the Git object using the new Object::read method
     let mut object = Object::read(&object_hash)?;
-    match object.kind {
-        Kind::Blob => {
-            // obtain stdout and lockit to avoid race conditions
-            let stdout = std::io::stdout();
-            let mut stdout = stdout.lock();
 
+    if args.show_type {
+        println!("{}", object.kind);
+        return Ok(());
+    }
+    if args.show_size {
+        println!("{}", object.size);
+        return Ok(());
+    }
+
+    // Ensure the "-p" flag is provided
+    anyhow::ensure!(args.pretty_print, "one of -p, -t, -s or -e is required to use this command");
+
+    // obtain stdout and lockit to avoid race conditions
+    let stdout = std::io::stdout();
+    let mut stdout = stdout.lock();
+
+    match object.kind {
+        Kind::Blob | Kind::Commit | Kind::Tag => {
+            // Blobs, commits and tags are printed as they are stored
             // Copy read data to stdout and ensure the size is coherent
             let n = std::io::copy(&mut object.reader, &mut stdout)
                 .context("write .git/objects file to stdout")?;
@@ -23,12 +90,71 @@ pub fn invoke(pretty_print: bool, object_hash: String) -> anyhow::Result<()> {
             );  
         }
         Kind::Tree => {
-            println!("Tree");
-        }
-        Kind::Commit => {
-            println!("Commit");
+            // Trees are binary, render one "<mode> <type> <hash>\t<name>" line per entry
+            let mut buf = Vec::new();
+            object
+                .reader
+                .read_to_end(&mut buf)
+                .context("read tree object")?;
+            let tree = Tree::parse(&buf).context("parse tree object")?;
+            for entry in tree.entries {
+                writeln!(
+                    stdout,
+                    "{:0>6} {} {}\t{}",
+                    entry.mode,
+                    entry.kind(),
+                    entry.hash,
+                    entry.name
+                )?;
+            }
         }
     }
     
     Ok(())
-}
\ No newline at end of file
+}
+
+// Stream "<hash> <type> <size>\n" records (followed by "<contents>\n" with --batch) for many
+// objects from a single process.
+fn batch(contents: bool, all_objects: bool) -> anyhow::Result<()> {
+    let stdout = std::io::stdout();
+    let mut stdout = std::io::BufWriter::new(stdout.lock());
+
+    if all_objects {
+        for id in objects::all_objects().context("list objects")? {
+            batch_one(&id.to_string(), contents, &mut stdout)?;
+        }
+        stdout.flush()?;
+        return Ok(());
+    }
+
+    let stdin = std::io::stdin();
+    for line in stdin.lock().lines() {
+        let line = line.context("read object name from stdin")?;
+        batch_one(line.trim(), contents, &mut stdout)?;
+        // Flush every record so callers can interleave requests and responses
+        stdout.flush()?;
+    }
+    Ok(())
+}
+
+fn batch_one(name: &str, contents: bool, out: &mut impl Write) -> anyhow::Result<()> {
+    let Ok((hash, mut object)) = revision::resolve(name).and_then(|hash| {
+        let object = Object::read(&hash)?;
+        Ok((hash, object))
+    }) else {
+        writeln!(out, "{name} missing")?;
+        return Ok(());
+    };
+    writeln!(out, "{hash} {} {}", object.kind, object.size)?;
+    if contents {
+        let n = std::io::copy(&mut object.reader, out).context("write object contents")?;
+        anyhow::ensure!(
+            n == object.size,
+            "object {name} was not the expected size (expected: {}, actual: {})",
+            object.size,
+            n
+        );
+        writeln!(out)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,82 @@
+use crate::ignore::IgnoreRules;
+use crate::index::Index;
+use crate::worktree;
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, clap::Args)]
+pub struct Args {
+    /// Show the pattern that matched each path
+    #[clap(short = 'v', long)]
+    verbose: bool,
+
+    /// With -v, also list paths that no pattern matched
+    #[clap(short = 'n', long, requires = "verbose")]
+    non_matching: bool,
+
+    /// Don't skip tracked files
+    #[clap(long)]
+    no_index: bool,
+
+    /// Read the paths from stdin, one per line
+    #[clap(long)]
+    stdin: bool,
+
+    #[clap(required_unless_present = "stdin")]
+    paths: Vec<PathBuf>,
+}
+
+pub fn invoke(args: Args) -> anyhow::Result<()> {
+    let mut ignore = IgnoreRules::load()?;
+    let index = if args.no_index { None } else { Some(Index::load()?) };
+
+    let mut paths = args.paths;
+    if args.stdin {
+        for line in std::io::stdin().lock().lines() {
+            paths.push(PathBuf::from(line?));
+        }
+    }
+
+    let stdout = std::io::stdout();
+    let mut stdout = stdout.lock();
+    let mut any_ignored = false;
+    for path in paths {
+        let repo_path = worktree::repo_path(&path)?;
+        anyhow::ensure!(!repo_path.is_empty(), "'{}' is not a path inside the repository", path.display());
+
+        // Tracked files are never ignored, so they aren't reported at all
+        if index.as_ref().is_some_and(|index| index.get(&repo_path).is_some()) {
+            continue;
+        }
+
+        let is_dir = path.to_string_lossy().ends_with('/') || Path::new(&repo_path).is_dir();
+        let pattern = ignore.matching(&repo_path, is_dir)?;
+        if pattern.as_ref().is_some_and(|pattern| !pattern.negated) {
+            any_ignored = true;
+        }
+
+        match pattern {
+            Some(pattern) if args.verbose => writeln!(
+                stdout,
+                "{}:{}:{}\t{}",
+                pattern.source,
+                pattern.line,
+                pattern.original,
+                path.display()
+            )?,
+            Some(pattern) if !pattern.negated => writeln!(stdout, "{}", path.display())?,
+            None if args.non_matching => writeln!(stdout, "::\t{}", path.display())?,
+            _ => {}
+        }
+        if args.stdin {
+            stdout.flush()?;
+        }
+    }
+
+    // Like git, exit with 1 when none of the paths are ignored
+    if !any_ignored {
+        stdout.flush()?;
+        std::process::exit(1);
+    }
+    Ok(())
+}
@@ -0,0 +1,106 @@
+use super::switch::{create_branch, switch_head, Target};
+use crate::index::Index;
+use crate::objects::{Kind, ObjectId, Tree};
+use crate::{refs, revision, worktree};
+use std::path::PathBuf;
+
+#[derive(Debug, clap::Args)]
+pub struct Args {
+    /// Branch to switch to, or commit to detach at (with paths: where to take them from)
+    target: Option<String>,
+
+    /// Create a new branch and switch to it
+    #[clap(short = 'b', value_name = "NEW_BRANCH")]
+    create: Option<String>,
+
+    /// Like -b, but reset the branch if it already exists
+    #[clap(short = 'B', value_name = "NEW_BRANCH", conflicts_with = "create")]
+    force_create: Option<String>,
+
+    /// Detach HEAD even when a branch is named
+    #[clap(long)]
+    detach: bool,
+
+    /// Throw away local changes that are in the way
+    #[clap(short = 'f', long)]
+    force: bool,
+
+    /// Restore these files (from the index, or from <target> if given)
+    #[clap(last = true)]
+    paths: Vec<PathBuf>,
+}
+
+pub fn invoke(args: Args) -> anyhow::Result<()> {
+    let Args { target, create, force_create, detach, force, paths } = args;
+
+    if !paths.is_empty() {
+        anyhow::ensure!(
+            create.is_none() && force_create.is_none() && !detach,
+            "cannot switch branches while restoring paths"
+        );
+        return restore_paths(target.as_deref(), &paths, force);
+    }
+
+    if let Some(new_branch) = create.as_ref().or(force_create.as_ref()) {
+        create_branch(new_branch, target.as_deref().unwrap_or("HEAD"), force_create.is_some())?;
+        return switch_head(Target::Branch(new_branch.clone()), force);
+    }
+
+    let Some(target) = target else {
+        // Nothing to switch to: like git, just stay where we are
+        return Ok(());
+    };
+    // A branch name switches to the branch; anything else detaches HEAD
+    if !detach && refs::resolve(&format!("refs/heads/{target}"))?.is_some() {
+        return switch_head(Target::Branch(target), force);
+    }
+    let commit = revision::peel(&revision::resolve(&target)?, Kind::Commit)?;
+    switch_head(Target::Detached(commit), force)
+}
+
+// "checkout [<rev>] -- <paths>": overwrite the working tree files with their staged version,
+// or with the version in <rev> (which is staged as well).
+fn restore_paths(source: Option<&str>, paths: &[PathBuf], force: bool) -> anyhow::Result<()> {
+    let mut index = Index::load()?;
+    let tree = source
+        .map(|source| Tree::flatten(&revision::peel(&revision::resolve(source)?, Kind::Tree)?))
+        .transpose()?;
+
+    for path in paths {
+        let repo_path = worktree::repo_path(path)?;
+        let in_dir = |file: &str| repo_path.is_empty() || file == repo_path || file.starts_with(&format!("{repo_path}/"));
+
+        // What to write: (path, mode, hash)
+        let files: Vec<(String, u32, ObjectId)> = match &tree {
+            Some(tree) => tree
+                .iter()
+                .filter(|(file, _)| in_dir(file))
+                .map(|(file, &(mode, hash))| (file.clone(), mode, hash))
+                .collect(),
+            None => {
+                let matched: Vec<_> = index.matching(&repo_path).collect();
+                // Conflicted files have no single version to restore
+                if let Some(unmerged) = matched.iter().find(|entry| entry.stage() != 0) {
+                    anyhow::ensure!(force, "path '{}' is unmerged", unmerged.path);
+                }
+                matched
+                    .into_iter()
+                    .filter(|entry| entry.stage() == 0)
+                    .map(|entry| (entry.path.clone(), entry.mode, entry.hash))
+                    .collect()
+            }
+        };
+        anyhow::ensure!(
+            !files.is_empty(),
+            "pathspec '{}' did not match any file(s) known to git",
+            path.display()
+        );
+
+        // Either way the index ends up with the restored version and its fresh stat data
+        for (file, mode, hash) in files {
+            index.add(worktree::checkout_file(&file, mode, hash)?);
+        }
+    }
+
+    index.write()
+}
@@ -1,8 +1,198 @@
+use super::init::init_repository;
+use crate::checkout::{self, Operation};
+use crate::config::ConfigFile;
+use crate::index::Index;
+use crate::objects::{self, Kind};
+use crate::pack::Filter;
+use crate::transport::{Limits, Location, RemoteRef, UploadPack};
+use crate::{date, refs, revision};
 use anyhow::Context;
+use std::fs;
+use std::path::{Path, PathBuf};
 
-pub fn invoke(url: String, dir: String) -> anyhow::Result<()> {
-    let repo = git2::Repository::clone(&url, &dir)
-        .with_context(|| format!("failed cloning {} into {}", url, dir))?;
-    println!("Cloned {} to {}", url, repo.path().display());
-    Ok(())
-}
\ No newline at end of file
+#[derive(Debug, clap::Args)]
+pub struct Args {
+    /// The repository to clone: a path, a file:// URL or an http(s):// URL
+    url: String,
+
+    /// Where to clone to [default: the last part of the URL, without .git]
+    dir: Option<PathBuf>,
+
+    /// The command that serves local repositories (paths and file:// URLs)
+    #[clap(short = 'u', long, value_name = "upload-pack", default_value = "git-upload-pack")]
+    upload_pack: String,
+
+    /// Make a shallow clone of only the last <depth> commits of history
+    #[clap(long)]
+    depth: Option<u32>,
+
+    /// Make a shallow clone of only the history after <date>
+    #[clap(long, value_name = "date")]
+    shallow_since: Option<String>,
+
+    /// Make a shallow clone without the history of a branch or tag of the remote (may be
+    /// given more than once)
+    #[clap(long, value_name = "rev")]
+    shallow_exclude: Vec<String>,
+
+    /// Fetch every branch for a shallow clone, not only the one to check out
+    #[clap(long)]
+    no_single_branch: bool,
+
+    /// Make a partial clone without the objects <filter-spec> leaves out (blob:none,
+    /// blob:limit=<n>[kmg] or tree:<depth>), to be fetched from the remote when needed
+    #[clap(long, value_name = "filter-spec")]
+    filter: Option<String>,
+}
+
+pub fn invoke(args: Args) -> anyhow::Result<()> {
+    if let Some(depth) = args.depth {
+        anyhow::ensure!(depth > 0, "depth {depth} is not a positive number");
+    }
+    let filter = match &args.filter {
+        Some(filter) => Some(filter.parse::<Filter>()?.to_string()),
+        None => None,
+    };
+    let since = match &args.shallow_since {
+        Some(since) => Some(date::parse(since)?.0),
+        None => None,
+    };
+    let limits = Limits { depth: args.depth, since, exclude: args.shallow_exclude, filter };
+    // Like git, a shallow clone only has the branch it checks out unless asked otherwise
+    let shallow = limits.depth.is_some() || limits.since.is_some() || !limits.exclude.is_empty();
+    let single_branch = shallow && !args.no_single_branch;
+
+    let dir = match args.dir {
+        Some(dir) => dir,
+        None => default_dir(&args.url)?,
+    };
+    let existed = dir.exists();
+    if existed {
+        let empty = dir.is_dir() && fs::read_dir(&dir).with_context(|| format!("list {}", dir.display()))?.next().is_none();
+        anyhow::ensure!(empty, "destination path '{}' already exists and is not an empty directory.", dir.display());
+    }
+
+    // Local repositories are remembered by their absolute path
+    let url = match Location::parse(&args.url)? {
+        Location::Local(path) if !args.url.starts_with("file://") => fs::canonicalize(&path)
+            .map_err(|_| anyhow::anyhow!("repository '{}' does not exist", args.url))?
+            .display()
+            .to_string(),
+        _ => args.url.clone(),
+    };
+
+    eprintln!("Cloning into '{}'...", dir.display());
+    let mut remote = UploadPack::connect(&url, &args.upload_pack)?;
+
+    fs::create_dir_all(&dir).with_context(|| format!("create {}", dir.display()))?;
+    let cwd = std::env::current_dir().context("get current directory")?;
+    std::env::set_current_dir(&dir).with_context(|| format!("change to {}", dir.display()))?;
+    let result = clone(&mut remote, &url, &limits, single_branch);
+    if result.is_err() {
+        // Don't leave a half-made clone behind
+        std::env::set_current_dir(&cwd).context("change back to the original directory")?;
+        let _ = fs::remove_dir_all(&dir);
+        if existed {
+            let _ = fs::create_dir(&dir);
+        }
+    }
+    result
+}
+
+// Set up the repository in the current directory from `remote`. With `single_branch`, only
+// the branch the remote's HEAD is on (and the tags pointing into it) is fetched.
+fn clone(remote: &mut UploadPack, url: &str, limits: &Limits, single_branch: bool) -> anyhow::Result<()> {
+    init_repository(remote.object_format()?)?;
+    let remote_refs = remote.ls_refs(&["HEAD", "refs/heads/", "refs/tags/"])?;
+    let head = remote_refs.iter().find(|remote_ref| remote_ref.name == "HEAD");
+    let mut wants: Vec<String> = match head {
+        Some(head) if single_branch => head.hash.iter().cloned().collect(),
+        _ => remote_refs.iter().filter_map(|remote_ref| remote_ref.hash.clone()).collect(),
+    };
+    wants.sort();
+    wants.dedup();
+    if !wants.is_empty() {
+        remote.fetch(&wants, &[], limits)?;
+    }
+
+    let mut config = ConfigFile::open(Path::new(".git/config"))?;
+    config.add("remote.origin.url", url)?;
+    let only_branch = head.and_then(|head| head.symref_target.as_deref()).filter(|_| single_branch);
+    match only_branch.and_then(|target| target.strip_prefix("refs/heads/")) {
+        Some(branch) => {
+            config.add("remote.origin.fetch", &format!("+refs/heads/{branch}:refs/remotes/origin/{branch}"))?
+        }
+        None => config.add("remote.origin.fetch", "+refs/heads/*:refs/remotes/origin/*")?,
+    }
+    if let Some(filter) = &limits.filter {
+        // What was left out is to be had from origin
+        config.set("core.repositoryformatversion", "1")?;
+        config.add("remote.origin.promisor", "true")?;
+        config.add("remote.origin.partialclonefilter", filter)?;
+    }
+
+    let message = format!("clone: from {url}");
+    let null = refs::null_hash()?;
+    for remote_ref in &remote_refs {
+        let Some(hash) = &remote_ref.hash else { continue };
+        if let Some(branch) = remote_ref.name.strip_prefix("refs/heads/") {
+            if !single_branch || only_branch == Some(remote_ref.name.as_str()) {
+                refs::update(&format!("refs/remotes/origin/{branch}"), hash, Some(&null), &message)?;
+            }
+        } else if remote_ref.name.starts_with("refs/tags/") {
+            // Only the tags that came along with the branch of a single branch clone
+            if !single_branch || objects::exists(hash)? {
+                refs::update(&remote_ref.name, hash, Some(&null), &message)?;
+            }
+        }
+    }
+
+    // Check out what the remote's HEAD is on: a branch of the same name, or its commit
+    let mut checkout = None;
+    match head {
+        Some(RemoteRef { symref_target: Some(target), hash, .. }) => {
+            refs::set_symbolic("HEAD", target, None)?;
+            match hash {
+                Some(hash) => {
+                    let branch = target.strip_prefix("refs/heads/").unwrap_or(target);
+                    refs::set_symbolic("refs/remotes/origin/HEAD", &format!("refs/remotes/origin/{branch}"), None)?;
+                    refs::update("HEAD", hash, Some(&null), &message)?;
+                    config.add(&format!("branch.{branch}.remote"), "origin")?;
+                    config.add(&format!("branch.{branch}.merge"), target)?;
+                    checkout = Some(hash);
+                }
+                None => eprintln!("warning: You appear to have cloned an empty repository."),
+            }
+        }
+        Some(RemoteRef { hash: Some(hash), .. }) => {
+            refs::update_no_deref("HEAD", hash, &message)?;
+            checkout = Some(hash);
+        }
+        _ if remote_refs.is_empty() => eprintln!("warning: You appear to have cloned an empty repository."),
+        _ => eprintln!("warning: remote HEAD refers to nonexistent ref, unable to checkout"),
+    }
+    // A partial clone needs its configuration to fetch the files it checks out
+    config.write()?;
+    match checkout {
+        Some(commit) => check_out(commit),
+        None => Ok(()),
+    }
+}
+
+fn check_out(commit: &str) -> anyhow::Result<()> {
+    let tree = revision::peel(commit, Kind::Tree)?;
+    let mut index = Index::load()?;
+    checkout::switch_trees(&mut index, None, &tree, false, Operation::Checkout)?;
+    index.write()
+}
+
+// The directory a clone of `url` goes to by default: "repo" for ".../repo.git" or
+// ".../repo/.git".
+fn default_dir(url: &str) -> anyhow::Result<PathBuf> {
+    let path = url.trim_end_matches('/');
+    let path = path.strip_suffix("/.git").unwrap_or(path).trim_end_matches('/');
+    let name = path.rsplit(['/', ':']).next().unwrap_or_default();
+    let name = name.strip_suffix(".git").unwrap_or(name);
+    anyhow::ensure!(!name.is_empty(), "could not guess a directory name for {url}; please give one");
+    Ok(PathBuf::from(name))
+}
@@ -0,0 +1,104 @@
+use super::commit_tree::write_commit;
+use crate::index::Index;
+use crate::objects::{Commit, Object};
+use crate::refs;
+use anyhow::Context;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+pub fn invoke(message: Option<String>, allow_empty: bool) -> anyhow::Result<()> {
+    // A merge that stopped (for conflicts or --no-commit) is concluded by the next commit
+    let merge_head = match fs::read_to_string(".git/MERGE_HEAD") {
+        Ok(hash) => Some(hash.trim().to_string()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e).context("read MERGE_HEAD"),
+    };
+    let message = match (message, &merge_head) {
+        (Some(message), _) => cleanup_message(&message),
+        (None, Some(_)) => {
+            let message = fs::read_to_string(".git/MERGE_MSG").context("read MERGE_MSG")?;
+            let lines: Vec<&str> = message.lines().filter(|line| !line.starts_with('#')).collect();
+            cleanup_message(&lines.join("\n"))
+        }
+        (None, None) => anyhow::bail!("no commit message given (use -m)"),
+    };
+    anyhow::ensure!(!message.is_empty(), "Aborting commit due to empty commit message.");
+
+    // The new tree is whatever is staged
+    let mut index = Index::load()?;
+    anyhow::ensure!(
+        index.entries.iter().all(|entry| entry.stage() == 0),
+        "Committing is not possible because you have unmerged files."
+    );
+    let parent = refs::resolve("HEAD")?;
+    anyhow::ensure!(
+        allow_empty || parent.is_some() || !index.entries.is_empty(),
+        "nothing to commit (use \"add\" to stage changes, or --allow-empty)"
+    );
+    let tree = index.write_tree("").context("failed to write tree")?.to_string();
+    // Save the refreshed tree cache so the next commit can reuse it
+    index.write()?;
+
+    // A merge is worth recording even if it leaves the tree as it was
+    if let Some(parent) = parent.as_ref().filter(|_| !allow_empty && merge_head.is_none()) {
+        let data = Object::read(parent)
+            .with_context(|| format!("read HEAD commit {parent}"))?
+            .into_bytes()?;
+        anyhow::ensure!(
+            Commit::parse(&data)?.tree != tree,
+            "nothing to commit (use \"add\" to stage changes, or --allow-empty)"
+        );
+    }
+
+    let parents: Vec<String> = parent.iter().chain(&merge_head).cloned().collect();
+    let hash = write_commit(&tree, &parents, &format!("{message}\n"))?;
+
+    // Advance the current branch (or a detached HEAD), unless someone moved it meanwhile
+    let subject = message.lines().next().unwrap_or_default();
+    let reflog_message = match (&parent, &merge_head) {
+        (_, Some(_)) => format!("commit (merge): {subject}"),
+        (Some(_), None) => format!("commit: {subject}"),
+        (None, None) => format!("commit (initial): {subject}"),
+    };
+    let expected = match &parent {
+        Some(parent) => parent.clone(),
+        None => refs::null_hash()?,
+    };
+    refs::update("HEAD", &hash, Some(&expected), &reflog_message)?;
+    if merge_head.is_some() {
+        for file in ["MERGE_HEAD", "MERGE_MSG", "MERGE_MODE"] {
+            match fs::remove_file(Path::new(".git").join(file)) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                    return Err(e).with_context(|| format!("remove {file}"))
+                }
+                _ => {}
+            }
+        }
+    }
+
+    let branch = match refs::symbolic_target("HEAD")? {
+        Some(target) => target.strip_prefix("refs/heads/").unwrap_or(&target).to_string(),
+        None => "detached HEAD".to_string(),
+    };
+    let root = if parent.is_none() { " (root-commit)" } else { "" };
+    println!("[{branch}{root} {}] {subject}", &hash[..7]);
+
+    Ok(())
+}
+
+// git's "whitespace" cleanup of messages given with -m: strip trailing whitespace from
+// every line, collapse runs of blank lines and drop leading and trailing ones.
+fn cleanup_message(message: &str) -> String {
+    let mut lines: Vec<&str> = Vec::new();
+    for line in message.lines().map(str::trim_end) {
+        if line.is_empty() && lines.last().map_or(true, |last| last.is_empty()) {
+            continue;
+        }
+        lines.push(line);
+    }
+    while lines.last().is_some_and(|last| last.is_empty()) {
+        lines.pop();
+    }
+    lines.join("\n")
+}
@@ -1,38 +1,106 @@
-use crate::objects::{Kind, Object};
+use crate::objects::{Commit, Kind, Object, Signature};
+use crate::revision;
 use anyhow::Context;
-use std::fmt::Write;
-use std::io::Cursor;
-use chrono::Local;
-
-pub fn invoke(
-    message: String,
-    tree_hash: String,
-    parent_hash: Option<String>,
-) -> anyhow::Result<()> {
-    let mut commit = String::new();
-    writeln!(commit, "tree {tree_hash}")?;
-    if let Some(parent_hash) = parent_hash {
-        writeln!(commit, "parent {parent_hash}")?;
-    }
-
-    let author_name = std::env::var("GIT_AUTHOR_NAME").unwrap_or_else(|_| "Unknown".into());
-    let author_email = std::env::var("GIT_AUTHOR_EMAIL").unwrap_or_else(|_| "unknown@example.com".into());
-    let committer_name = std::env::var("GIT_COMMITTER_NAME").unwrap_or_else(|_| author_name.clone());
-    let committer_email = std::env::var("GIT_COMMITTER_EMAIL").unwrap_or_else(|_| author_email.clone());
-
-    let now = Local::now();
-    let timestamp = now.timestamp();
-    let offset_secs = now.offset().local_minus_utc();
-    let sign = if offset_secs >= 0 { '+' } else { '-' };
-    let abs = offset_secs.abs();
-    let hours = abs / 3600;
-    let minutes = (abs % 3600) / 60;
-    let tz = format!("{sign}{hours:02}{minutes:02}");
-
-    writeln!(commit, "author {} <{}> {} {}", author_name, author_email, timestamp, tz)?;
-    writeln!(commit, "committer {} <{}> {} {}", committer_name, committer_email, timestamp, tz)?;
-    writeln!(commit, "")?;
-    writeln!(commit, "{message}")?;
+use std::fs;
+use std::io::{Cursor, Read};
+
+#[derive(Debug, clap::Args)]
+pub struct Args {
+    /// A paragraph of the commit message; may be given more than once
+    #[clap(short = 'm', value_name = "message")]
+    messages: Vec<String>,
+
+    /// Read the commit message from a file ("-" for stdin); may be given more than once
+    #[clap(short = 'F', value_name = "file")]
+    files: Vec<String>,
+
+    /// A parent commit; may be given more than once (for merge commits)
+    #[clap(short = 'p', value_name = "parent")]
+    parents: Vec<String>,
+
+    tree: String,
+}
+
+pub fn invoke(args: Args) -> anyhow::Result<()> {
+    let tree = object_of_kind(&args.tree, Kind::Tree)?;
+    let mut parents: Vec<String> = Vec::new();
+    for parent in &args.parents {
+        let parent = object_of_kind(parent, Kind::Commit)?;
+        if parents.contains(&parent) {
+            eprintln!("error: duplicate parent {parent} ignored");
+            continue;
+        }
+        parents.push(parent);
+    }
+
+    // Like git, paragraphs given with -m end in a newline and are separated by blank lines,
+    // while files are taken as they are. The -m paragraphs come first.
+    let mut message = String::new();
+    for paragraph in &args.messages {
+        if !message.is_empty() {
+            message.push('\n');
+        }
+        message.push_str(paragraph);
+        if !message.ends_with('\n') {
+            message.push('\n');
+        }
+    }
+    for file in &args.files {
+        if !message.is_empty() {
+            message.push('\n');
+        }
+        message.push_str(&read_message(file)?);
+    }
+    if args.messages.is_empty() && args.files.is_empty() {
+        message = read_message("-")?;
+    }
+
+    let hash = write_commit(&tree, &parents, &message)?;
+
+    println!("{hash}");
+
+    Ok(())
+}
+
+// The object `name` refers to, which must be a `kind` itself: tags and commits are not peeled.
+fn object_of_kind(name: &str, kind: Kind) -> anyhow::Result<String> {
+    let hash = revision::resolve(name).with_context(|| format!("not a valid object name {name}"))?;
+    let object = Object::read(&hash).with_context(|| format!("{hash} is not a valid object"))?;
+    anyhow::ensure!(object.kind == kind, "{hash} is not a valid '{kind}' object");
+    Ok(hash)
+}
+
+fn read_message(file: &str) -> anyhow::Result<String> {
+    if file == "-" {
+        let mut message = String::new();
+        std::io::stdin().read_to_string(&mut message).context("read commit message from stdin")?;
+        return Ok(message);
+    }
+    fs::read_to_string(file).with_context(|| format!("could not open '{file}' for reading"))
+}
+
+// Write a commit object for `tree` with the given parents, stamped with the author and
+// committer from the environment, and return its hash. The message is stored as it is, so it
+// should end with a newline.
+pub(crate) fn write_commit(tree: &str, parents: &[String], message: &str) -> anyhow::Result<String> {
+    let author = Signature::author()?;
+    let mut committer = Signature::committer()?;
+    // Unless dates were given, both lines carry the same timestamp
+    if std::env::var_os("GIT_AUTHOR_DATE").is_none() && std::env::var_os("GIT_COMMITTER_DATE").is_none() {
+        (committer.time, committer.offset) = (author.time, author.offset);
+    }
+
+    let commit = Commit {
+        tree: tree.to_string(),
+        parents: parents.to_vec(),
+        author,
+        committer,
+        encoding: None,
+        gpgsig: None,
+        extra: Vec::new(),
+        message: message.to_string(),
+    }
+    .to_string();
     let hash = Object {
         kind: Kind::Commit,
         size: commit.len() as u64,
@@ -41,7 +109,5 @@ pub fn invoke(
     .write_to_objects()
     .context("write commit object")?;
 
-    println!("{}", hex::encode(hash));
-
-    Ok(())
-}
\ No newline at end of file
+    Ok(hash.to_string())
+}
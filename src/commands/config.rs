@@ -0,0 +1,378 @@
+use crate::config::{self, Config, ConfigFile, Entry, Scope};
+use crate::repository;
+use regex::Regex;
+use std::path::PathBuf;
+
+#[derive(Debug, clap::Args)]
+#[command(group(clap::ArgGroup::new("location").multiple(false)))]
+#[command(group(clap::ArgGroup::new("action").multiple(false)))]
+pub struct Args {
+    /// Use the global file (~/.gitconfig, $XDG_CONFIG_HOME/git/config or $GIT_CONFIG_GLOBAL)
+    #[clap(long, group = "location")]
+    global: bool,
+
+    /// Use the system file (/etc/gitconfig or $GIT_CONFIG_SYSTEM)
+    #[clap(long, group = "location")]
+    system: bool,
+
+    /// Use the repository's .git/config (the default for changes)
+    #[clap(long, group = "location")]
+    local: bool,
+
+    /// Use .git/config.worktree if extensions.worktreeConfig is set, else .git/config
+    #[clap(long, group = "location")]
+    worktree: bool,
+
+    /// Use this file
+    #[clap(short = 'f', long, value_name = "file", group = "location")]
+    file: Option<PathBuf>,
+
+    /// Show the last value of a key: <name> [<value-pattern>]
+    #[clap(long, group = "action")]
+    get: bool,
+
+    /// Show every value of a key: <name> [<value-pattern>]
+    #[clap(long, group = "action")]
+    get_all: bool,
+
+    /// Show the keys matching a regex and their values: <name-regex> [<value-pattern>]
+    #[clap(long, group = "action")]
+    get_regexp: bool,
+
+    /// Add a value to a key, keeping the ones it has: <name> <value>
+    #[clap(long, group = "action")]
+    add: bool,
+
+    /// Replace every value of a key with one: <name> <value> [<value-pattern>]
+    #[clap(long, group = "action")]
+    replace_all: bool,
+
+    /// Remove a key (which must have one value): <name> [<value-pattern>]
+    #[clap(long, group = "action")]
+    unset: bool,
+
+    /// Remove every value of a key: <name> [<value-pattern>]
+    #[clap(long, group = "action")]
+    unset_all: bool,
+
+    /// List all settings
+    #[clap(short = 'l', long, group = "action")]
+    list: bool,
+
+    /// Read and write values as bool, int (with k, m or g suffixes), bool-or-int or path
+    #[clap(long = "type", value_name = "type")]
+    kind: Option<Type>,
+
+    /// Like --type=bool
+    #[clap(long, conflicts_with_all = ["kind", "int", "path"])]
+    bool: bool,
+
+    /// Like --type=int
+    #[clap(long, conflicts_with_all = ["kind", "path"])]
+    int: bool,
+
+    /// Like --type=path
+    #[clap(long, conflicts_with = "kind")]
+    path: bool,
+
+    /// With --get, the value to show when the key isn't set
+    #[clap(long, value_name = "value")]
+    default: Option<String>,
+
+    /// Show which file each setting comes from
+    #[clap(long)]
+    show_origin: bool,
+
+    /// Show the scope (system, global, local, worktree or command) of each setting
+    #[clap(long)]
+    show_scope: bool,
+
+    /// <name> to show, or <name> <value> [<value-pattern>] to set
+    args: Vec<String>,
+}
+
+// What --type values are read and written as.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Type {
+    Bool,
+    Int,
+    BoolOrInt,
+    Path,
+}
+
+impl std::str::FromStr for Type {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Type> {
+        Ok(match s {
+            "bool" => Type::Bool,
+            "int" => Type::Int,
+            "bool-or-int" => Type::BoolOrInt,
+            "path" => Type::Path,
+            _ => anyhow::bail!("unrecognized --type argument, {s}"),
+        })
+    }
+}
+
+// Which values of a key a command applies to: those matching a regex, or with a leading '!'
+// those that don't.
+struct ValuePattern {
+    regex: Regex,
+    negated: bool,
+}
+
+impl ValuePattern {
+    fn parse(pattern: Option<&String>) -> Option<ValuePattern> {
+        let pattern = pattern?;
+        let (negated, pattern) = match pattern.strip_prefix('!') {
+            Some(rest) => (true, rest),
+            None => (false, pattern.as_str()),
+        };
+        match Regex::new(pattern) {
+            Ok(regex) => Some(ValuePattern { regex, negated }),
+            Err(_) => exit(6, &format!("error: invalid pattern: {pattern}")),
+        }
+    }
+
+    fn matches(pattern: &Option<ValuePattern>, value: Option<&str>) -> bool {
+        pattern
+            .as_ref()
+            .map_or(true, |pattern| pattern.regex.is_match(value.unwrap_or_default()) != pattern.negated)
+    }
+}
+
+pub fn invoke(args: Args) -> anyhow::Result<()> {
+    let kind = match (args.kind, args.bool, args.int, args.path) {
+        (Some(kind), ..) => Some(kind),
+        (None, true, true, _) => Some(Type::BoolOrInt),
+        (None, true, _, _) => Some(Type::Bool),
+        (None, _, true, _) => Some(Type::Int),
+        (None, _, _, true) => Some(Type::Path),
+        _ => None,
+    };
+    let count = args.args.len();
+    let arity = |min: usize, max: usize| {
+        anyhow::ensure!(
+            (min..=max).contains(&count),
+            "wrong number of arguments, should be {}",
+            if min == max { min.to_string() } else { format!("from {min} to {max}") }
+        );
+        Ok(())
+    };
+
+    if args.list {
+        arity(0, 0)?;
+        for entry in &read(&args)?.entries {
+            let setting = match &entry.value {
+                Some(value) => format!("{}={value}", entry.key),
+                None => entry.key.clone(),
+            };
+            println!("{}{setting}", prefix(&args, entry));
+        }
+        return Ok(());
+    }
+
+    if args.get_regexp {
+        arity(1, 2)?;
+        let regex = Regex::new(&config::normalize_key(&args.args[0]))
+            .unwrap_or_else(|_| exit(6, &format!("error: invalid key pattern: {}", args.args[0])));
+        let pattern = ValuePattern::parse(args.args.get(1));
+        let mut found = false;
+        for entry in &read(&args)?.entries {
+            if !regex.is_match(&entry.key) || !ValuePattern::matches(&pattern, entry.value.as_deref()) {
+                continue;
+            }
+            found = true;
+            let value = match (&entry.value, kind) {
+                (None, None) => String::new(),
+                (value, kind) => format!(" {}", typed(&entry.key, value.as_deref(), kind)?),
+            };
+            println!("{}{}{value}", prefix(&args, entry), entry.key);
+        }
+        if !found {
+            exit(1, "");
+        }
+        return Ok(());
+    }
+
+    let setting = args.add || args.replace_all || (!args.get && !args.get_all && !args.unset && !args.unset_all && count > 1);
+    if !setting && !args.unset && !args.unset_all {
+        arity(1, 2)?;
+        let key = checked_key(&args.args[0]);
+        let pattern = ValuePattern::parse(args.args.get(1));
+        let config = read(&args)?;
+        let entries: Vec<&Entry> = config
+            .entries
+            .iter()
+            .filter(|entry| entry.key == key && ValuePattern::matches(&pattern, entry.value.as_deref()))
+            .collect();
+        let Some(last) = entries.last() else {
+            match &args.default {
+                Some(default) => println!("{}", typed(&key, Some(default), kind)?),
+                None => exit(1, ""),
+            }
+            return Ok(());
+        };
+        let shown = if args.get_all { &entries[..] } else { std::slice::from_ref(last) };
+        for entry in shown {
+            println!("{}{}", prefix(&args, entry), typed(&key, entry.value.as_deref(), kind)?);
+        }
+        return Ok(());
+    }
+
+    // Changes go to a single file
+    let (path, _) = location(&args)?;
+    let mut file = ConfigFile::open(&path)?;
+    let key = checked_key(&args.args[0]);
+    let matching = |file: &ConfigFile, pattern: &Option<ValuePattern>| -> Vec<usize> {
+        file.settings
+            .iter()
+            .enumerate()
+            .filter(|(_, setting)| setting.key == key && ValuePattern::matches(pattern, setting.value.as_deref()))
+            .map(|(i, _)| i)
+            .collect()
+    };
+
+    if args.unset || args.unset_all {
+        arity(1, 2)?;
+        let pattern = ValuePattern::parse(args.args.get(1));
+        let found = matching(&file, &pattern);
+        match found.len() {
+            0 => exit(5, ""),
+            1 => {}
+            _ if args.unset => exit(5, &format!("warning: {} has multiple values", args.args[0])),
+            _ => {}
+        }
+        for &i in found.iter().rev() {
+            file.replace(i, &args.args[0], None)?;
+        }
+        return file.write();
+    }
+
+    if args.add {
+        arity(2, 2)?;
+    } else {
+        arity(2, 3)?;
+    }
+    let value = match kind {
+        Some(kind) => typed(&key, Some(&args.args[1]), Some(kind))?,
+        None => args.args[1].clone(),
+    };
+    let pattern = ValuePattern::parse(args.args.get(2));
+    let found = if args.add { Vec::new() } else { matching(&file, &pattern) };
+    match found.as_slice() {
+        [] => file.add(&args.args[0], &value)?,
+        [only] => file.replace(*only, &args.args[0], Some(&value))?,
+        // The value takes the place of the last one, the way git does it
+        [rest @ .., last] if args.replace_all => {
+            file.replace(*last, &args.args[0], Some(&value))?;
+            for &i in rest.iter().rev() {
+                file.replace(i, &args.args[0], None)?;
+            }
+        }
+        _ => exit(
+            5,
+            &format!(
+                "warning: {key} has multiple values\n\
+                 error: cannot overwrite multiple values with a single value\n       \
+                 Use a regexp, --add or --replace-all to change {key}."
+            ),
+        ),
+    }
+    file.write()
+}
+
+// git config's distinct exit statuses, which scripts check for.
+fn exit(code: i32, message: &str) -> ! {
+    if !message.is_empty() {
+        eprintln!("{message}");
+    }
+    std::process::exit(code);
+}
+
+// The normalized form of a key given on the command line, after checking it is one.
+fn checked_key(key: &str) -> String {
+    let (Some(first), Some(last)) = (key.find('.'), key.rfind('.')) else {
+        exit(1, &format!("error: key does not contain a section: {key}"));
+    };
+    let section = &key[..first];
+    let valid_section = !section.is_empty() && section.chars().all(|c| c.is_ascii_alphanumeric() || c == '-');
+    if !valid_section || !config::valid_name(&key[last + 1..]) || key[first..last].contains('\n') {
+        exit(1, &format!("error: invalid key: {key}"));
+    }
+    config::normalize_key(key)
+}
+
+// The file a location option names, and its scope; the repository's config by default.
+fn location(args: &Args) -> anyhow::Result<(PathBuf, Scope)> {
+    if let Some(file) = &args.file {
+        return Ok((file.clone(), Scope::Command));
+    }
+    if args.global {
+        // The XDG file is only used if it exists and ~/.gitconfig doesn't
+        let files = config::scope_files(Scope::Global);
+        let path = match files.as_slice() {
+            [xdg, home] if xdg.exists() && !home.exists() => xdg.clone(),
+            [.., last] => last.clone(),
+            [] => anyhow::bail!("$HOME not set"),
+        };
+        return Ok((path, Scope::Global));
+    }
+    if args.system {
+        let path = std::env::var_os("GIT_CONFIG_SYSTEM").map_or_else(|| PathBuf::from("/etc/gitconfig"), PathBuf::from);
+        return Ok((path, Scope::System));
+    }
+
+    let git_dir = repository::git_dir();
+    anyhow::ensure!(git_dir.is_dir(), "not in a git directory");
+    if args.worktree && Config::load_file(&git_dir.join("config"), Scope::Local)?
+        .get_bool("extensions.worktreeConfig")?
+        .unwrap_or(false)
+    {
+        return Ok((git_dir.join("config.worktree"), Scope::Worktree));
+    }
+    Ok((git_dir.join("config"), Scope::Local))
+}
+
+// The settings to show: those of the file given by a location option, or else everything.
+fn read(args: &Args) -> anyhow::Result<Config> {
+    if args.file.is_some() || args.global || args.system || args.local || args.worktree {
+        let (path, scope) = location(args)?;
+        return Config::load_file(&path, scope);
+    }
+    Config::load()
+}
+
+// What --show-scope and --show-origin put before a setting.
+fn prefix(args: &Args, entry: &Entry) -> String {
+    let mut prefix = String::new();
+    if args.show_scope {
+        prefix.push_str(entry.scope.name());
+        prefix.push('\t');
+    }
+    if args.show_origin {
+        match &entry.origin {
+            Some(path) => prefix.push_str(&format!("file:{}\t", path.display())),
+            None => prefix.push_str("command line:\t"),
+        }
+    }
+    prefix
+}
+
+// A value as shown (or stored) for a --type: booleans as true or false, integers without
+// their unit suffix and paths with "~/" expanded.
+fn typed(key: &str, value: Option<&str>, kind: Option<Type>) -> anyhow::Result<String> {
+    Ok(match kind {
+        None => value.unwrap_or_default().to_string(),
+        Some(Type::Bool) => config::parse_bool(key, value)?.to_string(),
+        Some(Type::Int) => config::parse_int(key, value)?.to_string(),
+        Some(Type::BoolOrInt) => match config::parse_int(key, value) {
+            Ok(n) if value.is_some() => n.to_string(),
+            _ => config::parse_bool(key, value)?.to_string(),
+        },
+        Some(Type::Path) => match value {
+            Some(value) => config::expand_home(value).display().to_string(),
+            None => anyhow::bail!("missing value for '{key}'"),
+        },
+    })
+}
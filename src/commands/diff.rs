@@ -0,0 +1,354 @@
+use crate::diff::{self, Algorithm, FilePair, Format, Options, Renames, Side};
+use crate::index::{self, Index, IndexEntry};
+use crate::objects::{Kind, Tree};
+use crate::{refs, revision, worktree};
+use anyhow::Context;
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, clap::Args)]
+pub struct Args {
+    /// Compare the index with a commit (HEAD by default) instead of the working tree with the index
+    #[clap(long, visible_alias = "staged")]
+    cached: bool,
+
+    /// One commit to compare the working tree (or with --cached, the index) with, or two
+    /// commits ("<rev> <rev>" or "<rev>..<rev>") to compare with each other
+    revisions: Vec<String>,
+
+    /// Only show changes to these paths
+    #[clap(last = true)]
+    paths: Vec<PathBuf>,
+
+    #[clap(flatten)]
+    format: FormatArgs,
+}
+
+// Output and algorithm options shared by the diff commands.
+#[derive(Debug, clap::Args)]
+pub(crate) struct FormatArgs {
+    /// Show how many lines changed in each file instead of a patch
+    #[clap(long)]
+    stat: bool,
+
+    /// Show only the names of changed files
+    #[clap(long)]
+    name_only: bool,
+
+    /// Show the names of changed files with their status (A, D, M or T)
+    #[clap(long)]
+    name_status: bool,
+
+    /// Lines of context around each change
+    #[clap(short = 'U', long = "unified", default_value_t = 3)]
+    context: usize,
+
+    /// myers (the default), minimal, patience or histogram
+    #[clap(long)]
+    diff_algorithm: Option<Algorithm>,
+
+    /// Spend extra time to make the diff as small as possible
+    #[clap(long)]
+    minimal: bool,
+
+    /// Use the patience diff algorithm
+    #[clap(long)]
+    patience: bool,
+
+    /// Use the histogram diff algorithm
+    #[clap(long)]
+    histogram: bool,
+
+    /// Detect renames, of files at least <n> similar (50% by default)
+    #[clap(
+        short = 'M',
+        long = "find-renames",
+        value_name = "n",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = ""
+    )]
+    find_renames: Option<String>,
+
+    /// Detect copies as well as renames; given twice, like --find-copies-harder
+    #[clap(
+        short = 'C',
+        long = "find-copies",
+        value_name = "n",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "",
+        action = clap::ArgAction::Append
+    )]
+    find_copies: Vec<String>,
+
+    /// Look for copies of unchanged files too, not only of changed ones
+    #[clap(long)]
+    find_copies_harder: bool,
+
+    /// Don't detect renames, even where that is the default
+    #[clap(long)]
+    no_renames: bool,
+}
+
+// git takes the score of -M and -C attached ("-M90%"), but clap only reads an optional value
+// given with "=", as otherwise "diff -M HEAD~" would take the revision for a score. So the
+// command line of the diff commands gets "--find-renames=90%" and "--find-copies=90%" instead.
+pub fn attach_scores(mut args: Vec<OsString>) -> Vec<OsString> {
+    if !args.get(1).is_some_and(|command| command == "diff" || command == "diff-tree") {
+        return args;
+    }
+    for arg in args.iter_mut().skip(2) {
+        if arg == "--" {
+            break;
+        }
+        let Some(text) = arg.to_str() else { continue };
+        let long = match (text.strip_prefix("-M"), text.strip_prefix("-C")) {
+            (Some(score), _) if !score.is_empty() => format!("--find-renames={score}"),
+            (_, Some(score)) if !score.is_empty() => format!("--find-copies={score}"),
+            _ => continue,
+        };
+        *arg = long.into();
+    }
+    args
+}
+
+impl FormatArgs {
+    // The options asked for, showing `default` unless another format was requested, and
+    // detecting renames if asked to or `renames` by default.
+    pub(crate) fn options(&self, default: Format, renames: bool) -> anyhow::Result<Options> {
+        let format = if self.name_only {
+            Format::NameOnly
+        } else if self.name_status {
+            Format::NameStatus
+        } else if self.stat {
+            Format::Stat
+        } else {
+            default
+        };
+        let algorithm = if self.histogram {
+            Algorithm::Histogram
+        } else if self.patience {
+            Algorithm::Patience
+        } else if self.minimal {
+            Algorithm::Minimal
+        } else {
+            self.diff_algorithm.unwrap_or(Algorithm::Myers)
+        };
+
+        let harder = self.find_copies_harder || self.find_copies.len() > 1;
+        let copies = harder || !self.find_copies.is_empty();
+        let renames = if self.no_renames {
+            None
+        } else if renames || copies || self.find_renames.is_some() {
+            // A score given to -C wins over one given to -M
+            let score = self
+                .find_copies
+                .iter()
+                .rev()
+                .chain(&self.find_renames)
+                .find(|score| !score.is_empty())
+                .map(|score| Renames::parse_score(score))
+                .transpose()?
+                .unwrap_or(diff::DEFAULT_RENAME_SCORE);
+            Some(Renames { score, copies, harder })
+        } else {
+            None
+        };
+        Ok(Options { format, algorithm, context: self.context, renames })
+    }
+}
+
+pub fn invoke(args: Args) -> anyhow::Result<()> {
+    anyhow::ensure!(Path::new(".git").is_dir(), "not in a git directory");
+    // Unlike diff-tree, diff looks for renames unless told not to
+    let options = args.format.options(Format::Patch, true)?;
+    let unchanged = options.renames.is_some_and(|renames| renames.harder);
+
+    // Revisions come first; once an argument isn't one but names a file, the rest are paths
+    let mut trees = Vec::new();
+    let mut paths = Vec::new();
+    for (i, arg) in args.revisions.iter().enumerate() {
+        anyhow::ensure!(!arg.contains("..."), "symmetric ranges ('{arg}') are not supported");
+        if let Some((from, to)) = arg.split_once("..") {
+            trees.push(tree_of(if from.is_empty() { "HEAD" } else { from })?);
+            trees.push(tree_of(if to.is_empty() { "HEAD" } else { to })?);
+            continue;
+        }
+        match tree_of(arg) {
+            Ok(tree) => trees.push(tree),
+            Err(_) if Path::new(arg).exists() => {
+                paths.extend(args.revisions[i..].iter().map(PathBuf::from));
+                break;
+            }
+            Err(e) => {
+                return Err(e).with_context(|| {
+                    format!("ambiguous argument '{arg}': unknown revision or path not in the working tree")
+                })
+            }
+        }
+    }
+    paths.extend(args.paths.iter().cloned());
+    let paths = paths
+        .iter()
+        .map(|path| worktree::repo_path(path))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let mut unmerged = Vec::new();
+    let pairs = match (trees.as_slice(), args.cached) {
+        ([], false) => index_to_worktree(&Index::load()?, &paths, unchanged, &mut unmerged)?,
+        ([], true) => {
+            let head = refs::resolve("HEAD")?.map(|commit| tree_of(&commit)).transpose()?;
+            tree_to_index(head.as_deref(), &Index::load()?, &paths, unchanged, &mut unmerged)?
+        }
+        ([tree], true) => tree_to_index(Some(tree), &Index::load()?, &paths, unchanged, &mut unmerged)?,
+        ([tree], false) => tree_to_worktree(tree, &Index::load()?, &paths, unchanged, &mut unmerged)?,
+        ([old, new], false) => diff::diff_trees(Some(old), Some(new), true, unchanged, &paths)?,
+        _ => anyhow::bail!("usage: diff [--cached] [<commit> [<commit>]] [-- <paths>...]"),
+    };
+    let pairs = diff::detect_renames(pairs, &options)?;
+
+    let stdout = io::stdout();
+    let mut out = io::BufWriter::new(stdout.lock());
+    if options.format == Format::Patch {
+        for path in &unmerged {
+            writeln!(out, "* Unmerged path {path}")?;
+        }
+    }
+    let result = diff::write(&mut out, &pairs, &options).and_then(|()| Ok(out.flush()?));
+    match result {
+        // Stop quietly when the reader (e.g. a pager or `head`) went away
+        Err(e) if e.downcast_ref::<io::Error>().is_some_and(|e| e.kind() == io::ErrorKind::BrokenPipe) => Ok(()),
+        result => result.context("write diff"),
+    }
+}
+
+fn tree_of(rev: &str) -> anyhow::Result<String> {
+    revision::peel(&revision::resolve(rev)?, Kind::Tree)
+}
+
+// The working tree version of an index entry: None if the file is gone, the staged version
+// if it is unchanged, and otherwise the file as it is (hashed, but not written).
+fn worktree_side(entry: &IndexEntry, index_mtime: Option<(u32, u32)>) -> anyhow::Result<Option<Side>> {
+    let meta = match fs::symlink_metadata(&entry.path) {
+        Ok(meta) => meta,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("stat {}", entry.path)),
+    };
+    if entry.mode == 0o160000 {
+        return Ok(Some(Side::new(entry.mode, entry.hash)));
+    }
+    if meta.is_dir() {
+        return Ok(None);
+    }
+    if !entry.is_intent_to_add() && !worktree::is_modified(entry, index_mtime)? {
+        return Ok(Some(Side::new(entry.mode, entry.hash)));
+    }
+    Ok(Some(Side {
+        mode: index::mode_from_metadata(&meta),
+        hash: worktree::hash_file(Path::new(&entry.path), &meta, false)?,
+        worktree: true,
+    }))
+}
+
+// Whether two sides have the same mode and content, wherever that content is read from.
+fn same_version(a: Option<Side>, b: Option<Side>) -> bool {
+    a.map(|side| (side.mode, side.hash)) == b.map(|side| (side.mode, side.hash))
+}
+
+// Unmerged paths are left out of the diffs and listed separately, once each.
+fn note_unmerged(entry: &IndexEntry, unmerged: &mut Vec<String>) -> bool {
+    if entry.stage() == 0 {
+        return false;
+    }
+    if unmerged.last() != Some(&entry.path) {
+        unmerged.push(entry.path.clone());
+    }
+    true
+}
+
+// What changed in the working tree since the files were staged (and with `unchanged`, the
+// files that didn't, as copy sources).
+fn index_to_worktree(
+    index: &Index,
+    paths: &[String],
+    unchanged: bool,
+    unmerged: &mut Vec<String>,
+) -> anyhow::Result<Vec<FilePair>> {
+    let mut pairs = Vec::new();
+    for entry in &index.entries {
+        if !diff::in_paths(&entry.path, paths) || note_unmerged(entry, unmerged) {
+            continue;
+        }
+        // Files added with "add -N" show up as new, with all of their content
+        let old = (!entry.is_intent_to_add()).then(|| Side::new(entry.mode, entry.hash));
+        let new = worktree_side(entry, index.mtime)?;
+        if unchanged || !same_version(old, new) {
+            pairs.push(FilePair::new(entry.path.clone(), old, new));
+        }
+    }
+    Ok(pairs)
+}
+
+// What is staged compared to the tree `tree` (None for the empty tree of an unborn branch).
+fn tree_to_index(
+    tree: Option<&str>,
+    index: &Index,
+    paths: &[String],
+    unchanged: bool,
+    unmerged: &mut Vec<String>,
+) -> anyhow::Result<Vec<FilePair>> {
+    let mut sides: BTreeMap<String, (Option<Side>, Option<Side>)> = BTreeMap::new();
+    if let Some(tree) = tree {
+        for (path, (mode, hash)) in Tree::flatten(tree)? {
+            sides.entry(path).or_default().0 = Some(Side::new(mode, hash));
+        }
+    }
+    for entry in &index.entries {
+        if !diff::in_paths(&entry.path, paths) || note_unmerged(entry, unmerged) || entry.is_intent_to_add() {
+            continue;
+        }
+        sides.entry(entry.path.clone()).or_default().1 = Some(Side::new(entry.mode, entry.hash));
+    }
+    Ok(pairs_from(sides, paths, unchanged, unmerged))
+}
+
+// What changed in the working tree compared to the tree `tree`, for the files in the tree and
+// the ones that are tracked.
+fn tree_to_worktree(
+    tree: &str,
+    index: &Index,
+    paths: &[String],
+    unchanged: bool,
+    unmerged: &mut Vec<String>,
+) -> anyhow::Result<Vec<FilePair>> {
+    let mut sides: BTreeMap<String, (Option<Side>, Option<Side>)> = BTreeMap::new();
+    for (path, (mode, hash)) in Tree::flatten(tree)? {
+        sides.entry(path).or_default().0 = Some(Side::new(mode, hash));
+    }
+    for entry in &index.entries {
+        if !diff::in_paths(&entry.path, paths) || note_unmerged(entry, unmerged) {
+            continue;
+        }
+        sides.entry(entry.path.clone()).or_default().1 = worktree_side(entry, index.mtime)?;
+    }
+    Ok(pairs_from(sides, paths, unchanged, unmerged))
+}
+
+fn pairs_from(
+    sides: BTreeMap<String, (Option<Side>, Option<Side>)>,
+    paths: &[String],
+    unchanged: bool,
+    unmerged: &[String],
+) -> Vec<FilePair> {
+    sides
+        .into_iter()
+        .filter(|(path, (old, new))| {
+            (unchanged || !same_version(*old, *new)) && diff::in_paths(path, paths) && !unmerged.contains(path)
+        })
+        .map(|(path, (old, new))| FilePair::new(path, old, new))
+        .collect()
+}
@@ -0,0 +1,92 @@
+use super::diff::FormatArgs;
+use crate::diff::{self, Format};
+use crate::objects::Kind;
+use crate::{revision, worktree};
+use anyhow::Context;
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+#[derive(Debug, clap::Args)]
+pub struct Args {
+    /// Two tree-ishes to compare, or one commit to compare with its parent; anything after
+    /// those is taken as paths
+    #[clap(required = true)]
+    objects: Vec<String>,
+
+    /// Only show changes to these paths
+    #[clap(last = true)]
+    paths: Vec<PathBuf>,
+
+    /// Descend into subtrees instead of showing them as changed entries
+    #[clap(short = 'r')]
+    recursive: bool,
+
+    /// Show a patch instead of the raw format (implies -r)
+    #[clap(short = 'p', long = "patch")]
+    patch: bool,
+
+    /// Show a root commit as adding all of its files
+    #[clap(long)]
+    root: bool,
+
+    #[clap(flatten)]
+    format: FormatArgs,
+}
+
+pub fn invoke(args: Args) -> anyhow::Result<()> {
+    let options = args.format.options(if args.patch { Format::Patch } else { Format::Raw }, false)?;
+    let unchanged = options.renames.is_some_and(|renames| renames.harder);
+    // Everything but the name formats needs to look at files, not subtrees
+    let recursive = args.recursive || !matches!(options.format, Format::Raw | Format::NameOnly | Format::NameStatus);
+
+    let first = revision::resolve(&args.objects[0])?;
+    let second = match args.objects.get(1) {
+        Some(object) => revision::resolve(object)
+            .and_then(|hash| revision::peel(&hash, Kind::Tree))
+            .ok(),
+        None => None,
+    };
+    let mut paths: Vec<PathBuf> = args.objects[if second.is_some() { 2 } else { 1 }..]
+        .iter()
+        .map(PathBuf::from)
+        .collect();
+    paths.extend(args.paths.iter().cloned());
+    let paths = paths
+        .iter()
+        .map(|path| worktree::repo_path(path))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let stdout = io::stdout();
+    let mut out = io::BufWriter::new(stdout.lock());
+    let result = match second {
+        Some(new) => {
+            let old = revision::peel(&first, Kind::Tree)?;
+            let pairs = diff::diff_trees(Some(&old), Some(&new), recursive, unchanged, &paths)?;
+            diff::write(&mut out, &diff::detect_renames(pairs, &options)?, &options)
+        }
+        None => {
+            // A single commit is compared with its parent: a root commit only with --root, a
+            // merge not at all
+            let commit = revision::peel(&first, Kind::Commit)
+                .with_context(|| format!("{} is not a commit; diff-tree needs two trees", args.objects[0]))?;
+            let parsed = revision::read_commit(&commit)?;
+            let parent = match parsed.parents.as_slice() {
+                [] if args.root => None,
+                [parent] => Some(revision::peel(parent, Kind::Tree)?),
+                _ => return Ok(()),
+            };
+            let pairs = diff::diff_trees(parent.as_deref(), Some(&parsed.tree), recursive, unchanged, &paths)?;
+            let pairs = diff::detect_renames(pairs, &options)?;
+            if pairs.is_empty() {
+                return Ok(());
+            }
+            writeln!(out, "{commit}")?;
+            diff::write(&mut out, &pairs, &options)
+        }
+    };
+    match result.and_then(|()| Ok(out.flush()?)) {
+        // Stop quietly when the reader (e.g. a pager or `head`) went away
+        Err(e) if e.downcast_ref::<io::Error>().is_some_and(|e| e.kind() == io::ErrorKind::BrokenPipe) => Ok(()),
+        result => result.context("write diff"),
+    }
+}
@@ -0,0 +1,429 @@
+use crate::config::Config;
+use crate::objects::{self, Kind, Object};
+use crate::remote::Remote;
+use crate::transport::{Limits, RemoteRef, UploadPack};
+use crate::{refs, repository, revision};
+use anyhow::Context;
+use std::collections::HashSet;
+
+#[derive(Debug, Default, clap::Args)]
+pub struct Args {
+    /// The remote (or URL) to fetch from [default: the current branch's remote, or origin]
+    remote: Option<String>,
+
+    /// What to fetch: [+]<src>[:<dst>], ^<src> to leave out [default: the remote's fetch refspecs]
+    refspecs: Vec<String>,
+
+    /// Remove remote-tracking refs whose branch no longer exists on the remote
+    #[clap(short = 'p', long)]
+    prune: bool,
+
+    /// Update local refs even when that loses commits on them
+    #[clap(short = 'f', long)]
+    force: bool,
+
+    /// Fetch every tag of the remote, not only those pointing into the history fetched
+    #[clap(short = 't', long, conflicts_with = "no_tags")]
+    tags: bool,
+
+    /// Don't fetch the tags that point into the history fetched
+    #[clap(short = 'n', long)]
+    no_tags: bool,
+
+    /// The command that serves local repositories (paths and file:// URLs) [default:
+    /// remote.<name>.uploadpack, or git-upload-pack]
+    #[clap(long, value_name = "upload-pack")]
+    upload_pack: Option<String>,
+}
+
+// A ref of the remote to fetch, and the local ref it goes to.
+struct Fetch {
+    // The full name of the remote ref
+    name: String,
+    hash: String,
+    // Where it is stored; None for FETCH_HEAD only
+    dst: Option<String>,
+    force: bool,
+    // Whether it is recorded in FETCH_HEAD, and if so whether `pull` would merge it (an
+    // entry without "not-for-merge")
+    fetch_head: bool,
+    for_merge: bool,
+}
+
+// Fetch the refs `remote` is configured to fetch, as `remote add -f` does.
+pub(crate) fn fetch_remote(remote: &str) -> anyhow::Result<()> {
+    invoke(Args { remote: Some(remote.to_string()), ..Args::default() })
+}
+
+pub fn invoke(args: Args) -> anyhow::Result<()> {
+    let config = Config::load()?;
+    let head = refs::symbolic_target("HEAD")?;
+    let branch = head.as_deref().and_then(|head| head.strip_prefix("refs/heads/"));
+    let remote_name = match &args.remote {
+        Some(remote) => remote.clone(),
+        None => {
+            let configured = branch
+                .and_then(|branch| config.get(&format!("branch.{branch}.remote")))
+                .unwrap_or("origin");
+            anyhow::ensure!(
+                config.get(&format!("remote.{configured}.url")).is_some(),
+                "No remote repository specified.  Please, specify either a URL or a\n\
+                 remote name from which new revisions should be fetched."
+            );
+            configured.to_string()
+        }
+    };
+    let remote = Remote::get(&config, &remote_name)?;
+    // Like git, the reflog says how the fetch was asked for
+    let message = std::iter::once("fetch")
+        .chain(args.remote.as_deref())
+        .chain(args.refspecs.iter().map(String::as_str))
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    // Tags pointing into the fetched history come along, unless all tags or none are wanted
+    let (all_tags, follow_tags) = match (args.tags, args.no_tags, remote.tag_opt.as_deref()) {
+        (true, _, _) | (false, false, Some("--tags")) => (true, false),
+        (_, true, _) | (false, false, Some("--no-tags")) => (false, false),
+        _ => (false, true),
+    };
+    let from_command_line = !args.refspecs.is_empty();
+    let mut refspecs = if from_command_line { args.refspecs.clone() } else { remote.fetch.clone() };
+    // A URL without configuration fetches whatever its HEAD is on
+    if refspecs.is_empty() {
+        refspecs.push("HEAD".to_string());
+    }
+    let (excluded, mut refspecs): (Vec<String>, Vec<String>) =
+        refspecs.into_iter().partition(|refspec| refspec.starts_with('^'));
+    let configured_refspecs = refspecs.len();
+    if all_tags {
+        refspecs.push("refs/tags/*:refs/tags/*".to_string());
+    }
+
+    let upload_pack = args
+        .upload_pack
+        .as_deref()
+        .or(remote.upload_pack.as_deref())
+        .unwrap_or("git-upload-pack");
+    let mut connection = UploadPack::connect(&remote.url, upload_pack)?;
+    // A partial clone goes on leaving out what it did when it was made
+    let limits = Limits { filter: remote.filter.clone(), ..Limits::default() };
+    let mut prefixes: Vec<String> = refspecs.iter().flat_map(|refspec| ls_refs_prefixes(refspec)).collect();
+    if follow_tags {
+        prefixes.push("refs/tags/".to_string());
+    }
+    let prefixes: Vec<&str> = prefixes.iter().map(String::as_str).collect();
+    let remote_refs = connection.ls_refs(&prefixes)?;
+
+    // branch.<name>.merge of the current branch is what `pull` merges, if it is from here
+    let merge = branch
+        .filter(|branch| remote.name.is_some() && config.get(&format!("branch.{branch}.remote")) == remote.name.as_deref())
+        .and_then(|branch| config.get(&format!("branch.{branch}.merge")));
+    let mut fetches: Vec<Fetch> = Vec::new();
+    for refspec in &refspecs {
+        match_refspec(refspec, from_command_line, &remote, &remote_refs, &excluded, &mut fetches)?;
+    }
+    for fetch in &mut fetches {
+        fetch.force |= args.force;
+        if !from_command_line {
+            fetch.for_merge = merge == Some(fetch.name.as_str());
+        }
+    }
+    if let Some(head) = &head {
+        if fetches.iter().any(|fetch| fetch.dst.as_ref() == Some(head)) {
+            let cwd = std::env::current_dir().context("get current directory")?;
+            anyhow::bail!("refusing to fetch into branch '{head}' checked out at '{}'", cwd.display());
+        }
+    }
+
+    // Everything the new refs need that isn't here yet, negotiated from all our refs
+    let mut tips: Vec<String> = refs::list("refs/")?.into_iter().map(|(_, hash)| hash).collect();
+    tips.extend(refs::resolve("HEAD")?);
+    let wants = missing(fetches.iter().map(|fetch| &fetch.hash))?;
+    if !wants.is_empty() {
+        connection.fetch(&wants, &tips, &limits)?;
+    }
+
+    // Tags of the remote pointing at anything we now have, which we don't have yet
+    if follow_tags {
+        let mut followed = Vec::new();
+        for remote_ref in &remote_refs {
+            let Some(hash) = &remote_ref.hash else { continue };
+            if !remote_ref.name.starts_with("refs/tags/")
+                || fetches.iter().any(|fetch| fetch.name == remote_ref.name)
+                || refs::resolve(&remote_ref.name)?.is_some()
+            {
+                continue;
+            }
+            let target = remote_ref.peeled.as_ref().unwrap_or(hash);
+            if objects::exists(target)? {
+                followed.push(Fetch {
+                    name: remote_ref.name.clone(),
+                    hash: hash.clone(),
+                    dst: Some(remote_ref.name.clone()),
+                    force: false,
+                    fetch_head: true,
+                    for_merge: false,
+                });
+            }
+        }
+        // Annotated tags of objects we had already aren't in the pack
+        let wants = missing(followed.iter().map(|fetch| &fetch.hash))?;
+        if !wants.is_empty() {
+            connection.fetch(&wants, &tips, &limits)?;
+        }
+        fetches.extend(followed);
+    }
+    drop(connection);
+
+    let prune = args.prune || remote.prune.or(config.get_bool("fetch.prune")?).unwrap_or(false);
+    let mut lines = Vec::new();
+    if prune {
+        // Only what the remote's own refspecs map to, not the tags fetched by --tags
+        for refspec in &refspecs[..configured_refspecs] {
+            prune_stale(refspec, &remote_refs, &fetches, &mut lines)?;
+        }
+    }
+
+    let mut rejected = false;
+    for fetch in &fetches {
+        let Some(dst) = &fetch.dst else {
+            let kind = if fetch.name.starts_with("refs/tags/") {
+                "tag"
+            } else if fetch.name.starts_with("refs/remotes/") {
+                "remote-tracking branch"
+            } else {
+                "branch"
+            };
+            lines.push(('*', kind.to_string(), short(&fetch.name).to_string(), "FETCH_HEAD".to_string(), String::new()));
+            continue;
+        };
+        let Some((flag, summary, reason)) = update(fetch, dst, &message)? else { continue };
+        rejected |= flag == '!';
+        lines.push((flag, summary, short(&fetch.name).to_string(), short(dst).to_string(), reason));
+    }
+
+    write_fetch_head(&fetches, &remote.url)?;
+    if !lines.is_empty() {
+        // Remote names line up, in a column at least 10 wide
+        let width = lines.iter().map(|(_, _, from, _, _)| from.len()).max().unwrap_or(0).max(10);
+        eprintln!("From {}", remote.url);
+        for (flag, summary, from, to, reason) in lines {
+            eprintln!(" {flag} {summary:<17} {from:<width$} -> {to}{reason}");
+        }
+    }
+    if rejected {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+// Find the remote refs `refspec` covers, with where they go. A pattern like
+// "+refs/heads/*:refs/remotes/origin/*" covers every ref it matches but those `excluded`;
+// anything else must name a ref the remote has, "main" being short for "refs/heads/main".
+fn match_refspec(
+    refspec: &str,
+    from_command_line: bool,
+    remote: &Remote,
+    remote_refs: &[RemoteRef],
+    excluded: &[String],
+    fetches: &mut Vec<Fetch>,
+) -> anyhow::Result<()> {
+    let force = refspec.starts_with('+');
+    let refspec = refspec.trim_start_matches('+');
+    let (src, dst) = match refspec.split_once(':') {
+        Some((src, dst)) => (src, (!dst.is_empty()).then_some(dst)),
+        None => (refspec, None),
+    };
+    let mut add = |fetch: Fetch| {
+        if !fetches.iter().any(|known| known.name == fetch.name && known.dst == fetch.dst) {
+            fetches.push(fetch);
+        }
+    };
+
+    if src.contains('*') {
+        for remote_ref in remote_refs {
+            let Some(hash) = &remote_ref.hash else { continue };
+            let name = &remote_ref.name;
+            if refs::map_refspec(&format!("{src}:{src}"), name).is_none()
+                || excluded.iter().any(|excluded| {
+                    let excluded = excluded.trim_start_matches('^');
+                    refs::map_refspec(&format!("{excluded}:{excluded}"), name).is_some()
+                })
+            {
+                continue;
+            }
+            let dst = dst.and_then(|dst| refs::map_refspec(&format!("{src}:{dst}"), name));
+            add(Fetch { name: name.clone(), hash: hash.clone(), dst, force, fetch_head: true, for_merge: false });
+        }
+        return Ok(());
+    }
+
+    let found = ls_refs_prefixes(src)
+        .into_iter()
+        .find_map(|candidate| remote_refs.iter().find(|remote_ref| remote_ref.name == candidate));
+    let Some(RemoteRef { name, hash: Some(hash), .. }) = found else {
+        anyhow::bail!("couldn't find remote ref {src}");
+    };
+    let dst = match dst {
+        Some(dst) if dst.starts_with("refs/") => Some(dst.to_string()),
+        Some(dst) if name.starts_with("refs/tags/") => Some(format!("refs/tags/{dst}")),
+        Some(dst) => Some(format!("refs/heads/{dst}")),
+        None => None,
+    };
+    let fetch = Fetch { name: name.clone(), hash: hash.clone(), dst, force, fetch_head: true, for_merge: from_command_line };
+    // A ref fetched by name on its own still updates its remote-tracking ref, if it has one
+    if fetch.dst.is_none() && from_command_line {
+        let tracking = remote
+            .fetch
+            .iter()
+            .filter(|refspec| !refspec.starts_with('^'))
+            .find_map(|refspec| Some((refs::map_refspec(refspec, name)?, refspec.starts_with('+'))));
+        if let Some((tracking, force)) = tracking {
+            add(Fetch {
+                name: name.clone(),
+                hash: hash.clone(),
+                dst: Some(tracking),
+                force,
+                fetch_head: false,
+                for_merge: false,
+            });
+        }
+    }
+    add(fetch);
+    Ok(())
+}
+
+// The ref names ls-refs is asked about for the source of a refspec: what a pattern starts
+// with, or the full names a short name may stand for.
+fn ls_refs_prefixes(refspec: &str) -> Vec<String> {
+    let src = refspec.trim_start_matches('+').split(':').next().unwrap_or_default();
+    if let Some((prefix, _)) = src.split_once('*') {
+        return vec![prefix.to_string()];
+    }
+    vec![
+        src.to_string(),
+        format!("refs/{src}"),
+        format!("refs/tags/{src}"),
+        format!("refs/heads/{src}"),
+        format!("refs/remotes/{src}"),
+        format!("refs/remotes/{src}/HEAD"),
+    ]
+}
+
+// The objects of `hashes` we don't have, once each.
+fn missing<'a>(hashes: impl Iterator<Item = &'a String>) -> anyhow::Result<Vec<String>> {
+    let mut wants = Vec::new();
+    for hash in hashes {
+        if !objects::exists(hash)? {
+            wants.push(hash.clone());
+        }
+    }
+    wants.sort();
+    wants.dedup();
+    Ok(wants)
+}
+
+// Move `dst` to what was fetched for it, if that is allowed: new refs and fast-forwards are,
+// anything else only when forced, and tags never change unless forced. Returns the flag,
+// summary and reason to show, or None if the ref is up to date.
+fn update(fetch: &Fetch, dst: &str, message: &str) -> anyhow::Result<Option<(char, String, String)>> {
+    let abbreviated = |hash: &str| revision::abbreviate(hash, 7);
+    let old = refs::resolve(dst)?;
+    let Some(old) = old else {
+        let (summary, what) = if fetch.name.starts_with("refs/tags/") {
+            ("[new tag]", "storing tag")
+        } else if fetch.name.starts_with("refs/heads/") {
+            ("[new branch]", "storing head")
+        } else {
+            ("[new ref]", "storing ref")
+        };
+        refs::update(dst, &fetch.hash, Some(&refs::null_hash()?), &format!("{message}: {what}"))?;
+        return Ok(Some(('*', summary.to_string(), String::new())));
+    };
+    if old == fetch.hash {
+        return Ok(None);
+    }
+
+    if dst.starts_with("refs/tags/") {
+        if !fetch.force {
+            return Ok(Some(('!', "[rejected]".to_string(), "  (would clobber existing tag)".to_string())));
+        }
+        refs::update(dst, &fetch.hash, Some(&old), &format!("{message}: updating tag"))?;
+        return Ok(Some(('t', "[tag update]".to_string(), String::new())));
+    }
+    let is_commit = |hash: &str| Object::read(hash).is_ok_and(|object| object.kind == Kind::Commit);
+    if is_commit(&old) && is_commit(&fetch.hash) && revision::is_ancestor(&old, &fetch.hash)? {
+        refs::update(dst, &fetch.hash, Some(&old), &format!("{message}: fast-forward"))?;
+        let summary = format!("{}..{}", abbreviated(&old)?, abbreviated(&fetch.hash)?);
+        return Ok(Some((' ', summary, String::new())));
+    }
+    if !fetch.force {
+        return Ok(Some(('!', "[rejected]".to_string(), "  (non-fast-forward)".to_string())));
+    }
+    refs::update(dst, &fetch.hash, Some(&old), &format!("{message}: forced-update"))?;
+    let summary = format!("{}...{}", abbreviated(&old)?, abbreviated(&fetch.hash)?);
+    Ok(Some(('+', summary, "  (forced update)".to_string())))
+}
+
+// Delete the refs a pattern refspec maps remote refs to, for those the remote no longer has.
+fn prune_stale(
+    refspec: &str,
+    remote_refs: &[RemoteRef],
+    fetches: &[Fetch],
+    lines: &mut Vec<(char, String, String, String, String)>,
+) -> anyhow::Result<()> {
+    let Some((src, dst)) = refspec.trim_start_matches('+').split_once(':') else {
+        return Ok(());
+    };
+    let Some((prefix, _)) = dst.split_once('*') else {
+        return Ok(());
+    };
+    let remote_names: HashSet<&str> = remote_refs.iter().map(|remote_ref| remote_ref.name.as_str()).collect();
+    for (name, hash) in refs::list(prefix)? {
+        // Symbolic refs like refs/remotes/origin/HEAD go with what they point at
+        if refs::symbolic_target(&name)?.is_some() {
+            continue;
+        }
+        let Some(source) = refs::map_refspec(&format!("{dst}:{src}"), &name) else { continue };
+        if remote_names.contains(source.as_str()) || fetches.iter().any(|fetch| fetch.dst.as_ref() == Some(&name)) {
+            continue;
+        }
+        refs::delete(&name, Some(&hash))?;
+        lines.push(('-', "[deleted]".to_string(), "(none)".to_string(), short(&name).to_string(), String::new()));
+    }
+    Ok(())
+}
+
+// Record what was fetched in .git/FETCH_HEAD, what `pull` is to merge first:
+// "<hash>\t[not-for-merge]\t<kind> '<name>' of <url>".
+fn write_fetch_head(fetches: &[Fetch], url: &str) -> anyhow::Result<()> {
+    let url = url.trim_end_matches('/');
+    let url = url.strip_suffix(".git").unwrap_or(url);
+    let mut text = String::new();
+    for for_merge in [true, false] {
+        for fetch in fetches.iter().filter(|fetch| fetch.fetch_head && fetch.for_merge == for_merge) {
+            let marker = if for_merge { "" } else { "not-for-merge" };
+            let description = if fetch.name == "HEAD" {
+                url.to_string()
+            } else if let Some(branch) = fetch.name.strip_prefix("refs/heads/") {
+                format!("branch '{branch}' of {url}")
+            } else if let Some(tag) = fetch.name.strip_prefix("refs/tags/") {
+                format!("tag '{tag}' of {url}")
+            } else if let Some(tracking) = fetch.name.strip_prefix("refs/remotes/") {
+                format!("remote-tracking branch '{tracking}' of {url}")
+            } else {
+                format!("'{}' of {url}", fetch.name)
+            };
+            text.push_str(&format!("{}\t{marker}\t{description}\n", fetch.hash));
+        }
+    }
+    std::fs::write(repository::git_dir().join("FETCH_HEAD"), text).context("write .git/FETCH_HEAD")
+}
+
+fn short(name: &str) -> &str {
+    ["refs/heads/", "refs/tags/", "refs/remotes/"]
+        .iter()
+        .find_map(|prefix| name.strip_prefix(prefix))
+        .unwrap_or(name)
+}
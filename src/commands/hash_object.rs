@@ -4,13 +4,13 @@ use std::fs;
 use anyhow::Context;
 use flate2::write::ZlibEncoder;
 use flate2::Compression;
-use sha1::{Sha1, Digest};
-use hex;
+use crate::objects::{Format, Hasher};
+use crate::repository;
 
 // Struct to write data and compute its hash
 struct HashWriter<W> {
     writer: W,
-    hasher: Sha1,
+    hasher: Hasher,
 }
 
 // Implement writing for the HashWriter
@@ -32,14 +32,15 @@ where
 }
 
 pub fn invoke(write: bool, file: PathBuf) -> anyhow::Result<()> {
-    // Writes a blob in the Git format and returns its SHA-1 hash
+    // Writes a blob in the Git format and returns its hash (SHA-1, or SHA-256 in repositories
+    // of that format)
     fn write_blob<W>(file: &Path, writer: W) -> anyhow::Result<String>
     where
         W: Write,
     {
         // Obtain the file metadata
         let stat =
-            std::fs::metadata(&file).with_context(|| format!("stat {}", file.display()))?;
+            std::fs::metadata(file).with_context(|| format!("stat {}", file.display()))?;
 
         // Create a Zlib compressor to write in Git format
         let writer = ZlibEncoder::new(writer, Compression::default());
@@ -47,21 +48,21 @@ pub fn invoke(write: bool, file: PathBuf) -> anyhow::Result<()> {
         // Wrap the writer
         let mut writer = HashWriter {
             writer,
-            hasher: Sha1::new(),
+            hasher: Format::current()?.hasher(),
         };
 
         // Write the blob header: "blob <size>\0"
         write!(writer, "blob {}\0", stat.len())?;
 
         // Open the file and copy its contents to the writer
-        let mut file = std::fs::File::open(&file)
+        let mut file = std::fs::File::open(file)
             .with_context(|| format!("open {}", file.display()))?;
         std::io::copy(&mut file, &mut writer).context("stream file into blob")?;
 
-        // Finish the writing and obtain the SHA-1 hash
+        // Finish the writing and obtain the hash
         let _ = writer.writer.finish()?;
         let hash = writer.hasher.finalize();
-        Ok(hex::encode(hash))
+        Ok(hash.to_string())
     }
 
     // Determine if the object should be written to the file system based on the write parameter
@@ -76,11 +77,12 @@ pub fn invoke(write: bool, file: PathBuf) -> anyhow::Result<()> {
         .context("write out blob object")?;
 
         // Create the appropriate directory for the blob object based on its hash
-        fs::create_dir_all(format!(".git/objects/{}/", &hash[..2]))
+        let objects = repository::git_dir().join("objects");
+        fs::create_dir_all(objects.join(&hash[..2]))
             .context("create subdir of .git/objects")?;
 
         // Move the temporary file to its final location
-        std::fs::rename(tmp, format!(".git/objects/{}/{}", &hash[..2], &hash[2..]))
+        std::fs::rename(tmp, objects.join(format!("{}/{}", &hash[..2], &hash[2..])))
             .context("move blob file into .git/objects")?;
         hash
     } else {
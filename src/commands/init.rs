@@ -1,10 +1,31 @@
+use crate::objects::Format;
+use crate::refs;
+use anyhow::Context;
 use std::fs;
 
-pub fn invoke() -> anyhow::Result<()> {
+// What `git init` writes to .git/config for a non-bare repository.
+const CONFIG: &str = "[core]\n\trepositoryformatversion = 0\n\tfilemode = true\n\tbare = false\n\tlogallrefupdates = true\n";
+
+pub fn invoke(object_format: &str) -> anyhow::Result<()> {
+    init_repository(object_format.parse()?)?;
+    println!("Initialized git directory");
+    Ok(())
+}
+
+// Create .git in the current directory, with HEAD on an unborn main branch and objects named
+// by `format`.
+pub(crate) fn init_repository(format: Format) -> anyhow::Result<()> {
     fs::create_dir(".git")?;
     fs::create_dir(".git/objects")?;
     fs::create_dir(".git/refs")?;
-    fs::write(".git/HEAD", "ref: refs/heads/main\n")?;
-    println!("Initialized git directory");
-    Ok(())
-}
\ No newline at end of file
+    refs::set_symbolic("HEAD", "refs/heads/main", None)?;
+    let config = match format {
+        Format::Sha1 => CONFIG.to_string(),
+        // Versions of git that don't know about extensions must keep out of the repository
+        _ => format!(
+            "{}[extensions]\n\tobjectformat = {format}\n",
+            CONFIG.replace("repositoryformatversion = 0", "repositoryformatversion = 1")
+        ),
+    };
+    fs::write(".git/config", config).context("write .git/config")
+}
@@ -0,0 +1,901 @@
+use crate::date::{self, DateFormat};
+use crate::diff::{self, Renames};
+use crate::graph::Graph;
+use crate::objects::{Commit, Kind, Object, ObjectId, Signature, Tree};
+use crate::{refs, repository, revision, worktree};
+use anyhow::Context;
+use regex::Regex;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::slice;
+
+#[derive(Debug, clap::Args)]
+pub struct Args {
+    /// Commits to start from (default HEAD); "^<rev>" and "<rev>..<rev>" exclude history
+    revisions: Vec<String>,
+
+    /// Only show commits that change these paths
+    #[clap(last = true)]
+    paths: Vec<PathBuf>,
+
+    /// Show at most this many commits
+    #[clap(short = 'n', long)]
+    max_count: Option<usize>,
+
+    /// One line per commit: abbreviated hash and subject
+    #[clap(long)]
+    oneline: bool,
+
+    /// Draw the commit history as a graph next to the log
+    #[clap(long)]
+    graph: bool,
+
+    /// oneline, short, medium, full, fuller, raw, format:<string> or tformat:<string>
+    #[clap(long, visible_alias = "pretty")]
+    format: Option<String>,
+
+    /// Only commits whose author ("name <email>") matches this regular expression
+    #[clap(long)]
+    author: Option<String>,
+
+    /// Only commits made after this date
+    #[clap(long, visible_alias = "after")]
+    since: Option<String>,
+
+    /// Only commits made before this date
+    #[clap(long, visible_alias = "before")]
+    until: Option<String>,
+
+    /// Show no parent before all of its children, keeping lines of history together
+    #[clap(long)]
+    topo_order: bool,
+
+    /// Show no parent before all of its children, otherwise in commit date order
+    #[clap(long)]
+    date_order: bool,
+
+    /// How to show dates: default, iso, iso-strict, rfc, short, raw, unix or relative
+    #[clap(long, default_value = "default")]
+    date: DateFormat,
+
+    /// Continue the history of a file (the only path given) past renames and copies
+    #[clap(long)]
+    follow: bool,
+}
+
+// How each commit is shown.
+#[derive(Debug, PartialEq, Eq)]
+enum Format {
+    Oneline,
+    Short,
+    Medium,
+    Full,
+    Fuller,
+    Raw,
+    // A string with %-placeholders. "format:" separates commits with newlines, "tformat:"
+    // terminates each of them with one
+    User { template: String, terminator: bool },
+}
+
+impl std::str::FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Format> {
+        Ok(match s {
+            "oneline" => Format::Oneline,
+            "short" => Format::Short,
+            "medium" => Format::Medium,
+            "full" => Format::Full,
+            "fuller" => Format::Fuller,
+            "raw" => Format::Raw,
+            _ => {
+                if let Some(template) = s.strip_prefix("format:") {
+                    Format::User { template: template.to_string(), terminator: false }
+                } else if let Some(template) = s.strip_prefix("tformat:") {
+                    Format::User { template: template.to_string(), terminator: true }
+                } else if s.contains('%') {
+                    Format::User { template: s.to_string(), terminator: true }
+                } else {
+                    anyhow::bail!("invalid --pretty format: {s}")
+                }
+            }
+        })
+    }
+}
+
+// The commits in the requested range, in the order a date-ordered walk reaches them, after
+// git's default history simplification for the given paths.
+struct History {
+    commits: HashMap<String, Commit>,
+    order: Vec<String>,
+    // Parents as far as the walk is concerned: a merge that has the same content as one of
+    // its parents (for the paths) is reduced to that parent
+    parents: HashMap<String, Vec<String>>,
+    // Commits that don't change the paths, which are walked through but not shown
+    treesame: HashSet<String>,
+}
+
+pub fn invoke(args: Args) -> anyhow::Result<()> {
+    anyhow::ensure!(repository::git_dir().is_dir(), "not in a git directory");
+    let format = match &args.format {
+        Some(format) => format.parse()?,
+        None if args.oneline => Format::Oneline,
+        None => Format::Medium,
+    };
+
+    // Revisions come first; once an argument isn't one but names a file, the rest are paths
+    let mut includes = Vec::new();
+    let mut excludes = Vec::new();
+    let mut paths = Vec::new();
+    for (i, arg) in args.revisions.iter().enumerate() {
+        if let Some(rev) = arg.strip_prefix('^') {
+            excludes.push(commit_of(rev)?);
+        } else if let Some((from, to)) = arg.split_once("..").filter(|_| !arg.contains("...")) {
+            excludes.push(commit_of(if from.is_empty() { "HEAD" } else { from })?);
+            includes.push(commit_of(if to.is_empty() { "HEAD" } else { to })?);
+        } else {
+            match commit_of(arg) {
+                Ok(hash) => includes.push(hash),
+                Err(_) if Path::new(arg).exists() => {
+                    paths.extend(args.revisions[i..].iter().map(PathBuf::from));
+                    break;
+                }
+                Err(e) => {
+                    return Err(e).with_context(|| {
+                        format!("ambiguous argument '{arg}': unknown revision or path not in the working tree")
+                    })
+                }
+            }
+        }
+    }
+    if includes.is_empty() && excludes.is_empty() {
+        let head = refs::resolve("HEAD")?.ok_or_else(|| {
+            let branch = refs::symbolic_target("HEAD").ok().flatten().unwrap_or_default();
+            let branch = branch.strip_prefix("refs/heads/").unwrap_or(&branch).to_string();
+            anyhow::anyhow!("your current branch '{branch}' does not have any commits yet")
+        })?;
+        includes.push(head);
+    }
+    paths.extend(args.paths.iter().cloned());
+    let paths = paths
+        .iter()
+        .map(|path| worktree::repo_path(path))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    anyhow::ensure!(!args.follow || paths.len() == 1, "--follow requires exactly one pathspec");
+
+    let since = args.since.as_deref().map(date::parse).transpose()?.map(|(time, _)| time);
+    let until = args.until.as_deref().map(date::parse).transpose()?.map(|(time, _)| time);
+    let author = args
+        .author
+        .as_deref()
+        .map(Regex::new)
+        .transpose()
+        .context("invalid --author pattern")?;
+
+    // Following a file, the paths change along the way: history can't be simplified for them
+    let history = walk(&includes, &excludes, if args.follow { &[] } else { &paths }, since)?;
+    let in_range: HashSet<&str> = history.order.iter().map(String::as_str).collect();
+    let shown = |hash: &str| {
+        let Some(commit) = history.commits.get(hash) else {
+            return false;
+        };
+        in_range.contains(hash)
+            && !history.treesame.contains(hash)
+            && until.map_or(true, |until| commit.committer.time <= until)
+            && author.as_ref().map_or(true, |author| {
+                author.is_match(&format!("{} <{}>", commit.author.name, commit.author.email))
+            })
+    };
+
+    let order = if args.topo_order || args.date_order || args.graph {
+        sort_topologically(&history, args.date_order)
+    } else {
+        history.order.clone()
+    };
+    let following = if args.follow {
+        Some(follow(&history, &order, &paths[0], shown)?)
+    } else {
+        None
+    };
+
+    let decorations = match &format {
+        Format::User { template, .. } if template.contains("%d") || template.contains("%D") => decorations()?,
+        _ => HashMap::new(),
+    };
+    let terminator = match &format {
+        Format::Oneline => true,
+        Format::User { terminator, .. } => *terminator,
+        _ => false,
+    };
+
+    let stdout = io::stdout();
+    let mut out = Output {
+        out: io::BufWriter::new(stdout.lock()),
+        graph: args.graph.then(Graph::new),
+    };
+    let mut count = 0;
+    let mut shown_one = false;
+    let mut missing_newline = false;
+    for hash in &order {
+        if !shown(hash) {
+            continue;
+        }
+        let skipped = following.as_ref().is_some_and(|following| !following.contains(hash));
+        if !skipped && args.max_count.is_some_and(|max| count >= max) {
+            break;
+        }
+
+        let commit = &history.commits[hash];
+        // With paths, the graph connects each commit to its nearest shown ancestors
+        let parents = if args.graph && !paths.is_empty() {
+            rewrite_parents(&history, hash)
+        } else {
+            history.parents[hash].clone()
+        };
+        if let Some(graph) = &mut out.graph {
+            graph.update(hash, parents.iter().filter(|parent| shown(parent)).cloned().collect());
+        }
+        // Following a file, commits that don't change it are only walked: the graph shows
+        // where they were left out
+        if skipped {
+            continue;
+        }
+        count += 1;
+
+        if shown_one && !terminator {
+            if !missing_newline {
+                out.show_padding()?;
+            }
+            out.write("\n")?;
+        }
+        out.show_commit()?;
+
+        match &format {
+            Format::Oneline => {
+                let name = if args.oneline { revision::abbreviate(hash, 7)? } else { hash.clone() };
+                out.write(&format!("{name} "))?;
+            }
+            Format::User { .. } => {}
+            _ => {
+                out.write(&format!("commit {hash}\n"))?;
+                out.show_oneline()?;
+            }
+        }
+
+        let message = pretty(&format, hash, commit, &parents, args.date, &decorations)?;
+        missing_newline = !message.ends_with('\n');
+        out.show_message(&message)?;
+
+        if terminator {
+            if !missing_newline {
+                out.show_padding()?;
+            }
+            out.write("\n")?;
+        }
+        shown_one = true;
+    }
+
+    match out.out.flush() {
+        Err(e) if e.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+        result => result.context("write log"),
+    }
+}
+
+fn commit_of(rev: &str) -> anyhow::Result<String> {
+    revision::peel(&revision::resolve(rev)?, Kind::Commit)
+}
+
+// Walk from the `includes` towards the roots, newest commit first, leaving out everything
+// reachable from the `excludes` and not going past commits older than `since`.
+fn walk(includes: &[String], excludes: &[String], paths: &[String], since: Option<i64>) -> anyhow::Result<History> {
+    let mut hidden = HashSet::new();
+    for exclude in excludes {
+        hidden.extend(revision::reachable(exclude)?);
+    }
+
+    let mut history = History {
+        commits: HashMap::new(),
+        order: Vec::new(),
+        parents: HashMap::new(),
+        treesame: HashSet::new(),
+    };
+    // Ordered by commit date, then first come first served
+    let mut queue = BinaryHeap::new();
+    let mut queued = 0usize;
+    let mut seen = HashSet::new();
+    for hash in includes {
+        if !hidden.contains(hash) && seen.insert(hash.clone()) {
+            let commit = load(&mut history, hash)?;
+            queue.push((commit.committer.time, Reverse(queued), hash.clone()));
+            queued += 1;
+        }
+    }
+
+    while let Some((time, _, hash)) = queue.pop() {
+        if since.is_some_and(|since| time < since) {
+            continue;
+        }
+
+        let parents = if paths.is_empty() {
+            history.commits[&hash].parents.clone()
+        } else {
+            simplify(&mut history, &hash, paths, &hidden)?
+        };
+        for parent in &parents {
+            if !hidden.contains(parent) && seen.insert(parent.clone()) {
+                let commit = load(&mut history, parent)?;
+                queue.push((commit.committer.time, Reverse(queued), parent.clone()));
+                queued += 1;
+            }
+        }
+        history.parents.insert(hash.clone(), parents);
+        history.order.push(hash);
+    }
+    Ok(history)
+}
+
+fn load<'a>(history: &'a mut History, hash: &str) -> anyhow::Result<&'a Commit> {
+    if !history.commits.contains_key(hash) {
+        let commit = revision::read_commit(hash)?;
+        history.commits.insert(hash.to_string(), commit);
+    }
+    Ok(&history.commits[hash])
+}
+
+// Decide whether the commit changes any of `paths`, and which parents to follow: a merge
+// that has the same content as one of its parents only continues through that parent, as
+// the other branches didn't contribute to the result.
+fn simplify(history: &mut History, hash: &str, paths: &[String], hidden: &HashSet<String>) -> anyhow::Result<Vec<String>> {
+    let commit = history.commits[hash].clone();
+    if commit.parents.is_empty() {
+        let mut empty = true;
+        for path in paths {
+            empty &= path_entry(&commit.tree, path)?.is_none();
+        }
+        if empty {
+            history.treesame.insert(hash.to_string());
+        }
+        return Ok(Vec::new());
+    }
+
+    // Parents outside of the range can't make a merge interesting if others are in it
+    let mut relevant_parents = false;
+    let mut relevant_change = false;
+    let mut irrelevant_change = false;
+    for parent in &commit.parents {
+        let relevant = !hidden.contains(parent);
+        relevant_parents |= relevant;
+        let parent_tree = load(history, parent)?.tree.clone();
+        if same_paths(&parent_tree, &commit.tree, paths)? {
+            if relevant {
+                history.treesame.insert(hash.to_string());
+                return Ok(vec![parent.clone()]);
+            }
+        } else if relevant {
+            relevant_change = true;
+        } else {
+            irrelevant_change = true;
+        }
+    }
+    let changed = if relevant_parents { relevant_change } else { irrelevant_change };
+    if !changed {
+        history.treesame.insert(hash.to_string());
+    }
+    Ok(commit.parents)
+}
+
+// The commits that change the file `path`, going back in `order` (the order they are shown
+// in): where a commit creates the file, it is followed on from the file it was renamed or
+// copied from, if any. Merges are left out.
+fn follow(history: &History, order: &[String], path: &str, shown: impl Fn(&str) -> bool) -> anyhow::Result<HashSet<String>> {
+    let renames = Renames { score: diff::DEFAULT_RENAME_SCORE, copies: true, harder: true };
+    let mut path = path.to_string();
+    let mut changes = HashSet::new();
+    for hash in order.iter().filter(|hash| shown(hash)) {
+        let commit = &history.commits[hash];
+        let parent = match commit.parents.as_slice() {
+            [] => None,
+            [parent] => match history.commits.get(parent) {
+                Some(parent) => Some(parent.tree.clone()),
+                None => Some(revision::read_commit(parent)?.tree),
+            },
+            _ => continue,
+        };
+        let pairs = diff::diff_trees(parent.as_deref(), Some(&commit.tree), true, false, slice::from_ref(&path))?;
+        if pairs.is_empty() {
+            continue;
+        }
+        changes.insert(hash.clone());
+
+        if parent.is_some() && pairs.iter().any(|pair| pair.old.is_none()) {
+            let pairs = diff::diff_trees(parent.as_deref(), Some(&commit.tree), true, true, &[])?;
+            let origin = diff::find_renames(pairs, &renames, Some(&path))?
+                .into_iter()
+                .find(|pair| pair.path == path)
+                .and_then(|pair| pair.origin);
+            if let Some(origin) = origin {
+                path = origin.path;
+            }
+        }
+    }
+    Ok(changes)
+}
+
+// Whether the two trees have the same content at every one of `paths`.
+fn same_paths(a: &str, b: &str, paths: &[String]) -> anyhow::Result<bool> {
+    for path in paths {
+        if path_entry(a, path)? != path_entry(b, path)? {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+// The mode and hash of what `path` names in `tree` ("" for the tree itself), if anything.
+fn path_entry(tree: &str, path: &str) -> anyhow::Result<Option<(String, ObjectId)>> {
+    let mut hash = tree.to_string();
+    let mut mode = "40000".to_string();
+    for component in path.split('/').filter(|c| !c.is_empty()) {
+        if mode != "40000" {
+            return Ok(None);
+        }
+        let data = Object::read(&hash).with_context(|| format!("read tree {hash}"))?.into_bytes()?;
+        let Some(entry) = Tree::parse(&data)?.entries.into_iter().find(|entry| entry.name == component) else {
+            return Ok(None);
+        };
+        hash = entry.hash.to_string();
+        mode = entry.mode;
+    }
+    let id = hash.parse().with_context(|| format!("invalid hash {hash}"))?;
+    Ok(Some((mode, id)))
+}
+
+// The parents of a commit as drawn in the graph, skipping over commits that are not shown
+// because they don't change the paths.
+fn rewrite_parents(history: &History, hash: &str) -> Vec<String> {
+    let mut parents: Vec<String> = Vec::new();
+    for parent in &history.parents[hash] {
+        let mut parent = parent.as_str();
+        let rewritten = loop {
+            if !history.treesame.contains(parent) {
+                break Some(parent);
+            }
+            match history.parents.get(parent).and_then(|parents| parents.first()) {
+                Some(next) => parent = next,
+                None => break None,
+            }
+        };
+        if let Some(rewritten) = rewritten {
+            if !parents.iter().any(|p| p == rewritten) {
+                parents.push(rewritten.to_string());
+            }
+        }
+    }
+    parents
+}
+
+// Reorder the walk so that no commit comes before all of its children have been shown.
+// Without `by_date`, a line of history is followed as far as possible before moving on
+// (git's --topo-order); with it, the newest commit whose children are done comes next.
+fn sort_topologically(history: &History, by_date: bool) -> Vec<String> {
+    // One plus the number of children in the walk
+    let mut indegree: HashMap<&str, usize> = history.order.iter().map(|hash| (hash.as_str(), 1)).collect();
+    for hash in &history.order {
+        for parent in &history.parents[hash] {
+            if let Some(count) = indegree.get_mut(parent.as_str()) {
+                *count += 1;
+            }
+        }
+    }
+
+    // A stack for topo order, a date-ordered queue otherwise
+    let mut queue = BinaryHeap::new();
+    let mut queued = 0i64;
+    let mut push = |queue: &mut BinaryHeap<(i64, i64, String)>, hash: &str| {
+        let key = if by_date {
+            (history.commits[hash].committer.time, -queued)
+        } else {
+            (0, queued)
+        };
+        queue.push((key.0, key.1, hash.to_string()));
+        queued += 1;
+    };
+
+    // The tips come out in walk order
+    let tips: Vec<&String> = history.order.iter().filter(|hash| indegree[hash.as_str()] == 1).collect();
+    if by_date {
+        tips.iter().for_each(|tip| push(&mut queue, tip));
+    } else {
+        tips.iter().rev().for_each(|tip| push(&mut queue, tip));
+    }
+
+    let mut sorted = Vec::with_capacity(history.order.len());
+    while let Some((_, _, hash)) = queue.pop() {
+        for parent in &history.parents[&hash] {
+            if let Some(count) = indegree.get_mut(parent.as_str()) {
+                *count -= 1;
+                if *count == 1 {
+                    push(&mut queue, parent);
+                }
+            }
+        }
+        sorted.push(hash);
+    }
+    sorted
+}
+
+// The names of refs pointing at each commit, the way %d shows them: HEAD first (as
+// "HEAD -> <branch>" when on a branch), then the other refs.
+fn decorations() -> anyhow::Result<HashMap<String, Vec<String>>> {
+    let mut names: HashMap<String, Vec<(String, String)>> = HashMap::new();
+    // git lists them newest-added first, and adds them in reverse name order
+    for (name, hash) in refs::list("refs/")?.into_iter().rev() {
+        let short = if let Some(tag) = name.strip_prefix("refs/tags/") {
+            format!("tag: {tag}")
+        } else if let Some(short) = name.strip_prefix("refs/heads/").or_else(|| name.strip_prefix("refs/remotes/")) {
+            short.to_string()
+        } else {
+            name.clone()
+        };
+        let target = revision::peel_tags(&hash).unwrap_or_else(|_| hash.clone());
+        names.entry(target).or_default().push((name, short));
+    }
+
+    let mut decorations = HashMap::new();
+    let head = refs::resolve("HEAD")?;
+    let head_branch = refs::symbolic_target("HEAD")?;
+    for (hash, refs) in names {
+        let mut list = Vec::new();
+        let mut current = None;
+        if head.as_ref() == Some(&hash) {
+            current = refs.iter().find(|(name, _)| Some(name) == head_branch.as_ref());
+            match current {
+                Some((_, short)) => list.push(format!("HEAD -> {short}")),
+                None => list.push("HEAD".to_string()),
+            }
+        }
+        for decoration in &refs {
+            if Some(decoration) != current {
+                list.push(decoration.1.clone());
+            }
+        }
+        decorations.insert(hash, list);
+    }
+    if let Some(head) = head {
+        decorations.entry(head).or_insert_with(|| vec!["HEAD".to_string()]);
+    }
+    Ok(decorations)
+}
+
+// The text of one commit's log entry, after the "commit <hash>" line.
+fn pretty(
+    format: &Format,
+    hash: &str,
+    commit: &Commit,
+    parents: &[String],
+    date: DateFormat,
+    decorations: &HashMap<String, Vec<String>>,
+) -> anyhow::Result<String> {
+    let mut text = String::new();
+    let person = |text: &mut String, what: &str, signature: &Signature| {
+        // fuller lines the identities up with the dates below them
+        let pad = if *format == Format::Fuller { "    " } else { "" };
+        text.push_str(&format!("{what}: {pad}{} <{}>\n", signature.name, signature.email));
+    };
+    match format {
+        Format::Oneline => return Ok(commit.subject()),
+        Format::User { template, .. } => return expand(template, hash, commit, parents, date, decorations),
+        Format::Raw => {
+            // The headers as stored, not as parsed: the order and any git doesn't know of stay
+            let data = Object::read(hash)?.into_bytes()?;
+            let object = Commit::decode(&data);
+            let headers = object.split_once("\n\n").map_or(&*object, |(headers, _)| headers);
+            text.push_str(headers);
+            text.push('\n');
+        }
+        _ => {
+            if parents.len() > 1 {
+                text.push_str("Merge:");
+                for parent in parents {
+                    text.push(' ');
+                    text.push_str(&revision::abbreviate(parent, 7)?);
+                }
+                text.push('\n');
+            }
+            match format {
+                Format::Medium => {
+                    person(&mut text, "Author", &commit.author);
+                    let when = date::format(commit.author.time, commit.author.offset, date);
+                    text.push_str(&format!("Date:   {when}\n"));
+                }
+                Format::Fuller => {
+                    person(&mut text, "Author", &commit.author);
+                    let when = date::format(commit.author.time, commit.author.offset, date);
+                    text.push_str(&format!("AuthorDate: {when}\n"));
+                    person(&mut text, "Commit", &commit.committer);
+                    let when = date::format(commit.committer.time, commit.committer.offset, date);
+                    text.push_str(&format!("CommitDate: {when}\n"));
+                }
+                Format::Full => {
+                    person(&mut text, "Author", &commit.author);
+                    person(&mut text, "Commit", &commit.committer);
+                }
+                _ => person(&mut text, "Author", &commit.author),
+            }
+        }
+    }
+    text.push('\n');
+
+    // The message, indented by four spaces (short only shows its first paragraph)
+    let expand_tabs = matches!(format, Format::Medium | Format::Full | Format::Fuller);
+    let mut first = true;
+    for line in commit.message.lines() {
+        let line = line.trim_end();
+        if line.is_empty() {
+            if first {
+                continue;
+            }
+            if *format == Format::Short {
+                break;
+            }
+        }
+        first = false;
+        text.push_str("    ");
+        if expand_tabs {
+            text.push_str(&expand_tabs_in(line));
+        } else {
+            text.push_str(line);
+        }
+        text.push('\n');
+    }
+
+    text.truncate(text.trim_end().len());
+    text.push('\n');
+    Ok(text)
+}
+
+// Replace tabs by spaces up to the next multiple of eight columns.
+fn expand_tabs_in(line: &str) -> String {
+    let mut expanded = String::new();
+    let mut width = 0;
+    for c in line.chars() {
+        if c == '\t' {
+            let spaces = 8 - width % 8;
+            expanded.push_str(&" ".repeat(spaces));
+            width += spaces;
+        } else {
+            expanded.push(c);
+            width += 1;
+        }
+    }
+    expanded
+}
+
+// Fill in the %-placeholders of a user format.
+fn expand(
+    template: &str,
+    hash: &str,
+    commit: &Commit,
+    parents: &[String],
+    date: DateFormat,
+    decorations: &HashMap<String, Vec<String>>,
+) -> anyhow::Result<String> {
+    let mut out = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('%') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start + 1..];
+
+        let mut chars = rest.chars();
+        let consumed = match chars.next() {
+            Some('H') => {
+                out.push_str(hash);
+                1
+            }
+            Some('h') => {
+                out.push_str(&revision::abbreviate(hash, 7)?);
+                1
+            }
+            Some('T') => {
+                out.push_str(&commit.tree);
+                1
+            }
+            Some('t') => {
+                out.push_str(&revision::abbreviate(&commit.tree, 7)?);
+                1
+            }
+            Some('P') => {
+                out.push_str(&parents.join(" "));
+                1
+            }
+            Some('p') => {
+                let abbreviated = parents
+                    .iter()
+                    .map(|parent| revision::abbreviate(parent, 7))
+                    .collect::<anyhow::Result<Vec<_>>>()?;
+                out.push_str(&abbreviated.join(" "));
+                1
+            }
+            Some(who @ ('a' | 'c')) => {
+                let signature = if who == 'a' { &commit.author } else { &commit.committer };
+                match chars.next().and_then(|field| person_field(signature, field, date)) {
+                    Some(value) => {
+                        out.push_str(&value);
+                        2
+                    }
+                    None => {
+                        out.push('%');
+                        0
+                    }
+                }
+            }
+            Some('s') => {
+                out.push_str(&commit.subject());
+                1
+            }
+            Some('b') => {
+                out.push_str(commit.body());
+                1
+            }
+            Some('B') => {
+                out.push_str(commit.raw_body());
+                1
+            }
+            Some('e') => {
+                out.push_str(commit.encoding.as_deref().unwrap_or_default());
+                1
+            }
+            Some(kind @ ('d' | 'D')) => {
+                if let Some(names) = decorations.get(hash).filter(|names| !names.is_empty()) {
+                    if kind == 'd' {
+                        out.push_str(&format!(" ({})", names.join(", ")));
+                    } else {
+                        out.push_str(&names.join(", "));
+                    }
+                }
+                1
+            }
+            Some('n') => {
+                out.push('\n');
+                1
+            }
+            Some('%') => {
+                out.push('%');
+                1
+            }
+            Some('x') => match rest.get(1..3).and_then(|digits| u8::from_str_radix(digits, 16).ok()) {
+                Some(byte) => {
+                    out.push(byte as char);
+                    3
+                }
+                None => {
+                    out.push('%');
+                    0
+                }
+            },
+            // Unknown placeholders are shown as they are
+            _ => {
+                out.push('%');
+                0
+            }
+        };
+        rest = &rest[consumed..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+// %an, %ae, %ad and friends; `field` is the letter after 'a' or 'c'.
+fn person_field(signature: &Signature, field: char, date: DateFormat) -> Option<String> {
+    let when = |format| date::format(signature.time, signature.offset, format);
+    Some(match field {
+        'n' => signature.name.clone(),
+        'e' => signature.email.clone(),
+        'd' => when(date),
+        'D' => when(DateFormat::Rfc),
+        'r' => when(DateFormat::Relative),
+        't' => when(DateFormat::Unix),
+        'i' => when(DateFormat::Iso),
+        'I' => when(DateFormat::IsoStrict),
+        's' => when(DateFormat::Short),
+        _ => return None,
+    })
+}
+
+// Writes the log, with the graph (if any) to the left of every line.
+struct Output<W: Write> {
+    out: W,
+    graph: Option<Graph>,
+}
+
+impl<W: Write> Output<W> {
+    fn write(&mut self, text: &str) -> anyhow::Result<()> {
+        match self.out.write_all(text.as_bytes()) {
+            // Stop quietly when the reader (e.g. a pager or `head`) went away
+            Err(e) if e.kind() == io::ErrorKind::BrokenPipe => std::process::exit(0),
+            result => result.context("write log"),
+        }
+    }
+
+    // The graph's next line, as the prefix of a line of text.
+    fn show_oneline(&mut self) -> anyhow::Result<()> {
+        if let Some(graph) = &mut self.graph {
+            let (line, _) = graph.next_line();
+            self.write(&line)?;
+        }
+        Ok(())
+    }
+
+    fn show_padding(&mut self) -> anyhow::Result<()> {
+        if let Some(graph) = &mut self.graph {
+            let line = graph.padding_line();
+            self.write(&line)?;
+        }
+        Ok(())
+    }
+
+    // Graph lines up to and including the prefix of the commit's own line.
+    fn show_commit(&mut self) -> anyhow::Result<()> {
+        let Some(graph) = &mut self.graph else {
+            return Ok(());
+        };
+        if graph.is_finished() {
+            let line = graph.padding_line();
+            return self.write(&line);
+        }
+        loop {
+            let graph = self.graph.as_mut().expect("graph is present");
+            let (line, is_commit_line) = graph.next_line();
+            self.write(&line)?;
+            if is_commit_line {
+                return Ok(());
+            }
+            self.write("\n")?;
+        }
+    }
+
+    // The remaining graph lines of the current commit.
+    fn show_remainder(&mut self) -> anyhow::Result<()> {
+        while let Some(graph) = self.graph.as_mut().filter(|graph| !graph.is_finished()) {
+            let (line, _) = graph.next_line();
+            let finished = graph.is_finished();
+            self.write(&line)?;
+            if finished {
+                break;
+            }
+            self.write("\n")?;
+        }
+        Ok(())
+    }
+
+    // A commit's text, each line after the first with a graph prefix, followed by whatever
+    // graph lines the commit still needs.
+    fn show_message(&mut self, message: &str) -> anyhow::Result<()> {
+        let mut rest = message;
+        while !rest.is_empty() {
+            let (line, next) = match rest.find('\n') {
+                Some(end) => rest.split_at(end + 1),
+                None => (rest, ""),
+            };
+            self.write(line)?;
+            if !next.is_empty() {
+                self.show_oneline()?;
+            }
+            rest = next;
+        }
+
+        if self.graph.as_ref().is_some_and(|graph| !graph.is_finished()) {
+            let newline_terminated = message.ends_with('\n');
+            if !newline_terminated {
+                self.write("\n")?;
+            }
+            self.show_remainder()?;
+            if newline_terminated {
+                self.write("\n")?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,51 @@
+use crate::index::Index;
+use crate::worktree;
+use anyhow::Context;
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+pub fn invoke(stage: bool, paths: Vec<PathBuf>) -> anyhow::Result<()> {
+    let index = Index::load()?;
+    let filters = paths
+        .iter()
+        .map(|path| worktree::repo_path(path))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let stdout = io::stdout();
+    let mut out = io::BufWriter::new(stdout.lock());
+    match list(&index, &filters, stage, &mut out).and_then(|()| out.flush()) {
+        // Stop quietly when the reader (e.g. a pager or `head`) went away
+        Err(e) if e.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+        result => result.context("write file list"),
+    }
+}
+
+fn list(index: &Index, filters: &[String], stage: bool, out: &mut impl Write) -> io::Result<()> {
+    for entry in &index.entries {
+        let wanted = filters.is_empty()
+            || filters.iter().any(|filter| {
+                filter.is_empty() || entry.path == *filter || entry.path.starts_with(&format!("{filter}/"))
+            });
+        if !wanted {
+            continue;
+        }
+
+        if stage {
+            writeln!(
+                out,
+                "{:06o} {} {}\t{}",
+                entry.mode,
+                entry.hash,
+                entry.stage(),
+                entry.path
+            )?;
+        } else {
+            // Unmerged paths have several entries but are listed once
+            if entry.stage() > 1 && index.entries.iter().any(|e| e.path == entry.path && e.stage() < entry.stage()) {
+                continue;
+            }
+            writeln!(out, "{}", entry.path)?;
+        }
+    }
+    Ok(())
+}
@@ -1,57 +1,41 @@
-use crate::objects::{Kind, Object};
+use crate::objects::{Kind, Object, Tree};
+use crate::revision;
 use anyhow::Context;
-use std::{
-    ffi::CStr,
-    io::{BufRead, Read, Write},
-};
+use std::io::{Read, Write};
 
 pub fn invoke(name_only: bool, tree_hash: &str) -> anyhow::Result<()> {
+    // Accept any tree-ish: commits and tags are peeled down to their tree
+    let tree_hash = revision::resolve(tree_hash)?;
+    let tree_hash = revision::peel(&tree_hash, Kind::Tree)?;
+
     // Read the object file that corresponds to the tree hash
-    let mut object = Object::read(tree_hash).context("parse out tree object file")?;
+    let mut object = Object::read(&tree_hash).context("parse out tree object file")?;
 
     match object.kind {
         Kind::Tree => {
-            // Read the tree object file, one entry at a time
+            // Parse the whole tree object into its entries
             let mut buf = Vec::new();
-            let mut hashbuf = [0; 20];
+            object
+                .reader
+                .read_to_end(&mut buf)
+                .context("read tree object")?;
+            let tree = Tree::parse(&buf).context("parse tree object")?;
+
             let stdout = std::io::stdout();
             let mut stdout = stdout.lock();
-            loop {
-                // Read the next tree entry
-                buf.clear();
-                let n = object
-                    .reader
-                    .read_until(0, &mut buf)
-                    .context("read next tree object entry")?;
-                if n == 0 {
-                    break;
-                }
-
-                // Read the hash of the tree entry object
-                object
-                    .reader
-                    .read_exact(&mut hashbuf[..])
-                    .context("read tree entry object hash")?;
-
-                // Parse the tree entry
-                let mode_and_name =
-                    CStr::from_bytes_with_nul(&buf).context("invalid tree entry")?;
-
-                let mut bits = mode_and_name.to_bytes().splitn(2, |&b| b == b' ');
-                let mode = bits.next().expect("split always yields once");
-                let name = bits
-                    .next()
-                    .ok_or_else(|| anyhow::anyhow!("tree entry has no file name"))?;
-
-                let name = std::str::from_utf8(name).context("name is always valid utf-8")?;
-                let mode = std::str::from_utf8(mode).context("mode is always valid utf-8")?;
-
+            for entry in tree.entries {
                 // Print the tree entry
-                let hash = hex::encode(&hashbuf);
                 if name_only {
-                    writeln!(stdout, "{name}")?;
+                    writeln!(stdout, "{}", entry.name)?;
                 } else {
-                    writeln!(stdout, "{mode:0>6} tree {hash} {name}")?;
+                    writeln!(
+                        stdout,
+                        "{:0>6} {} {}\t{}",
+                        entry.mode,
+                        entry.kind(),
+                        entry.hash,
+                        entry.name
+                    )?;
                 }
             }
         }
@@ -59,4 +43,4 @@ pub fn invoke(name_only: bool, tree_hash: &str) -> anyhow::Result<()> {
     }
 
     Ok(())
-}
\ No newline at end of file
+}
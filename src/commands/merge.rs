@@ -0,0 +1,270 @@
+use super::commit_tree::write_commit;
+use crate::checkout::{self, Operation};
+use crate::config::Config;
+use crate::diff::{self, Algorithm, Format, Options, Renames};
+use crate::index::Index;
+use crate::merge::{self, ConflictStyle};
+use crate::objects::{Kind, Tree};
+use crate::{refs, revision, worktree};
+use anyhow::Context;
+use std::collections::BTreeSet;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+
+#[derive(Debug, clap::Args)]
+pub struct Args {
+    /// The commit to merge into the current branch
+    #[clap(required_unless_present = "abort")]
+    commit: Option<String>,
+
+    /// Create a merge commit even when the branch could be fast-forwarded
+    #[clap(long, conflicts_with = "ff_only")]
+    no_ff: bool,
+
+    /// Refuse to merge unless the branch can be fast-forwarded
+    #[clap(long)]
+    ff_only: bool,
+
+    /// Stop before committing the merge, as if it had conflicts
+    #[clap(long)]
+    no_commit: bool,
+
+    /// Message for the merge commit
+    #[clap(short = 'm', long)]
+    message: Option<String>,
+
+    /// Give up on a merge with conflicts and go back to the state before it
+    #[clap(long, conflicts_with_all = ["commit", "no_ff", "ff_only", "no_commit", "message"])]
+    abort: bool,
+}
+
+pub fn invoke(args: Args) -> anyhow::Result<()> {
+    if args.abort {
+        return abort();
+    }
+    let name = args.commit.as_deref().expect("clap requires a commit");
+
+    let mut index = Index::load()?;
+    anyhow::ensure!(
+        index.entries.iter().all(|entry| entry.stage() == 0),
+        "Merging is not possible because you have unmerged files."
+    );
+    anyhow::ensure!(
+        !Path::new(".git/MERGE_HEAD").exists(),
+        "You have not concluded your merge (MERGE_HEAD exists).\nPlease, commit your changes before you merge."
+    );
+
+    let theirs = revision::peel(&revision::resolve(name)?, Kind::Commit)
+        .with_context(|| format!("{name} - not something we can merge"))?;
+    let their_tree = revision::peel(&theirs, Kind::Tree)?;
+
+    // Merging into an unborn branch just checks the commit out
+    let Some(head) = refs::resolve("HEAD")? else {
+        checkout::switch_trees(&mut index, None, &their_tree, false, Operation::Merge)?;
+        index.write()?;
+        return refs::update("HEAD", &theirs, Some(&refs::null_hash()?), "initial pull");
+    };
+    let head_tree = revision::peel(&head, Kind::Tree)?;
+
+    if revision::is_ancestor(&theirs, &head)? {
+        println!("Already up to date.");
+        return Ok(());
+    }
+    if !args.no_ff && revision::is_ancestor(&head, &theirs)? {
+        println!("Updating {}..{}", revision::abbreviate(&head, 7)?, revision::abbreviate(&theirs, 7)?);
+        checkout::switch_trees(&mut index, Some(&head_tree), &their_tree, false, Operation::Merge)?;
+        index.write()?;
+        println!("Fast-forward");
+        refs::update_no_deref("ORIG_HEAD", &head, "")?;
+        refs::update("HEAD", &theirs, Some(&head), &format!("merge {name}: Fast-forward"))?;
+        return write_diffstat(&head_tree, &their_tree);
+    }
+    anyhow::ensure!(!args.ff_only, "Not possible to fast-forward, aborting.");
+
+    // The merge starts from the index matching HEAD, so nothing staged can get mixed into it
+    let staged = staged_changes(&index, &head_tree)?;
+    if !staged.is_empty() {
+        eprintln!(
+            "error: Your local changes to the following files would be overwritten by merge:\n  {}",
+            staged.join(" ")
+        );
+        fail();
+    }
+
+    let style = match Config::load()?.get("merge.conflictStyle") {
+        Some(style) => style.parse()?,
+        None => ConflictStyle::Merge,
+    };
+    let options = merge::Options { ours: "HEAD".to_string(), theirs: name.to_string(), style };
+    let outcome = merge::merge_commits(&head, &theirs, &options)?;
+
+    refs::update_no_deref("ORIG_HEAD", &head, "")?;
+    if let Err(e) = checkout::switch_trees(&mut index, Some(&head_tree), &outcome.tree, false, Operation::Merge) {
+        eprintln!("error: {e:#}");
+        fail();
+    }
+    for conflict in &outcome.conflicts {
+        index.add_conflict(&conflict.path, conflict.stages);
+    }
+    index.write()?;
+
+    for message in outcome.messages.values().flatten() {
+        println!("{message}");
+    }
+
+    let message = match &args.message {
+        Some(message) => message.trim_end().to_string(),
+        None => merge_message(name)?,
+    };
+    if outcome.conflicts.is_empty() && !args.no_commit {
+        let hash = write_commit(&outcome.tree, &[head.clone(), theirs], &format!("{message}\n"))?;
+        let strategy = "Merge made by the 'ort' strategy.";
+        refs::update("HEAD", &hash, Some(&head), &format!("merge {name}: {strategy}"))?;
+        println!("{strategy}");
+        return write_diffstat(&head_tree, &outcome.tree);
+    }
+
+    // Leave the rest to commit, which picks up MERGE_HEAD as the second parent
+    let mut merge_msg = format!("{message}\n");
+    if !outcome.conflicts.is_empty() {
+        let paths: BTreeSet<&str> = outcome.conflicts.iter().map(|conflict| conflict.path.as_str()).collect();
+        merge_msg.push_str("\n# Conflicts:\n");
+        for path in paths {
+            merge_msg.push_str(&format!("#\t{path}\n"));
+        }
+    }
+    fs::write(".git/MERGE_HEAD", format!("{theirs}\n")).context("write MERGE_HEAD")?;
+    fs::write(".git/MERGE_MSG", merge_msg).context("write MERGE_MSG")?;
+    fs::write(".git/MERGE_MODE", if args.no_ff { "no-ff" } else { "" }).context("write MERGE_MODE")?;
+
+    if outcome.conflicts.is_empty() {
+        println!("Automatic merge went well; stopped before committing as requested");
+        return Ok(());
+    }
+    println!("Automatic merge failed; fix conflicts and then commit the result.");
+    std::process::exit(1);
+}
+
+// A merge that couldn't start exits with status 2, like git's merge strategies.
+fn fail() -> ! {
+    eprintln!("Merge with strategy ort failed.");
+    std::process::exit(2);
+}
+
+// The paths where the index differs from the tree `head`.
+fn staged_changes(index: &Index, head: &str) -> anyhow::Result<Vec<String>> {
+    let mut tree = Tree::flatten(head)?;
+    let mut changed = Vec::new();
+    for entry in &index.entries {
+        if tree.remove(&entry.path) != Some((entry.mode, entry.hash)) {
+            changed.push(entry.path.clone());
+        }
+    }
+    changed.extend(tree.into_keys());
+    changed.sort();
+    Ok(changed)
+}
+
+// git's default message: "Merge branch 'topic'", naming the kind of ref merged and, unless
+// it is master or main, the branch merged into.
+fn merge_message(name: &str) -> anyhow::Result<String> {
+    // An ancestor of a branch ("topic~2", "topic^") is its "early part"
+    let mut stem = name;
+    loop {
+        if let Some(rest) = stem.strip_suffix('^') {
+            stem = rest;
+        } else if let Some((rest, _)) = stem.rsplit_once('~').filter(|(_, n)| n.bytes().all(|b| b.is_ascii_digit())) {
+            stem = rest;
+        } else {
+            break;
+        }
+    }
+    if stem != name && refs::resolve(&format!("refs/heads/{stem}"))?.is_some() {
+        return Ok(format!("Merge branch '{stem}' (early part){}", merge_destination()?));
+    }
+
+    let what = match refs::dwim(name)? {
+        Some(full) if full.starts_with("refs/heads/") => format!("branch '{}'", &full["refs/heads/".len()..]),
+        Some(full) if full.starts_with("refs/remotes/") => {
+            format!("remote-tracking branch '{}'", &full["refs/remotes/".len()..])
+        }
+        Some(full) if full.starts_with("refs/tags/") => format!("tag '{}'", &full["refs/tags/".len()..]),
+        _ => format!("commit '{name}'"),
+    };
+    Ok(format!("Merge {what}{}", merge_destination()?))
+}
+
+// " into <branch>" for merge messages, left out for master and main.
+fn merge_destination() -> anyhow::Result<String> {
+    Ok(match refs::symbolic_target("HEAD")? {
+        Some(target) => match target.strip_prefix("refs/heads/").unwrap_or(&target) {
+            "master" | "main" => String::new(),
+            branch => format!(" into {branch}"),
+        },
+        None => " into HEAD".to_string(),
+    })
+}
+
+// The stat and summary of what the merge brought in.
+fn write_diffstat(old: &str, new: &str) -> anyhow::Result<()> {
+    let options = Options {
+        format: Format::Stat,
+        algorithm: Algorithm::Myers,
+        context: 3,
+        renames: Some(Renames { score: diff::DEFAULT_RENAME_SCORE, copies: false, harder: false }),
+    };
+    let pairs = diff::diff_trees(Some(old), Some(new), true, false, &[])?;
+    let pairs = diff::detect_renames(pairs, &options)?;
+    if pairs.is_empty() {
+        return Ok(());
+    }
+    let stdout = io::stdout();
+    let mut out = io::BufWriter::new(stdout.lock());
+    diff::write(&mut out, &pairs, &options)?;
+    diff::write_summary(&mut out, &pairs)?;
+    out.flush().context("write diffstat")
+}
+
+// Put the index and working tree back the way HEAD has them, for the paths the merge
+// touched (those staged differently from HEAD or left unmerged); other local changes stay.
+fn abort() -> anyhow::Result<()> {
+    anyhow::ensure!(
+        Path::new(".git/MERGE_HEAD").exists(),
+        "There is no merge to abort (MERGE_HEAD missing)."
+    );
+    let head = refs::resolve("HEAD")?.context("HEAD does not point at a commit")?;
+    let tree = Tree::flatten(&revision::peel(&head, Kind::Tree)?)?;
+
+    let mut index = Index::load()?;
+    let mut paths: Vec<String> = index
+        .entries
+        .iter()
+        .filter(|entry| entry.stage() != 0 || tree.get(&entry.path) != Some(&(entry.mode, entry.hash)))
+        .map(|entry| entry.path.clone())
+        .collect();
+    paths.extend(tree.keys().filter(|path| index.get(path).is_none()).cloned());
+    paths.sort();
+    paths.dedup();
+
+    // Removals first, so files and directories can swap places
+    for path in paths.iter().rev() {
+        if index.remove(path) && !tree.contains_key(path) {
+            worktree::remove_file(path)?;
+        }
+    }
+    for path in &paths {
+        if let Some(&(mode, hash)) = tree.get(path) {
+            index.add(worktree::checkout_file(path, mode, hash)?);
+        }
+    }
+    index.write()?;
+
+    for file in ["MERGE_HEAD", "MERGE_MSG", "MERGE_MODE"] {
+        match fs::remove_file(Path::new(".git").join(file)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e).with_context(|| format!("remove {file}")),
+            _ => {}
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,42 @@
+use crate::objects::Kind;
+use crate::revision;
+
+#[derive(Debug, clap::Args)]
+pub struct Args {
+    /// Show every best common ancestor instead of just one
+    #[clap(long)]
+    all: bool,
+
+    /// Exit with status 0 if the first commit is an ancestor of the second, 1 if not
+    #[clap(long, conflicts_with = "all")]
+    is_ancestor: bool,
+
+    #[clap(required = true, num_args = 2)]
+    commits: Vec<String>,
+}
+
+pub fn invoke(args: Args) -> anyhow::Result<()> {
+    let [one, other] = &args.commits[..] else {
+        anyhow::bail!("usage: merge-base [--all | --is-ancestor] <commit> <commit>");
+    };
+    let one = revision::peel(&revision::resolve(one)?, Kind::Commit)?;
+    let other = revision::peel(&revision::resolve(other)?, Kind::Commit)?;
+
+    if args.is_ancestor {
+        if !revision::is_ancestor(&one, &other)? {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    let bases = revision::merge_bases(&[one], &[other])?;
+    // Like git, histories without a common ancestor make for a quiet failure
+    if bases.is_empty() {
+        std::process::exit(1);
+    }
+    let shown = if args.all { bases.len() } else { 1 };
+    for base in &bases[..shown] {
+        println!("{base}");
+    }
+    Ok(())
+}
@@ -5,11 +5,59 @@ mod ls_tree;
 mod write_tree;
 mod commit_tree;
 mod clone;
+mod repack;
+mod tag;
+mod rev_parse;
+mod add;
+mod rm;
+mod ls_files;
+mod check_ignore;
+mod commit;
+mod switch;
+mod branch;
+mod checkout;
+mod status;
+mod log;
+mod diff;
+mod diff_tree;
+mod merge;
+mod merge_base;
+mod config;
+mod push;
+mod upload_pack;
+mod receive_pack;
+mod serve;
+mod remote;
+mod fetch;
 
 pub use init::invoke as init;
 pub use hash_object::invoke as hash_object;  
-pub use cat_file::invoke as cat_file;
+pub use cat_file::{invoke as cat_file, Args as CatFileArgs};
 pub use ls_tree::invoke as ls_tree;
 pub use write_tree::invoke as write_tree;
-pub use commit_tree::invoke as commit_tree;
-pub use clone::invoke as clone;
\ No newline at end of file
+pub use commit_tree::{invoke as commit_tree, Args as CommitTreeArgs};
+pub use clone::{invoke as clone, Args as CloneArgs};
+pub use repack::invoke as repack;
+pub use tag::{invoke as tag, Args as TagArgs};
+pub use rev_parse::{invoke as rev_parse, Args as RevParseArgs};
+pub use add::invoke as add;
+pub use rm::invoke as rm;
+pub use ls_files::invoke as ls_files;
+pub use check_ignore::{invoke as check_ignore, Args as CheckIgnoreArgs};
+pub use commit::invoke as commit;
+pub use switch::{invoke as switch, Args as SwitchArgs};
+pub use branch::{invoke as branch, Args as BranchArgs};
+pub use checkout::{invoke as checkout, Args as CheckoutArgs};
+pub use status::{invoke as status, Args as StatusArgs};
+pub use log::{invoke as log, Args as LogArgs};
+pub use diff::{attach_scores, invoke as diff, Args as DiffArgs};
+pub use diff_tree::{invoke as diff_tree, Args as DiffTreeArgs};
+pub use merge::{invoke as merge, Args as MergeArgs};
+pub use merge_base::{invoke as merge_base, Args as MergeBaseArgs};
+pub use config::{invoke as config, Args as ConfigArgs};
+pub use push::{invoke as push, Args as PushArgs};
+pub use upload_pack::{invoke as upload_pack, Args as UploadPackArgs};
+pub use receive_pack::{invoke as receive_pack, Args as ReceivePackArgs};
+pub use serve::{invoke as serve, Args as ServeArgs};
+pub use remote::{invoke as remote, Args as RemoteArgs};
+pub use fetch::{invoke as fetch, Args as FetchArgs};
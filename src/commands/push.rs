@@ -0,0 +1,521 @@
+use crate::config::{Config, ConfigFile};
+use crate::objects::Kind;
+use crate::pack::{self, DeltaOptions, Omit};
+use crate::remote::Remote;
+use crate::transport::{ReceivePack, RefUpdate};
+use crate::{refs, repository, revision};
+use anyhow::Context;
+
+#[derive(Debug, clap::Args)]
+pub struct Args {
+    /// The remote (or URL) to push to [default: the current branch's push remote, or origin]
+    remote: Option<String>,
+
+    /// What to push: [+]<src>[:<dst>], :<dst> to delete [default: the current branch to its upstream]
+    refspecs: Vec<String>,
+
+    /// Update remote refs even when that loses commits on them
+    #[clap(short = 'f', long)]
+    force: bool,
+
+    /// Force updates, but only of refs still where we last saw them (their remote-tracking
+    /// ref, or <expect>)
+    #[clap(long, num_args = 0..=1, require_equals = true, default_missing_value = "", value_name = "ref[:expect]")]
+    force_with_lease: Vec<String>,
+
+    /// Update either all refs on the remote or none of them
+    #[clap(long)]
+    atomic: bool,
+
+    /// Delete the given refs on the remote
+    #[clap(short = 'd', long)]
+    delete: bool,
+
+    /// Make the pushed branches track the refs they were pushed to
+    #[clap(short = 'u', long)]
+    set_upstream: bool,
+
+    /// The command that serves local repositories (paths and file:// URLs)
+    #[clap(long, value_name = "receive-pack", default_value = "git-receive-pack")]
+    receive_pack: String,
+}
+
+// A ref to update on the remote, and what became of it.
+struct Push {
+    // The source as given (a ref name or any revision); None to delete
+    src: Option<String>,
+    // The local ref the source names, if it names one
+    src_ref: Option<String>,
+    // What the remote ref is to point at, the null hash to delete it
+    new: String,
+    // The full name of the remote ref
+    dst: String,
+    // Where the remote ref is now, the null hash if it doesn't exist
+    old: String,
+    force: bool,
+    // The value the remote ref must have for the push to go ahead (--force-with-lease)
+    lease: Option<String>,
+    status: Status,
+}
+
+enum Status {
+    UpToDate,
+    // To be sent: the flag and summary shown for it, e.g. ('+', "a...b") or ('*', "[new tag]")
+    Ok(char, String),
+    // Refused here, with the reason
+    Rejected(&'static str),
+    // Refused by the remote, with its reason
+    RemoteRejected(String),
+}
+
+pub fn invoke(args: Args) -> anyhow::Result<()> {
+    let config = Config::load()?;
+    let head = refs::symbolic_target("HEAD")?;
+    let branch = head.as_deref().and_then(|head| head.strip_prefix("refs/heads/"));
+
+    let remote_name = match &args.remote {
+        Some(remote) => remote.clone(),
+        None => {
+            let configured = branch
+                .and_then(|branch| config.get(&format!("branch.{branch}.pushRemote")))
+                .or_else(|| config.get("remote.pushDefault"))
+                .or_else(|| branch.and_then(|branch| config.get(&format!("branch.{branch}.remote"))))
+                .unwrap_or("origin");
+            anyhow::ensure!(
+                config.get(&format!("remote.{configured}.url")).is_some(),
+                "No configured push destination.\n\
+                 Either specify the URL from the command-line or configure a remote repository using\n\n    \
+                 git remote add <name> <url>\n\n\
+                 and then push using the remote name\n\n    \
+                 git push <name>\n"
+            );
+            configured.to_string()
+        }
+    };
+    let remote = Remote::get(&config, &remote_name)?;
+    let url = remote.push_url().to_string();
+
+    // Without refspecs, push the current branch to the branch it tracks (push.default=simple)
+    let mut refspecs = args.refspecs.clone();
+    let mut set_upstream = args.set_upstream;
+    if refspecs.is_empty() && !args.delete {
+        let configured: Vec<&str> = match &remote.name {
+            Some(name) => config.get_all(&format!("remote.{name}.push")),
+            None => Vec::new(),
+        };
+        if configured.is_empty() {
+            let auto_setup = config.get_bool("push.autoSetupRemote")?.unwrap_or(false);
+            refspecs.push(default_refspec(&config, branch, &remote_name, auto_setup)?);
+            set_upstream |= auto_setup && branch.is_some_and(|branch| config.get(&format!("branch.{branch}.merge")).is_none());
+        } else {
+            refspecs.extend(configured.into_iter().map(str::to_string));
+        }
+    }
+    anyhow::ensure!(!(args.delete && args.refspecs.is_empty()), "--delete doesn't make sense without any refs");
+
+    let mut receiver = ReceivePack::connect(&url, &args.receive_pack)?;
+    let mut pushes = Vec::new();
+    for refspec in &refspecs {
+        let refspec = if args.delete { format!(":{refspec}") } else { refspec.clone() };
+        // Nothing is pushed unless every refspec makes sense
+        if let Err(e) = resolve_refspec(&refspec, &receiver.refs, &mut pushes) {
+            drop(receiver);
+            eprintln!("error: {e}");
+            eprintln!("error: failed to push some refs to '{url}'");
+            std::process::exit(1);
+        }
+    }
+    for lease in &args.force_with_lease {
+        apply_lease(lease, &remote, &mut pushes)?;
+    }
+    for push in &mut pushes {
+        push.force |= args.force;
+        push.status = status(push)?;
+    }
+
+    // With --atomic, a single ref that can't be updated holds back all the others
+    if args.atomic && pushes.iter().any(|push| matches!(push.status, Status::Rejected(_))) {
+        for push in &mut pushes {
+            if let Status::Ok(..) = push.status {
+                push.status = Status::Rejected("atomic push failed");
+            }
+        }
+    }
+
+    let to_send: Vec<usize> = (0..pushes.len()).filter(|&i| matches!(pushes[i].status, Status::Ok(..))).collect();
+    if !to_send.is_empty() {
+        let updates: Vec<RefUpdate> = to_send
+            .iter()
+            .map(|&i| RefUpdate { name: pushes[i].dst.clone(), old: pushes[i].old.clone(), new: pushes[i].new.clone() })
+            .collect();
+
+        // Everything the new ref values need, short of what the remote has already
+        let wants: Vec<String> = updates
+            .iter()
+            .filter(|update| !refs::is_null(&update.new))
+            .map(|update| update.new.clone())
+            .collect();
+        let mut haves: Vec<String> = receiver.refs.iter().map(|(_, hash)| hash.clone()).collect();
+        haves.extend(receiver.extra_haves.iter().cloned());
+        let thin = receiver.capability("no-thin").is_none();
+        let mut data = Vec::new();
+        if !wants.is_empty() {
+            let selection = pack::collect(&wants, &haves, thin, &Omit::default()).context("find objects to push")?;
+            pack::write_pack(&selection.objects, &selection.bases, DeltaOptions::default(), &mut data)
+                .context("write pack to push")?;
+        }
+
+        let results = receiver.push(&updates, &data, args.atomic)?;
+        for (&i, (_, result)) in to_send.iter().zip(results) {
+            if let Err(reason) = result {
+                pushes[i].status = Status::RemoteRejected(reason);
+            }
+        }
+    }
+    drop(receiver);
+
+    report(&url, &pushes);
+    let mut failed = false;
+    let mut upstreams = Vec::new();
+    for push in &pushes {
+        match push.status {
+            Status::UpToDate | Status::Ok(..) => {}
+            _ => {
+                failed = true;
+                continue;
+            }
+        }
+        // Keep the remote-tracking refs in step with what the remote now has
+        if let Some(tracking) = remote.tracking_ref(&push.dst) {
+            if refs::is_null(&push.new) {
+                if refs::resolve(&tracking)?.is_some() {
+                    refs::delete(&tracking, None)?;
+                }
+            } else if refs::resolve(&tracking)?.as_deref() != Some(&push.new) {
+                refs::update(&tracking, &push.new, None, "update by push")?;
+            }
+        }
+        let local = push.src_ref.as_deref().and_then(|name| name.strip_prefix("refs/heads/"));
+        if let Some(local) = local.filter(|_| set_upstream && push.dst.starts_with("refs/heads/")) {
+            upstreams.push((local.to_string(), push.dst.clone()));
+        }
+    }
+
+    if !upstreams.is_empty() {
+        let mut file = ConfigFile::open(&repository::git_dir().join("config"))?;
+        let remote_name = remote.name.as_deref().unwrap_or(&remote.url);
+        for (local, dst) in &upstreams {
+            file.set(&format!("branch.{local}.remote"), remote_name)?;
+            file.set(&format!("branch.{local}.merge"), dst)?;
+        }
+        file.write()?;
+        for (local, dst) in &upstreams {
+            println!("branch '{local}' set up to track '{remote_name}/{}'.", short(dst));
+        }
+    }
+
+    if failed {
+        eprintln!("error: failed to push some refs to '{url}'");
+        hints(&pushes, head.as_deref());
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+// The refspec pushing the current branch to its upstream, for a push without refspecs.
+fn default_refspec(config: &Config, branch: Option<&str>, remote: &str, auto_setup: bool) -> anyhow::Result<String> {
+    let Some(branch) = branch else {
+        anyhow::bail!(
+            "You are not currently on a branch.\n\
+             To push the history leading to the current (detached HEAD)\n\
+             state now, use\n\n    \
+             git push {remote} HEAD:<name-of-remote-branch>\n"
+        );
+    };
+    // Pushing somewhere other than where the branch is fetched from goes to the same name
+    let fetch_remote = config.get(&format!("branch.{branch}.remote")).unwrap_or("origin");
+    if remote != fetch_remote || auto_setup && config.get(&format!("branch.{branch}.merge")).is_none() {
+        return Ok(format!("refs/heads/{branch}:refs/heads/{branch}"));
+    }
+    match config.get(&format!("branch.{branch}.merge")) {
+        Some(merge) if merge == format!("refs/heads/{branch}") => Ok(format!("refs/heads/{branch}:{merge}")),
+        Some(merge) => anyhow::bail!(
+            "The upstream branch of your current branch does not match\n\
+             the name of your current branch.  To push to the upstream branch\n\
+             on the remote, use\n\n    \
+             git push {remote} HEAD:{}\n\n\
+             To push to the branch of the same name on the remote, use\n\n    \
+             git push {remote} HEAD\n",
+            merge.strip_prefix("refs/heads/").unwrap_or(merge)
+        ),
+        None => anyhow::bail!(
+            "The current branch {branch} has no upstream branch.\n\
+             To push the current branch and set the remote as upstream, use\n\n    \
+             git push --set-upstream {remote} {branch}\n\n\
+             To have this happen automatically for branches without a tracking\n\
+             upstream, see 'push.autoSetupRemote' in 'git help config'.\n"
+        ),
+    }
+}
+
+// Turn a refspec into the refs to push, given the refs the remote has.
+fn resolve_refspec(refspec: &str, remote_refs: &[(String, String)], pushes: &mut Vec<Push>) -> anyhow::Result<()> {
+    let (force, spec) = match refspec.strip_prefix('+') {
+        Some(spec) => (true, spec),
+        None => (false, refspec),
+    };
+    let (src, dst) = match spec.split_once(':') {
+        Some((src, dst)) => (src, Some(dst)),
+        None => (spec, None),
+    };
+    let null = refs::null_hash()?;
+    let remote_value = |name: &str| {
+        remote_refs
+            .iter()
+            .find(|(remote, _)| remote == name)
+            .map_or_else(|| null.clone(), |(_, hash)| hash.clone())
+    };
+    let push = |src: Option<&str>, src_ref: Option<String>, new: String, dst: String| Push {
+        src: src.map(str::to_string),
+        src_ref,
+        new,
+        old: remote_value(&dst),
+        dst,
+        force,
+        lease: None,
+        status: Status::UpToDate,
+    };
+
+    // ":<dst>" deletes
+    if src.is_empty() {
+        let dst = dst.unwrap_or_default();
+        let name = remote_dwim(dst, remote_refs)
+            .ok_or_else(|| anyhow::anyhow!("unable to delete '{dst}': remote ref does not exist"))?;
+        pushes.push(push(None, None, null.clone(), name));
+        return Ok(());
+    }
+
+    // "refs/heads/*:refs/remotes/x/*" pushes every matching ref
+    if src.contains('*') {
+        let pattern = format!("{src}:{}", dst.unwrap_or(src));
+        for (name, hash) in refs::list("refs/")? {
+            if let Some(dst) = refs::map_refspec(&pattern, &name) {
+                pushes.push(push(Some(&name), Some(name.clone()), hash, dst));
+            }
+        }
+        return Ok(());
+    }
+
+    let src_ref = refs::dwim(src)?;
+    let new = match &src_ref {
+        Some(name) => refs::resolve(name)?,
+        None => revision::resolve(src).ok(),
+    };
+    let new = new.ok_or_else(|| anyhow::anyhow!("src refspec {src} does not match any"))?;
+    // The ref the source stands for: "HEAD" is the current branch
+    let full_src = match src_ref.as_deref() {
+        Some("HEAD") => refs::symbolic_target("HEAD")?,
+        other => other.map(str::to_string),
+    };
+    let dst = match dst {
+        Some(dst) if dst.starts_with("refs/") => dst.to_string(),
+        Some(dst) => match remote_dwim(dst, remote_refs) {
+            Some(name) => name,
+            // A new ref gets the kind of the source: a branch for a branch, a tag for a tag
+            None => match full_src.as_deref() {
+                Some(full) if full.starts_with("refs/tags/") => format!("refs/tags/{dst}"),
+                Some(full) if full.starts_with("refs/heads/") => format!("refs/heads/{dst}"),
+                _ => anyhow::bail!(
+                    "unable to push to unqualified destination: {dst}\n\
+                     The destination refspec neither matches an existing ref on the remote nor\n\
+                     begins with refs/, and we are unable to guess a prefix based on the source ref."
+                ),
+            },
+        },
+        None => full_src
+            .clone()
+            .filter(|full| full.starts_with("refs/"))
+            .ok_or_else(|| anyhow::anyhow!("src refspec {src} does not match any"))?,
+    };
+    anyhow::ensure!(refs::valid_name(&dst), "invalid destination ref '{dst}'");
+    pushes.push(push(Some(src), src_ref, new, dst));
+    Ok(())
+}
+
+// The remote ref a short name like "main" stands for, looked up the way local names are.
+fn remote_dwim(name: &str, remote_refs: &[(String, String)]) -> Option<String> {
+    let candidates = [
+        name.to_string(),
+        format!("refs/{name}"),
+        format!("refs/tags/{name}"),
+        format!("refs/heads/{name}"),
+        format!("refs/remotes/{name}"),
+    ];
+    candidates
+        .into_iter()
+        .find(|candidate| candidate.starts_with("refs/") && remote_refs.iter().any(|(remote, _)| remote == candidate))
+}
+
+// Apply --force-with-lease[=<ref>[:<expect>]]: with no ref, to every ref pushed.
+fn apply_lease(lease: &str, remote: &Remote, pushes: &mut [Push]) -> anyhow::Result<()> {
+    let (name, expect) = match lease.split_once(':') {
+        Some((name, expect)) => (name, Some(expect)),
+        None => (lease, None),
+    };
+    let expect = match expect {
+        Some("") => Some(refs::null_hash()?),
+        Some(expect) => Some(revision::resolve(expect).with_context(|| format!("cannot parse expected object name '{expect}'"))?),
+        None => None,
+    };
+    for push in pushes {
+        let matches = name.is_empty()
+            || push.dst == name
+            || [format!("refs/heads/{name}"), format!("refs/tags/{name}")].contains(&push.dst);
+        // A lease for a specific ref wins over a general one
+        if !matches || (name.is_empty() && push.lease.is_some()) {
+            continue;
+        }
+        push.lease = match &expect {
+            Some(expect) => Some(expect.clone()),
+            None => {
+                let tracking = remote.tracking_ref(&push.dst);
+                let value = match tracking {
+                    Some(tracking) => refs::resolve(&tracking)?,
+                    None => None,
+                };
+                Some(match value {
+                    Some(value) => value,
+                    None => refs::null_hash()?,
+                })
+            }
+        };
+    }
+    Ok(())
+}
+
+// Whether (and how) the remote ref can be updated, the way git decides it.
+fn status(push: &Push) -> anyhow::Result<Status> {
+    let deletion = refs::is_null(&push.new);
+    if !deletion && push.old == push.new {
+        return Ok(Status::UpToDate);
+    }
+    let mut force = push.force;
+    let mut reject = None;
+    if let Some(lease) = &push.lease {
+        if *lease != push.old {
+            return Ok(Status::Rejected("stale info"));
+        }
+        force = true;
+    }
+    if !deletion && !refs::is_null(&push.old) {
+        reject = if push.dst.starts_with("refs/tags/") {
+            Some("already exists")
+        } else if revision::peel_tags(&push.old).is_err() {
+            Some("fetch first")
+        } else {
+            match (revision::peel(&push.old, Kind::Commit), revision::peel(&push.new, Kind::Commit)) {
+                (Ok(old), Ok(new)) if revision::is_ancestor(&old, &new)? => None,
+                (Ok(_), Ok(_)) => Some("non-fast-forward"),
+                _ => Some("needs force"),
+            }
+        };
+    }
+    if let Some(reason) = reject {
+        if !force {
+            return Ok(Status::Rejected(reason));
+        }
+    }
+
+    let abbreviated = |hash: &str| revision::abbreviate(hash, 7);
+    Ok(if deletion {
+        Status::Ok('-', "[deleted]".to_string())
+    } else if refs::is_null(&push.old) {
+        let summary = if push.dst.starts_with("refs/tags/") {
+            "[new tag]"
+        } else if push.dst.starts_with("refs/heads/") {
+            "[new branch]"
+        } else {
+            "[new reference]"
+        };
+        Status::Ok('*', summary.to_string())
+    } else if reject.is_some() {
+        Status::Ok('+', format!("{}...{}", abbreviated(&push.old)?, abbreviated(&push.new)?))
+    } else {
+        Status::Ok(' ', format!("{}..{}", abbreviated(&push.old)?, abbreviated(&push.new)?))
+    })
+}
+
+// Show what happened to every ref that wasn't up to date already.
+fn report(url: &str, pushes: &[Push]) {
+    let shown: Vec<&Push> = pushes.iter().filter(|push| !matches!(push.status, Status::UpToDate)).collect();
+    if shown.is_empty() {
+        eprintln!("Everything up-to-date");
+        return;
+    }
+    eprintln!("To {url}");
+    for push in shown {
+        let refs = match &push.src {
+            Some(src) => {
+                let src = push.src_ref.as_deref().map_or(src.as_str(), short);
+                format!("{src} -> {}", short(&push.dst))
+            }
+            None => short(&push.dst).to_string(),
+        };
+        let (flag, summary, reason) = match &push.status {
+            Status::Ok(flag, summary) => {
+                let reason = if *flag == '+' { " (forced update)" } else { "" };
+                (*flag, summary.as_str(), reason.to_string())
+            }
+            Status::Rejected(reason) => ('!', "[rejected]", format!(" ({reason})")),
+            Status::RemoteRejected(reason) => ('!', "[remote rejected]", format!(" ({reason})")),
+            Status::UpToDate => unreachable!("up-to-date refs aren't shown"),
+        };
+        eprintln!(" {flag} {summary:<17} {refs}{reason}");
+    }
+}
+
+// Advice on the refs that were refused, the way git gives it.
+fn hints(pushes: &[Push], head: Option<&str>) {
+    let rejected = |reason: &'static str| {
+        pushes
+            .iter()
+            .filter(move |push| matches!(push.status, Status::Rejected(r) if r == reason))
+    };
+    let fast_forwards = "hint: See the 'Note about fast-forwards' in 'git push --help' for details.";
+    if rejected("non-fast-forward").any(|push| Some(push.dst.as_str()) == head) {
+        eprintln!(
+            "hint: Updates were rejected because the tip of your current branch is behind\n\
+             hint: its remote counterpart. Integrate the remote changes (e.g.\n\
+             hint: 'git pull ...') before pushing again.\n{fast_forwards}"
+        );
+    } else if rejected("non-fast-forward").next().is_some() {
+        eprintln!(
+            "hint: Updates were rejected because a pushed branch tip is behind its remote\n\
+             hint: counterpart. Check out this branch and integrate the remote changes\n\
+             hint: (e.g. 'git pull ...') before pushing again.\n{fast_forwards}"
+        );
+    } else if rejected("fetch first").next().is_some() {
+        eprintln!(
+            "hint: Updates were rejected because the remote contains work that you do\n\
+             hint: not have locally. This is usually caused by another repository pushing\n\
+             hint: to the same ref. You may want to first integrate the remote changes\n\
+             hint: (e.g., 'git pull ...') before pushing again.\n{fast_forwards}"
+        );
+    } else if rejected("already exists").next().is_some() {
+        eprintln!("hint: Updates were rejected because the tag already exists in the remote.");
+    } else if rejected("needs force").next().is_some() {
+        eprintln!(
+            "hint: You cannot update a remote ref that points at a non-commit object,\n\
+             hint: or update a remote ref to make it point at a non-commit object,\n\
+             hint: without using the '--force' option."
+        );
+    }
+}
+
+// A ref name the way it is shown: "main" for refs/heads/main.
+fn short(name: &str) -> &str {
+    ["refs/heads/", "refs/tags/", "refs/remotes/"]
+        .iter()
+        .find_map(|prefix| name.strip_prefix(prefix))
+        .unwrap_or(name)
+}
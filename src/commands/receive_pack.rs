@@ -0,0 +1,30 @@
+use crate::server::{self, receive_pack};
+use std::io::{self, BufWriter};
+use std::path::PathBuf;
+
+#[derive(Debug, clap::Args)]
+pub struct Args {
+    /// Answer a single request, without advertising refs first (for smart HTTP)
+    #[clap(long)]
+    stateless_rpc: bool,
+
+    /// Only advertise the refs and exit
+    #[clap(long, alias = "http-backend-info-refs")]
+    advertise_refs: bool,
+
+    directory: PathBuf,
+}
+
+// Take the objects and ref updates of a client pushing to the repository, over stdin and
+// stdout.
+pub fn invoke(args: Args) -> anyhow::Result<()> {
+    server::enter_repository(&args.directory)?;
+    let mut output = BufWriter::new(io::stdout().lock());
+    if !args.stateless_rpc {
+        receive_pack::advertise(&mut output)?;
+    }
+    if !args.advertise_refs {
+        receive_pack::serve(&mut io::stdin().lock(), &mut output)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,119 @@
+use crate::config::{Config, ConfigFile};
+use crate::repository;
+use crate::refs;
+use crate::remote::Remote;
+
+#[derive(Debug, clap::Args)]
+pub struct Args {
+    /// Show the URLs of the remotes along with their names
+    #[clap(short = 'v', long)]
+    verbose: bool,
+
+    #[command(subcommand)]
+    action: Option<Action>,
+}
+
+#[derive(Debug, clap::Subcommand)]
+enum Action {
+    /// Add a remote called <name> for the repository at <url>
+    Add {
+        /// Fetch from the remote right away
+        #[clap(short = 'f', long)]
+        fetch: bool,
+
+        /// Only track this branch (may be given more than once) instead of all of them
+        #[clap(short = 't', long = "track", value_name = "branch")]
+        track: Vec<String>,
+
+        name: String,
+        url: String,
+    },
+    /// Remove a remote, with its remote-tracking refs and the branch settings that use it
+    #[command(alias = "rm")]
+    Remove { name: String },
+}
+
+pub fn invoke(args: Args) -> anyhow::Result<()> {
+    let config = Config::load()?;
+    match args.action {
+        None => {
+            for name in Remote::names(&config) {
+                if args.verbose {
+                    let remote = Remote::get(&config, &name)?;
+                    println!("{name}\t{} (fetch)", remote.url);
+                    println!("{name}\t{} (push)", remote.push_url());
+                } else {
+                    println!("{name}");
+                }
+            }
+            Ok(())
+        }
+        Some(Action::Add { fetch, track, name, url }) => {
+            anyhow::ensure!(!Remote::names(&config).contains(&name), "remote {name} already exists.");
+            // Its remote-tracking refs go in refs/remotes/<name>/
+            anyhow::ensure!(
+                refs::valid_name(&format!("refs/remotes/{name}/test")),
+                "'{name}' is not a valid remote name"
+            );
+            let mut file = ConfigFile::open(&repository::git_dir().join("config"))?;
+            file.add(&format!("remote.{name}.url"), &url)?;
+            if track.is_empty() {
+                file.add(&format!("remote.{name}.fetch"), &format!("+refs/heads/*:refs/remotes/{name}/*"))?;
+            }
+            for branch in &track {
+                file.add(&format!("remote.{name}.fetch"), &format!("+refs/heads/{branch}:refs/remotes/{name}/{branch}"))?;
+            }
+            file.write()?;
+            if fetch {
+                super::fetch::fetch_remote(&name)?;
+            }
+            Ok(())
+        }
+        Some(Action::Remove { name }) => remove(&config, &name),
+    }
+}
+
+fn remove(config: &Config, name: &str) -> anyhow::Result<()> {
+    anyhow::ensure!(Remote::names(config).iter().any(|known| known == name), "No such remote: '{name}'");
+    let remote = Remote::get(config, name)?;
+
+    let mut file = ConfigFile::open(&repository::git_dir().join("config"))?;
+    file.remove_section(&format!("remote.{name}"))?;
+    // Branches that pulled from it no longer have an upstream
+    let mut branches = Vec::new();
+    for setting in &file.settings {
+        let Some(branch) = setting.key.strip_prefix("branch.").and_then(|rest| rest.strip_suffix(".remote")) else {
+            continue;
+        };
+        if setting.value.as_deref() == Some(name) {
+            branches.push(branch.to_string());
+        }
+    }
+    for branch in branches {
+        for key in ["remote", "merge"] {
+            let key = format!("branch.{branch}.{key}");
+            while let Some(i) = file.settings.iter().position(|setting| setting.key == key) {
+                file.replace(i, &key, None)?;
+            }
+        }
+    }
+    file.write()?;
+
+    // And its remote-tracking refs go, symbolic ones (like its HEAD) before what they point at
+    for refspec in &remote.fetch {
+        let Some((_, dst)) = refspec.split_once(':') else { continue };
+        let prefix = dst.split_once('*').map_or(dst, |(prefix, _)| prefix);
+        let mut tracking = Vec::new();
+        for (tracking_ref, _) in refs::list(prefix)? {
+            if refs::map_refspec(&format!("{dst}:{dst}"), &tracking_ref).is_some() {
+                let symbolic = refs::symbolic_target(&tracking_ref)?.is_some();
+                tracking.push((!symbolic, tracking_ref));
+            }
+        }
+        tracking.sort();
+        for (_, tracking_ref) in tracking {
+            refs::delete(&tracking_ref, None)?;
+        }
+    }
+    Ok(())
+}
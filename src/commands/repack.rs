@@ -0,0 +1,107 @@
+use crate::config::Config;
+use crate::repository;
+use crate::objects::{self, Object, ObjectId};
+use crate::pack::{self, DeltaOptions, PackObject};
+use anyhow::Context;
+use std::collections::BTreeSet;
+use std::fs;
+use std::io::Read;
+
+pub fn invoke(all: bool, delete: bool, window: Option<usize>, depth: Option<usize>) -> anyhow::Result<()> {
+    let config = Config::load()?;
+    let window = match window {
+        Some(window) => window,
+        None => config.get_int("pack.window")?.map_or(10, |n| n.max(0) as usize),
+    };
+    let depth = match depth {
+        Some(depth) => depth,
+        None => config.get_int("pack.depth")?.map_or(50, |n| n.max(0) as usize),
+    };
+
+    // Gather the objects to pack: every loose object, plus the packed ones with -a. Packs
+    // with a .keep file stay as they are, and so do the objects in them.
+    let loose = objects::loose_objects().context("list loose objects")?;
+    let (kept, old_packs): (Vec<_>, Vec<_>) = if all {
+        pack::packs()?.into_iter().partition(|pack| pack.path.with_extension("keep").exists())
+    } else {
+        (Vec::new(), Vec::new())
+    };
+
+    let mut ids: BTreeSet<ObjectId> = loose.iter().copied().collect();
+    for old in &old_packs {
+        ids.extend(old.index.ids());
+    }
+    ids.retain(|id| !kept.iter().any(|pack| pack.index.find(id).is_some()));
+    if ids.is_empty() {
+        println!("Nothing new to pack.");
+        return Ok(());
+    }
+
+    // Deltas are computed in memory, so load every object up front
+    let mut packed = Vec::with_capacity(ids.len());
+    for id in ids {
+        let hash = id.to_string();
+        let mut object = Object::read(&hash).with_context(|| format!("read object {hash}"))?;
+        let mut data = Vec::with_capacity(object.size as usize);
+        object
+            .reader
+            .read_to_end(&mut data)
+            .with_context(|| format!("read contents of {hash}"))?;
+        packed.push(PackObject {
+            id,
+            kind: object.kind,
+            data,
+        });
+    }
+
+    let (pack_path, deltas) = pack::store(&packed, DeltaOptions { window, depth })
+        .context("write new pack")?;
+    // Objects of a partial clone's promisor packs may refer to ones that were never fetched,
+    // which is only fine as long as the pack that holds them now says so too
+    if old_packs.iter().any(|old| old.path.with_extension("promisor").exists()) {
+        let marker = pack_path.with_extension("promisor");
+        fs::write(&marker, "").with_context(|| format!("write {}", marker.display()))?;
+    }
+
+    if delete {
+        // Old packs are fully contained in the new one when -a is given. Repacking the same
+        // objects again gives the same pack, though, which must not go with the old ones (pack
+        // paths are absolute and the new one relative, so compare only their names).
+        for old in &old_packs {
+            if old.path.file_name() == pack_path.file_name() {
+                continue;
+            }
+            fs::remove_file(old.path.with_extension("idx"))
+                .with_context(|| format!("remove index of {}", old.path.display()))?;
+            fs::remove_file(&old.path)
+                .with_context(|| format!("remove {}", old.path.display()))?;
+            // Along with whatever git keeps next to a pack
+            for extension in ["promisor", "rev", "bitmap", "mtimes"] {
+                let path = old.path.with_extension(extension);
+                match fs::remove_file(&path) {
+                    Ok(()) => {}
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                    Err(e) => return Err(e).with_context(|| format!("remove {}", path.display())),
+                }
+            }
+        }
+
+        // Loose objects are now redundant with the pack
+        for id in &loose {
+            let hash = id.to_string();
+            let dir = repository::git_dir().join(format!("objects/{}", &hash[..2]));
+            fs::remove_file(dir.join(&hash[2..]))
+                .with_context(|| format!("remove loose object {hash}"))?;
+            // Drop the fan-out directory once it's empty; failing means it still isn't
+            let _ = fs::remove_dir(&dir);
+        }
+    }
+
+    println!(
+        "Packed {} objects ({} deltas) into {}",
+        packed.len(),
+        deltas,
+        pack_path.display()
+    );
+    Ok(())
+}
@@ -0,0 +1,73 @@
+use crate::refs;
+use crate::revision;
+
+#[derive(Debug, clap::Args)]
+pub struct Args {
+    /// Require exactly one argument that resolves to an object
+    #[clap(long)]
+    verify: bool,
+
+    /// Print abbreviated hashes (7 characters, or more when needed to stay unique)
+    #[clap(long, num_args = 0..=1, default_missing_value = "7", require_equals = true)]
+    short: Option<usize>,
+
+    /// Print the short name of the ref instead of the hash (e.g. "main" for HEAD)
+    #[clap(long, conflicts_with = "symbolic_full_name")]
+    abbrev_ref: bool,
+
+    /// Print the full name of the ref instead of the hash (e.g. "refs/heads/main" for HEAD)
+    #[clap(long)]
+    symbolic_full_name: bool,
+
+    #[clap(required = true)]
+    revisions: Vec<String>,
+}
+
+pub fn invoke(args: Args) -> anyhow::Result<()> {
+    if args.verify {
+        anyhow::ensure!(args.revisions.len() == 1, "--verify needs a single revision");
+    }
+
+    for spec in &args.revisions {
+        if args.abbrev_ref || args.symbolic_full_name {
+            // Names that aren't refs (e.g. plain hashes) print nothing, like git
+            let Some(full_name) = full_ref_name(spec)? else {
+                revision::resolve(spec)?;
+                continue;
+            };
+            if args.abbrev_ref {
+                println!("{}", shorten(&full_name));
+            } else {
+                println!("{full_name}");
+            }
+            continue;
+        }
+
+        let hash = revision::resolve(spec)?;
+        match args.short {
+            Some(min) => println!("{}", revision::abbreviate(&hash, min)?),
+            None => println!("{hash}"),
+        }
+    }
+    Ok(())
+}
+
+// The ref a name stands for, looking through HEAD to the branch it points to.
+fn full_ref_name(spec: &str) -> anyhow::Result<Option<String>> {
+    let spec = if spec == "@" { "HEAD" } else { spec };
+    let Some(full_name) = refs::dwim(spec)? else {
+        return Ok(None);
+    };
+    if full_name == "HEAD" {
+        // A detached HEAD has no better name than itself
+        return Ok(Some(refs::symbolic_target("HEAD")?.unwrap_or(full_name)));
+    }
+    Ok(Some(full_name))
+}
+
+fn shorten(full_name: &str) -> &str {
+    ["refs/heads/", "refs/tags/", "refs/remotes/", "refs/"]
+        .iter()
+        .find_map(|prefix| full_name.strip_prefix(prefix))
+        .unwrap_or(full_name)
+}
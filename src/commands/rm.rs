@@ -0,0 +1,48 @@
+use crate::index::Index;
+use crate::worktree;
+use std::fs;
+use std::path::PathBuf;
+
+pub fn invoke(cached: bool, recursive: bool, force: bool, paths: Vec<PathBuf>) -> anyhow::Result<()> {
+    let mut index = Index::load()?;
+
+    // Check every pathspec before touching anything, so a failure leaves no partial removal
+    let mut removals = Vec::new();
+    for path in &paths {
+        let repo_path = worktree::repo_path(path)?;
+        let matched: Vec<String> = index.matching(&repo_path).map(|entry| entry.path.clone()).collect();
+        anyhow::ensure!(!matched.is_empty(), "pathspec '{}' did not match any files", path.display());
+        anyhow::ensure!(
+            recursive || matched.iter().all(|tracked| *tracked == repo_path),
+            "not removing '{}' recursively without -r",
+            path.display()
+        );
+        removals.extend(matched);
+    }
+    removals.sort();
+    removals.dedup();
+
+    // Deleting a file whose changes were never staged would lose them
+    if !cached && !force {
+        for path in &removals {
+            if fs::symlink_metadata(path).is_err() {
+                continue;
+            }
+            let entry = index.get(path).expect("matched paths are in the index");
+            anyhow::ensure!(
+                !worktree::is_modified(entry, index.mtime)?,
+                "'{path}' has local modifications (use --cached to keep the file, or -f to force removal)"
+            );
+        }
+    }
+
+    for path in &removals {
+        index.remove(path);
+        if !cached {
+            worktree::remove_file(path)?;
+        }
+        println!("rm '{path}'");
+    }
+
+    index.write()
+}
@@ -0,0 +1,17 @@
+use crate::server;
+use std::path::PathBuf;
+
+#[derive(Debug, clap::Args)]
+pub struct Args {
+    /// Serve over smart HTTP on <address> (e.g. 127.0.0.1:8080)
+    #[clap(long, value_name = "address", required = true)]
+    http: String,
+
+    /// The repository to serve, or a directory with repositories below it
+    #[clap(default_value = ".")]
+    directory: PathBuf,
+}
+
+pub fn invoke(args: Args) -> anyhow::Result<()> {
+    server::serve_http(&args.http, &args.directory)
+}
@@ -0,0 +1,675 @@
+use crate::config::Config;
+use crate::diff::{self, FilePair, Origin, Renames, Side};
+use crate::ignore::IgnoreRules;
+use crate::index::{self, Index, IndexEntry};
+use crate::objects::{Kind, ObjectId, Tree};
+use crate::{refs, revision, worktree};
+use anyhow::Context;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+#[derive(Debug, clap::Args)]
+pub struct Args {
+    /// Give the output in the short format
+    #[clap(short = 's', long)]
+    short: bool,
+
+    /// Show the branch and its upstream in the short formats
+    #[clap(short = 'b', long)]
+    branch: bool,
+
+    /// Machine-readable output: v1 (the short format) or v2
+    #[clap(
+        long,
+        num_args = 0..=1,
+        default_missing_value = "v1",
+        require_equals = true,
+        value_parser = ["v1", "v2"]
+    )]
+    porcelain: Option<String>,
+
+    /// Which untracked files to show: no, normal (whole directories collapsed) or all
+    #[clap(
+        short = 'u',
+        long = "untracked-files",
+        num_args = 0..=1,
+        default_value = "normal",
+        default_missing_value = "all",
+        value_parser = ["no", "normal", "all"]
+    )]
+    untracked: String,
+
+    /// Detect renames, of files at least <n> similar (50% by default)
+    #[clap(long = "find-renames", value_name = "n", num_args = 0..=1, require_equals = true, default_missing_value = "")]
+    find_renames: Option<String>,
+
+    /// Don't detect renames, whatever status.renames says
+    #[clap(long, conflicts_with = "find_renames")]
+    no_renames: bool,
+}
+
+// A path's mode and blob hash at one of the three places status compares.
+type Version = Option<(u32, ObjectId)>;
+
+// The state of one tracked path (in HEAD or in the index).
+struct Change {
+    path: String,
+    head: Version,
+    // The stage-0 entry; None for deleted and for unmerged paths
+    index: Version,
+    // Mode of the working tree file, None if it is gone
+    worktree: Option<u32>,
+    // Whether the working tree file differs from what is staged
+    modified: bool,
+    intent_to_add: bool,
+    // The versions at stages 1 (base), 2 (ours) and 3 (theirs) of an unmerged path
+    unmerged: Option<[Version; 3]>,
+    // Where the path was renamed or copied from between HEAD and the index
+    staged_origin: Option<Origin>,
+    // Where the path was renamed from between the index and the working tree (to a file
+    // added with "add -N"); `head` and `index` are then those of the file it came from
+    worktree_origin: Option<Origin>,
+}
+
+impl Change {
+    // The short-format letter for HEAD vs index.
+    fn staged(&self) -> char {
+        if self.intent_to_add || self.worktree_origin.is_some() {
+            return ' ';
+        }
+        if let Some(origin) = &self.staged_origin {
+            return if origin.copy { 'C' } else { 'R' };
+        }
+        match (self.head, self.index) {
+            (None, Some(_)) => 'A',
+            (Some(_), None) => 'D',
+            (Some((head_mode, head_hash)), Some((index_mode, index_hash))) => {
+                if file_type(head_mode) != file_type(index_mode) {
+                    'T'
+                } else if head_mode != index_mode || head_hash != index_hash {
+                    'M'
+                } else {
+                    ' '
+                }
+            }
+            (None, None) => ' ',
+        }
+    }
+
+    // The short-format letter for index vs working tree.
+    fn unstaged(&self) -> char {
+        if self.worktree_origin.is_some() {
+            return 'R';
+        }
+        let Some((index_mode, _)) = self.index else {
+            return ' ';
+        };
+        match self.worktree {
+            None => 'D',
+            Some(mode) if file_type(mode) != file_type(index_mode) => 'T',
+            Some(_) if self.intent_to_add => 'A',
+            Some(_) if self.modified => 'M',
+            Some(_) => ' ',
+        }
+    }
+
+    // The two letters of an unmerged path, e.g. "UU" when both sides modified it.
+    fn unmerged_code(&self) -> Option<&'static str> {
+        let [base, ours, theirs] = self.unmerged?;
+        Some(match (base.is_some(), ours.is_some(), theirs.is_some()) {
+            (true, false, false) => "DD",
+            (false, true, false) => "AU",
+            (true, true, false) => "UD",
+            (false, false, true) => "UA",
+            (true, false, true) => "DU",
+            (false, true, true) => "AA",
+            _ => "UU",
+        })
+    }
+
+    // The path as the short format shows it: "old -> new" for renames and copies.
+    fn display_path(&self) -> String {
+        match self.staged_origin.as_ref().or(self.worktree_origin.as_ref()) {
+            Some(origin) => format!("{} -> {}", origin.path, self.path),
+            None => self.path.clone(),
+        }
+    }
+}
+
+// Regular file, symlink or gitlink: changing between those is a "typechange".
+fn file_type(mode: u32) -> u32 {
+    mode & 0o170000
+}
+
+// Where HEAD is and how it relates to its upstream.
+struct BranchInfo {
+    commit: Option<String>,
+    // The short branch name, None when HEAD is detached
+    branch: Option<String>,
+    // Short name of the upstream, and (ahead, behind) if it still exists
+    upstream: Option<(String, Option<(usize, usize)>)>,
+}
+
+pub fn invoke(args: Args) -> anyhow::Result<()> {
+    // Without a repository there would be nothing to compare, which would look like all is clean
+    anyhow::ensure!(Path::new(".git").is_dir(), "not in a git directory");
+    let mut index = Index::load()?;
+    let branch = branch_info()?;
+    let renames = renames(&args)?;
+    let changes = changes(&mut index, branch.commit.as_deref(), renames.as_ref())?;
+    let untracked = match args.untracked.as_str() {
+        "no" => Vec::new(),
+        mode => untracked(&index, mode == "all")?,
+    };
+
+    let stdout = std::io::stdout();
+    let mut out = stdout.lock();
+    match args.porcelain.as_deref() {
+        Some("v2") => print_porcelain_v2(&mut out, &branch, &changes, &untracked, args.branch)?,
+        Some(_) => print_short(&mut out, &branch, &changes, &untracked, args.branch)?,
+        None if args.short => print_short(&mut out, &branch, &changes, &untracked, args.branch)?,
+        None => print_long(&mut out, &branch, &changes, &untracked, args.untracked != "no")?,
+    }
+    Ok(())
+}
+
+// Whether to look for renames, and copies: status.renames (by default diff.renames) may say
+// true, false or copies, as --find-renames and --no-renames may override.
+fn renames(args: &Args) -> anyhow::Result<Option<Renames>> {
+    let mut renames = Some(Renames { score: diff::DEFAULT_RENAME_SCORE, copies: false, harder: false });
+    let config = Config::load()?;
+    if let Some(key) = ["status.renames", "diff.renames"].into_iter().find(|key| config.get(key).is_some()) {
+        match config.get(key) {
+            Some("copies" | "copy") => renames = renames.map(|renames| Renames { copies: true, ..renames }),
+            _ if !config.get_bool(key)?.unwrap_or(true) => renames = None,
+            _ => {}
+        }
+    }
+    if args.no_renames {
+        return Ok(None);
+    }
+    if let Some(score) = &args.find_renames {
+        let copies = renames.is_some_and(|renames| renames.copies);
+        let score = match score.as_str() {
+            "" => diff::DEFAULT_RENAME_SCORE,
+            score => Renames::parse_score(score)?,
+        };
+        renames = Some(Renames { score, copies, harder: false });
+    }
+    Ok(renames)
+}
+
+fn branch_info() -> anyhow::Result<BranchInfo> {
+    let commit = refs::resolve("HEAD")?;
+    let branch = refs::symbolic_target("HEAD")?
+        .map(|target| target.strip_prefix("refs/heads/").unwrap_or(&target).to_string());
+
+    // The upstream is configured as branch.<name>.remote and branch.<name>.merge
+    let config = Config::load()?;
+    let upstream = match &branch {
+        Some(name) => match (
+            config.get(&format!("branch.{name}.remote")),
+            config.get(&format!("branch.{name}.merge")),
+        ) {
+            // "." is the repository itself; otherwise the remote's fetch refspecs say where
+            // its branch is tracked locally
+            (Some("."), Some(merge)) => Some(merge.to_string()),
+            (Some(remote), Some(merge)) => config
+                .get_all(&format!("remote.{remote}.fetch"))
+                .into_iter()
+                .find_map(|refspec| refs::map_refspec(refspec, merge)),
+            _ => None,
+        },
+        None => None,
+    };
+    let upstream = match upstream {
+        Some(full_name) => {
+            let counts = match (&commit, refs::resolve(&full_name)?) {
+                (Some(commit), Some(upstream)) => Some(revision::ahead_behind(commit, &upstream)?),
+                (None, Some(_)) => Some((0, 0)),
+                (_, None) => None,
+            };
+            let short_name = ["refs/heads/", "refs/remotes/"]
+                .iter()
+                .find_map(|prefix| full_name.strip_prefix(prefix))
+                .unwrap_or(&full_name)
+                .to_string();
+            Some((short_name, counts))
+        }
+        None => None,
+    };
+
+    Ok(BranchInfo { commit, branch, upstream })
+}
+
+// Compare HEAD with the index and the index with the working tree, for every tracked path.
+fn changes(index: &mut Index, head: Option<&str>, renames: Option<&Renames>) -> anyhow::Result<Vec<Change>> {
+    let head = match head {
+        Some(commit) => Tree::flatten(&revision::peel(commit, Kind::Tree)?)?,
+        None => BTreeMap::new(),
+    };
+
+    let mut changes: BTreeMap<String, Change> = BTreeMap::new();
+    let mut refreshed = false;
+    for i in 0..index.entries.len() {
+        let entry = &index.entries[i];
+        let change = changes.entry(entry.path.clone()).or_insert_with(|| Change {
+            path: entry.path.clone(),
+            head: head.get(&entry.path).copied(),
+            index: None,
+            worktree: None,
+            modified: false,
+            intent_to_add: false,
+            unmerged: None,
+            staged_origin: None,
+            worktree_origin: None,
+        });
+        let meta = fs::symlink_metadata(&entry.path).ok().filter(|meta| !meta.is_dir() || entry.mode == 0o160000);
+        change.worktree = meta.as_ref().map(|meta| match entry.mode {
+            0o160000 => 0o160000,
+            _ => index::mode_from_metadata(meta),
+        });
+
+        if entry.stage() != 0 {
+            change.unmerged.get_or_insert([None; 3])[entry.stage() as usize - 1] = Some((entry.mode, entry.hash));
+            continue;
+        }
+        change.index = Some((entry.mode, entry.hash));
+        change.intent_to_add = entry.is_intent_to_add();
+
+        let Some(meta) = meta else { continue };
+        if entry.mode == 0o160000 || entry.matches_stat(&meta, index.mtime) {
+            continue;
+        }
+        change.modified = if index::mode_from_metadata(&meta) != entry.mode {
+            true
+        } else if worktree::hash_file(Path::new(&entry.path), &meta, false)? == entry.hash {
+            // Unchanged after all: remember the new stat data so the file isn't read next time
+            let mut fresh = IndexEntry::from_metadata(entry.path.clone(), &meta, entry.hash);
+            fresh.flags = entry.flags;
+            fresh.extended_flags = entry.extended_flags;
+            index.entries[i] = fresh;
+            refreshed = true;
+            false
+        } else {
+            true
+        };
+    }
+
+    // Files in HEAD that are no longer in the index at all
+    for (path, &version) in &head {
+        changes.entry(path.clone()).or_insert_with(|| Change {
+            path: path.clone(),
+            head: Some(version),
+            index: None,
+            worktree: None,
+            modified: false,
+            intent_to_add: false,
+            unmerged: None,
+            staged_origin: None,
+            worktree_origin: None,
+        });
+    }
+
+    // Like git, only refresh the index opportunistically: someone else holding the lock
+    // doesn't make status fail
+    if refreshed {
+        let _ = index.write();
+    }
+    if let Some(renames) = renames {
+        find_staged_renames(&mut changes, renames)?;
+        find_worktree_renames(&mut changes, renames)?;
+    }
+
+    Ok(changes
+        .into_values()
+        .filter(|change| change.unmerged.is_some() || change.staged() != ' ' || change.unstaged() != ' ')
+        .collect())
+}
+
+// Pair up files deleted from the index with ones added to it (or, for copies, changed in it)
+// that came from them, comparing HEAD with the index like `diff --cached -M` does.
+fn find_staged_renames(changes: &mut BTreeMap<String, Change>, renames: &Renames) -> anyhow::Result<()> {
+    let side = |version: Version| version.map(|(mode, hash)| Side::new(mode, hash));
+    let pairs: Vec<FilePair> = changes
+        .values()
+        .filter(|change| change.unmerged.is_none() && change.staged() != ' ')
+        .map(|change| FilePair::new(change.path.clone(), side(change.head), side(change.index)))
+        .collect();
+    for pair in diff::find_renames(pairs, renames, None)? {
+        let Some(origin) = pair.origin else { continue };
+        if !origin.copy {
+            if let Some(source) = changes.get_mut(&origin.path) {
+                source.head = None;
+            }
+        }
+        let change = changes.get_mut(&pair.path).expect("renames are of listed paths");
+        change.head = pair.old.map(|old| (old.mode, old.hash));
+        change.staged_origin = Some(origin);
+    }
+    Ok(())
+}
+
+// Pair up files deleted from the working tree with ones added there with "add -N" that came
+// from them, comparing the index with the working tree like `diff -M` does. Only renames are
+// looked for here, as git does.
+fn find_worktree_renames(changes: &mut BTreeMap<String, Change>, renames: &Renames) -> anyhow::Result<()> {
+    let mut pairs = Vec::new();
+    for change in changes.values().filter(|change| change.unmerged.is_none()) {
+        match (change.index, change.worktree) {
+            (Some((mode, hash)), None) if !change.intent_to_add && change.staged() == ' ' => {
+                pairs.push(FilePair::new(change.path.clone(), Some(Side::new(mode, hash)), None));
+            }
+            (Some(_), Some(_)) if change.intent_to_add => {
+                let path = Path::new(&change.path);
+                let meta = fs::symlink_metadata(path).with_context(|| format!("stat {}", change.path))?;
+                let hash = worktree::hash_file(path, &meta, false)?;
+                let new = Side { mode: index::mode_from_metadata(&meta), hash, worktree: true };
+                pairs.push(FilePair::new(change.path.clone(), None, Some(new)));
+            }
+            _ => {}
+        }
+    }
+    let renames = Renames { copies: false, harder: false, ..*renames };
+    for pair in diff::find_renames(pairs, &renames, None)? {
+        let Some(origin) = pair.origin else { continue };
+        let source = changes.remove(&origin.path).expect("renames are of listed paths");
+        let change = changes.get_mut(&pair.path).expect("renames are of listed paths");
+        change.head = source.head;
+        change.index = source.index;
+        change.intent_to_add = false;
+        change.worktree_origin = Some(origin);
+    }
+    Ok(())
+}
+
+// Files that aren't tracked or ignored. Unless `all`, a directory holding no tracked files is
+// listed once (as "dir/") instead of file by file.
+fn untracked(index: &Index, all: bool) -> anyhow::Result<Vec<String>> {
+    let mut ignore = IgnoreRules::load()?;
+    let tracked: BTreeSet<&str> = index.entries.iter().map(|entry| entry.path.as_str()).collect();
+    let mut tracked_dirs = BTreeSet::new();
+    for path in &tracked {
+        let mut dir = *path;
+        while let Some((parent, _)) = dir.rsplit_once('/') {
+            tracked_dirs.insert(parent);
+            dir = parent;
+        }
+    }
+
+    let mut untracked = Vec::new();
+    for path in worktree::files(Path::new("."), "", Some(&mut ignore))? {
+        if tracked.contains(path.as_str()) {
+            continue;
+        }
+        // Submodules show up as the files inside them
+        if tracked.iter().any(|tracked| path.starts_with(&format!("{tracked}/"))) {
+            continue;
+        }
+        let mut shown = path.clone();
+        if !all {
+            let mut end = 0;
+            while let Some(slash) = path[end..].find('/') {
+                let dir = &path[..end + slash];
+                if !tracked_dirs.contains(dir) {
+                    shown = format!("{dir}/");
+                    break;
+                }
+                end += slash + 1;
+            }
+        }
+        if untracked.last() != Some(&shown) {
+            untracked.push(shown);
+        }
+    }
+    Ok(untracked)
+}
+
+fn print_long(
+    out: &mut impl Write,
+    branch: &BranchInfo,
+    changes: &[Change],
+    untracked: &[String],
+    show_untracked: bool,
+) -> anyhow::Result<()> {
+    match (&branch.branch, &branch.commit) {
+        (Some(name), _) => writeln!(out, "On branch {name}")?,
+        (None, Some(commit)) => writeln!(out, "HEAD detached at {}", revision::abbreviate(commit, 7)?)?,
+        (None, None) => writeln!(out, "Not currently on any branch.")?,
+    }
+    if let Some((upstream, counts)) = &branch.upstream {
+        match counts {
+            None => writeln!(out, "Your branch is based on '{upstream}', but the upstream is gone.")?,
+            Some((0, 0)) => writeln!(out, "Your branch is up to date with '{upstream}'.")?,
+            Some((ahead, 0)) => writeln!(
+                out,
+                "Your branch is ahead of '{upstream}' by {ahead} commit{}.",
+                plural(*ahead)
+            )?,
+            Some((0, behind)) => writeln!(
+                out,
+                "Your branch is behind '{upstream}' by {behind} commit{}, and can be fast-forwarded.",
+                plural(*behind)
+            )?,
+            Some((ahead, behind)) => writeln!(
+                out,
+                "Your branch and '{upstream}' have diverged,\n\
+                 and have {ahead} and {behind} different commits each, respectively."
+            )?,
+        }
+        writeln!(out)?;
+    }
+    if branch.commit.is_none() {
+        writeln!(out, "\nNo commits yet\n")?;
+    }
+    let unmerged: Vec<&Change> = changes.iter().filter(|change| change.unmerged.is_some()).collect();
+    // A merge stopped to let conflicts be resolved
+    if Path::new(".git/MERGE_HEAD").exists() {
+        if unmerged.is_empty() {
+            writeln!(out, "All conflicts fixed but you are still merging.\n")?;
+        } else {
+            writeln!(out, "You have unmerged paths.\n")?;
+        }
+    }
+
+    let staged: Vec<&Change> = changes
+        .iter()
+        .filter(|change| change.unmerged.is_none() && change.staged() != ' ')
+        .collect();
+    let unstaged: Vec<&Change> = changes
+        .iter()
+        .filter(|change| change.unmerged.is_none() && change.unstaged() != ' ')
+        .collect();
+
+    if !staged.is_empty() {
+        writeln!(out, "Changes to be committed:")?;
+        for change in &staged {
+            let path = match &change.staged_origin {
+                Some(origin) => format!("{} -> {}", origin.path, change.path),
+                None => change.path.clone(),
+            };
+            writeln!(out, "\t{:<12}{path}", label(change.staged()))?;
+        }
+        writeln!(out)?;
+    }
+    if !unmerged.is_empty() {
+        writeln!(out, "Unmerged paths:")?;
+        for change in &unmerged {
+            let what = match change.unmerged_code() {
+                Some("DD") => "both deleted:",
+                Some("AU") => "added by us:",
+                Some("UD") => "deleted by them:",
+                Some("UA") => "added by them:",
+                Some("DU") => "deleted by us:",
+                Some("AA") => "both added:",
+                _ => "both modified:",
+            };
+            writeln!(out, "\t{what:<17}{}", change.path)?;
+        }
+        writeln!(out)?;
+    }
+    if !unstaged.is_empty() {
+        writeln!(out, "Changes not staged for commit:")?;
+        for change in &unstaged {
+            let path = match &change.worktree_origin {
+                Some(origin) => format!("{} -> {}", origin.path, change.path),
+                None => change.path.clone(),
+            };
+            writeln!(out, "\t{:<12}{path}", label(change.unstaged()))?;
+        }
+        writeln!(out)?;
+    }
+    if !untracked.is_empty() {
+        writeln!(out, "Untracked files:")?;
+        for path in untracked {
+            writeln!(out, "\t{path}")?;
+        }
+        writeln!(out)?;
+    } else if !show_untracked && !staged.is_empty() {
+        writeln!(out, "Untracked files not listed")?;
+    }
+
+    // The closing summary, when there is nothing to commit
+    if !staged.is_empty() {
+        return Ok(());
+    }
+    if !unstaged.is_empty() || !unmerged.is_empty() {
+        writeln!(out, "no changes added to commit")?;
+    } else if !untracked.is_empty() {
+        writeln!(out, "nothing added to commit but untracked files present")?;
+    } else if branch.commit.is_some() && show_untracked {
+        writeln!(out, "nothing to commit, working tree clean")?;
+    } else {
+        writeln!(out, "nothing to commit")?;
+    }
+    Ok(())
+}
+
+fn label(status: char) -> &'static str {
+    match status {
+        'A' => "new file:",
+        'D' => "deleted:",
+        'T' => "typechange:",
+        'R' => "renamed:",
+        'C' => "copied:",
+        _ => "modified:",
+    }
+}
+
+fn plural(count: usize) -> &'static str {
+    if count == 1 {
+        ""
+    } else {
+        "s"
+    }
+}
+
+fn print_short(
+    out: &mut impl Write,
+    branch: &BranchInfo,
+    changes: &[Change],
+    untracked: &[String],
+    show_branch: bool,
+) -> anyhow::Result<()> {
+    if show_branch {
+        let mut header = match (&branch.branch, &branch.commit) {
+            (Some(name), Some(_)) => format!("## {name}"),
+            (Some(name), None) => format!("## No commits yet on {name}"),
+            (None, _) => "## HEAD (no branch)".to_string(),
+        };
+        if let Some((upstream, counts)) = &branch.upstream {
+            header.push_str(&format!("...{upstream}"));
+            match counts {
+                None => header.push_str(" [gone]"),
+                Some((0, 0)) => {}
+                Some((ahead, 0)) => header.push_str(&format!(" [ahead {ahead}]")),
+                Some((0, behind)) => header.push_str(&format!(" [behind {behind}]")),
+                Some((ahead, behind)) => header.push_str(&format!(" [ahead {ahead}, behind {behind}]")),
+            }
+        }
+        writeln!(out, "{header}")?;
+    }
+
+    for change in changes {
+        match change.unmerged_code() {
+            Some(code) => writeln!(out, "{code} {}", change.path)?,
+            None => writeln!(out, "{}{} {}", change.staged(), change.unstaged(), change.display_path())?,
+        }
+    }
+    for path in untracked {
+        writeln!(out, "?? {path}")?;
+    }
+    Ok(())
+}
+
+fn print_porcelain_v2(
+    out: &mut impl Write,
+    branch: &BranchInfo,
+    changes: &[Change],
+    untracked: &[String],
+    show_branch: bool,
+) -> anyhow::Result<()> {
+    if show_branch {
+        writeln!(out, "# branch.oid {}", branch.commit.as_deref().unwrap_or("(initial)"))?;
+        writeln!(out, "# branch.head {}", branch.branch.as_deref().unwrap_or("(detached)"))?;
+        if let Some((upstream, counts)) = &branch.upstream {
+            writeln!(out, "# branch.upstream {upstream}")?;
+            if let Some((ahead, behind)) = counts {
+                writeln!(out, "# branch.ab +{ahead} -{behind}")?;
+            }
+        }
+    }
+
+    let mode = |version: Version| version.map_or(0, |(mode, _)| mode);
+    let null = refs::null_hash()?;
+    let hash = |version: Version| version.map_or(null.clone(), |(_, hash)| hash.to_string());
+    let dot = |status: char| if status == ' ' { '.' } else { status };
+    for change in changes {
+        let submodule = "N...";
+        let worktree_mode = change.worktree.unwrap_or(0);
+        match (change.unmerged_code(), change.unmerged) {
+            (Some(code), Some([base, ours, theirs])) => writeln!(
+                out,
+                "u {code} {submodule} {:06o} {:06o} {:06o} {worktree_mode:06o} {} {} {} {}",
+                mode(base),
+                mode(ours),
+                mode(theirs),
+                hash(base),
+                hash(ours),
+                hash(theirs),
+                change.path
+            )?,
+            _ => {
+                // Nothing is staged yet for "add -N" entries
+                let index = change.index.filter(|_| !change.intent_to_add);
+                let fields = format!(
+                    "{}{} {submodule} {:06o} {:06o} {worktree_mode:06o} {} {}",
+                    dot(change.staged()),
+                    dot(change.unstaged()),
+                    mode(change.head),
+                    mode(index),
+                    hash(change.head),
+                    hash(index),
+                );
+                // Renames and copies are "2" records, with the score and the original path
+                match change.staged_origin.as_ref().or(change.worktree_origin.as_ref()) {
+                    Some(origin) => writeln!(
+                        out,
+                        "2 {fields} {}{} {}\t{}",
+                        if origin.copy { 'C' } else { 'R' },
+                        diff::similarity_index(origin.score),
+                        change.path,
+                        origin.path
+                    )?,
+                    None => writeln!(out, "1 {fields} {}", change.path)?,
+                }
+            }
+        }
+    }
+    for path in untracked {
+        writeln!(out, "? {path}")?;
+    }
+    Ok(())
+}
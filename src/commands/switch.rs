@@ -0,0 +1,130 @@
+use crate::checkout::{self, Operation};
+use crate::index::Index;
+use crate::objects::{Commit, Kind, Object};
+use crate::{refs, revision};
+use anyhow::Context;
+
+#[derive(Debug, clap::Args)]
+pub struct Args {
+    /// Branch to switch to (with -c/-C: the commit to start the new branch at)
+    branch: Option<String>,
+
+    /// Create a new branch and switch to it
+    #[clap(short = 'c', long, value_name = "NEW_BRANCH")]
+    create: Option<String>,
+
+    /// Like -c, but reset the branch if it already exists
+    #[clap(short = 'C', long, value_name = "NEW_BRANCH", conflicts_with = "create")]
+    force_create: Option<String>,
+
+    /// Switch to a commit without any branch
+    #[clap(long)]
+    detach: bool,
+
+    /// Throw away local changes that are in the way
+    #[clap(short = 'f', long, visible_alias = "discard-changes")]
+    force: bool,
+}
+
+// Where HEAD points after switching.
+pub(crate) enum Target {
+    Branch(String),
+    Detached(String),
+}
+
+pub fn invoke(args: Args) -> anyhow::Result<()> {
+    let Args { branch, create, force_create, detach, force } = args;
+
+    if let Some(new_branch) = create.as_ref().or(force_create.as_ref()) {
+        create_branch(new_branch, branch.as_deref().unwrap_or("HEAD"), force_create.is_some())?;
+        return switch_head(Target::Branch(new_branch.clone()), force);
+    }
+
+    let Some(name) = branch else {
+        anyhow::bail!("missing branch or commit argument");
+    };
+    if detach {
+        let commit = revision::peel(&revision::resolve(&name)?, Kind::Commit)?;
+        return switch_head(Target::Detached(commit), force);
+    }
+    if refs::resolve(&format!("refs/heads/{name}"))?.is_none() {
+        // Only branches can be switched to without --detach
+        anyhow::ensure!(revision::resolve(&name).is_err(), "a branch is expected, got '{name}' (use --detach)");
+        anyhow::bail!("invalid reference: {name}");
+    }
+    switch_head(Target::Branch(name), force)
+}
+
+// Create (or with `reset`, move) the branch `name` at the commit `start`.
+pub(crate) fn create_branch(name: &str, start: &str, reset: bool) -> anyhow::Result<()> {
+    anyhow::ensure!(refs::valid_name(name), "'{name}' is not a valid branch name");
+    let full_name = format!("refs/heads/{name}");
+    let commit = revision::peel(&revision::resolve(start)?, Kind::Commit)
+        .with_context(|| format!("not a valid starting point: '{start}'"))?;
+
+    let existing = refs::resolve(&full_name)?;
+    if existing.is_some() {
+        anyhow::ensure!(reset, "a branch named '{name}' already exists");
+        anyhow::ensure!(
+            refs::symbolic_target("HEAD")?.as_deref() != Some(full_name.as_str()),
+            "cannot force update the current branch"
+        );
+    }
+    let message = match existing {
+        Some(_) => format!("branch: Reset to {start}"),
+        None => format!("branch: Created from {start}"),
+    };
+    let expected = match existing {
+        Some(existing) => existing,
+        None => refs::null_hash()?,
+    };
+    refs::update(&full_name, &commit, Some(&expected), &message)
+}
+
+// Check out the commit `target` refers to and point HEAD at it.
+pub(crate) fn switch_head(target: Target, force: bool) -> anyhow::Result<()> {
+    let commit = match &target {
+        Target::Branch(name) => refs::resolve(&format!("refs/heads/{name}"))?
+            .ok_or_else(|| anyhow::anyhow!("invalid reference: {name}"))?,
+        Target::Detached(commit) => commit.clone(),
+    };
+    let current = refs::resolve("HEAD")?;
+    let current_branch = refs::symbolic_target("HEAD")?;
+
+    let mut index = Index::load()?;
+    let from = current
+        .as_deref()
+        .map(|current| revision::peel(current, Kind::Tree))
+        .transpose()?;
+    let to = revision::peel(&commit, Kind::Tree)?;
+    checkout::switch_trees(&mut index, from.as_deref(), &to, force, Operation::Checkout)?;
+    index.write()?;
+
+    let moving_from = match (&current_branch, &current) {
+        (Some(branch), _) => branch.strip_prefix("refs/heads/").unwrap_or(branch).to_string(),
+        (None, Some(current)) => current.clone(),
+        (None, None) => "HEAD".to_string(),
+    };
+    match target {
+        Target::Branch(name) => {
+            let full_name = format!("refs/heads/{name}");
+            let message = format!("checkout: moving from {moving_from} to {name}");
+            refs::set_symbolic("HEAD", &full_name, Some(&message))?;
+            if current_branch.as_deref() == Some(full_name.as_str()) {
+                eprintln!("Already on '{name}'");
+            } else {
+                eprintln!("Switched to branch '{name}'");
+            }
+        }
+        Target::Detached(commit) => {
+            let message = format!("checkout: moving from {moving_from} to {commit}");
+            refs::update_no_deref("HEAD", &commit, &message)?;
+            let data = Object::read(&commit)
+                .with_context(|| format!("read commit {commit}"))?
+                .into_bytes()?;
+            let subject = Commit::parse(&data)?.message.lines().next().unwrap_or_default().to_string();
+            eprintln!("HEAD is now at {} {subject}", revision::abbreviate(&commit, 7)?);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,127 @@
+use crate::objects::{Commit, Kind, Object, Signature, Tag};
+use crate::{refs, revision};
+use anyhow::Context;
+use std::io::Cursor;
+
+#[derive(Debug, clap::Args)]
+pub struct Args {
+    name: Option<String>,
+
+    object: Option<String>,
+
+    /// Create an annotated tag object
+    #[clap(short = 'a')]
+    annotate: bool,
+
+    /// Tag message (implies -a)
+    #[clap(short = 'm')]
+    message: Option<String>,
+
+    #[clap(short = 'd')]
+    delete: bool,
+
+    /// Replace an existing tag
+    #[clap(short = 'f')]
+    force: bool,
+
+    #[clap(short = 'l')]
+    list: bool,
+
+    /// When listing, also print the first line of each annotation
+    #[clap(short = 'n')]
+    lines: bool,
+}
+
+pub fn invoke(args: Args) -> anyhow::Result<()> {
+    let Args { name, object, annotate, message, delete, force, list, lines } = args;
+
+    // Without a name (or with -l) the command lists existing tags
+    let Some(name) = name.filter(|_| !list) else {
+        return list_tags(lines);
+    };
+
+    anyhow::ensure!(refs::valid_name(&name), "'{name}' is not a valid tag name.");
+    let ref_name = format!("refs/tags/{name}");
+
+    if delete {
+        let hash = read_tag(&name)?.ok_or_else(|| anyhow::anyhow!("tag '{name}' not found."))?;
+        refs::delete(&ref_name, Some(&hash))?;
+        println!("Deleted tag '{name}' (was {})", &hash[..7]);
+        return Ok(());
+    }
+
+    if !force && read_tag(&name)?.is_some() {
+        anyhow::bail!("tag '{name}' already exists");
+    }
+
+    // Tag HEAD unless told otherwise, and make sure the target actually exists
+    let target = object.as_deref().unwrap_or("HEAD");
+    let target = revision::resolve(target).with_context(|| format!("resolve {target}"))?;
+    let target_kind = Object::read(&target)
+        .with_context(|| format!("read tag target {target}"))?
+        .kind;
+
+    // -m implies an annotated tag
+    let hash = if annotate || message.is_some() {
+        let Some(message) = message else {
+            anyhow::bail!("annotated tags need a message, use -m <msg>");
+        };
+        let mut message = message;
+        if !message.ends_with('\n') {
+            message.push('\n');
+        }
+
+        let tag = Tag {
+            object: target,
+            kind: target_kind,
+            tag: name.clone(),
+            tagger: Some(Signature::committer()?),
+            message,
+        }
+        .to_string();
+        let hash = Object {
+            kind: Kind::Tag,
+            size: tag.len() as u64,
+            reader: Cursor::new(tag),
+        }
+        .write_to_objects()
+        .context("write tag object")?;
+        hash.to_string()
+    } else {
+        target
+    };
+
+    // Without -f, the tag must still not exist when the ref is written
+    let expected = if force { None } else { Some(refs::null_hash()?) };
+    refs::update(&ref_name, &hash, expected.as_deref(), "tag")?;
+
+    Ok(())
+}
+
+// The hash a tag points at, either from its loose ref or from packed-refs.
+fn read_tag(name: &str) -> anyhow::Result<Option<String>> {
+    refs::resolve(&format!("refs/tags/{name}"))
+}
+
+fn list_tags(lines: bool) -> anyhow::Result<()> {
+    for (name, hash) in refs::list("refs/tags/")? {
+        let name = name.strip_prefix("refs/tags/").unwrap_or(&name);
+        if !lines {
+            println!("{name}");
+            continue;
+        }
+
+        // With -n, show the first line of the annotation (or of the tagged commit)
+        let object = Object::read(&hash).with_context(|| format!("read tag {name}"))?;
+        let kind = object.kind;
+        let data = object.into_bytes()?;
+        let text = match kind {
+            Kind::Tag => Tag::parse(&data)?.message,
+            Kind::Commit => Commit::parse(&data)?.message,
+            _ => String::new(),
+        };
+        let summary = text.lines().next().unwrap_or_default();
+        println!("{name:<15} {summary}");
+    }
+    Ok(())
+}
@@ -0,0 +1,31 @@
+use crate::server::{self, upload_pack};
+use std::io::{self, BufWriter};
+use std::path::PathBuf;
+
+#[derive(Debug, clap::Args)]
+pub struct Args {
+    /// Answer a single request, without advertising refs first (for smart HTTP)
+    #[clap(long)]
+    stateless_rpc: bool,
+
+    /// Only advertise the refs (or, for protocol v2, the capabilities) and exit
+    #[clap(long, alias = "http-backend-info-refs")]
+    advertise_refs: bool,
+
+    directory: PathBuf,
+}
+
+// Send objects to a client fetching from the repository, over stdin and stdout. The protocol
+// version the client asked for comes in $GIT_PROTOCOL.
+pub fn invoke(args: Args) -> anyhow::Result<()> {
+    server::enter_repository(&args.directory)?;
+    let version = server::protocol_version(std::env::var("GIT_PROTOCOL").ok().as_deref());
+    let mut output = BufWriter::new(io::stdout().lock());
+    if !args.stateless_rpc {
+        upload_pack::advertise(version, &mut output)?;
+    }
+    if !args.advertise_refs {
+        upload_pack::serve(version, args.stateless_rpc, &mut io::stdin().lock(), &mut output)?;
+    }
+    Ok(())
+}
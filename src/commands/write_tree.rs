@@ -1,4 +1,7 @@
-use crate::objects::{Kind, Object};
+use crate::ignore::IgnoreRules;
+use crate::index::Index;
+use crate::objects::{Kind, Object, ObjectId};
+use crate::worktree;
 use anyhow::Context;
 use std::fs;
 use std::io::Cursor;
@@ -7,16 +10,27 @@ use std::path::Path;
 use std::cmp::Ordering;
 
 
-fn write_tree_for(path: &Path) -> anyhow::Result<Option<[u8; 20]>> {
+// `prefix` is the path of `path` relative to the repository root ("" for the root itself),
+// which is what ignore rules are matched against.
+fn write_tree_for(path: &Path, prefix: &str, ignore: &mut IgnoreRules) -> anyhow::Result<Option<ObjectId>> {
     // Read directory entries and collect metadata early so we can sort and decide behavior.
-    let mut dir = fs::read_dir(path).with_context(|| format!("failed to read directory {}", path.display()))?;
+    let dir = fs::read_dir(path).with_context(|| format!("failed to read directory {}", path.display()))?;
     let mut entries = Vec::new();
 
-    while let Some(entry) = dir.next() {
+    for entry in dir {
         let entry = entry.with_context(|| format!("failed to read entry in {}", path.display()))?;
         let name = entry.file_name();
         let meta = entry.metadata().with_context(|| format!("failed to read metadata for {}", name.to_string_lossy()))?;
-        entries.push((entry, name, meta));
+
+        // Leave out whatever .gitignore, info/exclude or core.excludesFile rule out
+        let repo_path = match prefix {
+            "" => name.to_string_lossy().into_owned(),
+            prefix => format!("{prefix}/{}", name.to_string_lossy()),
+        };
+        if ignore.is_ignored(&repo_path, meta.is_dir())? {
+            continue;
+        }
+        entries.push((entry, name, meta, repo_path));
     }
 
     // Sort entries using Git's tree sort order:
@@ -55,9 +69,9 @@ fn write_tree_for(path: &Path) -> anyhow::Result<Option<[u8; 20]>> {
 
     // Build the raw tree payload following the "tree" object format.
     // For each entry:
-    //   "<mode> <name>\0<20-byte raw hash>"
+    //   "<mode> <name>\0<raw hash>"
     let mut tree_object = Vec::new();
-    for (entry, file_name, meta) in entries {
+    for (entry, file_name, meta, repo_path) in entries {
         // Never include the repository's own .git directory in the tree.
         if file_name == ".git" {
             continue; // Skip the .git directory
@@ -84,7 +98,7 @@ fn write_tree_for(path: &Path) -> anyhow::Result<Option<[u8; 20]>> {
         // - For directories: recursively write a tree (skip if empty).
         // - For files/symlinks: create a blob object if necessary and store it.
         let hash = if meta.is_dir() {
-            let Some(hash) = write_tree_for(&path)?  else {
+            let Some(hash) = write_tree_for(&path, &repo_path, ignore)?  else {
                 // If the directory produced no entries, do not include it in this tree.
                 continue; // Skip empty directories
             };
@@ -100,7 +114,7 @@ fn write_tree_for(path: &Path) -> anyhow::Result<Option<[u8; 20]>> {
                 .context("open blob input file")?
                 .write(std::fs::File::create(tmp).context("construct temporary file for blob")?)
                 .context("stream file into blob")?;
-            let hash_hex = hex::encode(hash);
+            let hash_hex = hash.to_string();
             fs::create_dir_all(format!(".git/objects/{}/", &hash_hex[..2]))
                 .context("create subdir of .git/objects")?;
             std::fs::rename(
@@ -117,8 +131,8 @@ fn write_tree_for(path: &Path) -> anyhow::Result<Option<[u8; 20]>> {
         // Names are written as raw bytes; this matches Git behavior on Unix.
         tree_object.extend(file_name.as_encoded_bytes());
         tree_object.push(0);
-        // The hash is the raw 20-byte object id, not hex.
-        tree_object.extend(hash);
+        // The hash is the raw object id, not hex.
+        tree_object.extend(hash.as_bytes());
     }
 
     // If nothing was added, the directory is empty: propagate None upward.
@@ -138,14 +152,32 @@ fn write_tree_for(path: &Path) -> anyhow::Result<Option<[u8; 20]>> {
     }
 }
 
-pub fn invoke() -> anyhow::Result<()> {
-    // Build a tree for the current working directory.
-    let Some(hash) = write_tree_for(Path::new(".")).with_context(|| "failed to write tree")? else {
-        anyhow::bail!("no files to write to the tree");
+pub fn invoke(prefix: Option<String>) -> anyhow::Result<()> {
+    // Repositories that never staged anything keep the old behaviour of snapshotting the
+    // working directory; otherwise the tree is built from the index, like git does.
+    if !Path::new(".git/index").exists() {
+        anyhow::ensure!(prefix.is_none(), "--prefix requires an index");
+        // Build a tree for the current working directory.
+        let mut ignore = IgnoreRules::load()?;
+        let Some(hash) = write_tree_for(Path::new("."), "", &mut ignore).with_context(|| "failed to write tree")? else {
+            anyhow::bail!("no files to write to the tree");
+        };
+
+        // Print the tree id in hex, matching `git write-tree` output.
+        println!("{hash}");
+        return Ok(());
+    }
+
+    let prefix = match prefix {
+        Some(prefix) => worktree::repo_path(Path::new(&prefix))?,
+        None => String::new(),
     };
+    let mut index = Index::load()?;
+    let hash = index.write_tree(&prefix).context("failed to write tree")?;
+    // Save the refreshed tree cache so the next write-tree can reuse it
+    index.write()?;
 
-    // Print the tree id in hex, matching `git write-tree` output.
-    println!("{}", hex::encode(hash));
+    println!("{hash}");
 
     Ok(())
-}
\ No newline at end of file
+}
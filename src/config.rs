@@ -0,0 +1,309 @@
+use crate::{ignore, refs, repository};
+use anyhow::Context;
+use std::path::{Path, PathBuf};
+
+mod file;
+
+pub(crate) use file::{valid_name, ConfigFile};
+
+// How deep include.path may nest, so that files including each other are caught.
+const MAX_INCLUDE_DEPTH: usize = 10;
+
+// Where a setting comes from, from the lowest precedence to the highest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Scope {
+    // /etc/gitconfig or $GIT_CONFIG_SYSTEM
+    System,
+    // $XDG_CONFIG_HOME/git/config and ~/.gitconfig, or $GIT_CONFIG_GLOBAL
+    Global,
+    // .git/config
+    Local,
+    // .git/config.worktree, read when extensions.worktreeConfig is set
+    Worktree,
+    // $GIT_CONFIG_COUNT / $GIT_CONFIG_KEY_<n> / $GIT_CONFIG_VALUE_<n>, or a file given with
+    // `config --file`
+    Command,
+}
+
+impl Scope {
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Scope::System => "system",
+            Scope::Global => "global",
+            Scope::Local => "local",
+            Scope::Worktree => "worktree",
+            Scope::Command => "command",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Entry {
+    // Normalized to "section.subsection.name", with section and name lowercased
+    pub(crate) key: String,
+    // None for a key without '=' (a boolean true)
+    pub(crate) value: Option<String>,
+    pub(crate) scope: Scope,
+    // The file the setting is in, if it came from one
+    pub(crate) origin: Option<PathBuf>,
+}
+
+// Git configuration as read from the system, global, repository and worktree files (and the
+// files they include) and the environment. Later settings take precedence over earlier ones.
+#[derive(Debug, Default)]
+pub(crate) struct Config {
+    pub(crate) entries: Vec<Entry>,
+}
+
+impl Config {
+    pub(crate) fn load() -> anyhow::Result<Config> {
+        let mut config = Config::default();
+        for (scope, path) in [Scope::System, Scope::Global, Scope::Local]
+            .into_iter()
+            .flat_map(|scope| scope_files(scope).into_iter().map(move |path| (scope, path)))
+        {
+            config.read_file(&path, scope, 0)?;
+        }
+        if config.get_bool("extensions.worktreeConfig")?.unwrap_or(false) {
+            config.read_file(&repository::git_dir().join("config.worktree"), Scope::Worktree, 0)?;
+        }
+        config.read_env()?;
+        Ok(config)
+    }
+
+    // Just the settings in `path` (and the files it includes).
+    pub(crate) fn load_file(path: &Path, scope: Scope) -> anyhow::Result<Config> {
+        let mut config = Config::default();
+        config.read_file(path, scope, 0)?;
+        Ok(config)
+    }
+
+    fn read_file(&mut self, path: &Path, scope: Scope, depth: usize) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            depth <= MAX_INCLUDE_DEPTH,
+            "exceeded maximum include depth ({MAX_INCLUDE_DEPTH}) while including {}; \
+             this might be due to circular includes",
+            path.display()
+        );
+        let file = ConfigFile::open(path)?;
+        for setting in file.settings {
+            let include = match setting.key.strip_suffix(".path") {
+                Some("include") => true,
+                Some(section) => match section.strip_prefix("includeif.") {
+                    Some(condition) => include_condition(condition, path)?,
+                    None => false,
+                },
+                None => false,
+            };
+            let value = setting.value.clone();
+            self.entries.push(Entry { key: setting.key, value: setting.value, scope, origin: Some(path.to_path_buf()) });
+
+            // Included files are read right where they are included, relative to the
+            // including file
+            if let Some(included) = value.filter(|_| include) {
+                let included = expand_home(&included);
+                let included = match path.parent() {
+                    Some(dir) if included.is_relative() => dir.join(included),
+                    _ => included,
+                };
+                self.read_file(&included, scope, depth + 1)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Settings given in the environment, like `git -c` passes them on.
+    fn read_env(&mut self) -> anyhow::Result<()> {
+        let Ok(count) = std::env::var("GIT_CONFIG_COUNT") else {
+            return Ok(());
+        };
+        let count: usize = count.parse().with_context(|| format!("bogus count in GIT_CONFIG_COUNT: {count}"))?;
+        for i in 0..count {
+            let key = std::env::var(format!("GIT_CONFIG_KEY_{i}"))
+                .with_context(|| format!("missing config key GIT_CONFIG_KEY_{i}"))?;
+            let value = std::env::var(format!("GIT_CONFIG_VALUE_{i}"))
+                .with_context(|| format!("missing config value GIT_CONFIG_VALUE_{i}"))?;
+            let key = normalize_key(&key);
+            self.entries.push(Entry { key, value: Some(value), scope: Scope::Command, origin: None });
+        }
+        Ok(())
+    }
+
+    // The last value set for `key` (e.g. "core.excludesFile").
+    pub(crate) fn get(&self, key: &str) -> Option<&str> {
+        let key = normalize_key(key);
+        self.entries
+            .iter()
+            .rev()
+            .find(|entry| entry.key == key)
+            .map(|entry| entry.value.as_deref().unwrap_or("true"))
+    }
+
+    // Every value set for a multi-valued `key`, in order.
+    pub(crate) fn get_all(&self, key: &str) -> Vec<&str> {
+        let key = normalize_key(key);
+        self.entries
+            .iter()
+            .filter(|entry| entry.key == key)
+            .map(|entry| entry.value.as_deref().unwrap_or("true"))
+            .collect()
+    }
+
+    // A path value, with a leading "~/" expanded to the home directory.
+    pub(crate) fn get_path(&self, key: &str) -> Option<PathBuf> {
+        let value = self.get(key)?;
+        Some(expand_home(value))
+    }
+
+    pub(crate) fn get_bool(&self, key: &str) -> anyhow::Result<Option<bool>> {
+        let key = normalize_key(key);
+        match self.entries.iter().rev().find(|entry| entry.key == key) {
+            Some(entry) => Ok(Some(parse_bool(&key, entry.value.as_deref())?)),
+            None => Ok(None),
+        }
+    }
+
+    pub(crate) fn get_int(&self, key: &str) -> anyhow::Result<Option<i64>> {
+        let key = normalize_key(key);
+        match self.entries.iter().rev().find(|entry| entry.key == key) {
+            Some(entry) => Ok(Some(parse_int(&key, entry.value.as_deref())?)),
+            None => Ok(None),
+        }
+    }
+}
+
+// A boolean the way git reads them: true, yes, on and 1 or false, no, off, 0 and the empty
+// string, in any case. A key without a value is true.
+pub(crate) fn parse_bool(key: &str, value: Option<&str>) -> anyhow::Result<bool> {
+    let Some(value) = value else {
+        return Ok(true);
+    };
+    match value.to_ascii_lowercase().as_str() {
+        "true" | "yes" | "on" => Ok(true),
+        "false" | "no" | "off" | "" => Ok(false),
+        _ => match value.parse::<i64>() {
+            Ok(n) => Ok(n != 0),
+            Err(_) => anyhow::bail!("bad boolean config value '{value}' for '{key}'"),
+        },
+    }
+}
+
+// An integer, optionally scaled by a k, m or g suffix (1024, 1024² and 1024³).
+pub(crate) fn parse_int(key: &str, value: Option<&str>) -> anyhow::Result<i64> {
+    let value = value.unwrap_or_default();
+    let (digits, factor) = match value.chars().last().map(|c| c.to_ascii_lowercase()) {
+        Some('k') => (&value[..value.len() - 1], 1 << 10),
+        Some('m') => (&value[..value.len() - 1], 1 << 20),
+        Some('g') => (&value[..value.len() - 1], 1 << 30),
+        _ => (value, 1),
+    };
+    let n: i64 = digits
+        .trim()
+        .parse()
+        .map_err(|_| anyhow::anyhow!("bad numeric config value '{value}' for '{key}': invalid unit"))?;
+    n.checked_mul(factor)
+        .ok_or_else(|| anyhow::anyhow!("bad numeric config value '{value}' for '{key}': out of range"))
+}
+
+// Whether an includeIf.<condition>.path applies:
+//   gitdir:<pattern>, gitdir/i:<pattern>  the .git directory matches the glob
+//   onbranch:<pattern>                   the current branch matches the glob
+// Unknown conditions never apply.
+fn include_condition(condition: &str, including: &Path) -> anyhow::Result<bool> {
+    if let Some(pattern) = condition.strip_prefix("onbranch:") {
+        let Some(target) = refs::symbolic_target("HEAD").ok().flatten() else {
+            return Ok(false);
+        };
+        let branch = target.strip_prefix("refs/heads/").unwrap_or(&target);
+        let pattern = if pattern.ends_with('/') { format!("{pattern}**") } else { pattern.to_string() };
+        return Ok(ignore::wildmatch(pattern.as_bytes(), branch.as_bytes()));
+    }
+
+    let (pattern, fold_case) = match (condition.strip_prefix("gitdir:"), condition.strip_prefix("gitdir/i:")) {
+        (Some(pattern), _) => (pattern, false),
+        (_, Some(pattern)) => (pattern, true),
+        _ => return Ok(false),
+    };
+    let Ok(git_dir) = repository::git_dir().canonicalize() else {
+        return Ok(false);
+    };
+
+    // "~/" is the home directory and "./" the including file's directory; other relative
+    // patterns match anywhere, and a trailing slash matches everything below
+    let mut pattern = if pattern.starts_with("~/") {
+        expand_home(pattern).to_string_lossy().into_owned()
+    } else if let Some(rest) = pattern.strip_prefix("./") {
+        let dir = including.parent().and_then(|dir| dir.canonicalize().ok());
+        match dir {
+            Some(dir) => format!("{}/{rest}", dir.display()),
+            None => return Ok(false),
+        }
+    } else if pattern.starts_with('/') {
+        pattern.to_string()
+    } else {
+        format!("**/{pattern}")
+    };
+    if pattern.ends_with('/') {
+        pattern.push_str("**");
+    }
+
+    let git_dir = git_dir.to_string_lossy().into_owned();
+    Ok(if fold_case {
+        ignore::wildmatch(pattern.to_lowercase().as_bytes(), git_dir.to_lowercase().as_bytes())
+    } else {
+        ignore::wildmatch(pattern.as_bytes(), git_dir.as_bytes())
+    })
+}
+
+fn home() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(PathBuf::from)
+}
+
+pub(crate) fn expand_home(value: &str) -> PathBuf {
+    match (value.strip_prefix("~/"), home()) {
+        (Some(rest), Some(home)) => home.join(rest),
+        _ => PathBuf::from(value),
+    }
+}
+
+// The XDG configuration directory for git: $XDG_CONFIG_HOME/git or ~/.config/git.
+pub(crate) fn xdg_dir() -> Option<PathBuf> {
+    match std::env::var_os("XDG_CONFIG_HOME").filter(|dir| !dir.is_empty()) {
+        Some(dir) => Some(PathBuf::from(dir).join("git")),
+        None => home().map(|home| home.join(".config/git")),
+    }
+}
+
+// The files read for a scope, in order.
+pub(crate) fn scope_files(scope: Scope) -> Vec<PathBuf> {
+    match scope {
+        Scope::System => {
+            if std::env::var_os("GIT_CONFIG_NOSYSTEM").is_some_and(|value| !value.is_empty() && value != "0") {
+                return Vec::new();
+            }
+            vec![std::env::var_os("GIT_CONFIG_SYSTEM").map_or_else(|| PathBuf::from("/etc/gitconfig"), PathBuf::from)]
+        }
+        Scope::Global => {
+            if let Some(global) = std::env::var_os("GIT_CONFIG_GLOBAL") {
+                return vec![PathBuf::from(global)];
+            }
+            xdg_dir().map(|xdg| xdg.join("config")).into_iter().chain(home().map(|home| home.join(".gitconfig"))).collect()
+        }
+        Scope::Local => vec![repository::git_dir().join("config")],
+        Scope::Worktree => vec![repository::git_dir().join("config.worktree")],
+        Scope::Command => Vec::new(),
+    }
+}
+
+// "Section.Sub.Name" -> "section.Sub.name": only the subsection is case sensitive.
+pub(crate) fn normalize_key(key: &str) -> String {
+    match (key.find('.'), key.rfind('.')) {
+        (Some(first), Some(last)) if first != last => format!(
+            "{}{}{}",
+            key[..first].to_ascii_lowercase(),
+            &key[first..last],
+            key[last..].to_ascii_lowercase()
+        ),
+        _ => key.to_ascii_lowercase(),
+    }
+}
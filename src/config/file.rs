@@ -0,0 +1,464 @@
+use crate::lockfile::Lockfile;
+use anyhow::Context;
+use std::fs;
+use std::io::Write;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+// One config file as text, with where each setting and section header is in it, so settings
+// can be changed without disturbing the rest of the file (comments, layout, other keys).
+#[derive(Debug, Default)]
+pub(crate) struct ConfigFile {
+    pub(crate) path: PathBuf,
+    text: String,
+    pub(crate) settings: Vec<Setting>,
+    sections: Vec<Section>,
+}
+
+#[derive(Debug)]
+pub(crate) struct Setting {
+    // Normalized to "section.subsection.name", with section and name lowercased
+    pub(crate) key: String,
+    // None for a key without '=' (a boolean true)
+    pub(crate) value: Option<String>,
+    // The lines the setting takes up, including continuation lines and the final newline
+    span: Range<usize>,
+}
+
+#[derive(Debug)]
+struct Section {
+    // Normalized like keys: "section" or "section.subsection"
+    name: String,
+    // Where the header line starts
+    start: usize,
+    // Where the header line or else the last setting in the section ends
+    end: usize,
+}
+
+impl ConfigFile {
+    // Read and parse `path`; a file that doesn't exist is empty.
+    pub(crate) fn open(path: &Path) -> anyhow::Result<ConfigFile> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e).with_context(|| format!("read {}", path.display())),
+        };
+        ConfigFile::parse(path, text)
+    }
+
+    pub(crate) fn parse(path: &Path, text: String) -> anyhow::Result<ConfigFile> {
+        let mut file = ConfigFile { path: path.to_path_buf(), text, ..ConfigFile::default() };
+        file.index().with_context(|| format!("bad config file {}", path.display()))?;
+        Ok(file)
+    }
+
+    // Parse the INI dialect of git config files.
+    fn index(&mut self) -> anyhow::Result<()> {
+        self.settings.clear();
+        self.sections.clear();
+        let mut lines = Lines { text: &self.text, offset: 0, number: 0 };
+
+        while let Some((mut start, line)) = lines.next() {
+            let number = lines.number;
+            let mut trimmed = line.trim_start();
+            let comment = |text: &str| text.is_empty() || text.starts_with('#') || text.starts_with(';');
+            if comment(trimmed) {
+                continue;
+            }
+
+            if let Some(rest) = trimmed.strip_prefix('[') {
+                let end = header_end(rest)
+                    .ok_or_else(|| anyhow::anyhow!("line {number}: unterminated section header"))?;
+                let name = section_name(&rest[..end]).with_context(|| format!("line {number}"))?;
+                self.sections.push(Section { name, start, end: lines.offset });
+                // The header may be followed by a comment, or by the section's first setting
+                let after = rest[end + 1..].trim_start();
+                if comment(after) {
+                    continue;
+                }
+                start += line.len() - after.len();
+                trimmed = after;
+            }
+
+            let Some(section) = self.sections.last_mut() else {
+                anyhow::bail!("line {number}: key outside of any section");
+            };
+            let (name, value) = match trimmed.split_once('=') {
+                Some((name, value)) => (name.trim(), Some(value)),
+                None => (trimmed.split(['#', ';']).next().unwrap_or_default().trim(), None),
+            };
+            anyhow::ensure!(valid_name(name), "line {number}: invalid key '{name}'");
+
+            let value = match value {
+                Some(value) => {
+                    // Values may continue on the next line after a trailing backslash
+                    let mut raw = value.to_string();
+                    while raw.ends_with('\\') && !raw.ends_with("\\\\") {
+                        raw.pop();
+                        match lines.next() {
+                            Some((_, next)) => raw.push_str(next),
+                            None => break,
+                        }
+                    }
+                    Some(parse_value(&raw).with_context(|| format!("line {number}"))?)
+                }
+                None => None,
+            };
+            section.end = lines.offset;
+            let key = format!("{}.{}", section.name, name.to_ascii_lowercase());
+            self.settings.push(Setting { key, value, span: start..lines.offset });
+        }
+        Ok(())
+    }
+
+    // Replace the setting at `index` of `settings` with `key = value`, or remove it if there
+    // is no value.
+    pub(crate) fn replace(&mut self, index: usize, key: &str, value: Option<&str>) -> anyhow::Result<()> {
+        let mut span = self.settings[index].span.clone();
+        let mut line = value.map(|value| setting_line(key, value)).unwrap_or_default();
+        // A setting on its section's header line: the header keeps that line to itself
+        if span.start > 0 && !self.text[..span.start].ends_with('\n') {
+            span.start = self.text[..span.start].trim_end_matches([' ', '\t']).len();
+            line.insert(0, '\n');
+        }
+        self.text.replace_range(span, &line);
+        self.index()
+    }
+
+    // Add `key = value` at the end of the last section it belongs in, starting that section
+    // at the end of the file if there is none yet.
+    pub(crate) fn add(&mut self, key: &str, value: &str) -> anyhow::Result<()> {
+        let (section, _) = key.rsplit_once('.').expect("keys have a section");
+        let wanted = super::normalize_key(key);
+        let (wanted_section, _) = wanted.rsplit_once('.').expect("keys have a section");
+        let line = setting_line(key, value);
+
+        match self.sections.iter().rev().find(|s| s.name == wanted_section) {
+            Some(existing) => {
+                // The last line of a file may have no newline yet
+                let at = existing.end;
+                let line = if self.text[..at].ends_with('\n') { line } else { format!("\n{line}") };
+                self.text.insert_str(at, &line);
+            }
+            None => {
+                if !self.text.is_empty() && !self.text.ends_with('\n') {
+                    self.text.push('\n');
+                }
+                let header = match section.split_once('.') {
+                    Some((name, sub)) => format!("[{name} \"{}\"]\n", sub.replace('\\', "\\\\").replace('"', "\\\"")),
+                    None => format!("[{section}]\n"),
+                };
+                self.text.push_str(&header);
+                self.text.push_str(&line);
+            }
+        }
+        self.index()
+    }
+
+    // Give `key` a single value: replace its last setting (removing any others), or add it.
+    pub(crate) fn set(&mut self, key: &str, value: &str) -> anyhow::Result<()> {
+        let wanted = super::normalize_key(key);
+        let found: Vec<usize> = (0..self.settings.len()).filter(|&i| self.settings[i].key == wanted).collect();
+        match found.split_last() {
+            Some((&last, rest)) => {
+                self.replace(last, key, Some(value))?;
+                for &i in rest.iter().rev() {
+                    self.replace(i, key, None)?;
+                }
+                Ok(())
+            }
+            None => self.add(key, value),
+        }
+    }
+
+    // Remove every `name` section (e.g. "remote.origin") with all its settings. Returns
+    // whether there was one.
+    pub(crate) fn remove_section(&mut self, name: &str) -> anyhow::Result<bool> {
+        let wanted = super::normalize_key(&format!("{name}.x"));
+        let (wanted, _) = wanted.rsplit_once('.').expect("keys have a section");
+        let spans: Vec<Range<usize>> =
+            self.sections.iter().filter(|s| s.name == wanted).map(|s| s.start..s.end).collect();
+        for span in spans.iter().rev() {
+            self.text.replace_range(span.clone(), "");
+        }
+        self.index()?;
+        Ok(!spans.is_empty())
+    }
+
+    // Save the file, atomically.
+    pub(crate) fn write(&self) -> anyhow::Result<()> {
+        let mut lock = Lockfile::acquire(&self.path)?;
+        lock.write_all(self.text.as_bytes())
+            .with_context(|| format!("write {}", self.path.display()))?;
+        lock.commit()
+    }
+}
+
+// The lines of a text with the offsets they start at, without their line endings.
+struct Lines<'a> {
+    text: &'a str,
+    // Where the next line starts
+    offset: usize,
+    number: usize,
+}
+
+impl<'a> Iterator for Lines<'a> {
+    type Item = (usize, &'a str);
+
+    fn next(&mut self) -> Option<(usize, &'a str)> {
+        let rest = &self.text[self.offset..];
+        if rest.is_empty() {
+            return None;
+        }
+        let start = self.offset;
+        let len = rest.find('\n').map_or(rest.len(), |i| i + 1);
+        self.offset += len;
+        self.number += 1;
+        Some((start, rest[..len].trim_end_matches(['\n', '\r'])))
+    }
+}
+
+// Where the ']' closing a section header is in what follows its '[': the first one outside
+// the quotes of a subsection, in which backslashes escape.
+fn header_end(rest: &str) -> Option<usize> {
+    let mut quoted = false;
+    let mut chars = rest.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' if quoted => {
+                chars.next();
+            }
+            '"' => quoted = !quoted,
+            ']' if !quoted => return Some(i),
+            _ => {}
+        }
+    }
+    None
+}
+
+// The normalized name of a section header (what is between the brackets):
+//   section              -> "section"
+//   section "Sub"        -> "section.Sub" (the subsection keeps its case)
+//   section.Sub          -> "section.sub" (the deprecated form, which doesn't)
+fn section_name(header: &str) -> anyhow::Result<String> {
+    let valid = |name: &str| !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.');
+    match header.split_once(|c: char| c.is_ascii_whitespace()) {
+        Some((name, sub)) => {
+            anyhow::ensure!(valid(name), "invalid section name '{name}'");
+            let sub = sub
+                .trim()
+                .strip_prefix('"')
+                .and_then(|sub| sub.strip_suffix('"'))
+                .ok_or_else(|| anyhow::anyhow!("invalid subsection"))?;
+            let mut unescaped = String::new();
+            let mut chars = sub.chars();
+            while let Some(c) = chars.next() {
+                unescaped.push(if c == '\\' { chars.next().unwrap_or('\\') } else { c });
+            }
+            Ok(format!("{}.{unescaped}", name.to_ascii_lowercase()))
+        }
+        None => {
+            anyhow::ensure!(valid(header), "invalid section name '{header}'");
+            Ok(header.to_ascii_lowercase())
+        }
+    }
+}
+
+// Variable names start with a letter and go on with letters, digits and '-'.
+pub(crate) fn valid_name(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_alphabetic()) && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+}
+
+// Unquote a value: strip comments and surrounding whitespace outside of quotes and
+// process the \n, \t, \b, \" and \\ escapes.
+fn parse_value(raw: &str) -> anyhow::Result<String> {
+    let mut value = String::new();
+    let mut quoted = false;
+    // Whitespace is only kept when something follows it
+    let mut pending_space = String::new();
+    let mut chars = raw.trim_start().chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                continue;
+            }
+            '#' | ';' if !quoted => break,
+            '\\' => {
+                let escaped = match chars.next() {
+                    Some('n') => '\n',
+                    Some('t') => '\t',
+                    Some('b') => '\u{8}',
+                    Some('"') => '"',
+                    Some('\\') => '\\',
+                    Some(other) => anyhow::bail!("invalid escape '\\{other}' in value"),
+                    None => break,
+                };
+                value.push_str(&pending_space);
+                pending_space.clear();
+                value.push(escaped);
+                continue;
+            }
+            // Each whitespace character (tabs too) outside quotes counts as a space
+            c if c.is_whitespace() && !quoted => {
+                pending_space.push(' ');
+                continue;
+            }
+            _ => {}
+        }
+        value.push_str(&pending_space);
+        pending_space.clear();
+        value.push(c);
+    }
+    anyhow::ensure!(!quoted, "unterminated quote in value");
+    Ok(value)
+}
+
+// "\tname = value\n", with the value quoted the way git writes it: escapes for newlines,
+// tabs, quotes and backslashes, and the whole value in quotes if it has spaces at either end
+// or comment characters.
+fn setting_line(key: &str, value: &str) -> String {
+    let (_, name) = key.rsplit_once('.').expect("keys have a section");
+    let quote = value.starts_with(' ') || value.ends_with(' ') || value.contains(['#', ';']);
+    let mut line = format!("\t{name} = ");
+    if quote {
+        line.push('"');
+    }
+    for c in value.chars() {
+        match c {
+            '\n' => line.push_str("\\n"),
+            '\t' => line.push_str("\\t"),
+            '"' => line.push_str("\\\""),
+            '\\' => line.push_str("\\\\"),
+            c => line.push(c),
+        }
+    }
+    if quote {
+        line.push('"');
+    }
+    line.push('\n');
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> anyhow::Result<ConfigFile> {
+        ConfigFile::parse(Path::new("config"), text.to_string())
+    }
+
+    fn settings(file: &ConfigFile) -> Vec<(&str, Option<&str>)> {
+        file.settings.iter().map(|setting| (setting.key.as_str(), setting.value.as_deref())).collect()
+    }
+
+    #[test]
+    fn values_are_read_like_git_reads_them() {
+        let file = parse(
+            "# a comment\n\
+             [core]\n\
+             \tplain = value\n\
+             \tquoted = \"  keep # this  \"  ; comment\n\
+             \truns = a \t b   # comment\n\
+             \tescapes = tab\\there\\nnew \\\"q\\\" back\\\\slash\n\
+             \tflag\n\
+             \tcontinued = one \\\n  two\n\
+             ; another comment\n\
+             \tEmpty =\n",
+        )
+        .unwrap();
+        assert_eq!(
+            settings(&file),
+            [
+                ("core.plain", Some("value")),
+                ("core.quoted", Some("  keep # this  ")),
+                ("core.runs", Some("a   b")),
+                ("core.escapes", Some("tab\there\nnew \"q\" back\\slash")),
+                ("core.flag", None),
+                ("core.continued", Some("one   two")),
+                ("core.empty", Some("")),
+            ]
+        );
+    }
+
+    #[test]
+    fn section_names_keep_the_case_of_subsections() {
+        let file = parse(
+            "[Remote \"Origin\"]\n\tURL = x\n\
+             [Remote.Origin]\n\tfoo = y\n\
+             [remote \"a]b\\\"c\"] k = v\n\
+             [branch \"main\"] # comment\n\tmerge = refs/heads/main\n",
+        )
+        .unwrap();
+        assert_eq!(
+            settings(&file),
+            [
+                ("remote.Origin.url", Some("x")),
+                ("remote.origin.foo", Some("y")),
+                ("remote.a]b\"c.k", Some("v")),
+                ("branch.main.merge", Some("refs/heads/main")),
+            ]
+        );
+    }
+
+    #[test]
+    fn malformed_files_are_rejected() {
+        for text in [
+            "[core\n",
+            "[remote \"open]\n",
+            "key = outside\n",
+            "[core]\n\t1key = x\n",
+            "[core]\n\tkey = \"unterminated\n",
+            "[core]\n\tkey = bad\\escape\n",
+            "[bad name]\n",
+        ] {
+            assert!(parse(text).is_err(), "{text:?}");
+        }
+    }
+
+    #[test]
+    fn set_replaces_the_last_value_and_keeps_the_rest() {
+        let mut file = parse("# settings\n[core]\n\ta = 1 ; first\n\tb = 2\n[core]\n\ta = 3\n").unwrap();
+        file.set("core.a", "four").unwrap();
+        assert_eq!(file.text, "# settings\n[core]\n\tb = 2\n[core]\n\ta = four\n");
+        file.set("core.c", " padded\tvalue ").unwrap();
+        assert_eq!(file.text, "# settings\n[core]\n\tb = 2\n[core]\n\ta = four\n\tc = \" padded\\tvalue \"\n");
+        assert_eq!(settings(&parse(&file.text).unwrap())[2], ("core.c", Some(" padded\tvalue ")));
+    }
+
+    #[test]
+    fn set_adds_sections_with_quoted_subsections() {
+        let mut file = parse("[core]\n\tbare = false").unwrap();
+        file.set("remote.My \"Repo\".url", "/srv/repo").unwrap();
+        file.set("core.editor", "vi").unwrap();
+        assert_eq!(
+            file.text,
+            "[core]\n\tbare = false\n\teditor = vi\n[remote \"My \\\"Repo\\\"\"]\n\turl = /srv/repo\n"
+        );
+        assert_eq!(
+            settings(&parse(&file.text).unwrap()),
+            [("core.bare", Some("false")), ("core.editor", Some("vi")), ("remote.My \"Repo\".url", Some("/srv/repo"))]
+        );
+    }
+
+    #[test]
+    fn settings_on_a_header_line_can_be_changed() {
+        let mut file = parse("[core] a = 1\n[x] b = 2\n").unwrap();
+        file.set("core.a", "3").unwrap();
+        assert_eq!(file.text, "[core]\n\ta = 3\n[x] b = 2\n");
+        file.replace(1, "x.b", None).unwrap();
+        assert_eq!(file.text, "[core]\n\ta = 3\n[x]\n");
+    }
+
+    #[test]
+    fn remove_section_removes_every_one_of_that_name() {
+        let mut file = parse(
+            "[core]\n\tbare = false\n[remote \"origin\"]\n\turl = a\n# kept\n[remote \"Origin\"]\n\turl = b\n\
+             [remote \"origin\"]\n\tfetch = c\n",
+        )
+        .unwrap();
+        assert!(file.remove_section("remote.origin").unwrap());
+        assert_eq!(file.text, "[core]\n\tbare = false\n# kept\n[remote \"Origin\"]\n\turl = b\n");
+        assert!(!file.remove_section("remote.origin").unwrap());
+    }
+}
@@ -0,0 +1,322 @@
+use chrono::{DateTime, Datelike, FixedOffset, Local, NaiveDate, NaiveDateTime, TimeZone, Utc};
+
+// How dates are shown (log's --date option).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum DateFormat {
+    // "Thu Apr 7 15:13:13 2005 -0700"
+    Default,
+    // "2005-04-07 15:13:13 -0700"
+    Iso,
+    // "2005-04-07T15:13:13-07:00"
+    IsoStrict,
+    // "Thu, 7 Apr 2005 15:13:13 -0700"
+    Rfc,
+    // "2005-04-07"
+    Short,
+    // "1112911993 -0700"
+    Raw,
+    // "1112911993"
+    Unix,
+    // "2 hours ago"
+    Relative,
+}
+
+impl std::str::FromStr for DateFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<DateFormat> {
+        Ok(match s {
+            "default" => DateFormat::Default,
+            "iso" | "iso8601" => DateFormat::Iso,
+            "iso-strict" | "iso8601-strict" => DateFormat::IsoStrict,
+            "rfc" | "rfc2822" => DateFormat::Rfc,
+            "short" => DateFormat::Short,
+            "raw" => DateFormat::Raw,
+            "unix" => DateFormat::Unix,
+            "relative" => DateFormat::Relative,
+            _ => anyhow::bail!("unknown date format {s}"),
+        })
+    }
+}
+
+// Render `time` (seconds since the epoch) as seen in the timezone `offset` (minutes east of UTC).
+pub(crate) fn format(time: i64, offset: i32, format: DateFormat) -> String {
+    let tz = FixedOffset::east_opt(offset * 60).unwrap_or_else(|| FixedOffset::east_opt(0).expect("UTC is valid"));
+    let Some(date) = tz.timestamp_opt(time, 0).single() else {
+        return format!("{time} {}", tz_string(offset));
+    };
+    match format {
+        DateFormat::Default => format!("{} {}", date.format("%a %b %-d %H:%M:%S %Y"), tz_string(offset)),
+        DateFormat::Iso => format!("{} {}", date.format("%Y-%m-%d %H:%M:%S"), tz_string(offset)),
+        DateFormat::IsoStrict => match offset {
+            0 => date.format("%Y-%m-%dT%H:%M:%SZ").to_string(),
+            _ => date.format("%Y-%m-%dT%H:%M:%S%:z").to_string(),
+        },
+        DateFormat::Rfc => format!("{} {}", date.format("%a, %-d %b %Y %H:%M:%S"), tz_string(offset)),
+        DateFormat::Short => date.format("%Y-%m-%d").to_string(),
+        DateFormat::Raw => format!("{time} {}", tz_string(offset)),
+        DateFormat::Unix => time.to_string(),
+        DateFormat::Relative => relative(Utc::now().timestamp() - time),
+    }
+}
+
+// The timezone in git's "+hhmm" notation.
+fn tz_string(offset: i32) -> String {
+    let sign = if offset >= 0 { '+' } else { '-' };
+    let abs = offset.abs();
+    format!("{sign}{:02}{:02}", abs / 60, abs % 60)
+}
+
+// "3 days ago" for a point `diff` seconds in the past, rounding like git does.
+fn relative(diff: i64) -> String {
+    fn ago(count: i64, unit: &str) -> String {
+        format!("{count} {unit}{} ago", if count == 1 { "" } else { "s" })
+    }
+
+    if diff < 0 {
+        return "in the future".to_string();
+    }
+    if diff < 90 {
+        return ago(diff, "second");
+    }
+    let minutes = (diff + 30) / 60;
+    if minutes < 90 {
+        return ago(minutes, "minute");
+    }
+    let hours = (minutes + 30) / 60;
+    if hours < 36 {
+        return ago(hours, "hour");
+    }
+    let days = (hours + 12) / 24;
+    if days < 14 {
+        return ago(days, "day");
+    }
+    if days < 70 {
+        return ago((days + 3) / 7, "week");
+    }
+    if days < 365 {
+        return ago((days + 15) / 30, "month");
+    }
+    if days < 1825 {
+        let total_months = (days * 12 * 2 + 365) / (365 * 2);
+        let (years, months) = (total_months / 12, total_months % 12);
+        if months == 0 {
+            return ago(years, "year");
+        }
+        let years = format!("{years} year{}", if years == 1 { "" } else { "s" });
+        return format!("{years}, {}", ago(months, "month"));
+    }
+    ago((days + 183) / 365, "year")
+}
+
+// Parse a date the way git accepts them in GIT_AUTHOR_DATE, --since and friends:
+//   raw "<seconds> <+hhmm>" or "@<seconds>"
+//   RFC 2822 "Thu, 07 Apr 2005 22:13:13 +0200"
+//   ISO 8601 "2005-04-07T22:13:13+02:00", "2005-04-07 22:13:13 +0200", "2005-04-07"
+//   relative "now", "yesterday", "3 days ago", "2.weeks.ago"
+// Dates without a timezone are in local time. Returns (seconds since the epoch, offset in
+// minutes).
+pub(crate) fn parse(text: &str) -> anyhow::Result<(i64, i32)> {
+    let text = text.trim();
+    parse_raw(text)
+        .or_else(|| parse_rfc2822(text))
+        .or_else(|| parse_iso8601(text))
+        .or_else(|| parse_relative(text))
+        .ok_or_else(|| anyhow::anyhow!("invalid date format: {text}"))
+}
+
+fn parse_raw(text: &str) -> Option<(i64, i32)> {
+    if let Some(seconds) = text.strip_prefix('@') {
+        return Some((seconds.parse().ok()?, 0));
+    }
+    let (seconds, tz) = text.split_once(' ')?;
+    if !seconds.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    Some((seconds.parse().ok()?, parse_tz(tz.trim())?))
+}
+
+// "+hhmm", "-hh:mm", "+hh" or "Z".
+fn parse_tz(tz: &str) -> Option<i32> {
+    if tz == "Z" || tz == "UTC" || tz == "GMT" {
+        return Some(0);
+    }
+    let (sign, digits) = match tz.as_bytes().first()? {
+        b'+' => (1, &tz[1..]),
+        b'-' => (-1, &tz[1..]),
+        _ => return None,
+    };
+    let digits = digits.replace(':', "");
+    if !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let (hours, minutes) = match digits.len() {
+        2 => (digits.parse::<i32>().ok()?, 0),
+        4 => (digits[..2].parse::<i32>().ok()?, digits[2..].parse::<i32>().ok()?),
+        _ => return None,
+    };
+    (minutes < 60).then_some(sign * (hours * 60 + minutes))
+}
+
+fn parse_rfc2822(text: &str) -> Option<(i64, i32)> {
+    let date = DateTime::parse_from_rfc2822(text).ok()?;
+    Some((date.timestamp(), date.offset().local_minus_utc() / 60))
+}
+
+fn parse_iso8601(text: &str) -> Option<(i64, i32)> {
+    if let Ok(date) = DateTime::parse_from_rfc3339(text) {
+        return Some((date.timestamp(), date.offset().local_minus_utc() / 60));
+    }
+
+    // "<date>[T ]<time>[ ]<tz>", where time and timezone are optional
+    let tz_start = text
+        .get(10..)
+        .and_then(|time| time.rfind(['+', '-', 'Z']))
+        .map(|i| i + 10);
+    let (rest, tz) = match tz_start.and_then(|i| Some((i, parse_tz(text[i..].trim())?))) {
+        Some((i, offset)) => (text[..i].trim_end(), Some(offset)),
+        None => (text, None),
+    };
+
+    let naive = NaiveDateTime::parse_from_str(rest, "%Y-%m-%dT%H:%M:%S")
+        .or_else(|_| NaiveDateTime::parse_from_str(rest, "%Y-%m-%d %H:%M:%S"))
+        .or_else(|_| NaiveDateTime::parse_from_str(rest, "%Y-%m-%dT%H:%M"))
+        .or_else(|_| NaiveDateTime::parse_from_str(rest, "%Y-%m-%d %H:%M"))
+        .or_else(|_| NaiveDateTime::parse_from_str(rest, "%Y.%m.%d %H:%M:%S"))
+        .ok()
+        .or_else(|| {
+            // A date alone means the current time of day on that date, like git
+            let date = NaiveDate::parse_from_str(rest, "%Y-%m-%d")
+                .or_else(|_| NaiveDate::parse_from_str(rest, "%Y.%m.%d"))
+                .or_else(|_| NaiveDate::parse_from_str(rest, "%m/%d/%Y"))
+                .ok()?;
+            Some(date.and_time(Local::now().time()))
+        })?;
+    Some(match tz {
+        Some(offset) => (naive.and_utc().timestamp() - offset as i64 * 60, offset),
+        None => local(naive)?,
+    })
+}
+
+// The time of a local date and time, with the local offset in effect then.
+fn local(naive: NaiveDateTime) -> Option<(i64, i32)> {
+    let date = Local.from_local_datetime(&naive).earliest()?;
+    Some((date.timestamp(), date.offset().local_minus_utc() / 60))
+}
+
+fn parse_relative(text: &str) -> Option<(i64, i32)> {
+    let now = Local::now();
+    let offset = now.offset().local_minus_utc() / 60;
+    // git treats dots as spaces here ("2.weeks.ago")
+    let text = text.replace('.', " ").to_ascii_lowercase();
+    let words: Vec<&str> = text.split_whitespace().collect();
+    match words.as_slice() {
+        ["now"] => Some((now.timestamp(), offset)),
+        ["yesterday"] => Some((now.timestamp() - 24 * 60 * 60, offset)),
+        ["today"] | ["midnight"] => {
+            let midnight = now.date_naive().and_hms_opt(0, 0, 0)?;
+            local(midnight)
+        }
+        // Counts too large to go back by are no date, rather than an overflow
+        [count, unit, "ago"] => {
+            let count: i64 = count.parse().ok()?;
+            let unit = unit.strip_suffix('s').unwrap_or(unit);
+            let seconds = match unit {
+                "second" | "sec" => 1,
+                "minute" | "min" => 60,
+                "hour" => 60 * 60,
+                "day" => 24 * 60 * 60,
+                "week" => 7 * 24 * 60 * 60,
+                "month" => {
+                    let months = (now.year() as i64 * 12 + now.month0() as i64).checked_sub(count)?;
+                    let date = now
+                        .date_naive()
+                        .with_day(1)?
+                        .with_year(i32::try_from(months.div_euclid(12)).ok()?)?
+                        .with_month0(months.rem_euclid(12) as u32)?;
+                    let day = now.day().min(days_in_month(date.year(), date.month())?);
+                    return local(date.with_day(day)?.and_time(now.time()));
+                }
+                "year" => {
+                    let year = now.year().checked_sub(i32::try_from(count).ok()?)?;
+                    let date = now.date_naive().with_year(year)?;
+                    return local(date.and_time(now.time()));
+                }
+                _ => return None,
+            };
+            Some((now.timestamp().checked_sub(count.checked_mul(seconds)?)?, offset))
+        }
+        _ => None,
+    }
+}
+
+fn days_in_month(year: i32, month: u32) -> Option<u32> {
+    let next = if month == 12 {
+        NaiveDate::from_ymd_opt(year + 1, 1, 1)?
+    } else {
+        NaiveDate::from_ymd_opt(year, month + 1, 1)?
+    };
+    Some(next.pred_opt()?.day())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dates_with_a_timezone() {
+        // 2005-04-07 20:13:13 UTC
+        let cases = [
+            ("1112904793 +0200", (1112904793, 120)),
+            ("1112904793 -0130", (1112904793, -90)),
+            ("@1112904793", (1112904793, 0)),
+            ("Thu, 07 Apr 2005 22:13:13 +0200", (1112904793, 120)),
+            ("2005-04-07T22:13:13+02:00", (1112904793, 120)),
+            ("2005-04-07T20:13:13Z", (1112904793, 0)),
+            ("2005-04-07 22:13:13 +0200", (1112904793, 120)),
+            ("2005-04-07 17:13:13 -03", (1112904793, -180)),
+            ("2005-04-07T22:13+02:00", (1112904780, 120)),
+            ("  2005.04.07 20:13:13 Z  ", (1112904793, 0)),
+        ];
+        for (text, expected) in cases {
+            assert_eq!(parse(text).unwrap(), expected, "{text}");
+        }
+    }
+
+    #[test]
+    fn dates_without_a_timezone_are_local() {
+        let (time, offset) = parse("2005-04-07 22:13:13").unwrap();
+        let local = Local.with_ymd_and_hms(2005, 4, 7, 22, 13, 13).earliest().unwrap();
+        assert_eq!((time, offset), (local.timestamp(), local.offset().local_minus_utc() / 60));
+    }
+
+    #[test]
+    fn relative_dates_count_back_from_now() {
+        let now = Local::now().timestamp();
+        let close = |text: &str, ago: i64| {
+            let (time, _) = parse(text).unwrap();
+            assert!((time - (now - ago)).abs() <= 5, "{text}: {time} vs {}", now - ago);
+        };
+        close("now", 0);
+        close("yesterday", 24 * 60 * 60);
+        close("3 days ago", 3 * 24 * 60 * 60);
+        close("2.weeks.ago", 14 * 24 * 60 * 60);
+        close("1 hour ago", 60 * 60);
+        close("90 Minutes Ago", 90 * 60);
+    }
+
+    #[test]
+    fn nonsense_is_rejected() {
+        for text in ["", "tomorrow", "2005-13-07", "1112904793 +2", "12 parsecs ago", "1112904793 +0260"] {
+            assert!(parse(text).is_err(), "{text}");
+        }
+        for text in [
+            "99999999999999 years ago",
+            "99999999999999999 weeks ago",
+            "-9223372036854775808 months ago",
+            "-9223372036854775807 seconds ago",
+        ] {
+            assert!(parse(text).is_err(), "{text}");
+        }
+    }
+}
@@ -0,0 +1,607 @@
+use crate::objects::{self, Kind, Object, ObjectId, Tree, TreeEntry};
+use crate::revision;
+use anyhow::Context;
+use std::cmp::Ordering;
+use std::fs;
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+
+mod histogram;
+mod lines;
+mod myers;
+mod patience;
+mod rename;
+
+pub(crate) use lines::{diff as diff_lines, split_lines, Algorithm, Edit};
+pub(crate) use rename::{find_renames, Renames, DEFAULT_SCORE as DEFAULT_RENAME_SCORE};
+
+// Git looks at this many bytes to decide whether a file is binary.
+const FIRST_FEW_BYTES: usize = 8000;
+
+// One side of a changed path.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Side {
+    pub(crate) mode: u32,
+    pub(crate) hash: ObjectId,
+    // The content is that of the working tree file, which needn't be in the object database
+    pub(crate) worktree: bool,
+}
+
+impl Side {
+    pub(crate) fn new(mode: u32, hash: ObjectId) -> Side {
+        Side { mode, hash, worktree: false }
+    }
+}
+
+// Where a renamed or copied file came from.
+#[derive(Debug, Clone)]
+pub(crate) struct Origin {
+    pub(crate) path: String,
+    // How similar the two are, out of rename::MAX_SCORE
+    pub(crate) score: usize,
+    pub(crate) copy: bool,
+}
+
+// A path that differs between the two sides of a diff; None where it doesn't exist. The old
+// side of a renamed or copied file is that of its origin.
+#[derive(Debug, Clone)]
+pub(crate) struct FilePair {
+    pub(crate) path: String,
+    pub(crate) old: Option<Side>,
+    pub(crate) new: Option<Side>,
+    pub(crate) origin: Option<Origin>,
+}
+
+impl FilePair {
+    pub(crate) fn new(path: String, old: Option<Side>, new: Option<Side>) -> FilePair {
+        FilePair { path, old, new, origin: None }
+    }
+
+    // The path on the old side.
+    pub(crate) fn old_path(&self) -> &str {
+        self.origin.as_ref().map_or(&self.path, |origin| &origin.path)
+    }
+
+    // The letter used by --name-status and the raw format.
+    pub(crate) fn status(&self) -> char {
+        match (self.old, self.new, &self.origin) {
+            (None, _, _) => 'A',
+            (_, None, _) => 'D',
+            (Some(old), Some(new), _) if file_type(old.mode) != file_type(new.mode) => 'T',
+            (_, _, Some(origin)) if origin.copy => 'C',
+            (_, _, Some(_)) => 'R',
+            _ => 'M',
+        }
+    }
+
+    // The status, with the similarity for renames and copies ("R086"), and the paths
+    // involved, separated by tabs.
+    fn status_and_paths(&self) -> String {
+        match &self.origin {
+            Some(origin) if matches!(self.status(), 'R' | 'C') => format!(
+                "{}{:03}\t{}\t{}",
+                self.status(),
+                similarity_index(origin.score),
+                origin.path,
+                self.path
+            ),
+            _ => format!("{}\t{}", self.status(), self.path),
+        }
+    }
+}
+
+// A similarity score as a percentage, rounded down.
+pub(crate) fn similarity_index(score: usize) -> usize {
+    score * 100 / rename::MAX_SCORE
+}
+
+// Regular file, symlink, gitlink or tree: changing between those is a "typechange".
+fn file_type(mode: u32) -> u32 {
+    mode & 0o170000
+}
+
+// What a diff shows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Format {
+    Patch,
+    Stat,
+    NameOnly,
+    NameStatus,
+    // ":<old mode> <new mode> <old hash> <new hash> <status>\t<path>"
+    Raw,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Options {
+    pub(crate) format: Format,
+    pub(crate) algorithm: Algorithm,
+    // Lines of context around changes in patches
+    pub(crate) context: usize,
+    pub(crate) renames: Option<Renames>,
+}
+
+// Whether `path` is selected by the pathspecs `paths` (everything when there are none).
+pub(crate) fn in_paths(path: &str, paths: &[String]) -> bool {
+    paths.is_empty()
+        || paths
+            .iter()
+            .any(|filter| filter.is_empty() || path == filter || path.starts_with(&format!("{filter}/")))
+}
+
+// The paths that differ between two trees (None being the empty tree), in tree order. Both
+// trees are walked in parallel and subtrees with the same hash on both sides are skipped,
+// unless `unchanged` files are wanted too (as copy sources for --find-copies-harder).
+// Unless `recursive`, changed subtrees are reported as such instead of the files in them.
+pub(crate) fn diff_trees(
+    old: Option<&str>,
+    new: Option<&str>,
+    recursive: bool,
+    unchanged: bool,
+    paths: &[String],
+) -> anyhow::Result<Vec<FilePair>> {
+    let mut pairs = Vec::new();
+    walk_trees(old, new, "", recursive, unchanged, paths, &mut pairs)?;
+    Ok(pairs)
+}
+
+fn read_tree(hash: Option<&str>) -> anyhow::Result<Vec<TreeEntry>> {
+    let Some(hash) = hash else {
+        return Ok(Vec::new());
+    };
+    let object = Object::read(hash).with_context(|| format!("read tree {hash}"))?;
+    anyhow::ensure!(object.kind == Kind::Tree, "{hash} is a {}, not a tree", object.kind);
+    Ok(Tree::parse(&object.into_bytes()?)?.entries)
+}
+
+// Tree order: names compare as if directories had a trailing slash.
+fn compare_entries(a: &TreeEntry, b: &TreeEntry) -> Ordering {
+    let key = |entry: &TreeEntry| {
+        let mut key = entry.name.as_bytes().to_vec();
+        if entry.kind() == Kind::Tree {
+            key.push(b'/');
+        }
+        key
+    };
+    key(a).cmp(&key(b))
+}
+
+fn walk_trees(
+    old: Option<&str>,
+    new: Option<&str>,
+    prefix: &str,
+    recursive: bool,
+    unchanged: bool,
+    paths: &[String],
+    pairs: &mut Vec<FilePair>,
+) -> anyhow::Result<()> {
+    let old_entries = read_tree(old)?;
+    let new_entries = read_tree(new)?;
+    let (mut i, mut j) = (0, 0);
+    loop {
+        let (old_entry, new_entry) = match (old_entries.get(i), new_entries.get(j)) {
+            (None, None) => break,
+            (Some(a), Some(b)) => match compare_entries(a, b) {
+                Ordering::Less => (Some(a), None),
+                Ordering::Greater => (None, Some(b)),
+                Ordering::Equal => (Some(a), Some(b)),
+            },
+            (a, b) => (a.filter(|_| b.is_none()), b.filter(|_| a.is_none())),
+        };
+        i += old_entry.is_some() as usize;
+        j += new_entry.is_some() as usize;
+        if let (Some(a), Some(b)) = (old_entry, new_entry) {
+            if a.hash == b.hash && a.mode_bits()? == b.mode_bits()? && !(unchanged && recursive) {
+                continue;
+            }
+        }
+
+        let entry = old_entry.or(new_entry).expect("one side has an entry");
+        let path = if prefix.is_empty() {
+            entry.name.clone()
+        } else {
+            format!("{prefix}/{}", entry.name)
+        };
+        if entry.kind() == Kind::Tree && recursive {
+            // Only descend where a pathspec may match something below
+            let wanted = in_paths(&path, paths)
+                || paths.iter().any(|filter| filter.starts_with(&format!("{path}/")));
+            if wanted {
+                let old_tree = old_entry.map(|entry| entry.hash.to_string());
+                let new_tree = new_entry.map(|entry| entry.hash.to_string());
+                walk_trees(old_tree.as_deref(), new_tree.as_deref(), &path, recursive, unchanged, paths, pairs)?;
+            }
+            continue;
+        }
+        if !in_paths(&path, paths) {
+            continue;
+        }
+        pairs.push(FilePair::new(
+            path,
+            old_entry.map(|entry| Ok::<_, anyhow::Error>(Side::new(entry.mode_bits()?, entry.hash))).transpose()?,
+            new_entry.map(|entry| Ok::<_, anyhow::Error>(Side::new(entry.mode_bits()?, entry.hash))).transpose()?,
+        ));
+    }
+    Ok(())
+}
+
+// Pair up renamed and copied files, if `options` asks for it.
+pub(crate) fn detect_renames(pairs: Vec<FilePair>, options: &Options) -> anyhow::Result<Vec<FilePair>> {
+    match &options.renames {
+        Some(renames) => find_renames(pairs, renames, None),
+        None => Ok(pairs),
+    }
+}
+
+// The content of one side: the blob, the working tree file (a symlink's target), or for a
+// submodule the line git shows in its place.
+fn content(path: &str, side: &Side) -> anyhow::Result<Vec<u8>> {
+    if side.mode == 0o160000 {
+        return Ok(format!("Subproject commit {}\n", side.hash).into_bytes());
+    }
+    if side.worktree {
+        if side.mode == 0o120000 {
+            let target = fs::read_link(path).with_context(|| format!("read link {path}"))?;
+            return Ok(target.as_os_str().as_bytes().to_vec());
+        }
+        return fs::read(path).with_context(|| format!("read {path}"));
+    }
+    let hash = side.hash.to_string();
+    Object::read(&hash)
+        .with_context(|| format!("read blob {hash} of {path}"))?
+        .into_bytes()
+}
+
+pub(crate) fn is_binary(data: &[u8]) -> bool {
+    data[..data.len().min(FIRST_FEW_BYTES)].contains(&0)
+}
+
+// Show the differences in the requested format.
+pub(crate) fn write(out: &mut impl Write, pairs: &[FilePair], options: &Options) -> anyhow::Result<()> {
+    match options.format {
+        Format::Patch => {
+            for pair in pairs {
+                write_patch(out, pair, options)?;
+            }
+        }
+        Format::Stat => write_stat(out, pairs, options)?,
+        Format::NameOnly => {
+            for pair in pairs {
+                writeln!(out, "{}", pair.path)?;
+            }
+        }
+        Format::NameStatus => {
+            for pair in pairs {
+                writeln!(out, "{}", pair.status_and_paths())?;
+            }
+        }
+        Format::Raw => {
+            let null = objects::Format::current()?.null();
+            for pair in pairs {
+                let (old_mode, old_hash) = pair.old.map_or((0, null), |side| (side.mode, side.hash));
+                let (new_mode, new_hash) = pair.new.map_or((0, null), |side| (side.mode, side.hash));
+                writeln!(
+                    out,
+                    ":{old_mode:06o} {new_mode:06o} {} {} {}",
+                    old_hash,
+                    new_hash,
+                    pair.status_and_paths()
+                )?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn write_patch(out: &mut impl Write, pair: &FilePair, options: &Options) -> anyhow::Result<()> {
+    match (pair.old, pair.new) {
+        // Different kinds of files aren't diffed with each other: the change is shown as a
+        // deletion followed by a creation
+        (Some(old), Some(new)) if file_type(old.mode) != file_type(new.mode) => {
+            write_file_patch(out, &FilePair::new(pair.old_path().to_string(), Some(old), None), options)?;
+            write_file_patch(out, &FilePair::new(pair.path.clone(), None, Some(new)), options)
+        }
+        _ => write_file_patch(out, pair, options),
+    }
+}
+
+fn abbreviated(side: Option<Side>) -> anyhow::Result<String> {
+    match side {
+        Some(side) => revision::abbreviate(&side.hash.to_string(), 7),
+        None => Ok("0".repeat(7)),
+    }
+}
+
+fn write_file_patch(out: &mut impl Write, pair: &FilePair, options: &Options) -> anyhow::Result<()> {
+    let (old_path, path) = (pair.old_path(), pair.path.as_str());
+    let (old, new) = (pair.old, pair.new);
+    let mut header = format!("diff --git a/{old_path} b/{path}\n");
+    // A file appearing, disappearing or changing mode is shown even without content changes
+    let must_show_header = match (old, new) {
+        (None, Some(new)) => {
+            header.push_str(&format!("new file mode {:06o}\n", new.mode));
+            true
+        }
+        (Some(old), None) => {
+            header.push_str(&format!("deleted file mode {:06o}\n", old.mode));
+            true
+        }
+        (Some(old), Some(new)) if old.mode != new.mode => {
+            header.push_str(&format!("old mode {:06o}\nnew mode {:06o}\n", old.mode, new.mode));
+            true
+        }
+        _ => false,
+    };
+    let must_show_header = match &pair.origin {
+        Some(origin) => {
+            let (verb, index) = (if origin.copy { "copy" } else { "rename" }, similarity_index(origin.score));
+            header.push_str(&format!("similarity index {index}%\n{verb} from {old_path}\n{verb} to {path}\n"));
+            true
+        }
+        None => must_show_header,
+    };
+    if old.map(|side| side.hash) != new.map(|side| side.hash) {
+        header.push_str(&format!("index {}..{}", abbreviated(old)?, abbreviated(new)?));
+        match (old, new) {
+            (Some(old), Some(new)) if old.mode == new.mode => header.push_str(&format!(" {:06o}\n", old.mode)),
+            _ => header.push('\n'),
+        }
+    }
+
+    let old_data = old.map(|side| content(old_path, &side)).transpose()?.unwrap_or_default();
+    let new_data = new.map(|side| content(path, &side)).transpose()?.unwrap_or_default();
+    let old_label = if old.is_some() { format!("a/{old_path}") } else { "/dev/null".to_string() };
+    let new_label = if new.is_some() { format!("b/{path}") } else { "/dev/null".to_string() };
+
+    if is_binary(&old_data) || is_binary(&new_data) {
+        if old_data != new_data {
+            out.write_all(header.as_bytes())?;
+            writeln!(out, "Binary files {old_label} and {new_label} differ")?;
+        } else if must_show_header {
+            out.write_all(header.as_bytes())?;
+        }
+        return Ok(());
+    }
+
+    let old_lines = split_lines(&old_data);
+    let new_lines = split_lines(&new_data);
+    let edits = diff_lines(&old_lines, &new_lines, options.algorithm, true);
+    if edits.is_empty() {
+        if must_show_header {
+            out.write_all(header.as_bytes())?;
+        }
+        return Ok(());
+    }
+    out.write_all(header.as_bytes())?;
+    writeln!(out, "--- {old_label}\n+++ {new_label}")?;
+    lines::write_hunks(out, &old_lines, &new_lines, &edits, options.context)?;
+    Ok(())
+}
+
+// Lines added and deleted in one file, or for binary files their sizes.
+struct FileStat {
+    path: String,
+    added: usize,
+    deleted: usize,
+    binary: bool,
+}
+
+fn file_stat(pair: &FilePair, algorithm: Algorithm) -> anyhow::Result<FileStat> {
+    let old_data = pair.old.map(|side| content(pair.old_path(), &side)).transpose()?.unwrap_or_default();
+    let new_data = pair.new.map(|side| content(&pair.path, &side)).transpose()?.unwrap_or_default();
+    let same = pair.old.map(|side| side.hash) == pair.new.map(|side| side.hash);
+
+    let (added, deleted, binary) = if is_binary(&old_data) || is_binary(&new_data) {
+        if same {
+            (0, 0, true)
+        } else {
+            (new_data.len(), old_data.len(), true)
+        }
+    } else if same {
+        (0, 0, false)
+    } else {
+        let edits = diff_lines(&split_lines(&old_data), &split_lines(&new_data), algorithm, true);
+        (
+            edits.iter().map(|edit| edit.new_len).sum(),
+            edits.iter().map(|edit| edit.old_len).sum(),
+            false,
+        )
+    };
+    let path = match &pair.origin {
+        Some(origin) => rename_name(&origin.path, &pair.path),
+        None => pair.path.clone(),
+    };
+    Ok(FileStat { path, added, deleted, binary })
+}
+
+// How --stat names a renamed file: "old => new", with the directories at the start and end
+// the two have in common written once around braces, as in "src/{a => b}/lib.rs".
+fn rename_name(old: &str, new: &str) -> String {
+    let (a, b) = (old.as_bytes(), new.as_bytes());
+    let mut prefix = 0;
+    for (i, (x, y)) in a.iter().zip(b).enumerate() {
+        if x != y {
+            break;
+        }
+        if *x == b'/' {
+            prefix = i + 1;
+        }
+    }
+
+    // The common suffix may reach back into the slash ending the prefix, but no further
+    let mut suffix = 0;
+    let limit = prefix.saturating_sub(1);
+    let (mut i, mut j) = (a.len(), b.len());
+    while i > limit && j > limit && a[i - 1] == b[j - 1] {
+        i -= 1;
+        j -= 1;
+        if a[i] == b'/' {
+            suffix = a.len() - i;
+        }
+    }
+
+    let old_middle = &old[prefix..old.len().saturating_sub(suffix).max(prefix)];
+    let new_middle = &new[prefix..new.len().saturating_sub(suffix).max(prefix)];
+    if prefix + suffix > 0 {
+        format!("{}{{{old_middle} => {new_middle}}}{}", &old[..prefix], &old[old.len() - suffix..])
+    } else {
+        format!("{old_middle} => {new_middle}")
+    }
+}
+
+fn decimal_width(n: usize) -> usize {
+    n.to_string().len()
+}
+
+// Scale a change count to the width available for the +/- graph, keeping at least one
+// column for any change.
+fn scale_linear(it: usize, width: usize, max_change: usize) -> usize {
+    if it == 0 {
+        0
+    } else {
+        1 + it * (width - 1) / max_change
+    }
+}
+
+// "--stat": one line per file with the number of changed lines and a +/- graph, sized to fit
+// the terminal width ($COLUMNS, 80 by default) like git does, then a summary line.
+fn write_stat(out: &mut impl Write, pairs: &[FilePair], options: &Options) -> anyhow::Result<()> {
+    let stats = pairs
+        .iter()
+        .map(|pair| file_stat(pair, options.algorithm))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let mut max_change = 0;
+    let mut max_len = 0;
+    let mut bin_width = 0;
+    let mut number_width = 0;
+    for stat in &stats {
+        max_len = max_len.max(stat.path.chars().count());
+        if stat.binary {
+            // "Bin XXX -> YYY bytes"
+            bin_width = bin_width.max(14 + decimal_width(stat.added) + decimal_width(stat.deleted));
+            number_width = 3;
+            continue;
+        }
+        max_change = max_change.max(stat.added + stat.deleted);
+    }
+
+    let mut width: usize = std::env::var("COLUMNS")
+        .ok()
+        .and_then(|columns| columns.parse().ok())
+        .filter(|&columns| columns > 0)
+        .unwrap_or(80);
+    number_width = number_width.max(decimal_width(max_change));
+    width = width.max(16 + 6 + number_width);
+
+    let mut graph_width = if max_change + 4 > bin_width { max_change } else { bin_width - 4 };
+    let mut name_width = max_len;
+    if name_width + number_width + 6 + graph_width > width {
+        let three_eighths = (width * 3 / 8) as isize - number_width as isize - 6;
+        if graph_width as isize > three_eighths {
+            graph_width = three_eighths.max(6) as usize;
+        }
+        if name_width + number_width + 6 + graph_width > width {
+            name_width = width.saturating_sub(number_width + 6 + graph_width);
+        } else {
+            graph_width = width - number_width - 6 - name_width;
+        }
+    }
+
+    let (mut insertions, mut deletions) = (0, 0);
+    for stat in &stats {
+        // Long names keep their end, cut at a directory boundary if possible
+        let mut prefix = "";
+        let mut name = stat.path.as_str();
+        let mut len = name_width;
+        let name_len = name.chars().count();
+        if name_width < name_len {
+            prefix = "...";
+            len = len.saturating_sub(3);
+            let skip = name_len - len;
+            name = &name[name.char_indices().nth(skip).map_or(name.len(), |(i, _)| i)..];
+            if let Some(slash) = name.find('/') {
+                name = &name[slash..];
+            }
+        }
+        let padding = len.saturating_sub(name.chars().count());
+
+        if stat.binary {
+            write!(out, " {prefix}{name}{:padding$} | {:>number_width$}", "", "Bin")?;
+            if stat.added == 0 && stat.deleted == 0 {
+                writeln!(out)?;
+            } else {
+                writeln!(out, " {} -> {} bytes", stat.deleted, stat.added)?;
+            }
+            continue;
+        }
+
+        let (mut add, mut del) = (stat.added, stat.deleted);
+        if graph_width <= max_change {
+            let mut total = scale_linear(add + del, graph_width, max_change);
+            if total < 2 && add > 0 && del > 0 {
+                total = 2;
+            }
+            if add < del {
+                add = scale_linear(add, graph_width, max_change);
+                del = total - add;
+            } else {
+                del = scale_linear(del, graph_width, max_change);
+                add = total - del;
+            }
+        }
+        let changes = stat.added + stat.deleted;
+        writeln!(
+            out,
+            " {prefix}{name}{:padding$} | {changes:>number_width$}{}{}{}",
+            "",
+            if changes > 0 { " " } else { "" },
+            "+".repeat(add),
+            "-".repeat(del)
+        )?;
+        insertions += stat.added;
+        deletions += stat.deleted;
+    }
+
+    let files = stats.len();
+    if files == 0 {
+        writeln!(out, " 0 files changed")?;
+        return Ok(());
+    }
+    write!(out, " {files} file{} changed", if files == 1 { "" } else { "s" })?;
+    if insertions > 0 || deletions == 0 {
+        write!(out, ", {insertions} insertion{}(+)", if insertions == 1 { "" } else { "s" })?;
+    }
+    if deletions > 0 || insertions == 0 {
+        write!(out, ", {deletions} deletion{}(-)", if deletions == 1 { "" } else { "s" })?;
+    }
+    writeln!(out)?;
+    Ok(())
+}
+
+// "--summary": the files created, deleted, renamed or copied and the mode changes, as merges
+// and fast-forwards show them after the stat.
+pub(crate) fn write_summary(out: &mut impl Write, pairs: &[FilePair]) -> anyhow::Result<()> {
+    for pair in pairs {
+        match (pair.old, pair.new, &pair.origin) {
+            (None, Some(new), _) => writeln!(out, " create mode {:06o} {}", new.mode, pair.path)?,
+            (Some(old), None, _) => writeln!(out, " delete mode {:06o} {}", old.mode, pair.path)?,
+            (Some(old), Some(new), Some(origin)) => {
+                writeln!(
+                    out,
+                    " {} {} ({}%)",
+                    if origin.copy { "copy" } else { "rename" },
+                    rename_name(&origin.path, &pair.path),
+                    similarity_index(origin.score)
+                )?;
+                if old.mode != new.mode {
+                    writeln!(out, " mode change {:06o} => {:06o}", old.mode, new.mode)?;
+                }
+            }
+            (Some(old), Some(new), None) if old.mode != new.mode => {
+                writeln!(out, " mode change {:06o} => {:06o} {}", old.mode, new.mode, pair.path)?;
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,192 @@
+use super::myers;
+use std::collections::HashMap;
+
+// Lines occurring more often than this in `a` are never used to anchor the diff.
+const MAX_CHAIN_LENGTH: usize = 64;
+
+// A run of lines that match on both sides (1-based, inclusive); begin1 == 0 when none was found.
+#[derive(Default)]
+struct Region {
+    begin1: usize,
+    end1: usize,
+    begin2: usize,
+    end2: usize,
+}
+
+// Histogram diff: the longest run of matching lines that contains the least frequent line of
+// `a` anchors the diff, and the parts before and after it are diffed the same way. Regions
+// where every line is too common fall back to Myers.
+pub(super) fn diff(a: &[usize], b: &[usize], changed_a: &mut [bool], changed_b: &mut [bool]) {
+    let mut histogram = Histogram { a, b, changed_a, changed_b };
+    histogram.diff(1, a.len(), 1, b.len());
+}
+
+struct Histogram<'a> {
+    a: &'a [usize],
+    b: &'a [usize],
+    changed_a: &'a mut [bool],
+    changed_b: &'a mut [bool],
+}
+
+// The lines of the `a` side of a region, indexed for the search.
+struct Index {
+    // For each distinct line: its first occurrence and how often it occurs
+    records: HashMap<usize, (usize, usize)>,
+    // The next occurrence of the same line after each line (0 if none), from `first` on
+    next: Vec<usize>,
+    first: usize,
+    // Occurrence count of the rarest line in the best run found so far
+    count: usize,
+    has_common: bool,
+}
+
+impl Histogram<'_> {
+    // Diff `count1` lines of `a` starting at `line1` with `count2` lines of `b` starting at
+    // `line2` (both 1-based).
+    fn diff(&mut self, mut line1: usize, mut count1: usize, mut line2: usize, mut count2: usize) {
+        loop {
+            if count1 == 0 && count2 == 0 {
+                return;
+            }
+            if count1 == 0 {
+                self.changed_b[line2 - 1..line2 - 1 + count2].fill(true);
+                return;
+            }
+            if count2 == 0 {
+                self.changed_a[line1 - 1..line1 - 1 + count1].fill(true);
+                return;
+            }
+
+            let Some(lcs) = self.find_lcs(line1, count1, line2, count2) else {
+                let (range1, range2) = (line1 - 1..line1 - 1 + count1, line2 - 1..line2 - 1 + count2);
+                myers::diff(
+                    &self.a[range1.clone()],
+                    &self.b[range2.clone()],
+                    false,
+                    &mut self.changed_a[range1],
+                    &mut self.changed_b[range2],
+                );
+                return;
+            };
+            if lcs.begin1 == 0 && lcs.begin2 == 0 {
+                self.changed_a[line1 - 1..line1 - 1 + count1].fill(true);
+                self.changed_b[line2 - 1..line2 - 1 + count2].fill(true);
+                return;
+            }
+
+            self.diff(line1, lcs.begin1 - line1, line2, lcs.begin2 - line2);
+            count1 = line1 + count1 - 1 - lcs.end1;
+            line1 = lcs.end1 + 1;
+            count2 = line2 + count2 - 1 - lcs.end2;
+            line2 = lcs.end2 + 1;
+        }
+    }
+
+    // The best anchoring run of the region, or None if only lines too common to be used are
+    // shared by both sides.
+    fn find_lcs(&self, line1: usize, count1: usize, line2: usize, count2: usize) -> Option<Region> {
+        let mut index = Index {
+            records: HashMap::new(),
+            next: vec![0; count1],
+            first: line1,
+            count: MAX_CHAIN_LENGTH + 1,
+            has_common: false,
+        };
+        // Scanning backwards leaves each record at the line's first occurrence
+        for ptr in (line1..line1 + count1).rev() {
+            match index.records.get_mut(&self.a[ptr - 1]) {
+                Some((first, count)) => {
+                    index.next[ptr - line1] = *first;
+                    *first = ptr;
+                    *count += 1;
+                }
+                None => {
+                    index.records.insert(self.a[ptr - 1], (ptr, 1));
+                }
+            }
+        }
+
+        let mut lcs = Region::default();
+        let mut b_ptr = line2;
+        while b_ptr < line2 + count2 {
+            b_ptr = self.try_lcs(&mut index, &mut lcs, b_ptr, line1, count1, line2, count2);
+        }
+
+        if index.has_common && MAX_CHAIN_LENGTH < index.count {
+            None
+        } else {
+            Some(lcs)
+        }
+    }
+
+    // Look for a better run through the line `b_ptr` of `b`; returns the next line of `b` to
+    // try.
+    #[allow(clippy::too_many_arguments)]
+    fn try_lcs(
+        &self,
+        index: &mut Index,
+        lcs: &mut Region,
+        b_ptr: usize,
+        line1: usize,
+        count1: usize,
+        line2: usize,
+        count2: usize,
+    ) -> usize {
+        let (a, b) = (self.a, self.b);
+        let (last1, last2) = (line1 + count1 - 1, line2 + count2 - 1);
+        let mut b_next = b_ptr + 1;
+        let Some(&(first, count)) = index.records.get(&b[b_ptr - 1]) else {
+            return b_next;
+        };
+        index.has_common = true;
+        if count > index.count {
+            return b_next;
+        }
+        let occurrences = |ptr: usize| index.records[&a[ptr - 1]].1;
+
+        let mut as_ = first;
+        loop {
+            let mut np = index.next[as_ - index.first];
+            let mut bs = b_ptr;
+            let mut ae = as_;
+            let mut be = bs;
+            let mut rc = count;
+
+            while line1 < as_ && line2 < bs && a[as_ - 2] == b[bs - 2] {
+                as_ -= 1;
+                bs -= 1;
+                if 1 < rc {
+                    rc = rc.min(occurrences(as_));
+                }
+            }
+            while ae < last1 && be < last2 && a[ae] == b[be] {
+                ae += 1;
+                be += 1;
+                if 1 < rc {
+                    rc = rc.min(occurrences(ae));
+                }
+            }
+
+            if b_next <= be {
+                b_next = be + 1;
+            }
+            if lcs.end1 - lcs.begin1 < ae - as_ || rc < index.count {
+                *lcs = Region { begin1: as_, end1: ae, begin2: bs, end2: be };
+                index.count = rc;
+            }
+
+            if np == 0 {
+                break;
+            }
+            // Continue with the next occurrence that isn't part of this run
+            while np <= ae {
+                np = index.next[np - index.first];
+                if np == 0 {
+                    return b_next;
+                }
+            }
+            as_ = np;
+        }
+        b_next
+    }
+}
@@ -0,0 +1,624 @@
+use super::{histogram, myers, patience};
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+// The line diff algorithm (diff's --diff-algorithm).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Algorithm {
+    Myers,
+    // Myers without the heuristics that trade a minimal diff for speed
+    Minimal,
+    Patience,
+    Histogram,
+}
+
+impl std::str::FromStr for Algorithm {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Algorithm> {
+        Ok(match s {
+            "myers" | "default" => Algorithm::Myers,
+            "minimal" => Algorithm::Minimal,
+            "patience" => Algorithm::Patience,
+            "histogram" => Algorithm::Histogram,
+            _ => anyhow::bail!("unknown diff algorithm '{s}'"),
+        })
+    }
+}
+
+// `old_len` lines at `old_start` replaced by `new_len` lines at `new_start` (0-based).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Edit {
+    pub(crate) old_start: usize,
+    pub(crate) old_len: usize,
+    pub(crate) new_start: usize,
+    pub(crate) new_len: usize,
+}
+
+impl Edit {
+    pub(crate) fn old_end(&self) -> usize {
+        self.old_start + self.old_len
+    }
+
+    pub(crate) fn new_end(&self) -> usize {
+        self.new_start + self.new_len
+    }
+}
+
+// Split `data` into lines, each with its newline (only the last one may lack it).
+pub(crate) fn split_lines(data: &[u8]) -> Vec<&[u8]> {
+    data.split_inclusive(|&b| b == b'\n').collect()
+}
+
+// The changes turning the lines `old` into `new`, in order. Like git, changes that could be
+// placed at several positions are slid to where they line up with changes on the other side,
+// or else (with `indent_heuristic`) to where the indentation suggests a block boundary.
+pub(crate) fn diff(old: &[&[u8]], new: &[&[u8]], algorithm: Algorithm, indent_heuristic: bool) -> Vec<Edit> {
+    // Number the distinct lines so the algorithms only need to compare integers
+    let mut classes = HashMap::new();
+    let a = classify(old, &mut classes);
+    let b = classify(new, &mut classes);
+
+    let mut changed_a = vec![false; a.len()];
+    let mut changed_b = vec![false; b.len()];
+    match algorithm {
+        Algorithm::Myers => myers::diff(&a, &b, false, &mut changed_a, &mut changed_b),
+        Algorithm::Minimal => myers::diff(&a, &b, true, &mut changed_a, &mut changed_b),
+        Algorithm::Patience => patience::diff(&a, &b, &mut changed_a, &mut changed_b),
+        Algorithm::Histogram => histogram::diff(&a, &b, &mut changed_a, &mut changed_b),
+    }
+    compact(old, &a, &mut changed_a, &changed_b, indent_heuristic);
+    compact(new, &b, &mut changed_b, &changed_a, indent_heuristic);
+
+    let mut edits = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() || j < b.len() {
+        if changed_a.get(i) == Some(&true) || changed_b.get(j) == Some(&true) {
+            let (old_start, new_start) = (i, j);
+            while changed_a.get(i) == Some(&true) {
+                i += 1;
+            }
+            while changed_b.get(j) == Some(&true) {
+                j += 1;
+            }
+            edits.push(Edit { old_start, old_len: i - old_start, new_start, new_len: j - new_start });
+        } else {
+            i += 1;
+            j += 1;
+        }
+    }
+    edits
+}
+
+fn classify<'a>(lines: &[&'a [u8]], classes: &mut HashMap<&'a [u8], usize>) -> Vec<usize> {
+    lines
+        .iter()
+        .map(|&line| {
+            let next = classes.len();
+            *classes.entry(line).or_insert(next)
+        })
+        .collect()
+}
+
+// A run of changed lines, start..end; empty between two unchanged lines.
+struct Group {
+    start: usize,
+    end: usize,
+}
+
+impl Group {
+    fn first(changed: &[bool]) -> Group {
+        let end = changed.iter().take_while(|&&c| c).count();
+        Group { start: 0, end }
+    }
+
+    // Move to the group after the next unchanged line; false at the end of the file.
+    fn next(&mut self, changed: &[bool]) -> bool {
+        if self.end == changed.len() {
+            return false;
+        }
+        self.start = self.end + 1;
+        self.end = self.start;
+        while self.end < changed.len() && changed[self.end] {
+            self.end += 1;
+        }
+        true
+    }
+
+    // Move to the group before the previous unchanged line; false at the start of the file.
+    fn previous(&mut self, changed: &[bool]) -> bool {
+        if self.start == 0 {
+            return false;
+        }
+        self.end = self.start - 1;
+        self.start = self.end;
+        while self.start > 0 && changed[self.start - 1] {
+            self.start -= 1;
+        }
+        true
+    }
+
+    // Shift the group down by one line if the line after it equals its first line, merging
+    // with the group below if they meet.
+    fn slide_down(&mut self, classes: &[usize], changed: &mut [bool]) -> bool {
+        if self.end < changed.len() && classes[self.start] == classes[self.end] {
+            changed[self.start] = false;
+            changed[self.end] = true;
+            self.start += 1;
+            self.end += 1;
+            while self.end < changed.len() && changed[self.end] {
+                self.end += 1;
+            }
+            true
+        } else {
+            false
+        }
+    }
+
+    // Shift the group up by one line if the line before it equals its last line, merging
+    // with the group above if they meet.
+    fn slide_up(&mut self, classes: &[usize], changed: &mut [bool]) -> bool {
+        if self.start > 0 && classes[self.start - 1] == classes[self.end - 1] {
+            self.start -= 1;
+            self.end -= 1;
+            changed[self.start] = true;
+            changed[self.end] = false;
+            while self.start > 0 && changed[self.start - 1] {
+                self.start -= 1;
+            }
+            true
+        } else {
+            false
+        }
+    }
+}
+
+// Groups of changed lines may only slide this far for the indent heuristic.
+const INDENT_HEURISTIC_MAX_SLIDING: usize = 100;
+
+// Move each group of changed lines of one side to its best position: merged with neighbouring
+// groups where possible, lined up with a group of changes on the other side (`other`), or else
+// placed by the indent heuristic (if enabled; otherwise it stays as far down as it goes).
+fn compact(lines: &[&[u8]], classes: &[usize], changed: &mut [bool], other: &[bool], indent_heuristic: bool) {
+    let mut g = Group::first(changed);
+    let mut go = Group::first(other);
+
+    loop {
+        if g.end != g.start {
+            let mut earliest_end;
+            let mut end_matching_other;
+            let mut size;
+            loop {
+                size = g.end - g.start;
+                end_matching_other = None;
+
+                while g.slide_up(classes, changed) {
+                    go.previous(other);
+                }
+                earliest_end = g.end;
+                if go.end > go.start {
+                    end_matching_other = Some(g.end);
+                }
+
+                while g.slide_down(classes, changed) {
+                    go.next(other);
+                    if go.end > go.start {
+                        end_matching_other = Some(g.end);
+                    }
+                }
+                if size == g.end - g.start {
+                    break;
+                }
+            }
+
+            // The group is now as far down as it goes; only upward shifts remain to be chosen
+            if g.end == earliest_end {
+                // It can't move at all
+            } else if end_matching_other.is_some() {
+                while go.end == go.start {
+                    g.slide_up(classes, changed);
+                    go.previous(other);
+                }
+            } else if indent_heuristic {
+                let mut shift = earliest_end.max(g.end.saturating_sub(size + 1));
+                shift = shift.max(g.end.saturating_sub(INDENT_HEURISTIC_MAX_SLIDING));
+                let mut best: Option<(usize, Score)> = None;
+                while shift <= g.end {
+                    let mut score = Score::default();
+                    score.add(&measure_split(lines, shift));
+                    score.add(&measure_split(lines, shift - size));
+                    if best.as_ref().map_or(true, |(_, best)| score.cmp(best) <= 0) {
+                        best = Some((shift, score));
+                    }
+                    shift += 1;
+                }
+                let best_shift = best.map_or(g.end, |(shift, _)| shift);
+                while g.end > best_shift {
+                    g.slide_up(classes, changed);
+                    go.previous(other);
+                }
+            }
+        }
+
+        if !g.next(changed) {
+            break;
+        }
+        go.next(other);
+    }
+}
+
+// Lines indented further than this all count as this much.
+const MAX_INDENT: i32 = 200;
+// Blank lines beyond this many in a row aren't counted.
+const MAX_BLANKS: i32 = 20;
+
+// The surroundings of a split between two lines, as the indent heuristic sees them.
+struct Split {
+    end_of_file: bool,
+    // Indentation of the line after the split (-1 if blank)
+    indent: i32,
+    // Blank lines right before the split, and the indentation of the line before those
+    pre_blank: i32,
+    pre_indent: i32,
+    // Blank lines after the line after the split, and the indentation of the line after those
+    post_blank: i32,
+    post_indent: i32,
+}
+
+// The width of a line's leading whitespace (tabs to multiples of 8), or -1 for a blank line.
+fn indent(line: &[u8]) -> i32 {
+    let mut width = 0;
+    for &c in line {
+        if !c.is_ascii_whitespace() && c != 0x0b {
+            return width;
+        }
+        if c == b' ' {
+            width += 1;
+        } else if c == b'\t' {
+            width += 8 - width % 8;
+        }
+        if width >= MAX_INDENT {
+            return MAX_INDENT;
+        }
+    }
+    -1
+}
+
+// Measure the split before line `split`.
+fn measure_split(lines: &[&[u8]], split: usize) -> Split {
+    let (end_of_file, indent_after) = match lines.get(split) {
+        Some(line) => (false, indent(line)),
+        None => (true, -1),
+    };
+
+    let mut pre_blank = 0;
+    let mut pre_indent = -1;
+    for line in lines[..split.min(lines.len())].iter().rev() {
+        pre_indent = indent(line);
+        if pre_indent != -1 {
+            break;
+        }
+        pre_blank += 1;
+        if pre_blank == MAX_BLANKS {
+            pre_indent = 0;
+            break;
+        }
+    }
+
+    let mut post_blank = 0;
+    let mut post_indent = -1;
+    for line in lines.iter().skip(split + 1) {
+        post_indent = indent(line);
+        if post_indent != -1 {
+            break;
+        }
+        post_blank += 1;
+        if post_blank == MAX_BLANKS {
+            post_indent = 0;
+            break;
+        }
+    }
+
+    Split { end_of_file, indent: indent_after, pre_blank, pre_indent, post_blank, post_indent }
+}
+
+const START_OF_FILE_PENALTY: i32 = 1;
+const END_OF_FILE_PENALTY: i32 = 21;
+const TOTAL_BLANK_WEIGHT: i32 = -30;
+const POST_BLANK_WEIGHT: i32 = 6;
+const RELATIVE_INDENT_PENALTY: i32 = -4;
+const RELATIVE_INDENT_WITH_BLANK_PENALTY: i32 = 10;
+const RELATIVE_OUTDENT_PENALTY: i32 = 24;
+const RELATIVE_OUTDENT_WITH_BLANK_PENALTY: i32 = 17;
+const RELATIVE_DEDENT_PENALTY: i32 = 23;
+const RELATIVE_DEDENT_WITH_BLANK_PENALTY: i32 = 17;
+const INDENT_WEIGHT: i32 = 60;
+
+// How bad a position of a group of changes looks; lower is better.
+#[derive(Default)]
+struct Score {
+    effective_indent: i32,
+    penalty: i32,
+}
+
+impl Score {
+    fn add(&mut self, m: &Split) {
+        if m.pre_indent == -1 && m.pre_blank == 0 {
+            self.penalty += START_OF_FILE_PENALTY;
+        }
+        if m.end_of_file {
+            self.penalty += END_OF_FILE_PENALTY;
+        }
+
+        // Blank lines after the split, including the line right after it
+        let post_blank = if m.indent == -1 { 1 + m.post_blank } else { 0 };
+        let total_blank = m.pre_blank + post_blank;
+        self.penalty += TOTAL_BLANK_WEIGHT * total_blank;
+        self.penalty += POST_BLANK_WEIGHT * post_blank;
+
+        let indent = if m.indent != -1 { m.indent } else { m.post_indent };
+        let any_blanks = total_blank != 0;
+        self.effective_indent += indent;
+
+        if indent == -1 || m.pre_indent == -1 {
+            // Nothing to compare with
+        } else if indent > m.pre_indent {
+            self.penalty += if any_blanks {
+                RELATIVE_INDENT_WITH_BLANK_PENALTY
+            } else {
+                RELATIVE_INDENT_PENALTY
+            };
+        } else if indent == m.pre_indent {
+            // Same level as the line before
+        } else if m.post_indent != -1 && m.post_indent > indent {
+            // Less indented than the line before but more than the line after: probably the
+            // start of a block
+            self.penalty += if any_blanks {
+                RELATIVE_OUTDENT_WITH_BLANK_PENALTY
+            } else {
+                RELATIVE_OUTDENT_PENALTY
+            };
+        } else {
+            // Probably the end of a block
+            self.penalty += if any_blanks {
+                RELATIVE_DEDENT_WITH_BLANK_PENALTY
+            } else {
+                RELATIVE_DEDENT_PENALTY
+            };
+        }
+    }
+
+    // Negative if `self` is better than `other`.
+    fn cmp(&self, other: &Score) -> i32 {
+        let indents = (self.effective_indent > other.effective_indent) as i32
+            - (self.effective_indent < other.effective_indent) as i32;
+        INDENT_WEIGHT * indents + (self.penalty - other.penalty)
+    }
+}
+
+// Function names shown in hunk headers are cut to this many bytes.
+const FUNCNAME_MAX: usize = 80;
+
+// Write the hunks of a unified diff: `context` lines around each change, and changes closer
+// than twice that merged into one hunk. Each hunk header names the nearest line before the
+// hunk that looks like the start of a function (one starting with a letter, '_' or '$').
+pub(crate) fn write_hunks(
+    out: &mut impl Write,
+    old: &[&[u8]],
+    new: &[&[u8]],
+    edits: &[Edit],
+    context: usize,
+) -> io::Result<()> {
+    let mut funcname: Vec<u8> = Vec::new();
+    // Where the previous search for a function line stopped
+    let mut searched_to = 0;
+    let mut first = 0;
+    while first < edits.len() {
+        let mut last = first;
+        while last + 1 < edits.len() && edits[last + 1].old_start - edits[last].old_end() <= 2 * context {
+            last += 1;
+        }
+        let (head, tail) = (&edits[first], &edits[last]);
+
+        let old_start = head.old_start.saturating_sub(context);
+        let new_start = head.new_start.saturating_sub(context);
+        let after = context.min(old.len() - tail.old_end()).min(new.len() - tail.new_end());
+        let old_count = tail.old_end() + after - old_start;
+        let new_count = tail.new_end() + after - new_start;
+
+        for line in old[searched_to..old_start].iter().rev() {
+            if line.first().is_some_and(|&c| c.is_ascii_alphabetic() || c == b'_' || c == b'$') {
+                let line = &line[..line.len().min(FUNCNAME_MAX)];
+                let end = line.iter().rposition(|c| !c.is_ascii_whitespace() && *c != 0x0b).map_or(0, |i| i + 1);
+                funcname = line[..end].to_vec();
+                break;
+            }
+        }
+        searched_to = old_start;
+
+        write!(
+            out,
+            "@@ -{} +{} @@",
+            range(old_start, old_count),
+            range(new_start, new_count)
+        )?;
+        if !funcname.is_empty() {
+            out.write_all(b" ")?;
+            out.write_all(&funcname)?;
+        }
+        out.write_all(b"\n")?;
+
+        let mut position = new_start;
+        for edit in &edits[first..=last] {
+            for line in &new[position..edit.new_start] {
+                write_line(out, b' ', line)?;
+            }
+            for line in &old[edit.old_start..edit.old_end()] {
+                write_line(out, b'-', line)?;
+            }
+            for line in &new[edit.new_start..edit.new_end()] {
+                write_line(out, b'+', line)?;
+            }
+            position = edit.new_end();
+        }
+        for line in &new[position..tail.new_end() + after] {
+            write_line(out, b' ', line)?;
+        }
+        first = last + 1;
+    }
+    Ok(())
+}
+
+// "start,count" of a hunk header from a 0-based start; a count of 1 is left out and an empty
+// range is given as starting at the line before it.
+fn range(start: usize, count: usize) -> String {
+    match count {
+        0 => format!("{start},0"),
+        1 => format!("{}", start + 1),
+        _ => format!("{},{count}", start + 1),
+    }
+}
+
+fn write_line(out: &mut impl Write, prefix: u8, line: &[u8]) -> io::Result<()> {
+    out.write_all(&[prefix])?;
+    out.write_all(line)?;
+    if !line.ends_with(b"\n") {
+        out.write_all(b"\n\\ No newline at end of file\n")?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALGORITHMS: [Algorithm; 4] = [Algorithm::Myers, Algorithm::Minimal, Algorithm::Patience, Algorithm::Histogram];
+
+    // The hunks of a diff with 3 lines of context, as text.
+    fn unified(old: &[u8], new: &[u8], algorithm: Algorithm) -> String {
+        let (old, new) = (split_lines(old), split_lines(new));
+        let edits = diff(&old, &new, algorithm, true);
+        let mut out = Vec::new();
+        write_hunks(&mut out, &old, &new, &edits, 3).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    // One line for every letter.
+    fn letters(text: &str) -> Vec<u8> {
+        text.chars().flat_map(|c| [c as u8, b'\n']).collect()
+    }
+
+    #[test]
+    fn algorithms_give_the_diffs_git_gives() {
+        let myers = "@@ -1,6 +1,8 @@\n c\n-d\n a\n+b\n a\n+b\n c\n+d\n e\n";
+        let anchored = "@@ -1,6 +1,8 @@\n c\n+a\n+b\n+a\n+b\n+c\n d\n-a\n-a\n-c\n e\n";
+        for (algorithm, expected) in ALGORITHMS.into_iter().zip([myers, myers, anchored, anchored]) {
+            assert_eq!(unified(&letters("cdaace"), &letters("cababcde"), algorithm), expected, "{algorithm:?}");
+        }
+
+        let myers = "@@ -1,4 +1,6 @@\n b\n-c\n b\n+b\n+c\n+c\n c\n";
+        let histogram = "@@ -1,4 +1,6 @@\n b\n+b\n+b\n+c\n c\n-b\n c\n";
+        for (algorithm, expected) in ALGORITHMS.into_iter().zip([myers, myers, myers, histogram]) {
+            assert_eq!(unified(&letters("bcbc"), &letters("bbbccc"), algorithm), expected, "{algorithm:?}");
+        }
+    }
+
+    #[test]
+    fn edits_turn_the_old_lines_into_the_new() {
+        // A fixed pseudo-random sequence of small files and changes to them
+        let mut seed: u64 = 1;
+        let mut random = |n: usize| {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (seed >> 33) as usize % n
+        };
+        for _ in 0..500 {
+            let alphabet = 2 + random(4);
+            let old: Vec<u8> = (0..random(20)).map(|_| b'a' + random(alphabet) as u8).collect();
+            let mut new = old.clone();
+            for _ in 0..1 + random(5) {
+                if random(3) == 0 && !new.is_empty() {
+                    new.remove(random(new.len()));
+                } else {
+                    new.insert(random(new.len() + 1), b'a' + random(alphabet) as u8);
+                }
+            }
+            let old: Vec<&[u8]> = old.chunks(1).collect();
+            let new: Vec<&[u8]> = new.chunks(1).collect();
+
+            for algorithm in ALGORITHMS {
+                let edits = diff(&old, &new, algorithm, true);
+                let mut rebuilt: Vec<&[u8]> = Vec::new();
+                let mut at = 0;
+                for edit in &edits {
+                    assert!(edit.old_start >= at && edit.old_len + edit.new_len > 0, "{algorithm:?} {edits:?}");
+                    assert_eq!(old[at..edit.old_start], new[rebuilt.len()..edit.new_start], "{algorithm:?}");
+                    rebuilt.extend(&old[at..edit.old_start]);
+                    rebuilt.extend(&new[edit.new_start..edit.new_end()]);
+                    at = edit.old_end();
+                }
+                rebuilt.extend(&old[at..]);
+                assert_eq!(rebuilt, new, "{algorithm:?} {old:?} {new:?}");
+
+                if algorithm == Algorithm::Minimal {
+                    let changed: usize = edits.iter().map(|edit| edit.old_len + edit.new_len).sum();
+                    assert_eq!(changed, old.len() + new.len() - 2 * common(&old, &new), "{old:?} {new:?}");
+                }
+            }
+        }
+    }
+
+    // The length of the longest common subsequence of `a` and `b`.
+    fn common(a: &[&[u8]], b: &[&[u8]]) -> usize {
+        let mut lengths = vec![vec![0; b.len() + 1]; a.len() + 1];
+        for i in 0..a.len() {
+            for j in 0..b.len() {
+                lengths[i + 1][j + 1] = if a[i] == b[j] {
+                    lengths[i][j] + 1
+                } else {
+                    lengths[i][j + 1].max(lengths[i + 1][j])
+                };
+            }
+        }
+        lengths[a.len()][b.len()]
+    }
+
+    #[test]
+    fn hunks_are_written_like_git_writes_them() {
+        let mut old = "fn first() {\n".to_string();
+        (1..=8).for_each(|i| old.push_str(&format!("    one {i}\n")));
+        old.push_str("}\n\nfn second() {\n");
+        (1..=8).for_each(|i| old.push_str(&format!("    two {i}\n")));
+        old.push_str("}\n");
+        let new = old.replace("one 3", "ONE 3").replace("two 5", "TWO 5").replace("two 6\n", "two 6\n    added\n");
+        let new = new.trim_end();
+
+        let expected = "\
+@@ -1,7 +1,7 @@
+ fn first() {
+     one 1
+     one 2
+-    one 3
++    ONE 3
+     one 4
+     one 5
+     one 6
+@@ -14,8 +14,9 @@ fn second() {
+     two 2
+     two 3
+     two 4
+-    two 5
++    TWO 5
+     two 6
++    added
+     two 7
+     two 8
+-}
++}
+\\ No newline at end of file
+";
+        assert_eq!(unified(old.as_bytes(), new.as_bytes(), Algorithm::Myers), expected);
+        assert_eq!(unified(b"", b"", Algorithm::Myers), "");
+        assert_eq!(unified(b"", b"new\n", Algorithm::Myers), "@@ -0,0 +1 @@\n+new\n");
+        assert_eq!(unified(b"old\n", b"", Algorithm::Myers), "@@ -1 +0,0 @@\n-old\n");
+    }
+}
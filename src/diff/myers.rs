@@ -0,0 +1,410 @@
+use std::collections::HashMap;
+use std::ops::Range;
+
+// Lines occurring more often than this on the other side count as "very common" no matter
+// how long the file is.
+const MAX_EQLIMIT: usize = 1024;
+// How far around a very common line to look for unmatched lines when deciding to discard it.
+const SIMSCAN_WINDOW: isize = 100;
+const KPDIS_RUN: isize = 4;
+// Below this edit cost the search always runs to the end.
+const MAX_COST_MIN: isize = 256;
+// Edit cost after which long snakes are taken as good enough split points.
+const HEUR_MIN_COST: isize = 256;
+const SNAKE_CNT: isize = 20;
+const K_HEUR: isize = 4;
+
+// Myers' O(ND) difference algorithm as xdiff implements it: the middle snake is searched from
+// both ends of the box at once and the problem split there, with heuristics that give up on a
+// minimal result when the search gets expensive (unless `minimal`). `a` and `b` are lines as
+// equivalence class numbers; the lines found to be changed are marked in `changed_a` and
+// `changed_b`.
+pub(super) fn diff(a: &[usize], b: &[usize], minimal: bool, changed_a: &mut [bool], changed_b: &mut [bool]) {
+    // Lines at both ends which are the same take no part in the search
+    let common = a.len().min(b.len());
+    let prefix = (0..common).take_while(|&i| a[i] == b[i]).count();
+    let suffix = (0..common - prefix)
+        .take_while(|&i| a[a.len() - 1 - i] == b[b.len() - 1 - i])
+        .count();
+
+    let mut counts_a = HashMap::new();
+    for &line in a {
+        *counts_a.entry(line).or_insert(0) += 1;
+    }
+    let mut counts_b = HashMap::new();
+    for &line in b {
+        *counts_b.entry(line).or_insert(0) += 1;
+    }
+    let index_a = discard(a, prefix..a.len() - suffix, &counts_b, changed_a);
+    let index_b = discard(b, prefix..b.len() - suffix, &counts_a, changed_b);
+
+    let lines_a: Vec<usize> = index_a.iter().map(|&i| a[i]).collect();
+    let lines_b: Vec<usize> = index_b.iter().map(|&i| b[i]).collect();
+    let diagonals = lines_a.len() + lines_b.len() + 3;
+    let mut search = Search {
+        a: &lines_a,
+        b: &lines_b,
+        index_a: &index_a,
+        index_b: &index_b,
+        changed_a,
+        changed_b,
+        forward: vec![0; diagonals],
+        backward: vec![0; diagonals],
+        offset: lines_b.len() as isize + 1,
+        max_cost: (bogosqrt(diagonals) as isize).max(MAX_COST_MIN),
+    };
+    search.compare(0, lines_a.len() as isize, 0, lines_b.len() as isize, minimal);
+}
+
+// Integer square root approximation, as used by xdiff for its limits.
+fn bogosqrt(mut n: usize) -> usize {
+    let mut root = 1;
+    while n > 0 {
+        root <<= 1;
+        n >>= 2;
+    }
+    root
+}
+
+// Lines that don't occur on the other side at all are changed for sure and lines occurring
+// there very often are unlikely to be matched sensibly when they sit among unmatched lines:
+// both are marked as changed right away and left out of the search. Returns the positions of
+// the lines in `range` that take part in it.
+fn discard(lines: &[usize], range: Range<usize>, other: &HashMap<usize, usize>, changed: &mut [bool]) -> Vec<usize> {
+    let limit = bogosqrt(lines.len()).min(MAX_EQLIMIT);
+    // 0: no match, 1: some matches, 2: very many matches
+    let mut matches = vec![0u8; lines.len()];
+    for i in range.clone() {
+        matches[i] = match other.get(&lines[i]).copied().unwrap_or(0) {
+            0 => 0,
+            n if n >= limit => 2,
+            _ => 1,
+        };
+    }
+
+    let mut kept = Vec::new();
+    for i in range.clone() {
+        if matches[i] == 1 || (matches[i] == 2 && !among_unmatched(&matches, i, range.start, range.end - 1)) {
+            kept.push(i);
+        } else {
+            changed[i] = true;
+        }
+    }
+    kept
+}
+
+// Whether the very common line `i` is surrounded by runs of unmatched (and very common) lines
+// on both sides, with few enough very common lines among them.
+fn among_unmatched(matches: &[u8], i: usize, start: usize, end: usize) -> bool {
+    let i = i as isize;
+    let start = (start as isize).max(i - SIMSCAN_WINDOW);
+    let end = (end as isize).min(i + SIMSCAN_WINDOW);
+
+    let (mut unmatched_before, mut common_before) = (0, 1);
+    let mut r = 1;
+    while i - r >= start {
+        match matches[(i - r) as usize] {
+            0 => unmatched_before += 1,
+            2 => common_before += 1,
+            _ => break,
+        }
+        r += 1;
+    }
+    if unmatched_before == 0 {
+        return false;
+    }
+
+    let (mut unmatched_after, mut common_after) = (0, 1);
+    let mut r = 1;
+    while i + r <= end {
+        match matches[(i + r) as usize] {
+            0 => unmatched_after += 1,
+            2 => common_after += 1,
+            _ => break,
+        }
+        r += 1;
+    }
+    if unmatched_after == 0 {
+        return false;
+    }
+
+    let unmatched = unmatched_before + unmatched_after;
+    let common = common_before + common_after;
+    common * KPDIS_RUN < common + unmatched
+}
+
+// Where to split a box, and whether each half still has to be diffed minimally.
+struct Split {
+    i1: isize,
+    i2: isize,
+    minimal_low: bool,
+    minimal_high: bool,
+}
+
+struct Search<'a> {
+    // The lines taking part in the search, and their positions in the whole files
+    a: &'a [usize],
+    b: &'a [usize],
+    index_a: &'a [usize],
+    index_b: &'a [usize],
+    changed_a: &'a mut [bool],
+    changed_b: &'a mut [bool],
+    // Furthest reaching paths of the forward and backward searches, by diagonal (shifted by
+    // `offset` so negative diagonals fit)
+    forward: Vec<isize>,
+    backward: Vec<isize>,
+    offset: isize,
+    max_cost: isize,
+}
+
+impl Search<'_> {
+    fn compare(&mut self, mut off1: isize, mut lim1: isize, mut off2: isize, mut lim2: isize, minimal: bool) {
+        // Shrink the box by the snakes at both of its ends
+        while off1 < lim1 && off2 < lim2 && self.a[off1 as usize] == self.b[off2 as usize] {
+            off1 += 1;
+            off2 += 1;
+        }
+        while off1 < lim1 && off2 < lim2 && self.a[lim1 as usize - 1] == self.b[lim2 as usize - 1] {
+            lim1 -= 1;
+            lim2 -= 1;
+        }
+
+        // With one side empty, everything on the other side has changed
+        if off1 == lim1 {
+            for i in off2..lim2 {
+                self.changed_b[self.index_b[i as usize]] = true;
+            }
+        } else if off2 == lim2 {
+            for i in off1..lim1 {
+                self.changed_a[self.index_a[i as usize]] = true;
+            }
+        } else {
+            let split = self.split(off1, lim1, off2, lim2, minimal);
+            self.compare(off1, split.i1, off2, split.i2, split.minimal_low);
+            self.compare(split.i1, lim1, split.i2, lim2, split.minimal_high);
+        }
+    }
+
+    fn fwd(&self, d: isize) -> isize {
+        self.forward[(d + self.offset) as usize]
+    }
+
+    fn set_fwd(&mut self, d: isize, value: isize) {
+        self.forward[(d + self.offset) as usize] = value;
+    }
+
+    fn bwd(&self, d: isize) -> isize {
+        self.backward[(d + self.offset) as usize]
+    }
+
+    fn set_bwd(&mut self, d: isize, value: isize) {
+        self.backward[(d + self.offset) as usize] = value;
+    }
+
+    // Find the middle snake of the box, or a good enough split point once the search becomes
+    // too expensive.
+    fn split(&mut self, off1: isize, lim1: isize, off2: isize, lim2: isize, minimal: bool) -> Split {
+        let (a, b) = (self.a, self.b);
+        let dmin = off1 - lim2;
+        let dmax = lim1 - off2;
+        let fmid = off1 - off2;
+        let bmid = lim1 - lim2;
+        let odd = (fmid - bmid) & 1 != 0;
+        let (mut fmin, mut fmax) = (fmid, fmid);
+        let (mut bmin, mut bmax) = (bmid, bmid);
+
+        self.set_fwd(fmid, off1);
+        self.set_bwd(bmid, lim1);
+
+        let mut cost = 1;
+        loop {
+            let mut got_snake = false;
+
+            // Extend the forward domain by one diagonal (or shrink it at the box's edges)
+            if fmin > dmin {
+                fmin -= 1;
+                self.set_fwd(fmin - 1, -1);
+            } else {
+                fmin += 1;
+            }
+            if fmax < dmax {
+                fmax += 1;
+                self.set_fwd(fmax + 1, -1);
+            } else {
+                fmax -= 1;
+            }
+
+            let mut d = fmax;
+            while d >= fmin {
+                let mut i1 = if self.fwd(d - 1) >= self.fwd(d + 1) {
+                    self.fwd(d - 1) + 1
+                } else {
+                    self.fwd(d + 1)
+                };
+                let prev1 = i1;
+                let mut i2 = i1 - d;
+                while i1 < lim1 && i2 < lim2 && a[i1 as usize] == b[i2 as usize] {
+                    i1 += 1;
+                    i2 += 1;
+                }
+                if i1 - prev1 > SNAKE_CNT {
+                    got_snake = true;
+                }
+                self.set_fwd(d, i1);
+                if odd && bmin <= d && d <= bmax && self.bwd(d) <= i1 {
+                    return Split { i1, i2, minimal_low: true, minimal_high: true };
+                }
+                d -= 2;
+            }
+
+            // The same for the backward search
+            if bmin > dmin {
+                bmin -= 1;
+                self.set_bwd(bmin - 1, isize::MAX);
+            } else {
+                bmin += 1;
+            }
+            if bmax < dmax {
+                bmax += 1;
+                self.set_bwd(bmax + 1, isize::MAX);
+            } else {
+                bmax -= 1;
+            }
+
+            let mut d = bmax;
+            while d >= bmin {
+                let mut i1 = if self.bwd(d - 1) < self.bwd(d + 1) {
+                    self.bwd(d - 1)
+                } else {
+                    self.bwd(d + 1) - 1
+                };
+                let prev1 = i1;
+                let mut i2 = i1 - d;
+                while i1 > off1 && i2 > off2 && a[i1 as usize - 1] == b[i2 as usize - 1] {
+                    i1 -= 1;
+                    i2 -= 1;
+                }
+                if prev1 - i1 > SNAKE_CNT {
+                    got_snake = true;
+                }
+                self.set_bwd(d, i1);
+                if !odd && fmin <= d && d <= fmax && i1 <= self.fwd(d) {
+                    return Split { i1, i2, minimal_low: true, minimal_high: true };
+                }
+                d -= 2;
+            }
+
+            if minimal {
+                cost += 1;
+                continue;
+            }
+
+            // Past the heuristic threshold, a diagonal that got far from its corner along a
+            // long enough snake is taken as the split point
+            if got_snake && cost > HEUR_MIN_COST {
+                let mut best = 0;
+                let mut split = None;
+                let mut d = fmax;
+                while d >= fmin {
+                    let dd = (d - fmid).abs();
+                    let i1 = self.fwd(d);
+                    let i2 = i1 - d;
+                    let v = (i1 - off1) + (i2 - off2) - dd;
+                    if v > K_HEUR * cost
+                        && v > best
+                        && off1 + SNAKE_CNT <= i1
+                        && i1 < lim1
+                        && off2 + SNAKE_CNT <= i2
+                        && i2 < lim2
+                    {
+                        let mut k = 1;
+                        while a[(i1 - k) as usize] == b[(i2 - k) as usize] {
+                            if k == SNAKE_CNT {
+                                best = v;
+                                split = Some((i1, i2));
+                                break;
+                            }
+                            k += 1;
+                        }
+                    }
+                    d -= 2;
+                }
+                if let Some((i1, i2)) = split {
+                    return Split { i1, i2, minimal_low: true, minimal_high: false };
+                }
+
+                let mut best = 0;
+                let mut d = bmax;
+                while d >= bmin {
+                    let dd = (d - bmid).abs();
+                    let i1 = self.bwd(d);
+                    let i2 = i1 - d;
+                    let v = (lim1 - i1) + (lim2 - i2) - dd;
+                    if v > K_HEUR * cost
+                        && v > best
+                        && off1 < i1
+                        && i1 <= lim1 - SNAKE_CNT
+                        && off2 < i2
+                        && i2 <= lim2 - SNAKE_CNT
+                    {
+                        let mut k = 0;
+                        while a[(i1 + k) as usize] == b[(i2 + k) as usize] {
+                            if k == SNAKE_CNT - 1 {
+                                best = v;
+                                split = Some((i1, i2));
+                                break;
+                            }
+                            k += 1;
+                        }
+                    }
+                    d -= 2;
+                }
+                if let Some((i1, i2)) = split {
+                    return Split { i1, i2, minimal_low: false, minimal_high: true };
+                }
+            }
+
+            // Enough is enough: split at the furthest reaching path of either search
+            if cost >= self.max_cost {
+                let (mut fbest, mut fbest1) = (-1, -1);
+                let mut d = fmax;
+                while d >= fmin {
+                    let mut i1 = self.fwd(d).min(lim1);
+                    let mut i2 = i1 - d;
+                    if lim2 < i2 {
+                        i1 = lim2 + d;
+                        i2 = lim2;
+                    }
+                    if fbest < i1 + i2 {
+                        fbest = i1 + i2;
+                        fbest1 = i1;
+                    }
+                    d -= 2;
+                }
+
+                let (mut bbest, mut bbest1) = (isize::MAX, isize::MAX);
+                let mut d = bmax;
+                while d >= bmin {
+                    let mut i1 = off1.max(self.bwd(d));
+                    let mut i2 = i1 - d;
+                    if i2 < off2 {
+                        i1 = off2 + d;
+                        i2 = off2;
+                    }
+                    if i1 + i2 < bbest {
+                        bbest = i1 + i2;
+                        bbest1 = i1;
+                    }
+                    d -= 2;
+                }
+
+                return if (lim1 + lim2) - bbest < fbest - (off1 + off2) {
+                    Split { i1: fbest1, i2: fbest - fbest1, minimal_low: true, minimal_high: false }
+                } else {
+                    Split { i1: bbest1, i2: bbest - bbest1, minimal_low: false, minimal_high: true }
+                };
+            }
+            cost += 1;
+        }
+    }
+}
@@ -0,0 +1,134 @@
+use super::myers;
+use std::collections::HashMap;
+
+// How often a line of `a` occurs in `b`.
+#[derive(Clone, Copy)]
+enum Occurrence {
+    Missing,
+    Once(usize),
+    // More than once in `b`, or already more than once in `a`
+    Multiple,
+}
+
+// Patience diff: the longest sequence of lines that occur exactly once on both sides and
+// appear in the same order anchors the diff, and the gaps between those lines are diffed the
+// same way. Where no such lines exist, Myers takes over.
+pub(super) fn diff(a: &[usize], b: &[usize], changed_a: &mut [bool], changed_b: &mut [bool]) {
+    if a.is_empty() {
+        changed_b.fill(true);
+        return;
+    }
+    if b.is_empty() {
+        changed_a.fill(true);
+        return;
+    }
+
+    // The distinct lines of `a` in the order they first occur, with where they are in `b`
+    let mut lines: Vec<(usize, Occurrence)> = Vec::new();
+    let mut by_class: HashMap<usize, usize> = HashMap::new();
+    for (i, class) in a.iter().enumerate() {
+        match by_class.get(class) {
+            Some(&entry) => lines[entry].1 = Occurrence::Multiple,
+            None => {
+                by_class.insert(*class, lines.len());
+                lines.push((i, Occurrence::Missing));
+            }
+        }
+    }
+    let mut any_match = false;
+    for (j, class) in b.iter().enumerate() {
+        if let Some(&entry) = by_class.get(class) {
+            any_match = true;
+            lines[entry].1 = match lines[entry].1 {
+                Occurrence::Missing => Occurrence::Once(j),
+                _ => Occurrence::Multiple,
+            };
+        }
+    }
+    if !any_match {
+        changed_a.fill(true);
+        changed_b.fill(true);
+        return;
+    }
+
+    let anchors = longest_common_sequence(&lines);
+    if anchors.is_empty() {
+        myers::diff(a, b, false, changed_a, changed_b);
+        return;
+    }
+
+    // Diff the gaps between the anchors, after growing the matched runs around each anchor
+    let (mut line1, mut line2) = (0, 0);
+    let mut k = 0;
+    loop {
+        let (mut next1, mut next2) = match anchors.get(k) {
+            Some(&anchor) => anchor,
+            None => (a.len(), b.len()),
+        };
+        if k < anchors.len() {
+            while next1 > line1 && next2 > line2 && a[next1 - 1] == b[next2 - 1] {
+                next1 -= 1;
+                next2 -= 1;
+            }
+        }
+        while line1 < next1 && line2 < next2 && a[line1] == b[line2] {
+            line1 += 1;
+            line2 += 1;
+        }
+
+        if next1 > line1 || next2 > line2 {
+            diff(
+                &a[line1..next1],
+                &b[line2..next2],
+                &mut changed_a[line1..next1],
+                &mut changed_b[line2..next2],
+            );
+        }
+        if k == anchors.len() {
+            return;
+        }
+
+        // Skip over anchors that directly follow each other
+        while k + 1 < anchors.len() && anchors[k + 1].0 == anchors[k].0 + 1 && anchors[k + 1].1 == anchors[k].1 + 1 {
+            k += 1;
+        }
+        line1 = anchors[k].0 + 1;
+        line2 = anchors[k].1 + 1;
+        k += 1;
+    }
+}
+
+// The longest increasing (in `b`) sequence of the lines unique on both sides, taken in their
+// order in `a`, by patience sorting. Returns (line in a, line in b) pairs.
+fn longest_common_sequence(lines: &[(usize, Occurrence)]) -> Vec<(usize, usize)> {
+    // For each length, the sequence of that length ending in the smallest line of `b`,
+    // represented by its last element
+    let mut tails: Vec<usize> = Vec::new();
+    let mut previous: Vec<Option<usize>> = vec![None; lines.len()];
+    let line2 = |entry: usize| match lines[entry].1 {
+        Occurrence::Once(j) => j,
+        _ => unreachable!("only unique lines are in the sequence"),
+    };
+
+    for (entry, &(_, occurrence)) in lines.iter().enumerate() {
+        let Occurrence::Once(j) = occurrence else {
+            continue;
+        };
+        let length = tails.partition_point(|&tail| line2(tail) < j);
+        previous[entry] = length.checked_sub(1).map(|i| tails[i]);
+        if length == tails.len() {
+            tails.push(entry);
+        } else {
+            tails[length] = entry;
+        }
+    }
+
+    let mut sequence = Vec::new();
+    let mut entry = tails.last().copied();
+    while let Some(current) = entry {
+        sequence.push((lines[current].0, line2(current)));
+        entry = previous[current];
+    }
+    sequence.reverse();
+    sequence
+}
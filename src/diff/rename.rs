@@ -0,0 +1,560 @@
+use super::{content, is_binary, FilePair, Origin, Side};
+use crate::objects::ObjectId;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+// Similarity scores are out of this, like git's.
+pub(crate) const MAX_SCORE: usize = 60000;
+
+// -M without a score: files at least 50% similar.
+pub(crate) const DEFAULT_SCORE: usize = MAX_SCORE / 2;
+
+// Past this many sources times destinations (squared), only exact renames are looked for.
+const RENAME_LIMIT: usize = 1000;
+
+// How many of the most similar sources are kept for each destination.
+const CANDIDATES_PER_DESTINATION: usize = 4;
+
+// Chunk hashes are taken modulo this prime, between 2^16 and 2^17.
+const HASHBASE: u32 = 107927;
+
+// Rename and copy detection (-M, -C, --find-copies-harder).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Renames {
+    // Minimum similarity for a pair that isn't an exact match, out of MAX_SCORE
+    pub(crate) score: usize,
+    // Changed files may be the source of copies, not only deleted ones
+    pub(crate) copies: bool,
+    // So may unchanged files, which the pairs must then include
+    pub(crate) harder: bool,
+}
+
+impl Renames {
+    // Parse a similarity the way git does: "90%" or "90" is 90%, "5" and ".5" are 50%.
+    pub(crate) fn parse_score(s: &str) -> anyhow::Result<usize> {
+        let (mut num, mut scale) = (0usize, 1usize);
+        let mut dot = false;
+        let mut rest = s;
+        while let Some(c) = rest.chars().next() {
+            if c == '.' && !dot {
+                scale = 1;
+                dot = true;
+            } else if c == '%' {
+                scale = if dot { scale * 100 } else { 100 };
+                rest = &rest[1..];
+                break;
+            } else if c.is_ascii_digit() {
+                if scale < 100000 {
+                    scale *= 10;
+                    num = num * 10 + (c as usize - '0' as usize);
+                }
+            } else {
+                break;
+            }
+            rest = &rest[1..];
+        }
+        anyhow::ensure!(rest.is_empty(), "invalid similarity '{s}'");
+        Ok(if num >= scale { MAX_SCORE } else { MAX_SCORE * num / scale })
+    }
+}
+
+// The size of a file and the signature of its content: for each chunk (a line, or 64 bytes
+// of a longer one) a hash, with how many bytes had that hash. Sorted by hash.
+struct Signature {
+    size: usize,
+    chunks: Vec<(u32, usize)>,
+}
+
+impl Signature {
+    fn new(data: &[u8]) -> Signature {
+        // CRs of CRLF line ends don't count in text, so line end changes don't hurt
+        let text = !is_binary(data);
+        let mut chunks: HashMap<u32, usize> = HashMap::new();
+        let (mut accum1, mut accum2) = (0u32, 0u32);
+        let mut n = 0;
+        for (i, &c) in data.iter().enumerate() {
+            if text && c == b'\r' && data.get(i + 1) == Some(&b'\n') {
+                continue;
+            }
+            let old1 = accum1;
+            accum1 = (accum1 << 7) ^ (accum2 >> 25);
+            accum2 = (accum2 << 7) ^ (old1 >> 25);
+            accum1 = accum1.wrapping_add(c as u32);
+            n += 1;
+            if n < 64 && c != b'\n' {
+                continue;
+            }
+            *chunks.entry(accum1.wrapping_add(accum2.wrapping_mul(0x61)) % HASHBASE).or_default() += n;
+            n = 0;
+            accum1 = 0;
+            accum2 = 0;
+        }
+        if n > 0 {
+            *chunks.entry(accum1.wrapping_add(accum2.wrapping_mul(0x61)) % HASHBASE).or_default() += n;
+        }
+        let mut chunks: Vec<(u32, usize)> = chunks.into_iter().collect();
+        chunks.sort_unstable();
+        Signature { size: data.len(), chunks }
+    }
+
+    // How many bytes of `self` made it into `other`.
+    fn copied_to(&self, other: &Signature) -> usize {
+        let mut copied = 0;
+        let mut theirs = other.chunks.iter().peekable();
+        for &(hash, count) in &self.chunks {
+            while theirs.next_if(|&&(other_hash, _)| other_hash < hash).is_some() {}
+            if let Some(&(_, other_count)) = theirs.next_if(|&&(other_hash, _)| other_hash == hash) {
+                copied += count.min(other_count);
+            }
+        }
+        copied
+    }
+}
+
+fn is_regular(mode: u32) -> bool {
+    mode & 0o170000 == 0o100000
+}
+
+// Whether two paths have the same file name.
+fn same_basename(a: &str, b: &str) -> bool {
+    a.rsplit('/').next() == b.rsplit('/').next()
+}
+
+// A possible source for a destination, as kept in its candidate slots.
+#[derive(Clone, Copy)]
+struct Candidate {
+    score: usize,
+    same_basename: bool,
+    destination: usize,
+    source: usize,
+}
+
+// Better candidates first, unused slots (None) last.
+fn compare_candidates(a: &Option<Candidate>, b: &Option<Candidate>) -> Ordering {
+    match (a, b) {
+        (None, None) => Ordering::Equal,
+        (None, Some(_)) => Ordering::Greater,
+        (Some(_), None) => Ordering::Less,
+        (Some(a), Some(b)) => b.score.cmp(&a.score).then(b.same_basename.cmp(&a.same_basename)),
+    }
+}
+
+struct Detection<'a> {
+    pairs: &'a [FilePair],
+    renames: &'a Renames,
+    // Indexes into `pairs` of the files that may have been renamed or copied, and how often
+    // each was used (changed files that stay start at one)
+    sources: Vec<usize>,
+    used: Vec<usize>,
+    // Indexes into `pairs` of the created files, and the source and score each was matched with
+    destinations: Vec<usize>,
+    matches: Vec<Option<(usize, usize)>>,
+    // Signatures of the contents, loaded when first needed: where each blob's signature is,
+    // and which one each source and destination has
+    signatures: Vec<Signature>,
+    by_hash: HashMap<ObjectId, usize>,
+    source_signatures: Vec<Option<usize>>,
+    destination_signatures: Vec<Option<usize>>,
+}
+
+impl<'a> Detection<'a> {
+    fn source_side(&self, source: usize) -> (&'a str, Side) {
+        let pair = &self.pairs[self.sources[source]];
+        (&pair.path, pair.old.expect("sources have an old side"))
+    }
+
+    fn destination_side(&self, destination: usize) -> (&'a str, Side) {
+        let pair = &self.pairs[self.destinations[destination]];
+        (&pair.path, pair.new.expect("destinations have a new side"))
+    }
+
+    fn record(&mut self, destination: usize, source: usize, score: usize) {
+        self.matches[destination] = Some((source, score));
+        self.used[source] += 1;
+    }
+
+    fn load(&mut self, (path, side): (&str, Side)) -> anyhow::Result<usize> {
+        if let Some(&signature) = self.by_hash.get(&side.hash) {
+            return Ok(signature);
+        }
+        self.signatures.push(Signature::new(&content(path, &side)?));
+        self.by_hash.insert(side.hash, self.signatures.len() - 1);
+        Ok(self.signatures.len() - 1)
+    }
+
+    // How similar a source and destination are: how much of the larger of the two is
+    // content found in both. Only regular files can be similar without being the same, and
+    // files of too different sizes aren't compared.
+    fn similarity(&mut self, source: usize, destination: usize) -> anyhow::Result<usize> {
+        if !is_regular(self.source_side(source).1.mode) || !is_regular(self.destination_side(destination).1.mode) {
+            return Ok(0);
+        }
+        let from = match self.source_signatures[source] {
+            Some(signature) => signature,
+            None => {
+                let signature = self.load(self.source_side(source))?;
+                *self.source_signatures[source].insert(signature)
+            }
+        };
+        let to = match self.destination_signatures[destination] {
+            Some(signature) => signature,
+            None => {
+                let signature = self.load(self.destination_side(destination))?;
+                *self.destination_signatures[destination].insert(signature)
+            }
+        };
+        let (from, to) = (&self.signatures[from], &self.signatures[to]);
+
+        let max_size = from.size.max(to.size);
+        let delta = max_size - from.size.min(to.size);
+        if max_size * (MAX_SCORE - self.renames.score) < delta * MAX_SCORE || to.size == 0 {
+            return Ok(0);
+        }
+        Ok(from.copied_to(to) * MAX_SCORE / max_size)
+    }
+
+    // Destinations whose content is exactly that of a source, preferring unused sources and
+    // ones with the same file name.
+    fn find_exact(&mut self) {
+        let mut by_hash: HashMap<ObjectId, Vec<usize>> = HashMap::new();
+        for source in 0..self.sources.len() {
+            by_hash.entry(self.source_side(source).1.hash).or_default().push(source);
+        }
+        for destination in 0..self.destinations.len() {
+            let (path, side) = self.destination_side(destination);
+            let mut best: Option<(usize, usize)> = None;
+            let mut tries = 100;
+            for &source in by_hash.get(&side.hash).map_or(&[][..], Vec::as_slice) {
+                let (source_path, source_side) = self.source_side(source);
+                // Only regular files may change mode in a rename
+                if (!is_regular(source_side.mode) || !is_regular(side.mode)) && source_side.mode != side.mode {
+                    continue;
+                }
+                if self.used[source] > 0 && !self.renames.copies {
+                    continue;
+                }
+                let score = (self.used[source] == 0) as usize + same_basename(source_path, path) as usize;
+                if best.map_or(true, |(_, best_score)| score > best_score) {
+                    best = Some((source, score));
+                    if score == 2 {
+                        break;
+                    }
+                }
+                tries -= 1;
+                if tries == 0 {
+                    break;
+                }
+            }
+            if let Some((source, _)) = best {
+                self.record(destination, source, MAX_SCORE);
+            }
+        }
+    }
+
+    // For renames only: a deleted file and a created one with the same name, both unique
+    // among the remaining ones, are a rename if they are similar enough (halfway between
+    // the minimum score and identical).
+    fn find_by_basename(&mut self) -> anyhow::Result<()> {
+        let min_score = self.renames.score + (MAX_SCORE - self.renames.score) / 2;
+        let basename = |path: &str| path.rsplit('/').next().unwrap_or(path).to_string();
+        let mut sources: HashMap<String, Option<usize>> = HashMap::new();
+        for source in (0..self.sources.len()).filter(|&source| self.used[source] == 0) {
+            sources
+                .entry(basename(self.source_side(source).0))
+                .and_modify(|unique| *unique = None)
+                .or_insert(Some(source));
+        }
+        let mut destinations: HashMap<String, Option<usize>> = HashMap::new();
+        for destination in (0..self.destinations.len()).filter(|&destination| self.matches[destination].is_none()) {
+            destinations
+                .entry(basename(self.destination_side(destination).0))
+                .and_modify(|unique| *unique = None)
+                .or_insert(Some(destination));
+        }
+
+        for source in 0..self.sources.len() {
+            if self.used[source] > 0 {
+                continue;
+            }
+            let name = basename(self.source_side(source).0);
+            let (Some(Some(_)), Some(&Some(destination))) = (sources.get(&name), destinations.get(&name)) else {
+                continue;
+            };
+            if self.matches[destination].is_some() {
+                continue;
+            }
+            let score = self.similarity(source, destination)?;
+            if score >= min_score {
+                self.record(destination, source, score);
+            }
+        }
+        Ok(())
+    }
+
+    // Compare every remaining destination with every source, keeping the best few
+    // candidates of each; then pair them up, most similar first.
+    fn find_inexact(&mut self) -> anyhow::Result<()> {
+        // Without copies, a source can only be renamed once
+        let sources: Vec<usize> = (0..self.sources.len())
+            .filter(|&source| self.renames.copies || self.used[source] == 0)
+            .collect();
+        let remaining: Vec<usize> = (0..self.destinations.len())
+            .filter(|&destination| self.matches[destination].is_none())
+            .collect();
+        if remaining.is_empty() || sources.is_empty() {
+            return Ok(());
+        }
+
+        let unchanged = |pair: &FilePair| pair.old == pair.new;
+        let mut skip_unchanged = false;
+        if remaining.len() * sources.len() > RENAME_LIMIT * RENAME_LIMIT {
+            let changed = sources.iter().filter(|&&source| !unchanged(&self.pairs[self.sources[source]])).count();
+            if self.renames.harder && remaining.len() * changed <= RENAME_LIMIT * RENAME_LIMIT {
+                eprintln!("warning: only found copies from modified paths due to too many files.");
+                skip_unchanged = true;
+            } else {
+                eprintln!("warning: exhaustive rename detection was skipped due to too many files.");
+                return Ok(());
+            }
+        }
+
+        let mut candidates: Vec<Option<Candidate>> = Vec::new();
+        for &destination in &remaining {
+            let mut slots = [None; CANDIDATES_PER_DESTINATION];
+            for &source in &sources {
+                if skip_unchanged && unchanged(&self.pairs[self.sources[source]]) {
+                    continue;
+                }
+                let candidate = Some(Candidate {
+                    score: self.similarity(source, destination)?,
+                    same_basename: same_basename(self.source_side(source).0, self.destination_side(destination).0),
+                    destination,
+                    source,
+                });
+                // Replace the worst candidate so far if this one is better
+                let mut worst = 0;
+                for slot in 1..CANDIDATES_PER_DESTINATION {
+                    if compare_candidates(&slots[slot], &slots[worst]) == Ordering::Greater {
+                        worst = slot;
+                    }
+                }
+                if compare_candidates(&slots[worst], &candidate) == Ordering::Greater {
+                    slots[worst] = candidate;
+                }
+            }
+            candidates.extend(slots);
+        }
+        candidates.sort_by(compare_candidates);
+
+        // Renames first, then copies from whatever is left
+        for copies in [false, true] {
+            if copies && !self.renames.copies {
+                break;
+            }
+            for candidate in &candidates {
+                let Some(candidate) = candidate.filter(|candidate| candidate.score >= self.renames.score) else {
+                    break;
+                };
+                if self.matches[candidate.destination].is_some() || (!copies && self.used[candidate.source] > 0) {
+                    continue;
+                }
+                self.record(candidate.destination, candidate.source, candidate.score);
+            }
+        }
+        Ok(())
+    }
+}
+
+// Pair up deleted (with copies, also changed) files with created ones of similar content, and
+// report those as renames or copies instead. A source used several times is copied, except
+// for the last use of a deleted file, which is its rename. Unchanged pairs, only there as
+// copy sources, are dropped. With `follow`, only that path is looked for as a destination.
+pub(crate) fn find_renames(pairs: Vec<FilePair>, renames: &Renames, follow: Option<&str>) -> anyhow::Result<Vec<FilePair>> {
+    let mut detection = Detection {
+        pairs: &pairs,
+        renames,
+        sources: Vec::new(),
+        used: Vec::new(),
+        destinations: Vec::new(),
+        matches: Vec::new(),
+        signatures: Vec::new(),
+        by_hash: HashMap::new(),
+        source_signatures: Vec::new(),
+        destination_signatures: Vec::new(),
+    };
+    for (i, pair) in pairs.iter().enumerate() {
+        match (pair.old, pair.new) {
+            (None, Some(_)) if follow.map_or(true, |follow| pair.path == follow) => detection.destinations.push(i),
+            (None, _) => {}
+            (Some(_), None) => {
+                detection.sources.push(i);
+                detection.used.push(0);
+            }
+            (Some(_), Some(_)) if renames.copies => {
+                detection.sources.push(i);
+                detection.used.push(1);
+            }
+            (Some(_), Some(_)) => {}
+        }
+    }
+    detection.matches = vec![None; detection.destinations.len()];
+    detection.source_signatures = vec![None; detection.sources.len()];
+    detection.destination_signatures = vec![None; detection.destinations.len()];
+
+    detection.find_exact();
+    if !renames.copies {
+        detection.find_by_basename()?;
+    }
+    detection.find_inexact()?;
+
+    let mut found: HashMap<usize, (usize, usize)> = HashMap::new();
+    for (destination, matched) in detection.matches.iter().enumerate() {
+        if let Some((source, score)) = *matched {
+            found.insert(detection.destinations[destination], (detection.sources[source], score));
+        }
+    }
+    // Each use of a source is a copy, except the last one of a deleted file, which is
+    // not shown as deleted anymore
+    let mut uses: HashMap<usize, usize> =
+        detection.sources.iter().copied().zip(detection.used.iter().copied()).collect();
+    let renamed: Vec<usize> = uses.iter().filter(|&(_, &used)| used > 0).map(|(&pair, _)| pair).collect();
+
+    let mut result = Vec::new();
+    for (i, pair) in pairs.iter().enumerate() {
+        match (pair.old, pair.new) {
+            (None, Some(new)) => match found.get(&i) {
+                Some(&(source, score)) => {
+                    let source_pair = &pairs[source];
+                    let remaining = uses.get_mut(&source).expect("sources are counted");
+                    *remaining -= 1;
+                    result.push(FilePair {
+                        path: pair.path.clone(),
+                        old: source_pair.old,
+                        new: Some(new),
+                        origin: Some(Origin { path: source_pair.path.clone(), score, copy: *remaining > 0 }),
+                    });
+                }
+                None => result.push(pair.clone()),
+            },
+            (Some(_), None) if renamed.contains(&i) => {}
+            (old, new) if old == new => {}
+            _ => result.push(pair.clone()),
+        }
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diff::similarity_index;
+
+    fn side(byte: u8) -> Side {
+        Side { mode: 0o100644, hash: ObjectId::from_bytes(&[byte; 20]).unwrap(), worktree: true }
+    }
+
+    fn renames(score: &str, copies: bool) -> Renames {
+        Renames { score: Renames::parse_score(score).unwrap(), copies, harder: false }
+    }
+
+    // Each pair by file name, with where it came from ("new <- old 90%", "copy" for copies).
+    fn origins(pairs: &[FilePair]) -> Vec<String> {
+        let name = |path: &str| path.rsplit('/').next().unwrap_or(path).to_string();
+        pairs
+            .iter()
+            .map(|pair| match &pair.origin {
+                Some(origin) => format!(
+                    "{} <- {} {}%{}",
+                    name(&pair.path),
+                    name(&origin.path),
+                    similarity_index(origin.score),
+                    if origin.copy { " copy" } else { "" }
+                ),
+                None => name(&pair.path),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn scores_are_parsed_like_git_parses_them() {
+        let cases = [
+            ("90%", 54000),
+            ("90", 54000),
+            ("5", 30000),
+            (".5", 30000),
+            ("0.5", 30000),
+            ("1", 6000),
+            ("12.5%", 7500),
+            ("100%", MAX_SCORE),
+            ("150%", MAX_SCORE),
+            ("0", 0),
+        ];
+        for (text, score) in cases {
+            assert_eq!(Renames::parse_score(text).unwrap(), score, "{text}");
+        }
+        for text in ["abc", "5%x", "-1"] {
+            assert!(Renames::parse_score(text).is_err(), "{text}");
+        }
+    }
+
+    #[test]
+    fn signatures_count_the_bytes_in_common() {
+        let text = Signature::new(b"one\ntwo\nthree\n");
+        assert_eq!(text.copied_to(&text), 14);
+        assert_eq!(text.copied_to(&Signature::new(b"two\nfour\n")), 4);
+        // Line ends don't matter in text
+        assert_eq!(Signature::new(b"one\r\ntwo\r\n").copied_to(&Signature::new(b"one\ntwo\n")), 8);
+        // Long lines are cut into chunks of 64 bytes
+        let long = Signature::new(&[b'x'; 200]);
+        assert_eq!(long.chunks.iter().map(|&(_, count)| count).sum::<usize>(), 200);
+        assert_eq!(long.copied_to(&Signature::new(&[b'x'; 100])), 64);
+    }
+
+    #[test]
+    fn similar_files_are_scored_like_git_scores_them() {
+        let temp = tempfile::tempdir().unwrap();
+        let lines: Vec<String> = (1..=20).map(|i| format!("line number {i} of the file\n")).collect();
+        let mut changed = lines.clone();
+        changed[4] = "changed line\n".to_string();
+        changed[14] = "another changed line\n".to_string();
+        let files = [
+            ("a", lines.concat()),
+            ("a2", changed.concat()),
+            ("b", lines[..10].concat()),
+            ("b2", lines[..10].concat() + &"extra line here\n".repeat(5)),
+            ("c", "short\n".to_string()),
+        ];
+        for (name, content) in &files {
+            std::fs::write(temp.path().join(name), content).unwrap();
+        }
+        let path = |name: &str| temp.path().join(name).to_str().unwrap().to_string();
+        let pairs = || {
+            vec![
+                FilePair::new(path("a"), Some(side(1)), None),
+                FilePair::new(path("a2"), None, Some(side(2))),
+                FilePair::new(path("b"), Some(side(3)), None),
+                FilePair::new(path("b2"), None, Some(side(4))),
+                FilePair::new(path("c"), Some(side(5)), None),
+            ]
+        };
+
+        let found = find_renames(pairs(), &renames("50%", false), None).unwrap();
+        assert_eq!(origins(&found), ["a2 <- a 90%", "b2 <- b 76%", "c"]);
+        // Not similar enough any more
+        let found = find_renames(pairs(), &renames("90%", false), None).unwrap();
+        assert_eq!(origins(&found), ["a2 <- a 90%", "b", "b2", "c"]);
+    }
+
+    #[test]
+    fn exact_copies_prefer_sources_of_the_same_name() {
+        let pairs = vec![
+            FilePair::new("d1/name".to_string(), Some(side(1)), None),
+            FilePair::new("d2/other".to_string(), Some(side(1)), None),
+            FilePair::new("d3/name".to_string(), None, Some(side(1))),
+            FilePair::new("kept".to_string(), Some(side(2)), Some(side(3))),
+            FilePair::new("copy".to_string(), None, Some(side(2))),
+        ];
+        let found = find_renames(pairs, &renames("50%", true), None).unwrap();
+        assert_eq!(origins(&found), ["other", "name <- name 100%", "kept", "copy <- kept 100% copy"]);
+    }
+}
@@ -0,0 +1,490 @@
+// ASCII rendering of the commit graph next to log output, following git's graph.c so the
+// drawings come out the same:
+//
+//   *   merge
+//   |\
+//   | * side
+//   * | main
+//   |/
+//   * base
+//
+// Each branch line occupies a column two characters wide. For every commit, the graph
+// emits a few kinds of lines: an optional expansion before octopus merges, the line with
+// the commit's '*', the line fanning out a merge's parents, and lines collapsing branch
+// lines towards their new column. Between commits, padding lines just continue the columns.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Padding,
+    Skip,
+    PreCommit,
+    Commit,
+    PostMerge,
+    Collapsing,
+}
+
+pub(crate) struct Graph {
+    // The commit being drawn and its parents that are shown in the log
+    commit: String,
+    parents: Vec<String>,
+    // Width of the widest line for the current commit, used to pad all of its lines
+    width: i32,
+    // Which of the lines widening the graph before an octopus merge we are on
+    expansion_row: i32,
+    state: State,
+    prev_state: State,
+    // The column of the current commit, and of the previous one
+    commit_index: i32,
+    prev_commit_index: i32,
+    // For merges: 0 if the first parent is to the left of the merge, 1 if it is below it
+    merge_layout: i32,
+    // How many columns the current merge added to the right of it
+    edges_added: i32,
+    prev_edges_added: i32,
+    // The branch lines (by the commit they lead to) before and after the current commit
+    columns: Vec<String>,
+    new_columns: Vec<String>,
+    // For each screen position (two per column), the index in `new_columns` of the branch
+    // line passing through it, or -1
+    mapping: Vec<i32>,
+    old_mapping: Vec<i32>,
+    mapping_size: usize,
+}
+
+impl Graph {
+    pub(crate) fn new() -> Graph {
+        Graph {
+            commit: String::new(),
+            parents: Vec::new(),
+            width: 0,
+            expansion_row: 0,
+            state: State::Padding,
+            prev_state: State::Padding,
+            commit_index: 0,
+            prev_commit_index: 0,
+            merge_layout: 0,
+            edges_added: 0,
+            prev_edges_added: 0,
+            columns: Vec::new(),
+            new_columns: Vec::new(),
+            mapping: Vec::new(),
+            old_mapping: Vec::new(),
+            mapping_size: 0,
+        }
+    }
+
+    // Move on to the next commit to show. `parents` are those of its parents that the log
+    // will also show.
+    pub(crate) fn update(&mut self, commit: &str, parents: Vec<String>) {
+        self.commit = commit.to_string();
+        self.parents = parents;
+        self.prev_commit_index = self.commit_index;
+        self.update_columns();
+        self.expansion_row = 0;
+
+        // Output of the previous commit may not be finished (e.g. when -n cut it short)
+        self.state = if self.state != State::Padding {
+            State::Skip
+        } else if self.needs_pre_commit_line() {
+            State::PreCommit
+        } else {
+            State::Commit
+        };
+    }
+
+    // Whether all lines belonging to the current commit have been produced.
+    pub(crate) fn is_finished(&self) -> bool {
+        self.state == State::Padding
+    }
+
+    // The next line of graph output, and whether it is the one with the commit on it.
+    pub(crate) fn next_line(&mut self) -> (String, bool) {
+        let mut line = String::new();
+        let mut is_commit_line = false;
+        match self.state {
+            State::Padding => self.padding(&mut line),
+            State::Skip => self.skip(&mut line),
+            State::PreCommit => self.pre_commit(&mut line),
+            State::Commit => {
+                self.commit_line(&mut line);
+                is_commit_line = true;
+            }
+            State::PostMerge => self.post_merge(&mut line),
+            State::Collapsing => self.collapsing(&mut line),
+        }
+        self.pad(&mut line);
+        (line, is_commit_line)
+    }
+
+    // A line that only continues the branch lines, for text that comes after the commit
+    // line (or the next regular line, if the commit line hasn't been shown yet).
+    pub(crate) fn padding_line(&mut self) -> String {
+        if self.state != State::Commit {
+            return self.next_line().0;
+        }
+
+        let mut line = String::new();
+        for column in &self.columns {
+            line.push('|');
+            if *column == self.commit && self.parents.len() > 2 {
+                line.push_str(&" ".repeat((self.parents.len() - 2) * 2));
+            } else {
+                line.push(' ');
+            }
+        }
+        self.pad(&mut line);
+        self.prev_state = State::Padding;
+        line
+    }
+
+    fn set_state(&mut self, state: State) {
+        self.prev_state = self.state;
+        self.state = state;
+    }
+
+    // All lines of a commit have the same width, so text next to them lines up.
+    fn pad(&self, line: &mut String) {
+        let width = line.chars().count() as i32;
+        if width < self.width {
+            line.push_str(&" ".repeat((self.width - width) as usize));
+        }
+    }
+
+    fn num_dashed_parents(&self) -> i32 {
+        self.parents.len() as i32 + self.merge_layout - 3
+    }
+
+    fn num_expansion_rows(&self) -> i32 {
+        self.num_dashed_parents() * 2
+    }
+
+    // Octopus merges that aren't in the last column need room for their edges first.
+    fn needs_pre_commit_line(&self) -> bool {
+        self.parents.len() >= 3
+            && self.commit_index < self.columns.len() as i32 - 1
+            && self.expansion_row < self.num_expansion_rows()
+    }
+
+    // Whether every branch line is in its final column.
+    fn is_mapping_correct(&self) -> bool {
+        self.mapping[..self.mapping_size]
+            .iter()
+            .enumerate()
+            .all(|(i, &target)| target < 0 || target == i as i32 / 2)
+    }
+
+    fn find_new_column(&self, commit: &str) -> Option<usize> {
+        self.new_columns.iter().position(|column| column == commit)
+    }
+
+    // Work out the columns after the current commit, replacing it with its parents.
+    fn update_columns(&mut self) {
+        std::mem::swap(&mut self.columns, &mut self.new_columns);
+        self.new_columns.clear();
+
+        let max_new_columns = self.columns.len() + self.parents.len();
+        self.mapping_size = 2 * max_new_columns;
+        // The commit line looks at where the lines ended up after the last collapse
+        self.old_mapping = std::mem::replace(&mut self.mapping, vec![-1; self.mapping_size]);
+
+        self.width = 0;
+        self.prev_edges_added = self.edges_added;
+        self.edges_added = 0;
+
+        // The commit is either in one of the columns (a child was shown before it) or starts
+        // a new one on the right
+        let mut seen_this = false;
+        for i in 0..=self.columns.len() {
+            let column = if i == self.columns.len() {
+                if seen_this {
+                    break;
+                }
+                self.commit.clone()
+            } else {
+                self.columns[i].clone()
+            };
+
+            if column == self.commit {
+                seen_this = true;
+                self.commit_index = i as i32;
+                self.merge_layout = -1;
+                for parent in self.parents.clone() {
+                    self.insert_into_new_columns(&parent, i as i32);
+                }
+                // The commit takes up at least one column, even without parents
+                if self.parents.is_empty() {
+                    self.width += 2;
+                }
+            } else {
+                self.insert_into_new_columns(&column, -1);
+            }
+        }
+
+        while self.mapping_size > 1 && self.mapping[self.mapping_size - 1] < 0 {
+            self.mapping_size -= 1;
+        }
+    }
+
+    // Add a branch line leading to `commit` (coming from column `index` of the current
+    // commit, or -1 for lines just passing by).
+    fn insert_into_new_columns(&mut self, commit: &str, index: i32) {
+        let column = match self.find_new_column(commit) {
+            Some(column) => column,
+            None => {
+                self.new_columns.push(commit.to_string());
+                self.new_columns.len() - 1
+            }
+        } as i32;
+
+        let mapping_index;
+        if self.parents.len() > 1 && index > -1 && self.merge_layout == -1 {
+            // The first parent of a merge: lay out the merge depending on whether that
+            // parent's line is to the left of the merge
+            let distance = index - column;
+            let shift = if distance > 1 { 2 * distance - 3 } else { 1 };
+            self.merge_layout = if distance > 0 { 0 } else { 1 };
+            self.edges_added = self.parents.len() as i32 + self.merge_layout - 2;
+            mapping_index = self.width + (self.merge_layout - 1) * shift;
+            self.width += 2 * self.merge_layout;
+        } else if self.edges_added > 0 && self.width >= 2 && column == self.mapping[self.width as usize - 2] {
+            // The merge added a line that immediately joins the last existing one
+            mapping_index = self.width - 2;
+            self.edges_added = -1;
+        } else {
+            mapping_index = self.width;
+            self.width += 2;
+        }
+        self.mapping[mapping_index as usize] = column;
+    }
+
+    fn padding(&self, line: &mut String) {
+        for _ in &self.new_columns {
+            line.push_str("| ");
+        }
+    }
+
+    // Marks a gap, when the previous commit's lines could not be finished.
+    fn skip(&mut self, line: &mut String) {
+        line.push_str("...");
+        if self.needs_pre_commit_line() {
+            self.set_state(State::PreCommit);
+        } else {
+            self.set_state(State::Commit);
+        }
+    }
+
+    // Widen the graph to the right of an octopus merge, making room for its edges.
+    fn pre_commit(&mut self, line: &mut String) {
+        let mut seen_this = false;
+        for (i, column) in self.columns.iter().enumerate() {
+            if *column == self.commit {
+                seen_this = true;
+                line.push('|');
+                line.push_str(&" ".repeat(self.expansion_row as usize));
+            } else if seen_this && self.expansion_row == 0 {
+                // Lines after a previous merge were drawn as '\'; keep them going that way
+                if self.prev_state == State::PostMerge && self.prev_commit_index < i as i32 {
+                    line.push('\\');
+                } else {
+                    line.push('|');
+                }
+            } else if seen_this && self.expansion_row > 0 {
+                line.push('\\');
+            } else {
+                line.push('|');
+            }
+            line.push(' ');
+        }
+
+        self.expansion_row += 1;
+        if !self.needs_pre_commit_line() {
+            self.set_state(State::Commit);
+        }
+    }
+
+    fn commit_line(&mut self, line: &mut String) {
+        let mut seen_this = false;
+        for i in 0..=self.columns.len() {
+            let column = if i == self.columns.len() {
+                if seen_this {
+                    break;
+                }
+                &self.commit
+            } else {
+                &self.columns[i]
+            };
+            let i = i as i32;
+
+            if *column == self.commit {
+                seen_this = true;
+                line.push('*');
+                if self.parents.len() > 2 {
+                    // The dashes leading to an octopus merge's extra parents
+                    let dashed = self.num_dashed_parents();
+                    for j in 0..dashed {
+                        line.push('-');
+                        line.push(if j == dashed - 1 { '.' } else { '-' });
+                    }
+                }
+            } else if seen_this && self.edges_added > 1 {
+                line.push('\\');
+            } else if seen_this && self.edges_added == 1 {
+                // Continue a '\' from the previous merge's last line
+                if self.prev_state == State::PostMerge && self.prev_edges_added > 0 && self.prev_commit_index < i {
+                    line.push('\\');
+                } else {
+                    line.push('|');
+                }
+            } else if self.prev_state == State::Collapsing
+                && self.old_mapping.get(2 * i as usize + 1) == Some(&i)
+                && self.mapping.get(2 * i as usize).is_some_and(|&target| target < i)
+            {
+                line.push('/');
+            } else {
+                line.push('|');
+            }
+            line.push(' ');
+        }
+
+        if self.parents.len() > 1 {
+            self.set_state(State::PostMerge);
+        } else if self.is_mapping_correct() {
+            self.set_state(State::Padding);
+        } else {
+            self.set_state(State::Collapsing);
+        }
+    }
+
+    // The line below a merge, where its parents' branch lines split off.
+    fn post_merge(&mut self, line: &mut String) {
+        const MERGE_CHARS: [char; 3] = ['/', '|', '\\'];
+
+        let mut seen_this = false;
+        let mut seen_parent = false;
+        for i in 0..=self.columns.len() {
+            let column = if i == self.columns.len() {
+                if seen_this {
+                    break;
+                }
+                &self.commit
+            } else {
+                &self.columns[i]
+            };
+
+            if *column == self.commit {
+                seen_this = true;
+                let mut kind = self.merge_layout as usize;
+                for j in 0..self.parents.len() {
+                    line.push(MERGE_CHARS[kind]);
+                    if kind == 2 {
+                        if self.edges_added > 0 || j < self.parents.len() - 1 {
+                            line.push(' ');
+                        }
+                    } else {
+                        kind += 1;
+                    }
+                }
+                if self.edges_added == 0 {
+                    line.push(' ');
+                }
+            } else if seen_this {
+                line.push(if self.edges_added > 0 { '\\' } else { '|' });
+                line.push(' ');
+            } else {
+                line.push('|');
+                if self.merge_layout != 0 || i as i32 != self.commit_index - 1 {
+                    line.push(if seen_parent { '_' } else { ' ' });
+                }
+            }
+
+            if *column == self.parents[0] {
+                seen_parent = true;
+            }
+        }
+
+        if self.is_mapping_correct() {
+            self.set_state(State::Padding);
+        } else {
+            self.set_state(State::Collapsing);
+        }
+    }
+
+    // Move branch lines one step towards their final column.
+    fn collapsing(&mut self, line: &mut String) {
+        std::mem::swap(&mut self.mapping, &mut self.old_mapping);
+        self.mapping = vec![-1; self.old_mapping.len().max(self.mapping_size)];
+
+        let mut horizontal_edge = -1;
+        let mut horizontal_edge_target = -1;
+        for i in 0..self.mapping_size {
+            let target = self.old_mapping[i];
+            if target < 0 {
+                continue;
+            }
+            let position = i as i32;
+
+            if target * 2 == position {
+                // Already in the right place
+                self.mapping[i] = target;
+            } else if self.mapping[i - 1] < 0 {
+                // Nothing to the left: move there
+                self.mapping[i - 1] = target;
+                if horizontal_edge == -1 {
+                    horizontal_edge = position;
+                    horizontal_edge_target = target;
+                    let mut j = target * 2 + 3;
+                    while j < position - 2 {
+                        self.mapping[j as usize] = target;
+                        j += 2;
+                    }
+                }
+            } else if self.mapping[i - 1] == target {
+                // The line to the left goes to the same commit, so this one merges into it
+            } else {
+                // Cross over the line to the left, which goes somewhere else
+                self.mapping[i - 2] = target;
+                if horizontal_edge == -1 {
+                    horizontal_edge_target = target;
+                    horizontal_edge = position - 1;
+                    let mut j = target * 2 + 3;
+                    while j < position - 2 {
+                        self.mapping[j as usize] = target;
+                        j += 2;
+                    }
+                }
+            }
+        }
+
+        // The lines may have moved out of the last position
+        if self.mapping[self.mapping_size - 1] < 0 {
+            self.mapping_size -= 1;
+        }
+
+        let mut used_horizontal = false;
+        for i in 0..self.mapping_size {
+            let target = self.mapping[i];
+            let position = i as i32;
+            if target < 0 {
+                line.push(' ');
+            } else if target * 2 == position {
+                line.push('|');
+            } else if target == horizontal_edge_target && position != horizontal_edge - 1 {
+                // All but the first segment of a horizontal line end here
+                if position != target * 2 + 3 {
+                    self.mapping[i] = -1;
+                }
+                used_horizontal = true;
+                line.push('_');
+            } else {
+                if used_horizontal && position < horizontal_edge {
+                    self.mapping[i] = -1;
+                }
+                line.push('/');
+            }
+        }
+
+        if self.is_mapping_correct() {
+            self.set_state(State::Padding);
+        }
+    }
+}
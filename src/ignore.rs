@@ -0,0 +1,391 @@
+use crate::config::{self, Config};
+use anyhow::Context;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+// One line of an ignore file.
+#[derive(Debug, Clone)]
+pub(crate) struct Pattern {
+    // The line as written, for reporting (e.g. by check-ignore -v)
+    pub(crate) original: String,
+    // Where the pattern comes from and on which line
+    pub(crate) source: String,
+    pub(crate) line: usize,
+    // "!pattern": re-include what an earlier pattern excluded
+    pub(crate) negated: bool,
+    glob: String,
+    // "pattern/": only matches directories
+    dir_only: bool,
+    // Contains a slash: matched against the path relative to `base`, not just the file name
+    anchored: bool,
+    // Directory of the .gitignore the pattern was read from ("" for the root)
+    base: String,
+}
+
+impl Pattern {
+    fn parse(line: &str, base: &str, source: &str, number: usize) -> Option<Pattern> {
+        // Trailing spaces are ignored unless escaped
+        let mut line = line.trim_end_matches('\r');
+        while line.ends_with(' ') && !line.ends_with("\\ ") {
+            line = &line[..line.len() - 1];
+        }
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+
+        let original = line.to_string();
+        let mut glob = line;
+        let negated = glob.starts_with('!');
+        if negated {
+            glob = &glob[1..];
+        }
+        // "\#" and "\!" escape the special meaning of a leading '#' or '!'
+        if glob.starts_with("\\#") || glob.starts_with("\\!") {
+            glob = &glob[1..];
+        }
+        let dir_only = glob.ends_with('/');
+        let glob = glob.trim_end_matches('/');
+        if glob.is_empty() {
+            return None;
+        }
+        let anchored = glob.contains('/');
+        let glob = glob.strip_prefix('/').unwrap_or(glob);
+
+        Some(Pattern {
+            original,
+            source: source.to_string(),
+            line: number,
+            negated,
+            glob: glob.to_string(),
+            dir_only,
+            anchored,
+            base: base.to_string(),
+        })
+    }
+
+    fn matches(&self, path: &str, is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+        let relative = if self.base.is_empty() {
+            path
+        } else {
+            match path.strip_prefix(&self.base).and_then(|rest| rest.strip_prefix('/')) {
+                Some(relative) => relative,
+                None => return false,
+            }
+        };
+        if self.anchored {
+            wildmatch(self.glob.as_bytes(), relative.as_bytes())
+        } else {
+            let name = relative.rsplit('/').next().unwrap_or(relative);
+            wildmatch(self.glob.as_bytes(), name.as_bytes())
+        }
+    }
+}
+
+// git's ignore rules, from (highest precedence first):
+//   .gitignore files, deeper directories before their parents
+//   .git/info/exclude
+//   core.excludesFile (default: $XDG_CONFIG_HOME/git/ignore)
+// Within one file, the last matching line wins.
+pub(crate) struct IgnoreRules {
+    global: Vec<Pattern>,
+    // Patterns of each directory's .gitignore, loaded on first use
+    per_dir: HashMap<String, Vec<Pattern>>,
+}
+
+impl IgnoreRules {
+    pub(crate) fn load() -> anyhow::Result<IgnoreRules> {
+        let config = Config::load()?;
+        let excludes_file = config
+            .get_path("core.excludesFile")
+            .or_else(|| config::xdg_dir().map(|dir| dir.join("ignore")));
+
+        let mut global = Vec::new();
+        if let Some(path) = excludes_file {
+            global.extend(read_patterns(&path, "", &path.display().to_string())?);
+        }
+        global.extend(read_patterns(Path::new(".git/info/exclude"), "", ".git/info/exclude")?);
+
+        Ok(IgnoreRules {
+            global,
+            per_dir: HashMap::new(),
+        })
+    }
+
+    fn dir_patterns(&mut self, dir: &str) -> anyhow::Result<&[Pattern]> {
+        if !self.per_dir.contains_key(dir) {
+            let source = if dir.is_empty() {
+                ".gitignore".to_string()
+            } else {
+                format!("{dir}/.gitignore")
+            };
+            let patterns = read_patterns(Path::new(&source), dir, &source)?;
+            self.per_dir.insert(dir.to_string(), patterns);
+        }
+        Ok(&self.per_dir[dir])
+    }
+
+    // The pattern deciding whether `path` (relative to the repository root) is ignored,
+    // without looking at its parent directories. A negated pattern means "not ignored".
+    fn decisive(&mut self, path: &str, is_dir: bool) -> anyhow::Result<Option<Pattern>> {
+        // Walk the .gitignore files from the deepest directory up to the root
+        let mut dir = path;
+        while let Some((parent, _)) = dir.rsplit_once('/') {
+            dir = parent;
+            if let Some(pattern) = self.dir_patterns(dir)?.iter().rev().find(|p| p.matches(path, is_dir)) {
+                return Ok(Some(pattern.clone()));
+            }
+        }
+        if let Some(pattern) = self.dir_patterns("")?.iter().rev().find(|p| p.matches(path, is_dir)) {
+            return Ok(Some(pattern.clone()));
+        }
+        Ok(self.global.iter().rev().find(|p| p.matches(path, is_dir)).cloned())
+    }
+
+    // The pattern that decides about `path`, taking into account that nothing inside an
+    // ignored directory can be re-included.
+    pub(crate) fn matching(&mut self, path: &str, is_dir: bool) -> anyhow::Result<Option<Pattern>> {
+        let mut end = 0;
+        while let Some(slash) = path[end..].find('/') {
+            let dir = &path[..end + slash];
+            if let Some(pattern) = self.decisive(dir, true)? {
+                if !pattern.negated {
+                    return Ok(Some(pattern));
+                }
+            }
+            end += slash + 1;
+        }
+        self.decisive(path, is_dir)
+    }
+
+    pub(crate) fn is_ignored(&mut self, path: &str, is_dir: bool) -> anyhow::Result<bool> {
+        Ok(self.matching(path, is_dir)?.is_some_and(|pattern| !pattern.negated))
+    }
+}
+
+fn read_patterns(path: &Path, base: &str, source: &str) -> anyhow::Result<Vec<Pattern>> {
+    let text = match fs::read(path) {
+        Ok(text) => text,
+        // Also covers a .gitignore "inside" a path that is a file rather than a directory
+        Err(_) if !path.exists() => return Ok(Vec::new()),
+        Err(e) => return Err(e).with_context(|| format!("read {}", path.display())),
+    };
+    Ok(String::from_utf8_lossy(&text)
+        .lines()
+        .enumerate()
+        .filter_map(|(i, line)| Pattern::parse(line, base, source, i + 1))
+        .collect())
+}
+
+// Match `text` against a glob with git's wildmatch rules for paths: '*' and '?' don't
+// match '/', '[...]' is a character class and '**' between slashes matches any number
+// of directories.
+pub(crate) fn wildmatch(pattern: &[u8], text: &[u8]) -> bool {
+    let Some((&p, rest)) = pattern.split_first() else {
+        return text.is_empty();
+    };
+    match p {
+        b'*' if rest.first() == Some(&b'*') => {
+            let after = &rest[1..];
+            match after.first() {
+                // "**" at the end matches everything that's left
+                None => true,
+                // "**/" matches zero or more leading directories
+                Some(b'/') => {
+                    let after = &after[1..];
+                    if wildmatch(after, text) {
+                        return true;
+                    }
+                    text.iter()
+                        .enumerate()
+                        .filter(|&(_, &c)| c == b'/')
+                        .any(|(i, _)| wildmatch(after, &text[i + 1..]))
+                }
+                // Otherwise it behaves like a single '*'
+                Some(_) => wildmatch(&rest[1..], text) || star(&rest[1..], text),
+            }
+        }
+        b'*' => star(rest, text),
+        b'?' => matches!(text.first(), Some(&c) if c != b'/') && wildmatch(rest, &text[1..]),
+        b'[' => match (text.first(), class(rest, text.first().copied())) {
+            (Some(&c), Some((true, len))) if c != b'/' => wildmatch(&rest[len..], &text[1..]),
+            (_, None) => text.first() == Some(&b'[') && wildmatch(rest, &text[1..]),
+            _ => false,
+        },
+        b'\\' if !rest.is_empty() => text.first() == Some(&rest[0]) && wildmatch(&rest[1..], &text[1..]),
+        _ => text.first() == Some(&p) && wildmatch(rest, &text[1..]),
+    }
+}
+
+// A single '*': any run of characters within one path component.
+fn star(rest: &[u8], text: &[u8]) -> bool {
+    let mut i = 0;
+    loop {
+        if wildmatch(rest, &text[i..]) {
+            return true;
+        }
+        if i == text.len() || text[i] == b'/' {
+            return false;
+        }
+        i += 1;
+    }
+}
+
+// Match `c` against the character class starting right after '['. Returns whether it
+// matched and how many pattern bytes the class took (including the closing ']'), or None
+// if the class is not terminated (then '[' is taken literally).
+fn class(pattern: &[u8], c: Option<u8>) -> Option<(bool, usize)> {
+    let mut i = 0;
+    let negated = matches!(pattern.first(), Some(b'!') | Some(b'^'));
+    if negated {
+        i += 1;
+    }
+    let mut matched = false;
+    let mut first = true;
+    while i < pattern.len() {
+        let mut lo = pattern[i];
+        if lo == b']' && !first {
+            let c_matched = c.is_some() && matched != negated;
+            return Some((c_matched, i + 1));
+        }
+        first = false;
+
+        // [:alpha:] and friends
+        if lo == b'[' && pattern.get(i + 1) == Some(&b':') {
+            if let Some(end) = pattern[i + 2..].windows(2).position(|w| w == b":]") {
+                let name = &pattern[i + 2..i + 2 + end];
+                if let Some(c) = c {
+                    matched |= match name {
+                        b"alnum" => c.is_ascii_alphanumeric(),
+                        b"alpha" => c.is_ascii_alphabetic(),
+                        b"digit" => c.is_ascii_digit(),
+                        b"lower" => c.is_ascii_lowercase(),
+                        b"upper" => c.is_ascii_uppercase(),
+                        b"space" => c.is_ascii_whitespace(),
+                        b"punct" => c.is_ascii_punctuation(),
+                        b"xdigit" => c.is_ascii_hexdigit(),
+                        _ => false,
+                    };
+                }
+                i += end + 4;
+                continue;
+            }
+        }
+
+        if lo == b'\\' && i + 1 < pattern.len() {
+            i += 1;
+            lo = pattern[i];
+        }
+        let mut hi = lo;
+        if pattern.get(i + 1) == Some(&b'-') && pattern.get(i + 2).is_some_and(|&h| h != b']') {
+            hi = pattern[i + 2];
+            if hi == b'\\' {
+                hi = *pattern.get(i + 3)?;
+                i += 1;
+            }
+            i += 2;
+        }
+        if c.is_some_and(|c| lo <= c && c <= hi) {
+            matched = true;
+        }
+        i += 1;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pattern(line: &str, base: &str) -> Pattern {
+        Pattern::parse(line, base, ".gitignore", 1).unwrap()
+    }
+
+    #[test]
+    fn wildmatch_follows_git() {
+        let cases: &[(&str, &str, bool)] = &[
+            ("*.o", "a.o", true),
+            ("*.o", "dir/a.o", false),
+            ("a?c", "abc", true),
+            ("a?c", "a/c", false),
+            ("**/foo", "foo", true),
+            ("**/foo", "a/b/foo", true),
+            ("foo/**", "foo/a/b", true),
+            ("a/**/b", "a/b", true),
+            ("a/**/b", "a/x/y/b", true),
+            ("a/**/b", "a/x/y/c", false),
+            ("[a-c]x", "bx", true),
+            ("[!a-c]x", "bx", false),
+            ("[!a-c]x", "dx", true),
+            ("[]]", "]", true),
+            ("[[:digit:]]*", "1abc", true),
+            ("[[:digit:]]*", "abc", false),
+            ("\\*", "*", true),
+            ("\\*", "a", false),
+            // An unterminated class is taken literally
+            ("[abc", "[abc", true),
+        ];
+        for &(glob, text, expected) in cases {
+            assert_eq!(wildmatch(glob.as_bytes(), text.as_bytes()), expected, "{glob} {text}");
+        }
+    }
+
+    #[test]
+    fn lines_are_parsed_like_git_parses_them() {
+        for line in ["", "# comment", "/", "   "] {
+            assert!(Pattern::parse(line, "", ".gitignore", 1).is_none(), "{line:?}");
+        }
+        let trailing = pattern("name   ", "");
+        assert_eq!((trailing.glob.as_str(), trailing.original.as_str()), ("name", "name"));
+        assert_eq!(pattern("name\\ ", "").glob, "name\\ ");
+        assert_eq!(pattern("\\#hash", "").glob, "#hash");
+        let negated = pattern("!keep", "");
+        assert!(negated.negated && negated.glob == "keep");
+        assert!(!pattern("\\!bang", "").negated);
+    }
+
+    #[test]
+    fn patterns_match_names_or_paths() {
+        let dir = pattern("build/", "");
+        assert!(dir.matches("build", true) && dir.matches("src/build", true));
+        assert!(!dir.matches("build", false));
+
+        let rooted = pattern("/root.txt", "");
+        assert!(rooted.matches("root.txt", false) && !rooted.matches("a/root.txt", false));
+        let nested = pattern("doc/*.txt", "");
+        assert!(nested.matches("doc/a.txt", false) && !nested.matches("x/doc/a.txt", false));
+
+        // Patterns of a .gitignore further down only apply below it
+        let below = pattern("*.log", "sub");
+        assert!(below.matches("sub/a.log", false) && below.matches("sub/deeper/a.log", false));
+        assert!(!below.matches("a.log", false) && !below.matches("subway/a.log", false));
+    }
+
+    #[test]
+    fn deeper_files_and_later_lines_take_precedence() {
+        let mut rules = IgnoreRules {
+            global: vec![pattern("*.tmp", ""), pattern("*.bak", "")],
+            per_dir: HashMap::from([
+                (
+                    "".to_string(),
+                    vec![pattern("*.log", ""), pattern("!keep.log", ""), pattern("build/", ""), pattern("!*.bak", "")],
+                ),
+                ("sub".to_string(), vec![pattern("!*.log", "sub")]),
+                ("build".to_string(), vec![pattern("!*.log", "build")]),
+            ]),
+        };
+        let mut ignored = |path: &str| rules.is_ignored(path, false).unwrap();
+        assert!(ignored("a.log"));
+        assert!(!ignored("keep.log"));
+        assert!(!ignored("sub/a.log"));
+        assert!(ignored("x.tmp"));
+        // The repository's .gitignore wins over the global excludes
+        assert!(!ignored("x.bak"));
+        // Nothing in an ignored directory can be brought back
+        assert!(ignored("build/a.log"));
+        assert_eq!(rules.matching("build/a.log", false).unwrap().unwrap().original, "build/");
+    }
+}
@@ -0,0 +1,557 @@
+use crate::lockfile::Lockfile;
+use crate::objects::{Format, ObjectId};
+use anyhow::Context;
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::MetadataExt;
+
+mod cache_tree;
+
+pub(crate) use cache_tree::CacheTree;
+
+const INDEX_PATH: &str = ".git/index";
+
+// Entry flag bits (the low 12 bits hold the length of the path)
+const FLAG_ASSUME_VALID: u16 = 0x8000;
+const FLAG_EXTENDED: u16 = 0x4000;
+const FLAG_STAGE_MASK: u16 = 0x3000;
+const FLAG_STAGE_SHIFT: u16 = 12;
+const NAME_MASK: u16 = 0x0fff;
+
+// Extended flag bits, only present in version 3 and later
+const EXT_FLAG_INTENT_TO_ADD: u16 = 0x2000;
+
+// One file in the staging area, with the stat data used to tell whether it changed on disk.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct IndexEntry {
+    pub(crate) ctime: (u32, u32),
+    pub(crate) mtime: (u32, u32),
+    pub(crate) dev: u32,
+    pub(crate) ino: u32,
+    pub(crate) mode: u32,
+    pub(crate) uid: u32,
+    pub(crate) gid: u32,
+    pub(crate) size: u32,
+    pub(crate) hash: ObjectId,
+    pub(crate) flags: u16,
+    pub(crate) extended_flags: u16,
+    pub(crate) path: String,
+}
+
+impl IndexEntry {
+    // A stage-0 entry for a file with the given stat data and blob hash.
+    pub(crate) fn from_metadata(path: String, meta: &fs::Metadata, hash: ObjectId) -> IndexEntry {
+        IndexEntry {
+            ctime: (meta.ctime() as u32, meta.ctime_nsec() as u32),
+            mtime: (meta.mtime() as u32, meta.mtime_nsec() as u32),
+            dev: meta.dev() as u32,
+            ino: meta.ino() as u32,
+            mode: mode_from_metadata(meta),
+            uid: meta.uid(),
+            gid: meta.gid(),
+            size: meta.size() as u32,
+            hash,
+            flags: 0,
+            extended_flags: 0,
+            path,
+        }
+    }
+
+    // An entry without stat data (so the file always has to be looked at), at `stage`.
+    pub(crate) fn new(path: String, mode: u32, hash: ObjectId, stage: u8) -> IndexEntry {
+        IndexEntry {
+            ctime: (0, 0),
+            mtime: (0, 0),
+            dev: 0,
+            ino: 0,
+            mode,
+            uid: 0,
+            gid: 0,
+            size: 0,
+            hash,
+            flags: (stage as u16) << FLAG_STAGE_SHIFT,
+            extended_flags: 0,
+            path,
+        }
+    }
+
+    // 0 for normal entries; 1 (base), 2 (ours) and 3 (theirs) for unmerged ones.
+    pub(crate) fn stage(&self) -> u8 {
+        ((self.flags & FLAG_STAGE_MASK) >> FLAG_STAGE_SHIFT) as u8
+    }
+
+    // Entries added with "add -N": tracked, but with no content staged yet.
+    pub(crate) fn is_intent_to_add(&self) -> bool {
+        self.extended_flags & EXT_FLAG_INTENT_TO_ADD != 0
+    }
+
+    // Whether the file on disk still has the stat data recorded in the entry, i.e. can be
+    // assumed unchanged without hashing it again. `index_mtime` is the modification time of
+    // the index file: entries written in the same second as the index are "racily clean" and
+    // never trusted.
+    pub(crate) fn matches_stat(&self, meta: &fs::Metadata, index_mtime: Option<(u32, u32)>) -> bool {
+        if self.flags & FLAG_ASSUME_VALID != 0 {
+            return true;
+        }
+        let same = self.mtime == (meta.mtime() as u32, meta.mtime_nsec() as u32)
+            && self.ctime == (meta.ctime() as u32, meta.ctime_nsec() as u32)
+            && self.ino == meta.ino() as u32
+            && self.size == meta.size() as u32
+            && self.mode == mode_from_metadata(meta);
+        let racy = index_mtime.is_some_and(|index_mtime| self.mtime >= index_mtime);
+        same && !racy
+    }
+}
+
+// The git mode recorded for a file: symlink, executable or regular file.
+pub(crate) fn mode_from_metadata(meta: &fs::Metadata) -> u32 {
+    if meta.file_type().is_symlink() {
+        0o120000
+    } else if meta.mode() & 0o111 != 0 {
+        0o100755
+    } else {
+        0o100644
+    }
+}
+
+// The `.git/index` file (a.k.a. DIRC, the directory cache).
+//
+// Layout:
+//   "DIRC", version (2, 3 or 4), number of entries
+//   entries, sorted by path and stage
+//   extensions: 4-byte signature, 4-byte size, data
+//   SHA-1 (or SHA-256) of everything above
+//
+// Entries and the checksum hold hashes of the repository's object format.
+#[derive(Debug)]
+pub(crate) struct Index {
+    pub(crate) version: u32,
+    pub(crate) format: Format,
+    pub(crate) entries: Vec<IndexEntry>,
+    // Tree hashes of unchanged directories, from the TREE extension
+    pub(crate) cache_tree: Option<CacheTree>,
+    // Modification time of the index file when it was loaded, for racy-git detection.
+    pub(crate) mtime: Option<(u32, u32)>,
+}
+
+impl Index {
+    // Load `.git/index`, or start with an empty one if it doesn't exist yet.
+    pub(crate) fn load() -> anyhow::Result<Index> {
+        let data = match fs::read(INDEX_PATH) {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(Index {
+                    version: 2,
+                    format: Format::current()?,
+                    entries: Vec::new(),
+                    cache_tree: None,
+                    mtime: None,
+                })
+            }
+            Err(e) => return Err(e).context("read .git/index"),
+        };
+        let mut index = Index::parse(&data, Format::current()?).context("parse .git/index")?;
+        let meta = fs::metadata(INDEX_PATH).context("stat .git/index")?;
+        index.mtime = Some((meta.mtime() as u32, meta.mtime_nsec() as u32));
+        Ok(index)
+    }
+
+    pub(crate) fn parse(data: &[u8], format: Format) -> anyhow::Result<Index> {
+        let len = format.len();
+        anyhow::ensure!(data.len() >= 12 + len, "index file is too short");
+        let (body, checksum) = data.split_at(data.len() - len);
+        let mut hasher = format.hasher();
+        hasher.update(body);
+        anyhow::ensure!(
+            hasher.finalize().as_bytes() == checksum,
+            "index file checksum does not match its contents"
+        );
+        anyhow::ensure!(&body[..4] == b"DIRC", "index file does not start with DIRC");
+        let version = be32(&body[4..8]);
+        anyhow::ensure!((2..=4).contains(&version), "unsupported index version {version}");
+        let count = be32(&body[8..12]) as usize;
+
+        let mut pos = 12;
+        let mut entries = Vec::with_capacity(count);
+        let mut previous_path: Vec<u8> = Vec::new();
+        for _ in 0..count {
+            let start = pos;
+            // Ten 32-bit stat fields, the hash and 16 bits of flags
+            let fixed = body
+                .get(pos..pos + 40 + len + 2)
+                .ok_or_else(|| anyhow::anyhow!("index entry is truncated"))?;
+            let word = |i: usize| be32(&fixed[i * 4..i * 4 + 4]);
+            let hash = ObjectId::read(&fixed[40..], format).expect("entry is long enough");
+            let flags = u16::from_be_bytes([fixed[40 + len], fixed[40 + len + 1]]);
+            pos += 40 + len + 2;
+
+            let mut extended_flags = 0;
+            if flags & FLAG_EXTENDED != 0 {
+                anyhow::ensure!(version >= 3, "extended index entry in a version 2 index");
+                let bytes = body
+                    .get(pos..pos + 2)
+                    .ok_or_else(|| anyhow::anyhow!("index entry is truncated"))?;
+                extended_flags = u16::from_be_bytes([bytes[0], bytes[1]]);
+                pos += 2;
+            }
+
+            let path = if version == 4 {
+                // Path compression: drop N bytes from the end of the previous path, then
+                // append the null-terminated suffix. Entries are not padded.
+                let strip = read_varint(body, &mut pos)?;
+                let nul = body[pos..]
+                    .iter()
+                    .position(|&b| b == 0)
+                    .ok_or_else(|| anyhow::anyhow!("index entry path is not terminated"))?;
+                anyhow::ensure!(strip <= previous_path.len(), "index entry strips too much of the previous path");
+                let mut path = previous_path[..previous_path.len() - strip].to_vec();
+                path.extend_from_slice(&body[pos..pos + nul]);
+                pos += nul + 1;
+                path
+            } else {
+                let nul = body[pos..]
+                    .iter()
+                    .position(|&b| b == 0)
+                    .ok_or_else(|| anyhow::anyhow!("index entry path is not terminated"))?;
+                let path = body[pos..pos + nul].to_vec();
+                // Entries are padded with 1-8 null bytes to a multiple of 8
+                pos = start + entry_len(pos - start + nul);
+                path
+            };
+            previous_path = path.clone();
+
+            entries.push(IndexEntry {
+                ctime: (word(0), word(1)),
+                mtime: (word(2), word(3)),
+                dev: word(4),
+                ino: word(5),
+                mode: word(6),
+                uid: word(7),
+                gid: word(8),
+                size: word(9),
+                hash,
+                flags,
+                extended_flags,
+                path: String::from_utf8(path).context("index entry path is not valid UTF-8")?,
+            });
+        }
+
+        // Extensions: optional ones (signature starting with an uppercase letter) are only
+        // caches and can be dropped; anything else changes how the entries must be read.
+        let mut cache_tree = None;
+        while pos + 8 <= body.len() {
+            let signature = &body[pos..pos + 4];
+            let size = be32(&body[pos + 4..pos + 8]) as usize;
+            let data = body
+                .get(pos + 8..pos + 8 + size)
+                .ok_or_else(|| anyhow::anyhow!("index extensions are truncated"))?;
+            match signature {
+                b"TREE" => cache_tree = Some(CacheTree::parse(data, format).context("parse TREE extension")?),
+                _ => anyhow::ensure!(
+                    signature[0].is_ascii_uppercase(),
+                    "index uses the unsupported extension '{}'",
+                    String::from_utf8_lossy(signature)
+                ),
+            }
+            pos += 8 + size;
+        }
+        anyhow::ensure!(pos == body.len(), "index extensions are truncated");
+
+        Ok(Index {
+            version,
+            format,
+            entries,
+            cache_tree,
+            mtime: None,
+        })
+    }
+
+    pub(crate) fn to_bytes(&self) -> Vec<u8> {
+        // Extended flags need at least version 3
+        let version = if self.version == 2 && self.entries.iter().any(|e| e.extended_flags != 0) {
+            3
+        } else {
+            self.version
+        };
+
+        let mut out = Vec::new();
+        out.extend_from_slice(b"DIRC");
+        out.extend_from_slice(&version.to_be_bytes());
+        out.extend_from_slice(&(self.entries.len() as u32).to_be_bytes());
+
+        let mut previous_path: &[u8] = b"";
+        for entry in &self.entries {
+            let start = out.len();
+            for word in [
+                entry.ctime.0,
+                entry.ctime.1,
+                entry.mtime.0,
+                entry.mtime.1,
+                entry.dev,
+                entry.ino,
+                entry.mode,
+                entry.uid,
+                entry.gid,
+                entry.size,
+            ] {
+                out.extend_from_slice(&word.to_be_bytes());
+            }
+            out.extend_from_slice(entry.hash.as_bytes());
+
+            let path = entry.path.as_bytes();
+            let mut flags = (entry.flags & !(NAME_MASK | FLAG_EXTENDED)) | (path.len().min(NAME_MASK as usize) as u16);
+            if entry.extended_flags != 0 && version >= 3 {
+                flags |= FLAG_EXTENDED;
+            }
+            out.extend_from_slice(&flags.to_be_bytes());
+            if flags & FLAG_EXTENDED != 0 {
+                out.extend_from_slice(&entry.extended_flags.to_be_bytes());
+            }
+
+            if version == 4 {
+                let common = previous_path
+                    .iter()
+                    .zip(path)
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                write_varint(&mut out, previous_path.len() - common);
+                out.extend_from_slice(&path[common..]);
+                out.push(0);
+            } else {
+                out.extend_from_slice(path);
+                let len = entry_len(out.len() - start);
+                out.resize(start + len, 0);
+            }
+            previous_path = path;
+        }
+
+        if let Some(cache_tree) = &self.cache_tree {
+            let data = cache_tree.to_bytes();
+            out.extend_from_slice(b"TREE");
+            out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+            out.extend_from_slice(&data);
+        }
+
+        let mut hasher = self.format.hasher();
+        hasher.update(&out);
+        out.extend_from_slice(hasher.finalize().as_bytes());
+        out
+    }
+
+    // Atomically replace `.git/index` with the current state.
+    pub(crate) fn write(&self) -> anyhow::Result<()> {
+        let mut lock = Lockfile::acquire(INDEX_PATH)?;
+        lock.write_all(&self.to_bytes()).context("write .git/index.lock")?;
+        lock.commit()
+    }
+
+    // Position of the entry for (path, stage), or where it would have to be inserted.
+    fn position(&self, path: &str, stage: u8) -> Result<usize, usize> {
+        self.entries
+            .binary_search_by(|entry| entry.path.as_str().cmp(path).then(entry.stage().cmp(&stage)))
+    }
+
+    pub(crate) fn get(&self, path: &str) -> Option<&IndexEntry> {
+        self.position(path, 0).ok().map(|i| &self.entries[i])
+    }
+
+    // Insert or replace an entry, resolving any conflict recorded for the same path.
+    pub(crate) fn add(&mut self, entry: IndexEntry) {
+        self.remove(&entry.path);
+        if let Some(cache_tree) = &mut self.cache_tree {
+            cache_tree.invalidate(&entry.path);
+        }
+        // A file replaces a directory of the same name and vice versa
+        let dir_prefix = format!("{}/", entry.path);
+        self.entries.retain(|e| !e.path.starts_with(&dir_prefix));
+        let mut parent = entry.path.as_str();
+        while let Some((dir, _)) = parent.rsplit_once('/') {
+            self.remove(dir);
+            parent = dir;
+        }
+
+        let at = self
+            .position(&entry.path, entry.stage())
+            .expect_err("entry was just removed");
+        self.entries.insert(at, entry);
+    }
+
+    // Record `path` as unmerged, with the versions that exist at stages 1 (base), 2 (ours) and
+    // 3 (theirs), in place of whatever was staged for it.
+    pub(crate) fn add_conflict(&mut self, path: &str, stages: [Option<(u32, ObjectId)>; 3]) {
+        self.remove(path);
+        for (stage, version) in (1..).zip(stages) {
+            let Some((mode, hash)) = version else { continue };
+            let at = self.position(path, stage).expect_err("path was just removed");
+            self.entries.insert(at, IndexEntry::new(path.to_string(), mode, hash, stage));
+        }
+    }
+
+    // Remove every stage of `path`; returns whether anything was removed.
+    pub(crate) fn remove(&mut self, path: &str) -> bool {
+        let before = self.entries.len();
+        self.entries.retain(|e| e.path != path);
+        let removed = before != self.entries.len();
+        if removed {
+            if let Some(cache_tree) = &mut self.cache_tree {
+                cache_tree.invalidate(path);
+            }
+        }
+        removed
+    }
+
+    // Entries at `path` itself or anywhere below it if it's a directory.
+    pub(crate) fn matching<'a>(&'a self, path: &'a str) -> impl Iterator<Item = &'a IndexEntry> + 'a {
+        let dir_prefix = format!("{path}/");
+        self.entries
+            .iter()
+            .filter(move |e| path.is_empty() || e.path == path || e.path.starts_with(&dir_prefix))
+    }
+}
+
+fn be32(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes(bytes[..4].try_into().expect("slice is 4 bytes"))
+}
+
+// Size of a padded v2/v3 entry whose fixed part and path take up `len` bytes.
+fn entry_len(len: usize) -> usize {
+    (len + 8) & !7
+}
+
+// The variable-length integers of index v4 (the same encoding as OFS_DELTA offsets).
+fn read_varint(data: &[u8], pos: &mut usize) -> anyhow::Result<usize> {
+    let mut next = || -> anyhow::Result<u8> {
+        let byte = *data
+            .get(*pos)
+            .ok_or_else(|| anyhow::anyhow!("index entry is truncated"))?;
+        *pos += 1;
+        Ok(byte)
+    };
+    let mut byte = next()?;
+    let mut value = (byte & 0x7f) as usize;
+    while byte & 0x80 != 0 {
+        byte = next()?;
+        value = ((value + 1) << 7) | (byte & 0x7f) as usize;
+    }
+    Ok(value)
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: usize) {
+    let mut bytes = vec![(value & 0x7f) as u8];
+    value >>= 7;
+    while value != 0 {
+        value -= 1;
+        bytes.push(0x80 | (value & 0x7f) as u8);
+        value >>= 7;
+    }
+    bytes.reverse();
+    out.extend_from_slice(&bytes);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(byte: u8) -> ObjectId {
+        ObjectId::from_bytes(&[byte; 20]).unwrap()
+    }
+
+    fn index(version: u32, paths: &[&str]) -> Index {
+        let mut index = Index {
+            version,
+            format: Format::Sha1,
+            entries: Vec::new(),
+            cache_tree: None,
+            mtime: None,
+        };
+        for (i, path) in paths.iter().enumerate() {
+            index.add(IndexEntry::new(path.to_string(), 0o100644, id(i as u8 + 1), 0));
+        }
+        index
+    }
+
+    #[test]
+    fn indexes_read_back_what_was_written() {
+        for version in [2, 3, 4] {
+            let mut written = index(version, &["README", "src/lib.rs", "src/main.rs", "src/x/deep.rs"]);
+            written.entries[1].mtime = (1112904793, 7);
+            written.entries[1].size = 42;
+            written.add_conflict("conflicted", [Some((0o100644, id(7))), None, Some((0o100755, id(9)))]);
+            let mut intent = IndexEntry::new("new".to_string(), 0o100644, id(8), 0);
+            intent.extended_flags = EXT_FLAG_INTENT_TO_ADD;
+            written.add(intent);
+
+            let read = Index::parse(&written.to_bytes(), Format::Sha1).unwrap();
+            // An intent-to-add entry needs the extended flags of version 3
+            assert_eq!(read.version, version.max(3), "version {version}");
+            // Flags as read also hold the length of the path and whether there are extended ones
+            let mut entries = read.entries.clone();
+            for entry in &mut entries {
+                entry.flags &= !(NAME_MASK | FLAG_EXTENDED);
+            }
+            assert_eq!(entries, written.entries, "version {version}");
+            assert!(read.get("new").unwrap().is_intent_to_add());
+            let stages: Vec<u8> = read.matching("conflicted").map(IndexEntry::stage).collect();
+            assert_eq!(stages, [1, 3]);
+        }
+    }
+
+    #[test]
+    fn version_4_compresses_paths() {
+        let paths = ["src/aaaaaaaaaaaaaaaa/one", "src/aaaaaaaaaaaaaaaa/two"];
+        let v2 = index(2, &paths).to_bytes();
+        let v4 = index(4, &paths).to_bytes();
+        assert!(v4.len() < v2.len());
+        // Strip "one", then add "two"
+        let second = 12 + 62 + 1 + paths[0].len() + 1 + 62;
+        assert_eq!(&v4[second..second + 5], b"\x03two\0");
+    }
+
+    #[test]
+    fn entries_stay_sorted_and_files_replace_directories() {
+        let mut index = index(2, &["b", "a/x", "a/y", "c"]);
+        let paths = |index: &Index| index.entries.iter().map(|e| e.path.clone()).collect::<Vec<_>>();
+        assert_eq!(paths(&index), ["a/x", "a/y", "b", "c"]);
+
+        index.add(IndexEntry::new("a".to_string(), 0o100644, id(9), 0));
+        assert_eq!(paths(&index), ["a", "b", "c"]);
+        index.add(IndexEntry::new("b/z".to_string(), 0o100644, id(9), 0));
+        assert_eq!(paths(&index), ["a", "b/z", "c"]);
+
+        assert!(index.remove("c"));
+        assert!(!index.remove("c"));
+        assert_eq!(index.get("a").unwrap().hash, id(9));
+    }
+
+    #[test]
+    fn damaged_indexes_are_rejected() {
+        let good = index(2, &["a", "b"]).to_bytes();
+        assert!(Index::parse(&good, Format::Sha1).is_ok());
+
+        let mut checksum = good.clone();
+        checksum[20] ^= 1;
+        assert!(Index::parse(&checksum, Format::Sha1).is_err());
+        assert!(Index::parse(&good[..good.len() - 1], Format::Sha1).is_err());
+
+        // An extension git requires to be understood ("link", for split indexes)
+        let mut bytes = index(2, &["a"]).to_bytes();
+        bytes.truncate(bytes.len() - 20);
+        bytes.extend_from_slice(b"link\0\0\0\0");
+        let mut hasher = Format::Sha1.hasher();
+        hasher.update(&bytes);
+        bytes.extend_from_slice(hasher.finalize().as_bytes());
+        let error = Index::parse(&bytes, Format::Sha1).unwrap_err();
+        assert!(error.to_string().contains("link"), "{error}");
+    }
+
+    #[test]
+    fn varints_round_trip() {
+        for value in [0, 1, 127, 128, 255, 16511, 16512, 1 << 30] {
+            let mut out = Vec::new();
+            write_varint(&mut out, value);
+            let mut pos = 0;
+            assert_eq!(read_varint(&out, &mut pos).unwrap(), value);
+            assert_eq!(pos, out.len());
+        }
+    }
+}
@@ -0,0 +1,317 @@
+use super::{Index, IndexEntry};
+use crate::objects::{Format, Kind, Object, ObjectId};
+use anyhow::Context;
+use std::io::Cursor;
+
+// The "TREE" index extension: the tree hashes of directories as of the last write-tree,
+// so unchanged directories don't have to be rebuilt. Any change below a directory
+// invalidates it (and all of its parents) by setting `entry_count` to -1.
+//
+// On disk, each node is:
+//   "<name>\0<entry_count> <subtree_count>\n" then, if entry_count >= 0, the raw hash,
+//   followed by its subtrees, recursively
+#[derive(Debug, Clone, Default)]
+pub(crate) struct CacheTree {
+    // Number of index entries covered by this tree, or -1 if it is out of date
+    pub(crate) entry_count: i32,
+    pub(crate) hash: Option<ObjectId>,
+    pub(crate) subtrees: Vec<(String, CacheTree)>,
+}
+
+impl CacheTree {
+    fn invalid() -> CacheTree {
+        CacheTree {
+            entry_count: -1,
+            hash: None,
+            subtrees: Vec::new(),
+        }
+    }
+
+    fn is_valid(&self) -> bool {
+        self.entry_count >= 0 && self.hash.is_some()
+    }
+
+    pub(crate) fn parse(data: &[u8], format: Format) -> anyhow::Result<CacheTree> {
+        let mut pos = 0;
+        let (_, root) = parse_node(data, &mut pos, format)?;
+        anyhow::ensure!(pos == data.len(), "TREE extension has trailing data");
+        Ok(root)
+    }
+
+    pub(crate) fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.write_node("", &mut out);
+        out
+    }
+
+    fn write_node(&self, name: &str, out: &mut Vec<u8>) {
+        out.extend_from_slice(name.as_bytes());
+        out.push(0);
+        let entry_count = if self.is_valid() { self.entry_count } else { -1 };
+        out.extend_from_slice(format!("{entry_count} {}\n", self.subtrees.len()).as_bytes());
+        if entry_count >= 0 {
+            out.extend_from_slice(self.hash.as_ref().expect("valid trees have a hash").as_bytes());
+        }
+        for (name, subtree) in &self.subtrees {
+            subtree.write_node(name, out);
+        }
+    }
+
+    // The node for directory `name` directly below this one, created if needed.
+    fn subtree_mut(&mut self, name: &str) -> &mut CacheTree {
+        let at = match self.subtrees.iter().position(|(n, _)| n == name) {
+            Some(at) => at,
+            None => {
+                self.subtrees.push((name.to_string(), CacheTree::invalid()));
+                // Same order as git: shorter names first, then bytewise
+                self.subtrees
+                    .sort_by(|(a, _), (b, _)| a.len().cmp(&b.len()).then(a.cmp(b)));
+                self.subtrees
+                    .iter()
+                    .position(|(n, _)| n == name)
+                    .expect("just inserted")
+            }
+        };
+        &mut self.subtrees[at].1
+    }
+
+    // Mark every directory on the way to `path` as out of date.
+    pub(crate) fn invalidate(&mut self, path: &str) {
+        self.entry_count = -1;
+        if let Some((dir, rest)) = path.split_once('/') {
+            if let Some((_, subtree)) = self.subtrees.iter_mut().find(|(n, _)| n == dir) {
+                subtree.invalidate(rest);
+            }
+        }
+    }
+}
+
+fn parse_node(data: &[u8], pos: &mut usize, format: Format) -> anyhow::Result<(String, CacheTree)> {
+    let nul = data[*pos..]
+        .iter()
+        .position(|&b| b == 0)
+        .ok_or_else(|| anyhow::anyhow!("TREE entry name is not terminated"))?;
+    let name = std::str::from_utf8(&data[*pos..*pos + nul])
+        .context("TREE entry name is not valid UTF-8")?
+        .to_string();
+    *pos += nul + 1;
+
+    let newline = data[*pos..]
+        .iter()
+        .position(|&b| b == b'\n')
+        .ok_or_else(|| anyhow::anyhow!("TREE entry counts are not terminated"))?;
+    let counts = std::str::from_utf8(&data[*pos..*pos + newline]).context("TREE entry counts")?;
+    *pos += newline + 1;
+    let (entry_count, subtree_count) = counts
+        .split_once(' ')
+        .ok_or_else(|| anyhow::anyhow!("invalid TREE entry counts '{counts}'"))?;
+    let entry_count: i32 = entry_count.parse().context("TREE entry count")?;
+    let subtree_count: usize = subtree_count.parse().context("TREE subtree count")?;
+
+    let hash = if entry_count >= 0 {
+        let hash = ObjectId::read(&data[*pos..], format)
+            .ok_or_else(|| anyhow::anyhow!("TREE entry hash is truncated"))?;
+        *pos += format.len();
+        Some(hash)
+    } else {
+        None
+    };
+
+    let mut subtrees = Vec::with_capacity(subtree_count);
+    for _ in 0..subtree_count {
+        subtrees.push(parse_node(data, pos, format)?);
+    }
+    Ok((
+        name,
+        CacheTree {
+            entry_count,
+            hash,
+            subtrees,
+        },
+    ))
+}
+
+impl Index {
+    // Write tree objects for the staged state (or for the subdirectory `prefix` of it) and
+    // return the hash of the resulting tree. Directories whose cached tree is still valid are
+    // reused as they are.
+    pub(crate) fn write_tree(&mut self, prefix: &str) -> anyhow::Result<ObjectId> {
+        let unmerged: Vec<&str> = self
+            .entries
+            .iter()
+            .filter(|e| e.stage() != 0)
+            .map(|e| e.path.as_str())
+            .collect();
+        if !unmerged.is_empty() {
+            anyhow::bail!(
+                "error building trees: unmerged entries for {}",
+                unmerged.join(", ")
+            );
+        }
+
+        // Narrow down to the entries (and cache node) of the requested subdirectory
+        let mut node = self.cache_tree.get_or_insert_with(CacheTree::invalid);
+        let mut entries: &[IndexEntry] = &self.entries;
+        if !prefix.is_empty() {
+            let dir_prefix = format!("{prefix}/");
+            let start = entries.partition_point(|e| e.path.as_str() < dir_prefix.as_str());
+            let len = entries[start..]
+                .iter()
+                .take_while(|e| e.path.starts_with(&dir_prefix))
+                .count();
+            anyhow::ensure!(len > 0, "prefix {prefix} not found");
+            entries = &entries[start..start + len];
+            for component in prefix.split('/') {
+                node = node.subtree_mut(component);
+            }
+        }
+
+        let base = if prefix.is_empty() { 0 } else { prefix.len() + 1 };
+        build(entries, base, node)
+    }
+}
+
+// Build the tree for `entries`, which all share the first `base` bytes of their path.
+fn build(entries: &[IndexEntry], base: usize, node: &mut CacheTree) -> anyhow::Result<ObjectId> {
+    if node.is_valid() && node.entry_count as usize == entries.len() {
+        return Ok(node.hash.expect("valid trees have a hash"));
+    }
+
+    let mut tree_object = Vec::new();
+    let mut seen_dirs = Vec::new();
+    let mut i = 0;
+    while i < entries.len() {
+        let entry = &entries[i];
+        let rest = &entry.path[base..];
+
+        // Index order matches tree order: all entries of a subdirectory are consecutive
+        let (mode, name, hash) = match rest.split_once('/') {
+            Some((dir, _)) => {
+                let dir_prefix = &entry.path[..base + dir.len() + 1];
+                let len = entries[i..]
+                    .iter()
+                    .take_while(|e| e.path.starts_with(dir_prefix))
+                    .count();
+                let subtree = node.subtree_mut(dir);
+                let hash = build(&entries[i..i + len], base + dir.len() + 1, subtree)?;
+                seen_dirs.push(dir.to_string());
+                i += len;
+                ("40000".to_string(), dir, hash)
+            }
+            None => {
+                i += 1;
+                // Entries added with "add -N" are placeholders, not content
+                if entry.is_intent_to_add() {
+                    continue;
+                }
+                (format!("{:o}", entry.mode), rest, entry.hash)
+            }
+        };
+
+        tree_object.extend(mode.as_bytes());
+        tree_object.push(b' ');
+        tree_object.extend(name.as_bytes());
+        tree_object.push(0);
+        tree_object.extend(hash.as_bytes());
+    }
+
+    // Forget about directories that no longer exist
+    node.subtrees.retain(|(name, _)| seen_dirs.contains(name));
+
+    let hash = Object {
+        kind: Kind::Tree,
+        size: tree_object.len() as u64,
+        reader: Cursor::new(tree_object),
+    }
+    .write_to_objects()
+    .context("write tree object")?;
+
+    node.entry_count = entries.len() as i32;
+    node.hash = Some(hash);
+    Ok(hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(byte: u8) -> ObjectId {
+        ObjectId::from_bytes(&[byte; 20]).unwrap()
+    }
+
+    fn valid(entry_count: i32, hash: u8, subtrees: Vec<(&str, CacheTree)>) -> CacheTree {
+        CacheTree {
+            entry_count,
+            hash: Some(id(hash)),
+            subtrees: subtrees.into_iter().map(|(name, tree)| (name.to_string(), tree)).collect(),
+        }
+    }
+
+    #[test]
+    fn extensions_read_back_what_was_written() {
+        // The root with 3 entries, a valid "doc" and an out of date "src"
+        let mut data = b"\x003 2\n".to_vec();
+        data.extend_from_slice(id(1).as_bytes());
+        data.extend_from_slice(b"doc\x001 0\n");
+        data.extend_from_slice(id(2).as_bytes());
+        data.extend_from_slice(b"src\0-1 0\n");
+
+        let tree = CacheTree::parse(&data, Format::Sha1).unwrap();
+        assert_eq!((tree.entry_count, tree.hash), (3, Some(id(1))));
+        assert_eq!(tree.subtrees[0].0, "doc");
+        assert!(tree.subtrees[0].1.is_valid());
+        assert!(!tree.subtrees[1].1.is_valid());
+        assert_eq!(tree.to_bytes(), data);
+
+        assert!(CacheTree::parse(&data[..data.len() - 1], Format::Sha1).is_err());
+        assert!(CacheTree::parse(&data[..10], Format::Sha1).is_err());
+        let mut trailing = data.clone();
+        trailing.push(0);
+        assert!(CacheTree::parse(&trailing, Format::Sha1).is_err());
+    }
+
+    #[test]
+    fn changes_invalidate_the_directories_above_them() {
+        let b = valid(2, 3, vec![("c", valid(1, 4, vec![]))]);
+        let mut tree = valid(4, 1, vec![("a", valid(1, 2, vec![])), ("b", b)]);
+        tree.invalidate("b/c/file");
+        assert!(!tree.is_valid());
+        assert!(tree.subtrees[0].1.is_valid());
+        let b = &tree.subtrees[1].1;
+        assert!(!b.is_valid() && !b.subtrees[0].1.is_valid());
+        // Written out of date, without a hash
+        assert!(tree.to_bytes().starts_with(b"\x00-1 2\na\x001 0\n"));
+    }
+
+    #[test]
+    fn subtrees_are_kept_in_git_order() {
+        let mut tree = CacheTree::invalid();
+        for name in ["zz", "b", "aaa", "a"] {
+            tree.subtree_mut(name);
+        }
+        tree.subtree_mut("b");
+        let names: Vec<&str> = tree.subtrees.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, ["a", "b", "zz", "aaa"]);
+    }
+
+    #[test]
+    fn valid_trees_are_reused_without_being_written() {
+        let mut index = Index {
+            version: 2,
+            format: Format::Sha1,
+            entries: ["README", "src/lib.rs", "src/main.rs"]
+                .iter()
+                .map(|path| IndexEntry::new(path.to_string(), 0o100644, id(9), 0))
+                .collect(),
+            cache_tree: Some(valid(3, 1, vec![("src", valid(2, 2, vec![]))])),
+            mtime: None,
+        };
+        assert_eq!(index.write_tree("").unwrap(), id(1));
+        assert_eq!(index.write_tree("src").unwrap(), id(2));
+        assert!(index.write_tree("doc").is_err());
+
+        index.add_conflict("README", [None, Some((0o100644, id(3))), Some((0o100644, id(4)))]);
+        let error = index.write_tree("").unwrap_err();
+        assert!(error.to_string().contains("unmerged entries for README"), "{error}");
+    }
+}
@@ -0,0 +1,70 @@
+use anyhow::Context;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+// Exclusive, atomic replacement of a file, following git's "<file>.lock" protocol:
+// the new contents are written to the lock file, which is then renamed over the original.
+// Dropping the lock without committing it leaves the original untouched.
+pub(crate) struct Lockfile {
+    path: PathBuf,
+    lock_path: PathBuf,
+    file: Option<File>,
+}
+
+impl Lockfile {
+    pub(crate) fn acquire(path: impl AsRef<Path>) -> anyhow::Result<Lockfile> {
+        let path = path.as_ref().to_path_buf();
+        let mut lock_path = path.clone().into_os_string();
+        lock_path.push(".lock");
+        let lock_path = PathBuf::from(lock_path);
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).with_context(|| format!("create {}", parent.display()))?;
+        }
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&lock_path)
+            .with_context(|| {
+                format!(
+                    "unable to create '{}': another process may be running; if not, remove the file",
+                    lock_path.display()
+                )
+            })?;
+
+        Ok(Lockfile {
+            path,
+            lock_path,
+            file: Some(file),
+        })
+    }
+
+    // Move the new contents into place.
+    pub(crate) fn commit(mut self) -> anyhow::Result<()> {
+        let file = self.file.take().expect("lock file is open until committed");
+        file.sync_all()
+            .with_context(|| format!("flush {}", self.lock_path.display()))?;
+        drop(file);
+        fs::rename(&self.lock_path, &self.path)
+            .with_context(|| format!("move {} into place", self.lock_path.display()))
+    }
+}
+
+impl Write for Lockfile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.file.as_mut().expect("lock file is open until committed").write(buf)
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.as_mut().expect("lock file is open until committed").flush()
+    }
+}
+
+impl Drop for Lockfile {
+    fn drop(&mut self) {
+        // Not committed: roll back by removing the lock
+        if self.file.take().is_some() {
+            let _ = fs::remove_file(&self.lock_path);
+        }
+    }
+}
@@ -1,7 +1,26 @@
 use clap::{Parser, Subcommand};
 use std::path::PathBuf;
+mod checkout;
 mod commands;
+mod config;
+mod date;
+mod diff;
+mod graph;
+mod ignore;
+mod index;
+mod lockfile;
+mod merge;
 mod objects;
+mod pack;
+mod promisor;
+mod refs;
+mod remote;
+mod repository;
+mod revision;
+mod server;
+mod shallow;
+mod transport;
+mod worktree;
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
@@ -12,12 +31,12 @@ struct Args {
 
 #[derive(Debug, Subcommand)]
 enum Command {
-    Init,
-    CatFile {
-        #[clap(short = 'p')]
-        pretty_print: bool,
-        object_hash: String,
+    Init {
+        /// The hash function that names the objects: sha1 or sha256
+        #[clap(long, value_name = "format", default_value = "sha1")]
+        object_format: String,
     },
+    CatFile(commands::CatFileArgs),
     HashObject {
         #[clap(short = 'w')]
         write: bool,
@@ -29,31 +48,100 @@ enum Command {
 
         tree_hash: String,
     },
-    WriteTree,
-    CommitTree {
-        #[clap(short = 'm')]
-        message: String,
+    WriteTree {
+        /// Write the tree of this subdirectory instead of the whole index
+        #[clap(long)]
+        prefix: Option<String>,
+    },
+    CommitTree(commands::CommitTreeArgs),
+    Clone(commands::CloneArgs),
+    Repack {
+        /// Also repack objects that are already in packs
+        #[clap(short = 'a')]
+        all: bool,
 
-        #[clap(short = 'p')]
-        parent_hash: Option<String>,
+        /// Remove the loose objects (and with -a, the old packs) made redundant by the new pack
+        #[clap(short = 'd')]
+        delete: bool,
 
-        tree_hash: String,
+        /// How many objects to try as delta bases for each object [default: pack.window or 10]
+        #[clap(long)]
+        window: Option<usize>,
+
+        /// How long delta chains may get [default: pack.depth or 50]
+        #[clap(long)]
+        depth: Option<usize>,
+    },
+    Tag(commands::TagArgs),
+    RevParse(commands::RevParseArgs),
+    Add {
+        /// Allow adding otherwise ignored files
+        #[clap(short, long)]
+        force: bool,
+
+        #[clap(required = true)]
+        paths: Vec<PathBuf>,
+    },
+    Rm {
+        /// Only remove from the index, keep the working tree file
+        #[clap(long)]
+        cached: bool,
+
+        /// Allow recursive removal when a directory is given
+        #[clap(short = 'r')]
+        recursive: bool,
+
+        /// Remove files even if they have unstaged changes
+        #[clap(short = 'f', long)]
+        force: bool,
+
+        #[clap(required = true)]
+        paths: Vec<PathBuf>,
     },
-    Clone {
-        url: String,
-        dir: String,
+    Config(commands::ConfigArgs),
+    Push(commands::PushArgs),
+    UploadPack(commands::UploadPackArgs),
+    ReceivePack(commands::ReceivePackArgs),
+    Serve(commands::ServeArgs),
+    Remote(commands::RemoteArgs),
+    Fetch(commands::FetchArgs),
+    LsFiles {
+        /// Show mode, hash and stage of each entry
+        #[clap(short = 's', long)]
+        stage: bool,
+
+        paths: Vec<PathBuf>,
     },
+    CheckIgnore(commands::CheckIgnoreArgs),
+    Commit {
+        /// The commit message (required unless concluding a merge, which has its own)
+        #[clap(short = 'm')]
+        message: Option<String>,
+
+        /// Record a commit even if its tree is the same as its parent's
+        #[clap(long)]
+        allow_empty: bool,
+    },
+    Branch(commands::BranchArgs),
+    Switch(commands::SwitchArgs),
+    Checkout(commands::CheckoutArgs),
+    Status(commands::StatusArgs),
+    Log(commands::LogArgs),
+    Diff(commands::DiffArgs),
+    DiffTree(commands::DiffTreeArgs),
+    Merge(commands::MergeArgs),
+    MergeBase(commands::MergeBaseArgs),
 }
 
 fn main() -> anyhow::Result<()> {
-    let args = Args::parse();
+    let args = Args::parse_from(commands::attach_scores(std::env::args_os().collect()));
 
     match args.command {
-        Command::Init => {
-            commands::init()?;
+        Command::Init { object_format } => {
+            commands::init(&object_format)?;
         }
-        Command::CatFile { pretty_print, object_hash } => {
-            commands::cat_file(pretty_print, object_hash)?;
+        Command::CatFile(args) => {
+            commands::cat_file(args)?;
         }
         Command::HashObject { write, file } => {
             commands::hash_object(write, file)?;
@@ -61,14 +149,86 @@ fn main() -> anyhow::Result<()> {
         Command::LsTree { name_only, tree_hash } => {
             commands::ls_tree(name_only, &tree_hash)?;
         }
-        Command::WriteTree => {
-            commands::write_tree()?;
+        Command::WriteTree { prefix } => {
+            commands::write_tree(prefix)?;
+        }
+        Command::CommitTree(args) => {
+            commands::commit_tree(args)?;
+        }
+        Command::Clone(args) => {
+            commands::clone(args)?;
+        }
+        Command::Repack { all, delete, window, depth } => {
+            commands::repack(all, delete, window, depth)?;
+        }
+        Command::Tag(args) => {
+            commands::tag(args)?;
+        }
+        Command::RevParse(args) => {
+            commands::rev_parse(args)?;
+        }
+        Command::Add { force, paths } => {
+            commands::add(paths, force)?;
+        }
+        Command::Rm { cached, recursive, force, paths } => {
+            commands::rm(cached, recursive, force, paths)?;
+        }
+        Command::CheckIgnore(args) => {
+            commands::check_ignore(args)?;
+        }
+        Command::Commit { message, allow_empty } => {
+            commands::commit(message, allow_empty)?;
+        }
+        Command::Branch(args) => {
+            commands::branch(args)?;
+        }
+        Command::Switch(args) => {
+            commands::switch(args)?;
+        }
+        Command::Checkout(args) => {
+            commands::checkout(args)?;
+        }
+        Command::Status(args) => {
+            commands::status(args)?;
+        }
+        Command::Log(args) => {
+            commands::log(args)?;
+        }
+        Command::Diff(args) => {
+            commands::diff(args)?;
+        }
+        Command::DiffTree(args) => {
+            commands::diff_tree(args)?;
+        }
+        Command::Merge(args) => {
+            commands::merge(args)?;
+        }
+        Command::MergeBase(args) => {
+            commands::merge_base(args)?;
+        }
+        Command::Config(args) => {
+            commands::config(args)?;
+        }
+        Command::Push(args) => {
+            commands::push(args)?;
+        }
+        Command::UploadPack(args) => {
+            commands::upload_pack(args)?;
+        }
+        Command::ReceivePack(args) => {
+            commands::receive_pack(args)?;
+        }
+        Command::Serve(args) => {
+            commands::serve(args)?;
+        }
+        Command::Remote(args) => {
+            commands::remote(args)?;
         }
-        Command::CommitTree { message, tree_hash, parent_hash } => {
-            commands::commit_tree(message, tree_hash, parent_hash)?;
+        Command::Fetch(args) => {
+            commands::fetch(args)?;
         }
-        Command::Clone { url, dir } => {
-            commands::clone(url, dir)?;
+        Command::LsFiles { stage, paths } => {
+            commands::ls_files(stage, paths)?;
         }
     }
 
@@ -0,0 +1,520 @@
+use crate::diff::{self, FilePair, Renames, Side};
+use crate::index::{Index, IndexEntry};
+use crate::objects::{self, Format, Kind, Object, ObjectId, Tree};
+use crate::revision;
+use anyhow::Context;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::io::Cursor;
+
+mod file;
+
+pub(crate) use file::{ConflictStyle, Labels};
+
+// A file's mode and blob hash.
+pub(crate) type Version = (u32, ObjectId);
+
+type Files = BTreeMap<String, Version>;
+
+pub(crate) struct Options {
+    // What our and their side are called in conflict markers and messages
+    pub(crate) ours: String,
+    pub(crate) theirs: String,
+    pub(crate) style: ConflictStyle,
+}
+
+// A path the merge couldn't resolve, with the versions to record at stages 1 (base), 2 (ours)
+// and 3 (theirs) of the index.
+pub(crate) struct Conflict {
+    pub(crate) path: String,
+    pub(crate) stages: [Option<Version>; 3],
+}
+
+pub(crate) struct Outcome {
+    // The merged tree; files with conflicts hold the content to leave in the working tree
+    // (conflict markers and all)
+    pub(crate) tree: String,
+    pub(crate) conflicts: Vec<Conflict>,
+    // What happened to the paths that needed more than a trivial merge, by path
+    pub(crate) messages: BTreeMap<String, Vec<String>>,
+}
+
+// One side of a merge: a commit, or a merge of merge bases that only exists in memory, as the
+// tree it produced and the commits it merged.
+struct Head {
+    tree: String,
+    commits: Vec<String>,
+}
+
+impl Head {
+    fn commit(hash: &str) -> anyhow::Result<Head> {
+        Ok(Head { tree: revision::read_commit(hash)?.tree, commits: vec![hash.to_string()] })
+    }
+}
+
+// Merge the commit `theirs` into `ours`, like git's "ort" strategy: the changes both made
+// since their merge base are combined file by file, following renames. When there are several
+// merge bases, those are merged first (recursively) and the result serves as the base.
+pub(crate) fn merge_commits(ours: &str, theirs: &str, options: &Options) -> anyhow::Result<Outcome> {
+    merge_heads(&Head::commit(ours)?, &Head::commit(theirs)?, options, 0)
+}
+
+fn merge_heads(ours: &Head, theirs: &Head, options: &Options, depth: usize) -> anyhow::Result<Outcome> {
+    let mut bases = revision::merge_bases(&ours.commits, &theirs.commits)?;
+    let (base, base_label) = match bases.len() {
+        0 => (None, "empty tree".to_string()),
+        1 => (Some(Head::commit(&bases[0])?), revision::abbreviate(&bases[0], 7)?),
+        _ => {
+            // Oldest first, each merged into the result of the ones before it
+            bases.reverse();
+            let inner = Options {
+                ours: "Temporary merge branch 1".to_string(),
+                theirs: "Temporary merge branch 2".to_string(),
+                style: options.style,
+            };
+            let mut merged = Head::commit(&bases[0])?;
+            for base in &bases[1..] {
+                let outcome = merge_heads(&merged, &Head::commit(base)?, &inner, depth + 1)?;
+                merged.tree = outcome.tree;
+                merged.commits.push(base.clone());
+            }
+            (Some(merged), "merged common ancestors".to_string())
+        }
+    };
+
+    let mut merge = TreeMerge {
+        options,
+        base_label: &base_label,
+        depth,
+        result: Files::new(),
+        conflicts: Vec::new(),
+        messages: BTreeMap::new(),
+    };
+    merge.merge_trees(base.as_ref().map(|base| base.tree.as_str()), &ours.tree, &theirs.tree)?;
+    let tree = write_tree(&merge.result)?;
+    Ok(Outcome { tree, conflicts: merge.conflicts, messages: merge.messages })
+}
+
+// A path of the result and the versions (base, ours, theirs) that go into it. Renamed files
+// bring versions from other paths, which are kept for the conflict markers.
+struct Entry {
+    stages: [Option<Version>; 3],
+    paths: [String; 3],
+    // Part of a conflict between renames, so unresolved whatever the content
+    path_conflict: bool,
+}
+
+impl Entry {
+    fn new(path: &str) -> Entry {
+        Entry { stages: [None; 3], paths: [path.to_string(), path.to_string(), path.to_string()], path_conflict: false }
+    }
+}
+
+struct TreeMerge<'a> {
+    options: &'a Options,
+    base_label: &'a str,
+    // How deep in merges of merge bases this is; only the outermost merge reports anything
+    depth: usize,
+    result: Files,
+    conflicts: Vec<Conflict>,
+    messages: BTreeMap<String, Vec<String>>,
+}
+
+impl TreeMerge<'_> {
+    fn note(&mut self, path: &str, message: String) {
+        if self.depth == 0 {
+            self.messages.entry(path.to_string()).or_default().push(message);
+        }
+    }
+
+    fn label(&self, side: usize) -> &str {
+        match side {
+            0 => self.base_label,
+            1 => &self.options.ours,
+            _ => &self.options.theirs,
+        }
+    }
+
+    fn merge_trees(&mut self, base: Option<&str>, ours: &str, theirs: &str) -> anyhow::Result<()> {
+        let sides = [base.map(Tree::flatten).transpose()?.unwrap_or_default(), Tree::flatten(ours)?, Tree::flatten(theirs)?];
+        let mut renames = [BTreeMap::new(), find_renames(&sides[0], &sides[1])?, find_renames(&sides[0], &sides[2])?];
+        // A rename onto a path the other side has too is left as a deletion and an addition
+        for (side, other) in [(1, 2), (2, 1)] {
+            let kept: BTreeMap<String, String> = renames[side]
+                .iter()
+                .filter(|(old, new)| !sides[other].contains_key(*new) || renames[other].get(*old) == Some(*new))
+                .map(|(old, new)| (old.clone(), new.clone()))
+                .collect();
+            renames[side] = kept;
+        }
+
+        let mut entries: BTreeMap<String, Entry> = BTreeMap::new();
+        let mut consumed: [HashSet<String>; 3] = Default::default();
+        for (side, other) in [(1, 2), (2, 1)] {
+            for (old, new) in &renames[side] {
+                if !consumed[0].insert(old.clone()) {
+                    // Renamed on both sides, and already dealt with
+                    continue;
+                }
+                consumed[side].insert(new.clone());
+                let mut entry = Entry::new(new);
+                entry.stages[0] = sides[0].get(old).copied();
+                entry.paths[0] = old.clone();
+                entry.stages[side] = sides[side].get(new).copied();
+
+                let other_path = match renames[other].get(old) {
+                    Some(other_new) if other_new != new => {
+                        consumed[other].insert(other_new.clone());
+                        self.rename_rename(old, [new, other_new], side, &sides, &mut entries)?;
+                        continue;
+                    }
+                    Some(other_new) => other_new.clone(),
+                    None => old.clone(),
+                };
+                consumed[other].insert(other_path.clone());
+                entry.stages[other] = sides[other].get(&other_path).copied();
+                entry.paths[other] = other_path;
+                if entry.stages[other].is_none() {
+                    entry.path_conflict = true;
+                    let message = format!(
+                        "CONFLICT (rename/delete): {old} renamed to {new} in {}, but deleted in {}.",
+                        self.label(side),
+                        self.label(other)
+                    );
+                    self.note(new, message);
+                }
+                entries.insert(new.clone(), entry);
+            }
+        }
+
+        // Everything else is merged path by path
+        let paths: BTreeSet<&String> = sides.iter().flat_map(|files| files.keys()).collect();
+        for path in paths {
+            for (side, files) in sides.iter().enumerate() {
+                if consumed[side].contains(path) {
+                    continue;
+                }
+                if let Some(&version) = files.get(path) {
+                    entries.entry(path.clone()).or_insert_with(|| Entry::new(path)).stages[side] = Some(version);
+                }
+            }
+        }
+
+        for (path, entry) in &entries {
+            self.resolve(path, entry)?;
+        }
+        self.move_files_out_of_the_way(&sides, &entries);
+        Ok(())
+    }
+
+    // One file renamed to different paths on the two sides: both new paths get the merged
+    // content, and the conflict leaves the base version at the old path.
+    fn rename_rename(
+        &mut self,
+        old: &str,
+        [new, other_new]: [&String; 2],
+        side: usize,
+        sides: &[Files; 3],
+        entries: &mut BTreeMap<String, Entry>,
+    ) -> anyhow::Result<()> {
+        let (ours_path, theirs_path) = if side == 1 { (new, other_new) } else { (other_new, new) };
+        let base = sides[0][old];
+        let (ours, theirs) = (sides[1][ours_path], sides[2][theirs_path]);
+        let merged = if file_type(ours.0) == file_type(theirs.0) {
+            let paths = [old.to_string(), ours_path.clone(), theirs_path.clone()];
+            self.merge_contents(old, Some(base), ours, theirs, &paths, 1 + 2 * self.depth)?.0
+        } else {
+            ours
+        };
+
+        let mut ours_entry = Entry::new(ours_path);
+        ours_entry.stages[1] = Some(merged);
+        ours_entry.path_conflict = true;
+        entries.insert(ours_path.clone(), ours_entry);
+        let mut theirs_entry = Entry::new(theirs_path);
+        theirs_entry.stages[2] = Some(merged);
+        theirs_entry.path_conflict = true;
+        entries.insert(theirs_path.clone(), theirs_entry);
+        let mut base_entry = Entry::new(old);
+        base_entry.stages[0] = Some(base);
+        base_entry.path_conflict = true;
+        entries.insert(old.to_string(), base_entry);
+
+        let message = format!(
+            "CONFLICT (rename/rename): {old} renamed to {ours_path} in {} and to {theirs_path} in {}.",
+            self.options.ours, self.options.theirs
+        );
+        self.note(old, message);
+        Ok(())
+    }
+
+    fn resolve(&mut self, path: &str, entry: &Entry) -> anyhow::Result<()> {
+        let [base, ours, theirs] = entry.stages;
+        if !entry.path_conflict {
+            // Changed on at most one side, or the same way on both
+            let trivial = if ours == theirs || base == ours {
+                Some(theirs)
+            } else if base == theirs {
+                Some(ours)
+            } else {
+                None
+            };
+            if let Some(version) = trivial {
+                if let Some(version) = version {
+                    self.result.insert(path.to_string(), version);
+                }
+                return Ok(());
+            }
+        }
+
+        match (ours, theirs) {
+            (Some(a), Some(b)) if file_type(a.0) != file_type(b.0) => self.distinct_types(path, entry),
+            (Some(a), Some(b)) => {
+                let (merged, clean) = self.merge_contents(path, base, a, b, &entry.paths, 2 * self.depth)?;
+                self.result.insert(path.to_string(), merged);
+                if !clean {
+                    let reason = if merged.0 == 0o160000 {
+                        "submodule"
+                    } else if base.is_none() {
+                        "add/add"
+                    } else {
+                        "content"
+                    };
+                    self.note(path, format!("CONFLICT ({reason}): Merge conflict in {path}"));
+                }
+                if !clean || entry.path_conflict {
+                    self.conflict(path, entry.stages);
+                }
+            }
+            (Some(version), None) | (None, Some(version)) => {
+                let side = if ours.is_some() { 1 } else { 2 };
+                match base {
+                    // Modified on one side, deleted on the other: the modified version stays
+                    // (in merges of merge bases, the base version)
+                    Some(base) => {
+                        self.result.insert(path.to_string(), if self.depth > 0 { base } else { version });
+                        // A file renamed but not otherwise changed was never modified
+                        if !(entry.path_conflict && base.1 == version.1) {
+                            let message = format!(
+                                "CONFLICT (modify/delete): {path} deleted in {} and modified in {}.  \
+                                 Version {} of {path} left in tree.",
+                                self.label(3 - side),
+                                self.label(side),
+                                self.label(side)
+                            );
+                            self.note(path, message);
+                        }
+                    }
+                    None => {
+                        self.result.insert(path.to_string(), version);
+                    }
+                }
+                self.conflict(path, entry.stages);
+            }
+            // Deleted on both sides
+            (None, None) => self.conflict(path, entry.stages),
+        }
+        Ok(())
+    }
+
+    fn conflict(&mut self, path: &str, stages: [Option<Version>; 3]) {
+        self.conflicts.push(Conflict { path: path.to_string(), stages });
+    }
+
+    // A regular file on one side and a symlink or submodule on the other can't be merged:
+    // both are kept, the regular file (or else both) under a new name.
+    fn distinct_types(&mut self, path: &str, entry: &Entry) {
+        let [base, ours, theirs] = entry.stages;
+        let (ours, theirs) = (ours.expect("both sides exist"), theirs.expect("both sides exist"));
+        if self.depth > 0 {
+            if let Some(base) = base {
+                self.result.insert(path.to_string(), base);
+            }
+            return;
+        }
+
+        let (rename_ours, rename_theirs) = if is_regular(ours.0) {
+            (true, false)
+        } else if is_regular(theirs.0) {
+            (false, true)
+        } else {
+            (true, true)
+        };
+        let which = if rename_ours && rename_theirs { "both" } else { "one" };
+        self.note(
+            path,
+            format!(
+                "CONFLICT (distinct types): {path} had different types on each side; renamed {which} of them so each \
+                 can be recorded somewhere."
+            ),
+        );
+
+        let same_type_base = |version: Version| base.filter(|base| file_type(base.0) == file_type(version.0));
+        for (side, version, rename) in [(1, ours, rename_ours), (2, theirs, rename_theirs)] {
+            let new_path = if rename { unique_path(path, self.label(side)) } else { path.to_string() };
+            let mut stages = [same_type_base(version), None, None];
+            stages[side] = Some(version);
+            self.result.insert(new_path.clone(), version);
+            self.conflict(&new_path, stages);
+        }
+    }
+
+    // Merge one file's versions: the mode and then the content, line by line for regular
+    // files. Returns the result and whether it merged cleanly; if not, the result has the
+    // conflicts marked (for regular files) or is our version.
+    fn merge_contents(
+        &mut self,
+        path: &str,
+        base: Option<Version>,
+        ours: Version,
+        theirs: Version,
+        paths: &[String; 3],
+        extra_marker_size: usize,
+    ) -> anyhow::Result<(Version, bool)> {
+        let (base_mode, base_hash) = (base.map_or(0, |base| base.0), base.map(|base| base.1));
+        let mut clean = true;
+        let mode = if ours.0 == theirs.0 || ours.0 == base_mode {
+            theirs.0
+        } else {
+            // One made the file executable, the other not
+            clean = theirs.0 == base_mode;
+            ours.0
+        };
+
+        let hash = if ours.1 == theirs.1 || Some(ours.1) == base_hash {
+            theirs.1
+        } else if Some(theirs.1) == base_hash {
+            ours.1
+        } else if is_regular(ours.0) {
+            // A base of another type doesn't count: the two sides were both added
+            let base = base.filter(|base| file_type(base.0) == file_type(ours.0));
+            let base_data = base.map(|base| read_blob(base.1)).transpose()?.unwrap_or_default();
+            let (ours_data, theirs_data) = (read_blob(ours.1)?, read_blob(theirs.1)?);
+            let labels: Vec<String> = if paths.iter().all(|other| *other == paths[0]) {
+                (0..3).map(|side| self.label(side).to_string()).collect()
+            } else {
+                (0..3).map(|side| format!("{}:{}", self.label(side), paths[side])).collect()
+            };
+
+            let (merged, conflicts) = if [&base_data, &ours_data, &theirs_data].iter().any(|data| diff::is_binary(data)) {
+                if self.depth == 0 {
+                    eprintln!("warning: Cannot merge binary files: {path} ({} vs. {})", labels[1], labels[2]);
+                }
+                (if self.depth > 0 { base_data } else { ours_data }, 1)
+            } else {
+                let labels = Labels { base: &labels[0], ours: &labels[1], theirs: &labels[2] };
+                let marker_size = file::MARKER_SIZE + extra_marker_size;
+                file::merge(&base_data, &ours_data, &theirs_data, &labels, self.options.style, marker_size)
+            };
+            clean &= conflicts == 0;
+            self.note(path, format!("Auto-merging {path}"));
+            write_blob(merged)?
+        } else {
+            // Symlinks and submodules can't be merged; merges of merge bases keep the base
+            clean = false;
+            match base {
+                Some(base) if self.depth > 0 => return Ok((base, false)),
+                _ => ours.1,
+            }
+        };
+        Ok(((mode, hash), clean))
+    }
+
+    // A file can't stay where the result has a directory: it is moved next to it, to a name
+    // saying which side it came from. Like git, this is reported even when the file was then
+    // resolved away.
+    fn move_files_out_of_the_way(&mut self, sides: &[Files; 3], entries: &BTreeMap<String, Entry>) {
+        let has_dir = |files: &Files, path: &str| {
+            let dir = format!("{path}/");
+            files.range(dir.clone()..).next().is_some_and(|(next, _)| next.starts_with(&dir))
+        };
+        let in_the_way: Vec<&String> = entries
+            .iter()
+            .filter(|(path, entry)| {
+                (entry.stages[1].is_some() || entry.stages[2].is_some()) && has_dir(&self.result, path)
+            })
+            .map(|(path, _)| path)
+            .collect();
+        for path in in_the_way {
+            let side = if has_dir(&sides[1], path) { 2 } else { 1 };
+            let new_path = unique_path(path, self.label(side));
+            if let Some(version) = self.result.remove(path) {
+                self.result.insert(new_path.clone(), version);
+                match self.conflicts.iter_mut().find(|conflict| conflict.path == *path) {
+                    Some(conflict) => conflict.path = new_path.clone(),
+                    None => {
+                        let mut stages = [None; 3];
+                        stages[side] = Some(version);
+                        self.conflict(&new_path, stages);
+                    }
+                }
+            }
+            let message = format!(
+                "CONFLICT (file/directory): directory in the way of {path} from {}; moving it to {new_path} instead.",
+                self.label(side)
+            );
+            self.note(&new_path, message);
+        }
+    }
+}
+
+// The files `side` renamed since `base`, old path to new path. Like git's merges, this only
+// looks for renames (50% similar or more), and never of empty files.
+fn find_renames(base: &Files, side: &Files) -> anyhow::Result<BTreeMap<String, String>> {
+    // The empty blob is never paired up as a rename
+    let empty_blob = objects::id_of(Kind::Blob, &[], Format::current()?);
+    let mut pairs = Vec::new();
+    for (path, &(mode, hash)) in base {
+        if !side.contains_key(path) && hash != empty_blob {
+            pairs.push(FilePair::new(path.clone(), Some(Side::new(mode, hash)), None));
+        }
+    }
+    for (path, &(mode, hash)) in side {
+        if !base.contains_key(path) && hash != empty_blob {
+            pairs.push(FilePair::new(path.clone(), None, Some(Side::new(mode, hash))));
+        }
+    }
+    if !pairs.iter().any(|pair| pair.old.is_none()) || !pairs.iter().any(|pair| pair.new.is_none()) {
+        return Ok(BTreeMap::new());
+    }
+    pairs.sort_by(|a, b| a.path.cmp(&b.path));
+
+    let renames = Renames { score: diff::DEFAULT_RENAME_SCORE, copies: false, harder: false };
+    Ok(diff::find_renames(pairs, &renames, None)?
+        .into_iter()
+        .filter_map(|pair| Some((pair.origin?.path, pair.path)))
+        .collect())
+}
+
+// Where to put a file that can't stay at `path`: "<path>~<side>".
+fn unique_path(path: &str, side: &str) -> String {
+    format!("{path}~{}", side.replace('/', "_"))
+}
+
+fn file_type(mode: u32) -> u32 {
+    mode & 0o170000
+}
+
+fn is_regular(mode: u32) -> bool {
+    file_type(mode) == 0o100000
+}
+
+fn read_blob(hash: ObjectId) -> anyhow::Result<Vec<u8>> {
+    Object::read(&hash.to_string()).with_context(|| format!("read blob {hash}"))?.into_bytes()
+}
+
+fn write_blob(data: Vec<u8>) -> anyhow::Result<ObjectId> {
+    Object { kind: Kind::Blob, size: data.len() as u64, reader: Cursor::new(data) }
+        .write_to_objects()
+        .context("write merged blob")
+}
+
+// Write the tree objects for `files` and return the hash of the top one.
+fn write_tree(files: &Files) -> anyhow::Result<String> {
+    let format = Format::current()?;
+    let mut index = Index { version: 2, format, entries: Vec::new(), cache_tree: None, mtime: None };
+    index.entries = files
+        .iter()
+        .map(|(path, &(mode, hash))| IndexEntry::new(path.clone(), mode, hash, 0))
+        .collect();
+    Ok(index.write_tree("")?.to_string())
+}
@@ -0,0 +1,432 @@
+use crate::diff::{self, Algorithm, Edit};
+
+// Length of the conflict markers unless a merge of merges needs longer ones.
+pub(crate) const MARKER_SIZE: usize = 7;
+
+// How conflicts are written out (merge.conflictStyle).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ConflictStyle {
+    // Our and their version of the conflicting lines
+    Merge,
+    // The base version as well, after a "|||||||" marker
+    Diff3,
+    // Like diff3, but with lines both sides agree on at either end moved out of the conflict
+    ZealousDiff3,
+}
+
+impl std::str::FromStr for ConflictStyle {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<ConflictStyle> {
+        Ok(match s {
+            "merge" => ConflictStyle::Merge,
+            "diff3" => ConflictStyle::Diff3,
+            "zdiff3" => ConflictStyle::ZealousDiff3,
+            _ => anyhow::bail!("unknown conflict style '{s}'"),
+        })
+    }
+}
+
+// The names written after the conflict markers.
+pub(crate) struct Labels<'a> {
+    pub(crate) base: &'a str,
+    pub(crate) ours: &'a str,
+    pub(crate) theirs: &'a str,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Resolution {
+    Conflict,
+    Ours,
+    Theirs,
+    // A conflict that turned out to be the same change on both sides
+    Identical,
+}
+
+// A region where at least one side changed the base: `base_len` lines at `base` became
+// `ours_len` lines at `ours` on our side and `theirs_len` lines at `theirs` on theirs.
+// Positions can briefly be negative while chunks are combined, as in xdiff.
+#[derive(Debug, Clone, Copy)]
+struct Chunk {
+    resolution: Resolution,
+    base: isize,
+    base_len: isize,
+    ours: isize,
+    ours_len: isize,
+    theirs: isize,
+    theirs_len: isize,
+}
+
+impl Chunk {
+    fn ours_end(&self) -> isize {
+        self.ours + self.ours_len
+    }
+
+    fn theirs_end(&self) -> isize {
+        self.theirs + self.theirs_len
+    }
+}
+
+// Three-way merge of text, line by line, the way git's xdiff does it: the changes each side
+// made to `base` (found with the histogram diff) are combined, and where both changed the
+// same lines differently, both versions are written between conflict markers. Returns the
+// result and the number of conflicts in it.
+pub(crate) fn merge(
+    base: &[u8],
+    ours: &[u8],
+    theirs: &[u8],
+    labels: &Labels,
+    style: ConflictStyle,
+    marker_size: usize,
+) -> (Vec<u8>, usize) {
+    let base_lines = diff::split_lines(base);
+    let ours_lines = diff::split_lines(ours);
+    let theirs_lines = diff::split_lines(theirs);
+    let ours_edits = diff::diff_lines(&base_lines, &ours_lines, Algorithm::Histogram, false);
+    let theirs_edits = diff::diff_lines(&base_lines, &theirs_lines, Algorithm::Histogram, false);
+    if ours_edits.is_empty() {
+        return (theirs.to_vec(), 0);
+    }
+    if theirs_edits.is_empty() {
+        return (ours.to_vec(), 0);
+    }
+
+    let lines = Lines { base: &base_lines, ours: &ours_lines, theirs: &theirs_lines };
+    let mut chunks = lines.combine(&ours_edits, &theirs_edits);
+    match style {
+        ConflictStyle::ZealousDiff3 => lines.trim_conflicts(&mut chunks),
+        // Showing the base only makes sense for conflicts as the two sides made them
+        ConflictStyle::Diff3 => {}
+        ConflictStyle::Merge => {
+            chunks = lines.refine_conflicts(chunks);
+            simplify_non_conflicts(&mut chunks);
+        }
+    }
+
+    let conflicts = chunks
+        .iter()
+        .filter(|chunk| chunk.resolution == Resolution::Conflict)
+        .count();
+    (lines.write(&chunks, labels, style, marker_size), conflicts)
+}
+
+struct Lines<'a> {
+    base: &'a [&'a [u8]],
+    ours: &'a [&'a [u8]],
+    theirs: &'a [&'a [u8]],
+}
+
+impl Lines<'_> {
+    // Walk the edits of both sides in order: changes touching only one side are taken from
+    // that side, identical changes from ours, and overlapping ones become conflicts covering
+    // the union of what both sides changed.
+    fn combine(&self, ours: &[Edit], theirs: &[Edit]) -> Vec<Chunk> {
+        let start = |edit: &Edit| (edit.old_start as isize, edit.old_len as isize, edit.new_start as isize, edit.new_len as isize);
+        let mut chunks = Vec::new();
+        let (mut x, mut y) = (0, 0);
+        while x < ours.len() && y < theirs.len() {
+            let (b1, l1, o1, n1) = start(&ours[x]);
+            let (b2, l2, o2, n2) = start(&theirs[y]);
+            if b1 + l1 < b2 {
+                append(&mut chunks, Resolution::Ours, [b1, l1, o1, n1, o2 - b2 + b1, l1]);
+                x += 1;
+                continue;
+            }
+            if b2 + l2 < b1 {
+                append(&mut chunks, Resolution::Theirs, [b2, l2, o1 - b1 + b2, l2, o2, n2]);
+                y += 1;
+                continue;
+            }
+            if b1 != b2 || l1 != l2 || n1 != n2 || !self.same_lines(o1, o2, n1) {
+                let off = b1 - b2;
+                let ffo = off + l1 - l2;
+                let (mut i0, mut i1, mut i2) = (b1, o1, o2);
+                if off > 0 {
+                    i0 -= off;
+                    i1 -= off;
+                } else {
+                    i2 += off;
+                }
+                let mut chg0 = b1 + l1 - i0;
+                let mut chg1 = o1 + n1 - i1;
+                let mut chg2 = o2 + n2 - i2;
+                if ffo < 0 {
+                    chg0 -= ffo;
+                    chg1 -= ffo;
+                } else {
+                    chg2 += ffo;
+                }
+                append(&mut chunks, Resolution::Conflict, [i0, chg0, i1, chg1, i2, chg2]);
+            }
+            let (end1, end2) = (b1 + l1, b2 + l2);
+            if end1 >= end2 {
+                y += 1;
+            }
+            if end2 >= end1 {
+                x += 1;
+            }
+        }
+        let (base_len, ours_len, theirs_len) = (self.base.len() as isize, self.ours.len() as isize, self.theirs.len() as isize);
+        for edit in &ours[x..] {
+            let (b1, l1, o1, n1) = start(edit);
+            append(&mut chunks, Resolution::Ours, [b1, l1, o1, n1, b1 + theirs_len - base_len, l1]);
+        }
+        for edit in &theirs[y..] {
+            let (b2, l2, o2, n2) = start(edit);
+            append(&mut chunks, Resolution::Theirs, [b2, l2, b2 + ours_len - base_len, l2, o2, n2]);
+        }
+        chunks
+    }
+
+    fn same_lines(&self, ours: isize, theirs: isize, count: isize) -> bool {
+        let (ours, theirs, count) = (ours as usize, theirs as usize, count as usize);
+        self.ours[ours..ours + count] == self.theirs[theirs..theirs + count]
+    }
+
+    // Conflicting changes often have most of their lines in common: diff the two sides of
+    // each conflict and keep only the parts that differ as conflicts.
+    fn refine_conflicts(&self, chunks: Vec<Chunk>) -> Vec<Chunk> {
+        let mut refined = Vec::with_capacity(chunks.len());
+        for mut chunk in chunks {
+            if chunk.resolution != Resolution::Conflict || chunk.ours_len == 0 || chunk.theirs_len == 0 {
+                refined.push(chunk);
+                continue;
+            }
+            let ours = &self.ours[chunk.ours as usize..chunk.ours_end() as usize];
+            let theirs = &self.theirs[chunk.theirs as usize..chunk.theirs_end() as usize];
+            let edits = diff::diff_lines(ours, theirs, Algorithm::Histogram, false);
+            if edits.is_empty() {
+                chunk.resolution = Resolution::Identical;
+                refined.push(chunk);
+                continue;
+            }
+            for edit in edits {
+                refined.push(Chunk {
+                    ours: chunk.ours + edit.old_start as isize,
+                    ours_len: edit.old_len as isize,
+                    theirs: chunk.theirs + edit.new_start as isize,
+                    theirs_len: edit.new_len as isize,
+                    ..chunk
+                });
+            }
+        }
+        refined
+    }
+
+    // For zdiff3: lines at the start and end of a conflict that both sides have are moved out
+    // of it (the base can't be refined like that, so that's as far as it goes).
+    fn trim_conflicts(&self, chunks: &mut [Chunk]) {
+        for chunk in chunks.iter_mut().filter(|chunk| chunk.resolution == Resolution::Conflict) {
+            while chunk.ours_len > 0
+                && chunk.theirs_len > 0
+                && self.ours[chunk.ours as usize] == self.theirs[chunk.theirs as usize]
+            {
+                chunk.ours += 1;
+                chunk.ours_len -= 1;
+                chunk.theirs += 1;
+                chunk.theirs_len -= 1;
+            }
+            while chunk.ours_len > 0
+                && chunk.theirs_len > 0
+                && self.ours[chunk.ours_end() as usize - 1] == self.theirs[chunk.theirs_end() as usize - 1]
+            {
+                chunk.ours_len -= 1;
+                chunk.theirs_len -= 1;
+            }
+        }
+    }
+
+    // The merged file: our lines, with their changes applied and conflicts marked.
+    fn write(&self, chunks: &[Chunk], labels: &Labels, style: ConflictStyle, marker_size: usize) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut i = 0;
+        for chunk in chunks {
+            // Lines both sides changed the same way are copied from ours with what follows
+            if chunk.resolution == Resolution::Identical {
+                continue;
+            }
+            copy(&mut out, &self.ours[i..chunk.ours as usize], false, false);
+            match chunk.resolution {
+                Resolution::Ours => copy(&mut out, self.ours_lines(chunk), false, false),
+                Resolution::Theirs => copy(&mut out, self.theirs_lines(chunk), false, false),
+                _ => {
+                    let crlf = self.needs_cr(chunk);
+                    marker(&mut out, b'<', marker_size, Some(labels.ours), crlf);
+                    copy(&mut out, self.ours_lines(chunk), crlf, true);
+                    if style != ConflictStyle::Merge {
+                        marker(&mut out, b'|', marker_size, Some(labels.base), crlf);
+                        let base = chunk.base as usize;
+                        copy(&mut out, &self.base[base..base + chunk.base_len as usize], crlf, true);
+                    }
+                    marker(&mut out, b'=', marker_size, None, crlf);
+                    copy(&mut out, self.theirs_lines(chunk), crlf, true);
+                    marker(&mut out, b'>', marker_size, Some(labels.theirs), crlf);
+                }
+            }
+            i = chunk.ours_end() as usize;
+        }
+        copy(&mut out, &self.ours[i..], false, false);
+        out
+    }
+
+    fn ours_lines(&self, chunk: &Chunk) -> &[&[u8]] {
+        &self.ours[chunk.ours as usize..chunk.ours_end() as usize]
+    }
+
+    fn theirs_lines(&self, chunk: &Chunk) -> &[&[u8]] {
+        &self.theirs[chunk.theirs as usize..chunk.theirs_end() as usize]
+    }
+
+    // Markers end in CRLF if the lines around the conflict on both sides do, and so does the
+    // first line of the base.
+    fn needs_cr(&self, chunk: &Chunk) -> bool {
+        let before = |position: isize| (position - 1).max(0) as usize;
+        is_eol_crlf(self.ours, before(chunk.ours)) != Some(false)
+            && is_eol_crlf(self.theirs, before(chunk.theirs)) != Some(false)
+            && is_eol_crlf(self.base, 0) == Some(true)
+    }
+}
+
+// Add a chunk, merging it into the previous one if the two touch on either side (the result
+// is a conflict unless both were taken from the same side).
+fn append(chunks: &mut Vec<Chunk>, resolution: Resolution, [base, base_len, ours, ours_len, theirs, theirs_len]: [isize; 6]) {
+    if let Some(last) = chunks.last_mut() {
+        if ours <= last.ours_end() || theirs <= last.theirs_end() {
+            if resolution != last.resolution {
+                last.resolution = Resolution::Conflict;
+            }
+            last.base_len = base + base_len - last.base;
+            last.ours_len = ours + ours_len - last.ours;
+            last.theirs_len = theirs + theirs_len - last.theirs;
+            return;
+        }
+    }
+    chunks.push(Chunk { resolution, base, base_len, ours, ours_len, theirs, theirs_len });
+}
+
+// Conflicts separated by three lines or less read better as one.
+fn simplify_non_conflicts(chunks: &mut Vec<Chunk>) {
+    let mut i = 0;
+    while i + 1 < chunks.len() {
+        let (current, next) = (chunks[i], chunks[i + 1]);
+        if current.resolution != Resolution::Conflict
+            || next.resolution != Resolution::Conflict
+            || next.ours - current.ours_end() > 3
+        {
+            i += 1;
+            continue;
+        }
+        // Like xdiff, only the sides grow; the base keeps the first conflict's lines
+        chunks[i].ours_len = next.ours_end() - current.ours;
+        chunks[i].theirs_len = next.theirs_end() - current.theirs;
+        chunks.remove(i + 1);
+    }
+}
+
+// Whether line `i` ends in CRLF; None when that can't be told (no lines, or a single line
+// without a newline). The last line, if it has no newline, goes by the one before it.
+fn is_eol_crlf(lines: &[&[u8]], i: usize) -> Option<bool> {
+    if i + 1 < lines.len() {
+        return Some(lines[i].ends_with(b"\r\n"));
+    }
+    let line = lines.get(i)?;
+    if line.ends_with(b"\n") {
+        return Some(line.ends_with(b"\r\n"));
+    }
+    if i == 0 {
+        return None;
+    }
+    Some(lines[i - 1].ends_with(b"\r\n"))
+}
+
+// Append `lines`; with `add_newline`, make sure the last one ends in a newline so a marker can
+// follow it.
+fn copy(out: &mut Vec<u8>, lines: &[&[u8]], crlf: bool, add_newline: bool) {
+    for line in lines {
+        out.extend_from_slice(line);
+    }
+    if add_newline && lines.last().is_some_and(|line| !line.ends_with(b"\n")) {
+        if crlf {
+            out.push(b'\r');
+        }
+        out.push(b'\n');
+    }
+}
+
+fn marker(out: &mut Vec<u8>, kind: u8, size: usize, label: Option<&str>, crlf: bool) {
+    out.extend(std::iter::repeat(kind).take(size));
+    if let Some(label) = label {
+        out.push(b' ');
+        out.extend_from_slice(label.as_bytes());
+    }
+    if crlf {
+        out.push(b'\r');
+    }
+    out.push(b'\n');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STYLES: [ConflictStyle; 3] = [ConflictStyle::Merge, ConflictStyle::Diff3, ConflictStyle::ZealousDiff3];
+
+    fn merged(base: &str, ours: &str, theirs: &str, style: ConflictStyle) -> (String, usize) {
+        let labels = Labels { base: "base", ours: "ours", theirs: "theirs" };
+        let (out, conflicts) = merge(base.as_bytes(), ours.as_bytes(), theirs.as_bytes(), &labels, style, MARKER_SIZE);
+        (String::from_utf8(out).unwrap(), conflicts)
+    }
+
+    #[test]
+    fn changes_apart_or_alike_merge_cleanly() {
+        let base = "1\n2\n3\n4\n5\n6\n7\n8\n9\n";
+        for style in STYLES {
+            let ours = "1\ntwo\n3\n4\n5\n6\n7\n8\n9\n";
+            let theirs = "1\n2\n3\n4\n5\n6\n7\neight\n9\n";
+            assert_eq!(merged(base, ours, theirs, style), ("1\ntwo\n3\n4\n5\n6\n7\neight\n9\n".to_string(), 0));
+            assert_eq!(merged(base, ours, base, style), (ours.to_string(), 0));
+            assert_eq!(merged(base, base, theirs, style), (theirs.to_string(), 0));
+            // The same change on both sides, one side with more after it
+            assert_eq!(merged("a\nb\nc\n", "a\nB\nc\nd\n", "a\nB\nc\n", style), ("a\nB\nc\nd\n".to_string(), 0));
+        }
+    }
+
+    #[test]
+    fn conflicts_are_written_in_each_style_as_git_does() {
+        let (base, ours, theirs) = ("a\nb\nc\nd\ne\n", "a\nb\n1\nx\n2\ne\n", "a\nb\n1\ny\n2\ne\n");
+        let expected = [
+            "a\nb\n1\n<<<<<<< ours\nx\n=======\ny\n>>>>>>> theirs\n2\ne\n",
+            "a\nb\n<<<<<<< ours\n1\nx\n2\n||||||| base\nc\nd\n=======\n1\ny\n2\n>>>>>>> theirs\ne\n",
+            "a\nb\n1\n<<<<<<< ours\nx\n||||||| base\nc\nd\n=======\ny\n>>>>>>> theirs\n2\ne\n",
+        ];
+        for (style, expected) in STYLES.into_iter().zip(expected) {
+            assert_eq!(merged(base, ours, theirs, style), (expected.to_string(), 1), "{style:?}");
+        }
+    }
+
+    #[test]
+    fn close_conflicts_are_joined_only_in_merge_style() {
+        let (base, ours, theirs) = ("a\nb\nc\nd\ne\n", "A\nb\nc\nd\nE\n", "1\nb\nc\nd\n5\n");
+        assert_eq!(
+            merged(base, ours, theirs, ConflictStyle::Merge),
+            ("<<<<<<< ours\nA\nb\nc\nd\nE\n=======\n1\nb\nc\nd\n5\n>>>>>>> theirs\n".to_string(), 1)
+        );
+        let diff3 = "<<<<<<< ours\nA\n||||||| base\na\n=======\n1\n>>>>>>> theirs\nb\nc\nd\n\
+                     <<<<<<< ours\nE\n||||||| base\ne\n=======\n5\n>>>>>>> theirs\n";
+        assert_eq!(merged(base, ours, theirs, ConflictStyle::Diff3), (diff3.to_string(), 2));
+    }
+
+    #[test]
+    fn markers_follow_the_line_endings() {
+        let expected = "a\r\n<<<<<<< ours\r\nx\r\n||||||| base\r\nb\r\n=======\r\ny\r\n>>>>>>> theirs\r\nc\r\n";
+        assert_eq!(merged("a\r\nb\r\nc\r\n", "a\r\nx\r\nc\r\n", "a\r\ny\r\nc\r\n", ConflictStyle::Diff3), (expected.to_string(), 1));
+
+        // A conflict at the end of files without a final newline still gets one before the markers
+        let expected = "a\n<<<<<<< ours\nx\n||||||| base\nb\n=======\ny\n>>>>>>> theirs\n";
+        assert_eq!(merged("a\nb", "a\nx", "a\ny", ConflictStyle::Diff3), (expected.to_string(), 1));
+
+        let labels = Labels { base: "base", ours: "ours", theirs: "theirs" };
+        let (out, _) = merge(b"a\n", b"b\n", b"c\n", &labels, ConflictStyle::Merge, MARKER_SIZE + 2);
+        assert_eq!(out, b"<<<<<<<<< ours\nb\n=========\nc\n>>>>>>>>> theirs\n");
+    }
+}
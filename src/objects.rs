@@ -1,20 +1,33 @@
+use crate::{pack, promisor, repository};
 use anyhow::Context;
 use flate2::read::ZlibDecoder;
 use flate2::write::ZlibEncoder;
 use flate2::Compression;
-use sha1::Sha1;
 use std::ffi::CStr;
 use std::fmt;
-use std::io::{BufRead, BufReader, Write, Read};
+use std::io::{BufRead, BufReader, Cursor, Write, Read};
 use std::fs;
-use sha1::Digest;
 use std::path::Path;
+use std::str::FromStr;
 
-#[derive(Debug, PartialEq, Eq)]
+mod commit;
+mod id;
+mod signature;
+mod tag;
+mod tree;
+
+pub(crate) use commit::Commit;
+pub(crate) use id::{Format, Hasher, ObjectId};
+pub(crate) use signature::Signature;
+pub(crate) use tag::Tag;
+pub(crate) use tree::{Tree, TreeEntry};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub(crate) enum Kind {
     Blob,
     Tree,
     Commit,
+    Tag,
 }
 
 impl fmt::Display for Kind {
@@ -23,6 +36,21 @@ impl fmt::Display for Kind {
             Kind::Blob => write!(f, "blob"),
             Kind::Tree => write!(f, "tree"),
             Kind::Commit => write!(f, "commit"),
+            Kind::Tag => write!(f, "tag"),
+        }
+    }
+}
+
+impl FromStr for Kind {
+    type Err = anyhow::Error;
+
+    fn from_str(kind: &str) -> anyhow::Result<Kind> {
+        match kind {
+            "blob" => Ok(Kind::Blob),
+            "tree" => Ok(Kind::Tree),
+            "commit" => Ok(Kind::Commit),
+            "tag" => Ok(Kind::Tag),
+            _ => anyhow::bail!("what even is a '{kind}'"),
         }
     }
 }
@@ -47,13 +75,18 @@ impl Object<()> {
     }
 
     pub(crate) fn read(hash: &str) -> anyhow::Result<Object<impl BufRead>> {
+        // Reject anything that isn't a full hash before using it as a path
+        anyhow::ensure!(Format::current()?.is_hex(hash), "'{hash}' is not a valid object hash");
+
         // Build the Git object file path (based on a hash)
-        let f = std::fs::File::open(format!(
-            ".git/objects/{}/{}",
-            &hash[..2],
-            &hash[2..]
-        ))
-        .context("open in .git/objects")?;
+        let f = match std::fs::File::open(repository::git_dir().join(format!("objects/{}/{}", &hash[..2], &hash[2..]))) {
+            Ok(f) => f,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                // Not a loose object: it may still live in one of the packs
+                return Self::read_packed(hash);
+            }
+            Err(e) => return Err(e).context("open in .git/objects"),
+        };
 
         // Decompress the Git object
         let z = ZlibDecoder::new(f);
@@ -77,12 +110,7 @@ impl Object<()> {
         };
 
         // Convert object type string into an enum variant
-        let kind = match kind {
-            "blob" => Kind::Blob,
-            "tree" => Kind::Tree,
-            "commit" => Kind::Commit,
-            _ => anyhow::bail!("what even is a '{kind}'"),
-        };
+        let kind = kind.parse::<Kind>()?;
 
         // Convert size string into number
         let size = size
@@ -95,48 +123,168 @@ impl Object<()> {
         // Return the object with the kind and the reader
         Ok(Object {
             kind,
-            reader: z,
+            reader: Box::new(z) as Box<dyn BufRead>,
             size,
         })
     }
+
+    fn read_packed(hash: &str) -> anyhow::Result<Object<Box<dyn BufRead>>> {
+        let id: ObjectId = hash.parse()?;
+
+        // Packed objects are inflated (and un-deltified) into memory
+        let mut found = pack::read_object(&id)?;
+        // A partial clone fetches what it left out when it is first needed
+        if found.is_none() && promisor::fetch(&[hash.to_string()])? {
+            found = pack::read_object(&id)?;
+        }
+        let Some((kind, data)) = found else {
+            anyhow::bail!("object {hash} not found in .git/objects");
+        };
+        Ok(Object {
+            kind,
+            size: data.len() as u64,
+            reader: Box::new(Cursor::new(data)),
+        })
+    }
 }
 
 impl<R> Object<R>
 where
     R: Read,
 {
-    pub(crate) fn write(mut self, writer: impl Write) -> anyhow::Result<[u8; 20]> {
+    // Read the whole (decompressed) contents of the object into memory.
+    pub(crate) fn into_bytes(mut self) -> anyhow::Result<Vec<u8>> {
+        let mut data = Vec::with_capacity(self.size as usize);
+        self.reader
+            .read_to_end(&mut data)
+            .context("read object contents")?;
+        anyhow::ensure!(
+            data.len() as u64 == self.size,
+            "object was not the expected size (expected: {}, actual: {})",
+            self.size,
+            data.len()
+        );
+        Ok(data)
+    }
+
+    pub(crate) fn write(mut self, writer: impl Write) -> anyhow::Result<ObjectId> {
         let writer = ZlibEncoder::new(writer, Compression::default());
         let mut writer = HashWriter {
             writer,
-            hasher: Sha1::new(),
+            hasher: Format::current()?.hasher(),
         };
         write!(writer, "{} {}\0", self.kind, self.size)?;
         std::io::copy(&mut self.reader, &mut writer).context("stream file into blob")?;
         let _ = writer.writer.finish()?;
-        let hash = writer.hasher.finalize();
-        Ok(hash.into())
+        Ok(writer.hasher.finalize())
     }
-    pub(crate) fn write_to_objects(self) -> anyhow::Result<[u8; 20]> {
-        let tmp = "temporary";
+    pub(crate) fn write_to_objects(self) -> anyhow::Result<ObjectId> {
+        // Keep the temporary file inside .git so it never shows up in the working tree
+        let objects = repository::git_dir().join("objects");
+        let tmp = objects.join(format!("tmp_obj_{}", std::process::id()));
         let hash = self
-            .write(std::fs::File::create(tmp).context("construct temporary file for tree")?)
+            .write(std::fs::File::create(&tmp).context("construct temporary file for tree")?)
             .context("stream tree object into tree object file")?;
-        let hash_hex = hex::encode(hash);
-        fs::create_dir_all(format!(".git/objects/{}/", &hash_hex[..2]))
+        let hash_hex = hash.to_string();
+        fs::create_dir_all(objects.join(&hash_hex[..2]))
             .context("create subdir of .git/objects")?;
         fs::rename(
-            tmp,
-            format!(".git/objects/{}/{}", &hash_hex[..2], &hash_hex[2..]),
+            &tmp,
+            objects.join(format!("{}/{}", &hash_hex[..2], &hash_hex[2..])),
         )
         .context("move tree file into .git/objects")?;
         Ok(hash)
     }
 }
 
+// The name an object with these contents has in a repository of `format`, without storing it.
+pub(crate) fn id_of(kind: Kind, data: &[u8], format: Format) -> ObjectId {
+    let mut hasher = format.hasher();
+    hasher.update(format!("{kind} {}\0", data.len()));
+    hasher.update(data);
+    hasher.finalize()
+}
+
+// Whether the object is here, loose or packed. Unlike reading it, this never fetches it
+// from the promisor remote of a partial clone.
+pub(crate) fn exists(hash: &str) -> anyhow::Result<bool> {
+    let Some(id) = ObjectId::from_hex(hash, Format::current()?) else {
+        return Ok(false);
+    };
+    if repository::git_dir().join(format!("objects/{}/{}", &hash[..2], &hash[2..])).exists() {
+        return Ok(true);
+    }
+    Ok(pack::packs()?.iter().any(|pack| pack.index.find(&id).is_some()))
+}
+
+// Names of all loose objects, i.e. the files under `.git/objects/xx/`.
+pub(crate) fn loose_objects() -> anyhow::Result<Vec<ObjectId>> {
+    let format = Format::current()?;
+    let mut ids = Vec::new();
+    for dir in fs::read_dir(repository::git_dir().join("objects")).context("list .git/objects")? {
+        let dir = dir.context("read entry of .git/objects")?;
+        let prefix = dir.file_name();
+        let Some(prefix) = prefix.to_str().filter(|p| p.len() == 2 && hex::decode(p).is_ok()) else {
+            // "pack", "info" and anything else that is not a fan-out directory
+            continue;
+        };
+        for file in fs::read_dir(dir.path()).context("list fan-out directory of .git/objects")? {
+            let file = file.context("read entry of .git/objects fan-out directory")?;
+            let rest = file.file_name();
+            let Some(rest) = rest.to_str() else { continue };
+            if let Some(id) = ObjectId::from_hex(&format!("{prefix}{rest}"), format) {
+                ids.push(id);
+            }
+        }
+    }
+    ids.sort_unstable();
+    Ok(ids)
+}
+
+// Names of every object whose hex name starts with `prefix`, loose or packed.
+pub(crate) fn find_by_prefix(prefix: &str) -> anyhow::Result<Vec<ObjectId>> {
+    let format = Format::current()?;
+    let prefix = prefix.to_ascii_lowercase();
+    let mut ids = Vec::new();
+    if prefix.len() >= 2 {
+        // Only one fan-out directory can hold matching loose objects
+        let dir = repository::git_dir().join(format!("objects/{}", &prefix[..2]));
+        if let Ok(entries) = fs::read_dir(&dir) {
+            for entry in entries {
+                let entry = entry.with_context(|| format!("read entry of {}", dir.display()))?;
+                let name = format!("{}{}", &prefix[..2], entry.file_name().to_string_lossy());
+                if name.starts_with(&prefix) {
+                    if let Some(id) = ObjectId::from_hex(&name, format) {
+                        ids.push(id);
+                    }
+                }
+            }
+        }
+    } else {
+        ids.extend(loose_objects()?.into_iter().filter(|id| id.to_string().starts_with(&prefix)));
+    }
+    for pack in pack::packs()? {
+        ids.extend(pack.index.find_prefix(&prefix));
+    }
+    ids.sort_unstable();
+    ids.dedup();
+    Ok(ids)
+}
+
+// Names of every object in the repository, loose or packed, sorted and without duplicates.
+pub(crate) fn all_objects() -> anyhow::Result<Vec<ObjectId>> {
+    let mut ids = loose_objects()?;
+    for pack in pack::packs()? {
+        ids.extend(pack.index.ids());
+    }
+    ids.sort_unstable();
+    ids.dedup();
+    Ok(ids)
+}
+
 struct HashWriter<W> {
     writer: W,
-    hasher: Sha1,
+    hasher: Hasher,
 }
 impl<W> Write for HashWriter<W>
 where
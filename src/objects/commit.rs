@@ -0,0 +1,180 @@
+use super::Signature;
+use std::borrow::Cow;
+use std::fmt;
+
+// A commit object:
+//   tree <hash>
+//   parent <hash>      (zero or more)
+//   author <signature>
+//   committer <signature>
+//   encoding <name>    (optional)
+//   gpgsig <signature> (optional, continued on lines starting with a space)
+//
+//   <message>
+#[derive(Debug, Clone)]
+pub(crate) struct Commit {
+    pub(crate) tree: String,
+    pub(crate) parents: Vec<String>,
+    pub(crate) author: Signature,
+    pub(crate) committer: Signature,
+    // Character encoding of the message, when it isn't UTF-8
+    pub(crate) encoding: Option<String>,
+    pub(crate) gpgsig: Option<String>,
+    // Headers this parser doesn't interpret (e.g. mergetag)
+    pub(crate) extra: Vec<(String, String)>,
+    pub(crate) message: String,
+}
+
+impl Commit {
+    pub(crate) fn parse(data: &[u8]) -> anyhow::Result<Commit> {
+        let text = Commit::decode(data);
+        let (headers, message) = text.split_once("\n\n").unwrap_or((&text, ""));
+
+        // Join continuation lines (starting with a space) to the header they belong to
+        let mut fields: Vec<(String, String)> = Vec::new();
+        for line in headers.lines() {
+            if let Some(continued) = line.strip_prefix(' ') {
+                if let Some((_, value)) = fields.last_mut() {
+                    value.push('\n');
+                    value.push_str(continued);
+                }
+                continue;
+            }
+            let (key, value) = line.split_once(' ').unwrap_or((line, ""));
+            fields.push((key.to_string(), value.to_string()));
+        }
+
+        let mut tree = None;
+        let mut parents = Vec::new();
+        let mut author = None;
+        let mut committer = None;
+        // Looked up in the data, as the header is gone from a transcoded commit
+        let encoding = encoding_header(data).map(|name| String::from_utf8_lossy(name).into_owned());
+        let mut gpgsig = None;
+        let mut extra = Vec::new();
+        for (key, value) in fields {
+            match key.as_str() {
+                "tree" => tree = Some(value),
+                "parent" => parents.push(value),
+                "author" => author = Some(Signature::parse(&value)?),
+                "committer" => committer = Some(Signature::parse(&value)?),
+                "encoding" => {}
+                "gpgsig" => gpgsig = Some(value),
+                _ => extra.push((key, value)),
+            }
+        }
+
+        Ok(Commit {
+            tree: tree.ok_or_else(|| anyhow::anyhow!("commit object has no 'tree' line"))?,
+            parents,
+            author: author.ok_or_else(|| anyhow::anyhow!("commit object has no 'author' line"))?,
+            committer: committer.ok_or_else(|| anyhow::anyhow!("commit object has no 'committer' line"))?,
+            encoding,
+            gpgsig,
+            extra,
+            message: message.to_string(),
+        })
+    }
+
+    // The commit `data` as UTF-8 text. When the encoding header names another encoding, the
+    // commit (message, names and all) is transcoded from it and the header left out, which is
+    // what git shows; anything that isn't valid in the encoding is replaced.
+    pub(crate) fn decode(data: &[u8]) -> Cow<'_, str> {
+        let transcoding = encoding_header(data)
+            .and_then(encoding_rs::Encoding::for_label)
+            .filter(|&encoding| encoding != encoding_rs::UTF_8);
+        let Some(encoding) = transcoding else {
+            return String::from_utf8_lossy(data);
+        };
+        let (text, _) = encoding.decode_without_bom_handling(data);
+        let (headers, message) = text.split_once("\n\n").unwrap_or((&text, ""));
+        let headers: Vec<&str> = headers.lines().filter(|line| !line.starts_with("encoding ")).collect();
+        Cow::Owned(format!("{}\n\n{message}", headers.join("\n")))
+    }
+
+    // The first paragraph of the message with its lines joined by spaces, which is what git
+    // shows as the subject (%s, --oneline).
+    pub(crate) fn subject(&self) -> String {
+        let mut text = skip_blank_lines(&self.message);
+        let mut lines = Vec::new();
+        while !text.is_empty() {
+            let (line, rest) = split_line(text);
+            if line.trim().is_empty() {
+                break;
+            }
+            lines.push(line.trim_end());
+            text = rest;
+        }
+        lines.join(" ")
+    }
+
+    // The message after the subject paragraph (%b).
+    pub(crate) fn body(&self) -> &str {
+        let mut text = skip_blank_lines(&self.message);
+        while !text.is_empty() {
+            let (line, rest) = split_line(text);
+            if line.trim().is_empty() {
+                break;
+            }
+            text = rest;
+        }
+        skip_blank_lines(text)
+    }
+
+    // The message without leading blank lines (%B).
+    pub(crate) fn raw_body(&self) -> &str {
+        skip_blank_lines(&self.message)
+    }
+}
+
+// The value of the encoding header of the commit `data`, if it has one.
+fn encoding_header(data: &[u8]) -> Option<&[u8]> {
+    let end = data.windows(2).position(|pair| pair == b"\n\n").unwrap_or(data.len());
+    data[..end].split(|&b| b == b'\n').find_map(|line| line.strip_prefix(b"encoding "))
+}
+
+// Split off the first line of `text`, including its newline.
+fn split_line(text: &str) -> (&str, &str) {
+    match text.find('\n') {
+        Some(end) => text.split_at(end + 1),
+        None => (text, ""),
+    }
+}
+
+fn skip_blank_lines(mut text: &str) -> &str {
+    while !text.is_empty() {
+        let (line, rest) = split_line(text);
+        if !line.trim().is_empty() {
+            break;
+        }
+        text = rest;
+    }
+    text
+}
+
+// Write a multi-line header value, continuing each line after the first with a space.
+fn write_header(f: &mut fmt::Formatter<'_>, key: &str, value: &str) -> fmt::Result {
+    writeln!(f, "{key} {}", value.replace('\n', "\n "))
+}
+
+impl fmt::Display for Commit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "tree {}", self.tree)?;
+        for parent in &self.parents {
+            writeln!(f, "parent {parent}")?;
+        }
+        writeln!(f, "author {}", self.author)?;
+        writeln!(f, "committer {}", self.committer)?;
+        if let Some(encoding) = &self.encoding {
+            writeln!(f, "encoding {encoding}")?;
+        }
+        for (key, value) in &self.extra {
+            write_header(f, key, value)?;
+        }
+        if let Some(gpgsig) = &self.gpgsig {
+            write_header(f, "gpgsig", gpgsig)?;
+        }
+        writeln!(f)?;
+        write!(f, "{}", self.message)
+    }
+}
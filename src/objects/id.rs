@@ -0,0 +1,211 @@
+use crate::config::{Config, Scope};
+use crate::repository;
+use anyhow::Context;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::io::Write;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+// The hash function a repository names its objects with: SHA-1, or SHA-256 for repositories
+// made with `init --object-format=sha256` (which have extensions.objectFormat set).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum Format {
+    Sha1,
+    Sha256,
+}
+
+// The format of the repository last looked at, keyed by the directory it was found in.
+static CURRENT: Mutex<Option<(PathBuf, Format)>> = Mutex::new(None);
+
+impl Format {
+    // The format of the repository in the current directory.
+    pub(crate) fn current() -> anyhow::Result<Format> {
+        let dir = std::env::current_dir().context("get current directory")?;
+        let mut cache = CURRENT.lock().expect("object format cache poisoned");
+        if let Some((cached_dir, format)) = cache.as_ref() {
+            if *cached_dir == dir {
+                return Ok(*format);
+            }
+        }
+        // Outside of a repository (or before init has written its config) everything is SHA-1
+        let path = repository::git_dir().join("config");
+        if !path.exists() {
+            return Ok(Format::Sha1);
+        }
+        let config = Config::load_file(&path, Scope::Local)?;
+        let format = match config.get("extensions.objectformat") {
+            Some(name) => name.parse()?,
+            None => Format::Sha1,
+        };
+        *cache = Some((dir, format));
+        Ok(format)
+    }
+
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Format::Sha1 => "sha1",
+            Format::Sha256 => "sha256",
+        }
+    }
+
+    // The length of an object id in bytes.
+    pub(crate) fn len(self) -> usize {
+        match self {
+            Format::Sha1 => 20,
+            Format::Sha256 => 32,
+        }
+    }
+
+    // The length of an object id in hex digits.
+    pub(crate) fn hex_len(self) -> usize {
+        self.len() * 2
+    }
+
+    // The all-zero id, which stands for "no object" (like a ref that doesn't exist).
+    pub(crate) fn null(self) -> ObjectId {
+        ObjectId { bytes: [0; MAX_LEN], len: self.len() as u8 }
+    }
+
+    pub(crate) fn hasher(self) -> Hasher {
+        match self {
+            Format::Sha1 => Hasher::Sha1(Sha1::new()),
+            Format::Sha256 => Hasher::Sha256(Sha256::new()),
+        }
+    }
+
+    // Whether `hash` is a full hex object id of this format.
+    pub(crate) fn is_hex(self, hash: &str) -> bool {
+        hash.len() == self.hex_len() && hash.bytes().all(|b| b.is_ascii_hexdigit())
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(name: &str) -> anyhow::Result<Format> {
+        match name.to_ascii_lowercase().as_str() {
+            "sha1" => Ok(Format::Sha1),
+            "sha256" => Ok(Format::Sha256),
+            _ => anyhow::bail!("unknown hash algorithm '{name}'"),
+        }
+    }
+}
+
+// A running hash of an object (or of an index or pack file), in either format.
+#[derive(Clone)]
+pub(crate) enum Hasher {
+    Sha1(Sha1),
+    Sha256(Sha256),
+}
+
+impl Hasher {
+    pub(crate) fn update(&mut self, data: impl AsRef<[u8]>) {
+        match self {
+            Hasher::Sha1(hasher) => hasher.update(data),
+            Hasher::Sha256(hasher) => hasher.update(data),
+        }
+    }
+
+    pub(crate) fn finalize(self) -> ObjectId {
+        match self {
+            Hasher::Sha1(hasher) => ObjectId::from_bytes(&hasher.finalize()),
+            Hasher::Sha256(hasher) => ObjectId::from_bytes(&hasher.finalize()),
+        }
+        .expect("digests are object id sized")
+    }
+}
+
+impl Write for Hasher {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+const MAX_LEN: usize = 32;
+
+// The name of an object: the hash of its header and contents, 20 bytes long for SHA-1 and 32
+// for SHA-256. Ids of one format sort like their hex forms do.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub(crate) struct ObjectId {
+    // The id, padded with zeros
+    bytes: [u8; MAX_LEN],
+    len: u8,
+}
+
+impl ObjectId {
+    // The id whose raw bytes are `bytes`, if it is as long as one of either format.
+    pub(crate) fn from_bytes(bytes: &[u8]) -> Option<ObjectId> {
+        if bytes.len() != Format::Sha1.len() && bytes.len() != Format::Sha256.len() {
+            return None;
+        }
+        let mut id = ObjectId { bytes: [0; MAX_LEN], len: bytes.len() as u8 };
+        id.bytes[..bytes.len()].copy_from_slice(bytes);
+        Some(id)
+    }
+
+    // The id `hash` spells out in hex, if it is a full one of `format`.
+    pub(crate) fn from_hex(hash: &str, format: Format) -> Option<ObjectId> {
+        if !format.is_hex(hash) {
+            return None;
+        }
+        hash.parse().ok()
+    }
+
+    // The `format` id at the start of `data`, like the ones in trees, indexes and packs.
+    pub(crate) fn read(data: &[u8], format: Format) -> Option<ObjectId> {
+        data.get(..format.len()).and_then(ObjectId::from_bytes)
+    }
+
+    pub(crate) fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len as usize]
+    }
+
+    pub(crate) fn format(&self) -> Format {
+        if self.len as usize == Format::Sha1.len() {
+            Format::Sha1
+        } else {
+            Format::Sha256
+        }
+    }
+}
+
+impl fmt::Display for ObjectId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for b in self.as_bytes() {
+            write!(f, "{b:02x}")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for ObjectId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl FromStr for ObjectId {
+    type Err = anyhow::Error;
+
+    // A full hex id of either format.
+    fn from_str(hash: &str) -> anyhow::Result<ObjectId> {
+        hex::decode(hash)
+            .ok()
+            .and_then(|bytes| ObjectId::from_bytes(&bytes))
+            .ok_or_else(|| anyhow::anyhow!("'{hash}' is not a valid object hash"))
+    }
+}
@@ -0,0 +1,136 @@
+use crate::config::Config;
+use crate::date;
+use chrono::Local;
+use std::fmt;
+
+// An identity plus a point in time, as found on author, committer and tagger lines:
+//   "<name> <<email>> <seconds since epoch> <+hhmm timezone>"
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Signature {
+    pub(crate) name: String,
+    pub(crate) email: String,
+    pub(crate) time: i64,
+    // Offset from UTC in minutes
+    pub(crate) offset: i32,
+}
+
+impl Signature {
+    // Signature for the current local time.
+    pub(crate) fn now(name: String, email: String) -> Signature {
+        let now = Local::now();
+        Signature {
+            name,
+            email,
+            time: now.timestamp(),
+            offset: now.offset().local_minus_utc() / 60,
+        }
+    }
+
+    // The author of new commits: GIT_AUTHOR_NAME / GIT_AUTHOR_EMAIL, or else the author.* and
+    // user.* settings (and $EMAIL), dated GIT_AUTHOR_DATE or now.
+    pub(crate) fn author() -> anyhow::Result<Signature> {
+        Signature::from_env("author", "Author")
+    }
+
+    // The identity recorded as committer and tagger, looked up like the author's but from
+    // GIT_COMMITTER_NAME / GIT_COMMITTER_EMAIL / GIT_COMMITTER_DATE and committer.*.
+    pub(crate) fn committer() -> anyhow::Result<Signature> {
+        Signature::from_env("committer", "Committer")
+    }
+
+    // The committer for reflog entries, which git never refuses to write: without a
+    // configured identity, the login name and an empty email stand in.
+    pub(crate) fn reflog_committer() -> Signature {
+        Signature::committer().unwrap_or_else(|_| {
+            let name = std::env::var("USER").unwrap_or_else(|_| "unknown".into());
+            Signature::now(name, String::new())
+        })
+    }
+
+    fn from_env(role: &str, title: &str) -> anyhow::Result<Signature> {
+        let upper = role.to_ascii_uppercase();
+        let config = Config::load()?;
+        let lookup = |field: &str| {
+            std::env::var(format!("GIT_{upper}_{}", field.to_ascii_uppercase()))
+                .ok()
+                .or_else(|| config.get(&format!("{role}.{field}")).map(str::to_string))
+                .or_else(|| config.get(&format!("user.{field}")).map(str::to_string))
+                .filter(|value| !value.trim().is_empty())
+        };
+        let name = lookup("name");
+        let email = lookup("email").or_else(|| std::env::var("EMAIL").ok().filter(|email| !email.is_empty()));
+        let (Some(name), Some(email)) = (name, email) else {
+            anyhow::bail!(
+                "{title} identity unknown\n\n\
+                 *** Please tell me who you are.\n\n\
+                 Run\n\n  \
+                 git config --global user.email \"you@example.com\"\n  \
+                 git config --global user.name \"Your Name\"\n\n\
+                 to set your account's default identity.\n\
+                 Omit --global to set the identity only in this repository."
+            );
+        };
+
+        let mut signature = Signature::now(name, email);
+        if let Ok(date) = std::env::var(format!("GIT_{upper}_DATE")) {
+            (signature.time, signature.offset) = date::parse(&date)?;
+        }
+        Ok(signature)
+    }
+
+    pub(crate) fn parse(line: &str) -> anyhow::Result<Signature> {
+        // The name may contain anything but '<', so split on the brackets around the email
+        let (name, rest) = line
+            .split_once('<')
+            .ok_or_else(|| anyhow::anyhow!("signature has no email: '{line}'"))?;
+        let (email, rest) = rest
+            .split_once('>')
+            .ok_or_else(|| anyhow::anyhow!("signature email is not terminated: '{line}'"))?;
+
+        let mut when = rest.split_whitespace();
+        let time = match when.next() {
+            Some(time) => time
+                .parse::<i64>()
+                .map_err(|_| anyhow::anyhow!("signature has invalid time: '{line}'"))?,
+            None => 0,
+        };
+        let offset = match when.next() {
+            Some(tz) => parse_tz(tz).ok_or_else(|| anyhow::anyhow!("signature has invalid timezone: '{line}'"))?,
+            None => 0,
+        };
+
+        Ok(Signature {
+            name: name.trim_end().to_string(),
+            email: email.to_string(),
+            time,
+            offset,
+        })
+    }
+
+    // The timezone in git's "+hhmm" notation.
+    pub(crate) fn tz(&self) -> String {
+        let sign = if self.offset >= 0 { '+' } else { '-' };
+        let abs = self.offset.abs();
+        format!("{sign}{:02}{:02}", abs / 60, abs % 60)
+    }
+}
+
+fn parse_tz(tz: &str) -> Option<i32> {
+    let (sign, digits) = match tz.as_bytes().first()? {
+        b'+' => (1, &tz[1..]),
+        b'-' => (-1, &tz[1..]),
+        _ => return None,
+    };
+    if digits.len() != 4 || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let hours: i32 = digits[..2].parse().ok()?;
+    let minutes: i32 = digits[2..].parse().ok()?;
+    Some(sign * (hours * 60 + minutes))
+}
+
+impl fmt::Display for Signature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} <{}> {} {}", self.name, self.email, self.time, self.tz())
+    }
+}
@@ -0,0 +1,89 @@
+use super::{Kind, Signature};
+use std::fmt;
+
+// An annotated tag object:
+//   object <hash>
+//   type <kind>
+//   tag <name>
+//   tagger <signature>
+//
+//   <message>
+#[derive(Debug, Clone)]
+pub(crate) struct Tag {
+    pub(crate) object: String,
+    pub(crate) kind: Kind,
+    pub(crate) tag: String,
+    // Very old tags were created without a tagger line
+    pub(crate) tagger: Option<Signature>,
+    pub(crate) message: String,
+}
+
+impl Tag {
+    // Parse the tag `data`; anything that isn't valid UTF-8 (a tagger or message in another
+    // encoding) is replaced, as in commits.
+    pub(crate) fn parse(data: &[u8]) -> anyhow::Result<Tag> {
+        let text = String::from_utf8_lossy(data);
+        let (headers, message) = text.split_once("\n\n").unwrap_or((&text, ""));
+
+        let mut object = None;
+        let mut kind = None;
+        let mut tag = None;
+        let mut tagger = None;
+        for line in headers.lines() {
+            let (key, value) = line.split_once(' ').unwrap_or((line, ""));
+            match key {
+                "object" => object = Some(value.to_string()),
+                "type" => kind = Some(value.parse::<Kind>()?),
+                "tag" => tag = Some(value.to_string()),
+                "tagger" => tagger = Some(Signature::parse(value)?),
+                // Unknown headers (e.g. from newer git versions) are ignored
+                _ => {}
+            }
+        }
+
+        Ok(Tag {
+            object: object.ok_or_else(|| anyhow::anyhow!("tag object has no 'object' line"))?,
+            kind: kind.ok_or_else(|| anyhow::anyhow!("tag object has no 'type' line"))?,
+            tag: tag.ok_or_else(|| anyhow::anyhow!("tag object has no 'tag' line"))?,
+            tagger,
+            message: message.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for Tag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "object {}", self.object)?;
+        writeln!(f, "type {}", self.kind)?;
+        writeln!(f, "tag {}", self.tag)?;
+        if let Some(tagger) = &self.tagger {
+            writeln!(f, "tagger {tagger}")?;
+        }
+        writeln!(f)?;
+        write!(f, "{}", self.message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tags_in_other_encodings_are_read() {
+        let mut data = b"object 0123456789abcdef0123456789abcdef01234567\ntype commit\ntag v1\n".to_vec();
+        data.extend_from_slice(b"tagger Ren\xe9 <r@example.com> 1112904793 +0200\n\nVersion \xe9t\xe9\n");
+        let tag = Tag::parse(&data).unwrap();
+        assert_eq!(tag.object, "0123456789abcdef0123456789abcdef01234567");
+        assert_eq!(tag.kind, Kind::Commit);
+        assert_eq!(tag.tag, "v1");
+        assert_eq!(tag.tagger.unwrap().name, "Ren\u{fffd}");
+        assert_eq!(tag.message, "Version \u{fffd}t\u{fffd}\n");
+    }
+
+    #[test]
+    fn tags_without_a_tagger() {
+        let tag = Tag::parse(b"object 0123456789abcdef0123456789abcdef01234567\ntype tree\ntag old\n\nold\n").unwrap();
+        assert_eq!((tag.kind, tag.tagger.is_none(), tag.message.as_str()), (Kind::Tree, true, "old\n"));
+        assert!(Tag::parse(b"type commit\ntag v1\n\n").is_err());
+    }
+}
@@ -0,0 +1,94 @@
+use super::{Format, Kind, Object, ObjectId};
+use anyhow::Context;
+use std::collections::BTreeMap;
+use std::ffi::CStr;
+
+// One entry of a tree object: "<mode> <name>\0<raw hash>", 20 bytes of it for SHA-1 and 32
+// for SHA-256
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct TreeEntry {
+    pub(crate) mode: String,
+    pub(crate) name: String,
+    pub(crate) hash: ObjectId,
+}
+
+impl TreeEntry {
+    // The mode as a number, e.g. 0o100644.
+    pub(crate) fn mode_bits(&self) -> anyhow::Result<u32> {
+        u32::from_str_radix(&self.mode, 8).with_context(|| format!("invalid mode '{}' of {}", self.mode, self.name))
+    }
+
+    // The kind of object the entry points to, as implied by its mode.
+    pub(crate) fn kind(&self) -> Kind {
+        match self.mode.as_str() {
+            "40000" | "040000" => Kind::Tree,
+            // Submodules are recorded as gitlinks to a commit in another repository
+            "160000" => Kind::Commit,
+            _ => Kind::Blob,
+        }
+    }
+}
+
+pub(crate) struct Tree {
+    pub(crate) entries: Vec<TreeEntry>,
+}
+
+impl Tree {
+    pub(crate) fn parse(mut data: &[u8]) -> anyhow::Result<Tree> {
+        let format = Format::current()?;
+        let mut entries = Vec::new();
+        while !data.is_empty() {
+            // Everything up to the null byte is "<mode> <name>", followed by the raw hash
+            let nul = data
+                .iter()
+                .position(|&b| b == 0)
+                .ok_or_else(|| anyhow::anyhow!("tree entry is not null-terminated"))?;
+            let mode_and_name =
+                CStr::from_bytes_with_nul(&data[..=nul]).context("invalid tree entry")?;
+            let hash = ObjectId::read(&data[nul + 1..], format)
+                .ok_or_else(|| anyhow::anyhow!("tree entry hash is truncated"))?;
+            data = &data[nul + 1 + format.len()..];
+
+            let mut bits = mode_and_name.to_bytes().splitn(2, |&b| b == b' ');
+            let mode = bits.next().expect("split always yields once");
+            let name = bits
+                .next()
+                .ok_or_else(|| anyhow::anyhow!("tree entry has no file name"))?;
+
+            let name = std::str::from_utf8(name).context("name is always valid utf-8")?;
+            let mode = std::str::from_utf8(mode).context("mode is always valid utf-8")?;
+            entries.push(TreeEntry {
+                mode: mode.to_string(),
+                name: name.to_string(),
+                hash,
+            });
+        }
+        Ok(Tree { entries })
+    }
+
+    // Every file, symlink and gitlink below the tree `hash`, keyed by its full path, with its
+    // mode and object hash. The map iterates in path order, the same order as the index.
+    pub(crate) fn flatten(hash: &str) -> anyhow::Result<BTreeMap<String, (u32, ObjectId)>> {
+        let mut files = BTreeMap::new();
+        flatten_into(hash, "", &mut files)?;
+        Ok(files)
+    }
+}
+
+fn flatten_into(hash: &str, prefix: &str, files: &mut BTreeMap<String, (u32, ObjectId)>) -> anyhow::Result<()> {
+    let object = Object::read(hash).with_context(|| format!("read tree {hash}"))?;
+    anyhow::ensure!(object.kind == Kind::Tree, "{hash} is a {}, not a tree", object.kind);
+    for entry in Tree::parse(&object.into_bytes()?)?.entries {
+        let path = if prefix.is_empty() {
+            entry.name.clone()
+        } else {
+            format!("{prefix}/{}", entry.name)
+        };
+        if entry.kind() == Kind::Tree {
+            flatten_into(&entry.hash.to_string(), &path, files)?;
+        } else {
+            files.insert(path, (entry.mode_bits()?, entry.hash));
+        }
+    }
+    Ok(())
+}
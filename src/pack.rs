@@ -0,0 +1,420 @@
+use crate::objects::{Format, Kind, Object, ObjectId};
+use crate::repository;
+use anyhow::Context;
+use flate2::bufread::ZlibDecoder;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+mod collect;
+mod delta;
+mod idx;
+mod index_pack;
+mod write;
+
+pub(crate) use collect::{collect, Filter, Omit};
+pub(crate) use idx::PackIndex;
+pub(crate) use index_pack::index_pack;
+pub(crate) use write::{write_index, write_pack, DeltaOptions, PackObject};
+
+// Entry types as encoded in the pack entry header.
+const OBJ_COMMIT: u8 = 1;
+const OBJ_TREE: u8 = 2;
+const OBJ_BLOB: u8 = 3;
+const OBJ_TAG: u8 = 4;
+const OBJ_OFS_DELTA: u8 = 6;
+const OBJ_REF_DELTA: u8 = 7;
+
+// Upper bound on the bytes kept around as already-resolved delta bases.
+const BASE_CACHE_LIMIT: usize = 32 * 1024 * 1024;
+
+// Longest delta chain read, the deepest git writes; a longer one is taken to be a loop of
+// REF_DELTA entries that are each other's bases.
+const MAX_DELTA_DEPTH: usize = 4095;
+
+// A `.pack` file together with its `.idx`.
+pub(crate) struct Pack {
+    pub(crate) path: PathBuf,
+    pub(crate) index: PackIndex,
+    format: Format,
+    file: File,
+    // Delta chains tend to share bases, so keep the most recently resolved ones by offset.
+    bases: Mutex<BaseCache>,
+}
+
+#[derive(Default)]
+struct BaseCache {
+    entries: HashMap<u64, (Kind, Arc<[u8]>)>,
+    bytes: usize,
+}
+
+// What the header of a pack entry says, before inflating its data.
+enum EntryKind {
+    Base(Kind),
+    OfsDelta(u64),
+    RefDelta(ObjectId),
+}
+
+impl Pack {
+    pub(crate) fn open(idx_path: &Path) -> anyhow::Result<Pack> {
+        let format = Format::current()?;
+        let index = PackIndex::open(idx_path)?;
+        let path = idx_path.with_extension("pack");
+        let mut file = File::open(&path).with_context(|| format!("open {}", path.display()))?;
+
+        // Check the pack header: "PACK", version 2 or 3, object count.
+        let mut header = [0; 12];
+        file.read_exact(&mut header)
+            .with_context(|| format!("read header of {}", path.display()))?;
+        anyhow::ensure!(&header[..4] == b"PACK", "{} is not a pack file", path.display());
+        let version = u32::from_be_bytes(header[4..8].try_into().expect("slice is 4 bytes"));
+        anyhow::ensure!(
+            version == 2 || version == 3,
+            "{} has unsupported version {version}",
+            path.display()
+        );
+        let count = u32::from_be_bytes(header[8..12].try_into().expect("slice is 4 bytes"));
+        anyhow::ensure!(
+            count as usize == index.len(),
+            "{} holds {count} objects but its index lists {}",
+            path.display(),
+            index.len()
+        );
+
+        Ok(Pack {
+            path,
+            index,
+            format,
+            file,
+            bases: Mutex::new(BaseCache::default()),
+        })
+    }
+
+    pub(crate) fn read(&self, id: &ObjectId) -> anyhow::Result<Option<(Kind, Vec<u8>)>> {
+        let Some(offset) = self.index.find(id) else {
+            return Ok(None);
+        };
+        let (kind, data) = self
+            .read_at(offset)
+            .with_context(|| format!("read {id} from {}", self.path.display()))?;
+        Ok(Some((kind, data.to_vec())))
+    }
+
+    // Read and fully resolve the entry starting at `offset`. The chain of deltas is followed
+    // down to a base (or an entry resolved before) and then applied on the way back up.
+    pub(crate) fn read_at(&self, offset: u64) -> anyhow::Result<(Kind, Arc<[u8]>)> {
+        let mut deltas = Vec::new();
+        let mut at = offset;
+        let (kind, mut data) = loop {
+            if let Some(hit) = self.bases.lock().expect("base cache poisoned").entries.get(&at) {
+                break hit.clone();
+            }
+            anyhow::ensure!(
+                deltas.len() <= MAX_DELTA_DEPTH,
+                "delta chain at offset {offset} is longer than {MAX_DELTA_DEPTH} entries or loops"
+            );
+            let (entry, raw) = self.read_raw(at)?;
+            match entry {
+                EntryKind::Base(kind) => {
+                    let data: Arc<[u8]> = raw.into();
+                    self.remember(at, kind, &data);
+                    break (kind, data);
+                }
+                EntryKind::OfsDelta(base_offset) => {
+                    deltas.push((at, raw));
+                    at = base_offset;
+                }
+                EntryKind::RefDelta(base_id) => {
+                    deltas.push((at, raw));
+                    match self.index.find(&base_id) {
+                        Some(base_offset) => at = base_offset,
+                        None => {
+                            // The base lives elsewhere: another pack or a loose object.
+                            let mut object = Object::read(&base_id.to_string())
+                                .context("read base of REF_DELTA entry")?;
+                            let mut base = Vec::new();
+                            object.reader.read_to_end(&mut base)?;
+                            break (object.kind, base.into());
+                        }
+                    }
+                }
+            }
+        };
+        while let Some((at, delta)) = deltas.pop() {
+            data = delta::apply(&data, &delta)?.into();
+            self.remember(at, kind, &data);
+        }
+        Ok((kind, data))
+    }
+
+    // Remember resolved entries: they are likely to be the base of the next one.
+    fn remember(&self, offset: u64, kind: Kind, data: &Arc<[u8]>) {
+        let mut cache = self.bases.lock().expect("base cache poisoned");
+        if cache.bytes + data.len() > BASE_CACHE_LIMIT {
+            cache.entries.clear();
+            cache.bytes = 0;
+        }
+        cache.bytes += data.len();
+        cache.entries.insert(offset, (kind, data.clone()));
+    }
+
+    // Read the entry at `offset` without resolving deltas.
+    fn read_raw(&self, offset: u64) -> anyhow::Result<(EntryKind, Vec<u8>)> {
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(offset))?;
+        let mut reader = BufReader::new(file);
+        let (entry, size) = read_entry_header(&mut reader, offset, self.format)?;
+
+        let mut data = Vec::with_capacity(size as usize);
+        ZlibDecoder::new(reader)
+            .take(size)
+            .read_to_end(&mut data)
+            .with_context(|| format!("inflate pack entry at offset {offset}"))?;
+        anyhow::ensure!(
+            data.len() as u64 == size,
+            "pack entry at offset {offset} was not the expected size (expected: {size}, actual: {})",
+            data.len()
+        );
+        Ok((entry, data))
+    }
+}
+
+// Parse the header of the entry at `offset`: what it is and its inflated size. REF_DELTA
+// entries name their base with an id of `format`.
+fn read_entry_header(reader: &mut impl BufRead, offset: u64, format: Format) -> anyhow::Result<(EntryKind, u64)> {
+    // Type and inflated size: 3 bits of type and 4 bits of size in the first byte,
+    // then 7 more bits of size for every byte with the MSB set.
+    let mut byte = read_byte(reader)?;
+    let kind = (byte >> 4) & 0x7;
+    let mut size = (byte & 0x0f) as u64;
+    let mut shift = 4;
+    while byte & 0x80 != 0 {
+        byte = read_byte(reader)?;
+        size |= ((byte & 0x7f) as u64) << shift;
+        shift += 7;
+    }
+
+    let entry = match kind {
+        OBJ_COMMIT => EntryKind::Base(Kind::Commit),
+        OBJ_TREE => EntryKind::Base(Kind::Tree),
+        OBJ_BLOB => EntryKind::Base(Kind::Blob),
+        OBJ_TAG => EntryKind::Base(Kind::Tag),
+        OBJ_OFS_DELTA => {
+            // The base offset is relative to this entry, in a varint where every
+            // continuation adds one before shifting.
+            let mut byte = read_byte(reader)?;
+            let mut distance = (byte & 0x7f) as u64;
+            while byte & 0x80 != 0 {
+                byte = read_byte(reader)?;
+                distance = ((distance + 1) << 7) | (byte & 0x7f) as u64;
+            }
+            // A base at the entry itself would make it its own base
+            anyhow::ensure!(distance != 0, "OFS_DELTA entry at offset {offset} is its own base");
+            let base = offset
+                .checked_sub(distance)
+                .ok_or_else(|| anyhow::anyhow!("OFS_DELTA base is before the start of the pack"))?;
+            EntryKind::OfsDelta(base)
+        }
+        OBJ_REF_DELTA => {
+            let mut base = vec![0; format.len()];
+            reader.read_exact(&mut base).context("read REF_DELTA base")?;
+            EntryKind::RefDelta(ObjectId::from_bytes(&base).expect("ids are of a format's length"))
+        }
+        _ => anyhow::bail!("unknown pack entry type {kind} at offset {offset}"),
+    };
+    Ok((entry, size))
+}
+
+fn read_byte(reader: &mut impl BufRead) -> anyhow::Result<u8> {
+    let mut byte = [0];
+    reader.read_exact(&mut byte).context("read pack entry header")?;
+    Ok(byte[0])
+}
+
+// Packs that have been opened so far, keyed by the directory they were found in.
+static PACKS: Mutex<Option<(PathBuf, Vec<Arc<Pack>>)>> = Mutex::new(None);
+
+// All packs in `.git/objects/pack`.
+//
+// Opened packs are cached for the life of the process; the directory is re-listed on every
+// call so packs created or removed in the meantime (e.g. by `repack`) are picked up.
+pub(crate) fn packs() -> anyhow::Result<Vec<Arc<Pack>>> {
+    let dir = std::env::current_dir()
+        .context("get current directory")?
+        .join(repository::git_dir())
+        .join("objects/pack");
+
+    let mut idx_paths = Vec::new();
+    match std::fs::read_dir(&dir) {
+        Ok(entries) => {
+            for entry in entries {
+                let path = entry.context("read entry of .git/objects/pack")?.path();
+                if path.extension().is_some_and(|ext| ext == "idx") {
+                    idx_paths.push(path);
+                }
+            }
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e).context("list .git/objects/pack"),
+    }
+    idx_paths.sort();
+
+    let mut cache = PACKS.lock().expect("pack cache poisoned");
+    let previous = match cache.take() {
+        Some((cached_dir, packs)) if cached_dir == dir => packs,
+        _ => Vec::new(),
+    };
+    let mut packs = Vec::with_capacity(idx_paths.len());
+    for idx_path in idx_paths {
+        let pack_path = idx_path.with_extension("pack");
+        match previous.iter().find(|pack| pack.path == pack_path) {
+            Some(pack) => packs.push(pack.clone()),
+            None => packs.push(Arc::new(Pack::open(&idx_path)?)),
+        }
+    }
+    *cache = Some((dir, packs.clone()));
+    Ok(packs)
+}
+
+// Look up an object in every pack of the repository.
+pub(crate) fn read_object(id: &ObjectId) -> anyhow::Result<Option<(Kind, Vec<u8>)>> {
+    for pack in packs()? {
+        if let Some(object) = pack.read(id)? {
+            return Ok(Some(object));
+        }
+    }
+    Ok(None)
+}
+
+// Write `objects` into a new pack (and index) under `.git/objects/pack`.
+//
+// Both files are written under temporary names and moved in place pack first, so readers
+// never see an index without its pack. Returns the path of the new `.pack`.
+pub(crate) fn store(objects: &[PackObject], options: DeltaOptions) -> anyhow::Result<(PathBuf, usize)> {
+    let (tmp_pack, tmp_idx) = temporary_paths()?;
+    let file = File::create(&tmp_pack).context("create temporary pack file")?;
+    let mut written = write_pack(objects, &[], options, std::io::BufWriter::new(file))
+        .context("write pack file")?;
+    let file = File::create(&tmp_idx).context("create temporary index file")?;
+    write_index(&mut written.entries, &written.checksum, file).context("write pack index")?;
+
+    let pack_path = install(&tmp_pack, &tmp_idx, &written.checksum)?;
+    Ok((pack_path, written.deltas))
+}
+
+// Where to write a new pack and its index before they are complete.
+fn temporary_paths() -> anyhow::Result<(PathBuf, PathBuf)> {
+    let dir = &repository::git_dir().join("objects/pack");
+    std::fs::create_dir_all(dir).context("create .git/objects/pack")?;
+
+    let nonce = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or_default();
+    Ok((
+        dir.join(format!("tmp_pack_{}_{nonce}", std::process::id())),
+        dir.join(format!("tmp_idx_{}_{nonce}", std::process::id())),
+    ))
+}
+
+// Move a finished pack and index into place, returning the path of the `.pack`.
+fn install(tmp_pack: &Path, tmp_idx: &Path, checksum: &ObjectId) -> anyhow::Result<PathBuf> {
+    // Packs are named after their checksum, like `git index-pack` does.
+    let dir = &repository::git_dir().join("objects/pack");
+    let name = format!("pack-{checksum}");
+    let pack_path = dir.join(format!("{name}.pack"));
+    std::fs::rename(tmp_pack, &pack_path).context("move pack file into .git/objects/pack")?;
+    std::fs::rename(tmp_idx, dir.join(format!("{name}.idx")))
+        .context("move pack index into .git/objects/pack")?;
+    Ok(pack_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::ZlibEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+
+    // A pack entry of type `kind`: its header, the base id of a REF_DELTA and the deflated data.
+    fn entry(kind: u8, base: Option<&ObjectId>, data: &[u8]) -> Vec<u8> {
+        let mut size = data.len();
+        let mut out = vec![kind << 4 | (size & 0x0f) as u8];
+        size >>= 4;
+        while size != 0 {
+            *out.last_mut().unwrap() |= 0x80;
+            out.push((size & 0x7f) as u8);
+            size >>= 7;
+        }
+        if let Some(base) = base {
+            out.extend_from_slice(base.as_bytes());
+        }
+        let mut encoder = ZlibEncoder::new(out, Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    // A SHA-1 pack of the given objects and entries, with its index.
+    fn pack(entries: &[(ObjectId, Vec<u8>)]) -> Pack {
+        let mut data = b"PACK".to_vec();
+        data.extend_from_slice(&2u32.to_be_bytes());
+        data.extend_from_slice(&(entries.len() as u32).to_be_bytes());
+        let mut index = Vec::new();
+        for (id, entry) in entries {
+            index.push(write::IndexEntry { id: *id, offset: data.len() as u64, crc: 0 });
+            data.extend_from_slice(entry);
+        }
+        let mut idx = Vec::new();
+        write_index(&mut index, &id(0xee), &mut idx).unwrap();
+
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(&data).unwrap();
+        Pack {
+            path: PathBuf::from("test.pack"),
+            index: PackIndex::parse(idx, Format::Sha1).unwrap(),
+            format: Format::Sha1,
+            file,
+            bases: Mutex::new(BaseCache::default()),
+        }
+    }
+
+    fn id(byte: u8) -> ObjectId {
+        ObjectId::from_bytes(&[byte; 20]).unwrap()
+    }
+
+    #[test]
+    fn entries_read_are_cached() {
+        // The base, then a copy of it with "world\n" appended
+        let delta = [6, 12, 0x90, 6, 6, b'w', b'o', b'r', b'l', b'd', b'\n'];
+        let base = entry(OBJ_BLOB, None, b"hello\n");
+        let delta_offset = 12 + base.len() as u64;
+        let pack = pack(&[(id(1), base), (id(2), entry(OBJ_REF_DELTA, Some(&id(1)), &delta))]);
+
+        let (kind, data) = pack.read_at(delta_offset).unwrap();
+        assert_eq!((kind, &*data), (Kind::Blob, &b"hello\nworld\n"[..]));
+        let cached: Vec<u64> = pack.bases.lock().unwrap().entries.keys().copied().collect();
+        assert!(cached.contains(&12) && cached.contains(&delta_offset), "{cached:?}");
+        assert_eq!(pack.read(&id(1)).unwrap(), Some((Kind::Blob, b"hello\n".to_vec())));
+    }
+
+    #[test]
+    fn deltas_that_are_their_own_base_are_rejected() {
+        // Copy the 6 bytes of the base
+        let delta = [6, 6, 0x90, 6];
+        let itself = pack(&[(id(1), entry(OBJ_REF_DELTA, Some(&id(1)), &delta))]);
+        let error = itself.read_at(12).unwrap_err();
+        assert!(format!("{error:#}").contains("loops"), "{error:#}");
+
+        let each_other = pack(&[
+            (id(1), entry(OBJ_REF_DELTA, Some(&id(2)), &delta)),
+            (id(2), entry(OBJ_REF_DELTA, Some(&id(1)), &delta)),
+        ]);
+        assert!(each_other.read(&id(2)).is_err());
+
+        let mut header = &[OBJ_OFS_DELTA << 4 | 4, 0][..];
+        assert!(read_entry_header(&mut header, 12, Format::Sha1).is_err());
+    }
+}
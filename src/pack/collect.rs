@@ -0,0 +1,255 @@
+use super::PackObject;
+use crate::objects::{self, Commit, Kind, Object, ObjectId, Tag, Tree};
+use crate::{revision, shallow};
+use anyhow::Context;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::str::FromStr;
+
+// What to send so that the other end has everything reachable from some objects.
+pub(crate) struct Selection {
+    pub(crate) objects: Vec<PackObject>,
+    // Objects the other end has which some of `objects` resemble, for a thin pack
+    pub(crate) bases: Vec<PackObject>,
+}
+
+// What the other end is to be left without, beyond what it has already.
+#[derive(Debug, Default)]
+pub(crate) struct Omit {
+    // Commits the other end has without their parents (it is a shallow repository), so what
+    // their parents lead to isn't known to be there
+    pub(crate) shallow_haves: HashSet<String>,
+    // Commits to send without their parents, for the other end to make shallow
+    pub(crate) shallow: HashSet<String>,
+    // Trees and blobs to leave out, for a partial clone
+    pub(crate) filter: Option<Filter>,
+}
+
+// The objects a partial clone does without: "blob:none" (every blob), "blob:limit=<n>[kmg]"
+// (blobs of at least that size) or "tree:<depth>" (trees and blobs at least that deep, the
+// root tree being at depth 0).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Filter {
+    NoBlobs,
+    BlobLimit(u64),
+    TreeDepth(usize),
+}
+
+impl FromStr for Filter {
+    type Err = anyhow::Error;
+
+    fn from_str(spec: &str) -> anyhow::Result<Filter> {
+        let invalid = || anyhow::anyhow!("invalid filter-spec '{spec}'");
+        if spec == "blob:none" {
+            return Ok(Filter::NoBlobs);
+        }
+        if let Some(limit) = spec.strip_prefix("blob:limit=") {
+            let (digits, unit) = match limit.char_indices().last() {
+                Some((i, unit @ ('k' | 'm' | 'g' | 'K' | 'M' | 'G'))) => (&limit[..i], unit.to_ascii_lowercase()),
+                _ => (limit, ' '),
+            };
+            let shift = match unit {
+                'k' => 10,
+                'm' => 20,
+                'g' => 30,
+                _ => 0,
+            };
+            let limit: u64 = digits.parse().map_err(|_| invalid())?;
+            return limit.checked_mul(1 << shift).map(Filter::BlobLimit).ok_or_else(invalid);
+        }
+        if let Some(depth) = spec.strip_prefix("tree:") {
+            return depth.parse().map(Filter::TreeDepth).map_err(|_| invalid());
+        }
+        Err(invalid())
+    }
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Filter::NoBlobs => write!(f, "blob:none"),
+            Filter::BlobLimit(limit) => write!(f, "blob:limit={limit}"),
+            Filter::TreeDepth(depth) => write!(f, "tree:{depth}"),
+        }
+    }
+}
+
+impl Filter {
+    // Whether the filter leaves out an object of `kind` and `size` at `path`.
+    fn omits(self, kind: Kind, size: usize, path: &str) -> bool {
+        match self {
+            Filter::NoBlobs => kind == Kind::Blob,
+            Filter::BlobLimit(limit) => kind == Kind::Blob && size as u64 >= limit,
+            Filter::TreeDepth(depth) => {
+                let at = if path.is_empty() { 0 } else { path.split('/').count() };
+                at >= depth
+            }
+        }
+    }
+}
+
+// The objects reachable from `wants` but not from `haves`, which the other end is known to
+// have, short of what `omit` leaves out (though objects wanted directly are always sent).
+// Haves we don't have ourselves are ignored. With `thin`, the objects at the same path in
+// the commits at the boundary are picked as delta bases.
+pub(crate) fn collect(wants: &[String], haves: &[String], thin: bool, omit: &Omit) -> anyhow::Result<Selection> {
+    let mut known: HashSet<ObjectId> = HashSet::new();
+    let mut have_commits = Vec::new();
+    for have in haves {
+        if !objects::exists(have)? {
+            continue;
+        }
+        let object = Object::read(have)?;
+        known.insert(decode(have)?);
+        match object.kind {
+            Kind::Commit => have_commits.push(have.clone()),
+            Kind::Tag => {
+                // A tag object is known, and so is whatever it points at
+                let mut hash = Tag::parse(&object.into_bytes()?)?.object;
+                while objects::exists(&hash)? {
+                    let object = Object::read(&hash)?;
+                    known.insert(decode(&hash)?);
+                    match object.kind {
+                        Kind::Tag => hash = Tag::parse(&object.into_bytes()?)?.object,
+                        Kind::Commit => {
+                            have_commits.push(hash);
+                            break;
+                        }
+                        _ => break,
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    // The history of the haves, as far as the other end has it
+    let mut uninteresting = HashSet::new();
+    while let Some(have) = have_commits.pop() {
+        if uninteresting.insert(have.clone()) && !omit.shallow_haves.contains(&have) {
+            have_commits.extend(revision::read_commit(&have)?.parents);
+        }
+    }
+
+    // Commits to send, and the tags and other objects wanted directly
+    let mut selection = Selection { objects: Vec::new(), bases: Vec::new() };
+    let mut commits = Vec::new();
+    let mut roots = Vec::new();
+    let mut seen = HashSet::new();
+    let mut pending: Vec<String> = wants.to_vec();
+    while let Some(hash) = pending.pop() {
+        if uninteresting.contains(&hash) || known.contains(&decode(&hash)?) || !seen.insert(hash.clone()) {
+            continue;
+        }
+        let object = Object::read(&hash).with_context(|| format!("read object {hash}"))?;
+        let kind = object.kind;
+        let data = object.into_bytes()?;
+        match kind {
+            Kind::Commit => {
+                let mut commit = Commit::parse(&data).with_context(|| format!("parse commit {hash}"))?;
+                // History stops at the commits the other end is to get as shallow ones, and
+                // at our own
+                if omit.shallow.contains(&hash) || shallow::contains(&hash)? {
+                    commit.parents.clear();
+                }
+                pending.extend(commit.parents.iter().cloned());
+                commits.push(commit);
+            }
+            Kind::Tag => pending.push(Tag::parse(&data)?.object),
+            Kind::Tree | Kind::Blob => roots.push(hash.clone()),
+        }
+        selection.objects.push(PackObject { id: decode(&hash)?, kind, data });
+    }
+
+    // Everything in the trees of the commits at the boundary is there already
+    let mut boundary_paths = HashMap::new();
+    let boundary: HashSet<&String> = commits
+        .iter()
+        .flat_map(|commit| &commit.parents)
+        .filter(|parent| uninteresting.contains(*parent))
+        .collect();
+    for parent in boundary {
+        let tree = revision::read_commit(parent)?.tree;
+        known.insert(decode(&tree)?);
+        mark_known(&tree, "", &mut known, &mut boundary_paths)?;
+    }
+
+    // The least depth each tree and blob was walked at. Only tree:<depth> cares: an object it
+    // left out (or whose subtrees it did) may be met again closer to the root, where it isn't.
+    let by_depth = matches!(omit.filter, Some(Filter::TreeDepth(_)));
+    let mut walked: HashMap<ObjectId, usize> = HashMap::new();
+    let mut added = HashSet::new();
+    let mut trees: Vec<(String, String)> = commits.iter().map(|commit| (commit.tree.clone(), String::new())).collect();
+    trees.extend(roots.into_iter().map(|hash| (hash, String::new())));
+    let mut base_ids = HashSet::new();
+    // Trees are walked depth-first; the blobs they hold are added along the way
+    while let Some((hash, path)) = trees.pop() {
+        let id = decode(&hash)?;
+        let depth = if by_depth && !path.is_empty() { path.split('/').count() } else { 0 };
+        if known.contains(&id) || walked.get(&id).is_some_and(|&at| at <= depth) {
+            continue;
+        }
+        walked.insert(id, depth);
+        let object = Object::read(&hash).with_context(|| format!("read object {hash}"))?;
+        let kind = object.kind;
+        let data = object.into_bytes()?;
+        if omit.filter.is_some_and(|filter| filter.omits(kind, data.len(), &path)) && !seen.contains(&hash) {
+            continue;
+        }
+        if thin {
+            if let Some(&base) = boundary_paths.get(&path) {
+                base_ids.insert(base);
+            }
+        }
+        if kind == Kind::Tree {
+            for entry in Tree::parse(&data).with_context(|| format!("parse tree {hash}"))?.entries {
+                // Gitlinks point into other repositories
+                if entry.kind() == Kind::Commit {
+                    continue;
+                }
+                let entry_path = if path.is_empty() { entry.name } else { format!("{path}/{}", entry.name) };
+                trees.push((entry.hash.to_string(), entry_path));
+            }
+        }
+        // The tree or blob wanted directly was added with the commits already
+        if !seen.contains(&hash) && added.insert(id) {
+            selection.objects.push(PackObject { id, kind, data });
+        }
+    }
+
+    for id in base_ids {
+        let hash = id.to_string();
+        let object = Object::read(&hash).with_context(|| format!("read object {hash}"))?;
+        let kind = object.kind;
+        selection.bases.push(PackObject { id, kind, data: object.into_bytes()? });
+    }
+    Ok(selection)
+}
+
+// Record everything below the tree `hash`, found at `prefix`, as known, noting which object
+// is at which path.
+fn mark_known(
+    hash: &str,
+    prefix: &str,
+    known: &mut HashSet<ObjectId>,
+    paths: &mut HashMap<String, ObjectId>,
+) -> anyhow::Result<()> {
+    paths.insert(prefix.to_string(), decode(hash)?);
+    let object = Object::read(hash).with_context(|| format!("read tree {hash}"))?;
+    for entry in Tree::parse(&object.into_bytes()?)?.entries {
+        let path = if prefix.is_empty() { entry.name.clone() } else { format!("{prefix}/{}", entry.name) };
+        match entry.kind() {
+            Kind::Commit => {}
+            // Subtrees already seen hold nothing new
+            Kind::Tree if known.insert(entry.hash) => mark_known(&entry.hash.to_string(), &path, known, paths)?,
+            _ => {
+                known.insert(entry.hash);
+                paths.insert(path, entry.hash);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn decode(hash: &str) -> anyhow::Result<ObjectId> {
+    hash.parse()
+}
@@ -0,0 +1,290 @@
+// Delta instructions as stored in OFS_DELTA and REF_DELTA pack entries.
+//
+// A delta starts with the size of the base and of the result (both as little-endian base-128
+// varints) followed by a sequence of instructions:
+//   1xxxxxxx  copy a range of the base; the low 4 bits select which offset bytes follow and
+//             bits 4-6 which size bytes follow (a size of 0 means 0x10000)
+//   0xxxxxxx  insert the next xxxxxxx bytes of the delta verbatim (0 is reserved)
+
+fn read_size(delta: &[u8], pos: &mut usize) -> anyhow::Result<u64> {
+    let mut size = 0u64;
+    let mut shift = 0;
+    loop {
+        let Some(&byte) = delta.get(*pos) else {
+            anyhow::bail!("delta header is truncated");
+        };
+        *pos += 1;
+        size |= ((byte & 0x7f) as u64) << shift;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            return Ok(size);
+        }
+    }
+}
+
+pub(crate) fn apply(base: &[u8], delta: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut pos = 0;
+    let base_size = read_size(delta, &mut pos)?;
+    let result_size = read_size(delta, &mut pos)?;
+    anyhow::ensure!(
+        base_size == base.len() as u64,
+        "delta base has the wrong size (expected: {}, actual: {})",
+        base_size,
+        base.len()
+    );
+
+    let mut out = Vec::with_capacity(result_size as usize);
+    while pos < delta.len() {
+        let op = delta[pos];
+        pos += 1;
+        if op & 0x80 != 0 {
+            // Copy from the base: gather the optional offset and size bytes.
+            let mut offset = 0usize;
+            let mut size = 0usize;
+            for i in 0..4 {
+                if op & (1 << i) != 0 {
+                    let byte = *delta.get(pos).ok_or_else(|| anyhow::anyhow!("delta copy is truncated"))?;
+                    offset |= (byte as usize) << (8 * i);
+                    pos += 1;
+                }
+            }
+            for i in 0..3 {
+                if op & (0x10 << i) != 0 {
+                    let byte = *delta.get(pos).ok_or_else(|| anyhow::anyhow!("delta copy is truncated"))?;
+                    size |= (byte as usize) << (8 * i);
+                    pos += 1;
+                }
+            }
+            if size == 0 {
+                size = 0x10000;
+            }
+            let chunk = offset
+                .checked_add(size)
+                .and_then(|end| base.get(offset..end))
+                .ok_or_else(|| anyhow::anyhow!("delta copies outside of its base"))?;
+            out.extend_from_slice(chunk);
+        } else if op != 0 {
+            // Insert literal bytes from the delta itself.
+            let end = pos + op as usize;
+            let chunk = delta
+                .get(pos..end)
+                .ok_or_else(|| anyhow::anyhow!("delta insert is truncated"))?;
+            out.extend_from_slice(chunk);
+            pos = end;
+        } else {
+            anyhow::bail!("delta contains the reserved instruction 0");
+        }
+    }
+
+    anyhow::ensure!(
+        out.len() as u64 == result_size,
+        "delta produced the wrong size (expected: {}, actual: {})",
+        result_size,
+        out.len()
+    );
+    Ok(out)
+}
+
+// Base blocks are indexed at this granularity; shorter matches are not worth a copy.
+const BLOCK: usize = 16;
+
+// Largest copy a single instruction may describe (kept at the historical pack v2 limit).
+const MAX_COPY: usize = 0x10000;
+
+// Largest number of literal bytes a single insert instruction can carry.
+const MAX_INSERT: usize = 0x7f;
+
+// How many candidate offsets to remember per block, so repetitive input stays linear.
+const MAX_CANDIDATES: usize = 64;
+
+fn write_size(out: &mut Vec<u8>, mut size: usize) {
+    loop {
+        let byte = (size & 0x7f) as u8;
+        size >>= 7;
+        if size == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn flush_insert(out: &mut Vec<u8>, pending: &[u8]) {
+    for chunk in pending.chunks(MAX_INSERT) {
+        out.push(chunk.len() as u8);
+        out.extend_from_slice(chunk);
+    }
+}
+
+fn write_copy(out: &mut Vec<u8>, mut offset: usize, mut size: usize) {
+    while size > 0 {
+        let len = size.min(MAX_COPY);
+        let mut op = 0x80u8;
+        let mut args = Vec::with_capacity(7);
+        for i in 0..4 {
+            let byte = (offset >> (8 * i)) as u8;
+            if byte != 0 {
+                op |= 1 << i;
+                args.push(byte);
+            }
+        }
+        // A size of 0x10000 is encoded by leaving out all size bytes.
+        if len != MAX_COPY {
+            for i in 0..3 {
+                let byte = (len >> (8 * i)) as u8;
+                if byte != 0 {
+                    op |= 0x10 << i;
+                    args.push(byte);
+                }
+            }
+        }
+        out.push(op);
+        out.extend_from_slice(&args);
+        offset += len;
+        size -= len;
+    }
+}
+
+// Encode `target` as a delta against `base`.
+//
+// Returns None when the delta would not be smaller than `limit` bytes, so callers can give up
+// early on unrelated objects.
+pub(crate) fn create(base: &[u8], target: &[u8], limit: usize) -> Option<Vec<u8>> {
+    // Index every aligned block of the base.
+    let mut blocks: std::collections::HashMap<&[u8], Vec<usize>> = std::collections::HashMap::new();
+    for start in (0..base.len().saturating_sub(BLOCK - 1)).step_by(BLOCK) {
+        let candidates = blocks.entry(&base[start..start + BLOCK]).or_default();
+        if candidates.len() < MAX_CANDIDATES {
+            candidates.push(start);
+        }
+    }
+
+    let mut out = Vec::new();
+    write_size(&mut out, base.len());
+    write_size(&mut out, target.len());
+
+    let mut pending_start = 0;
+    let mut pos = 0;
+    while pos + BLOCK <= target.len() {
+        let mut best: Option<(usize, usize)> = None;
+        if let Some(candidates) = blocks.get(&target[pos..pos + BLOCK]) {
+            for &start in candidates {
+                let len = base[start..]
+                    .iter()
+                    .zip(&target[pos..])
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                if best.map_or(true, |(_, best_len)| len > best_len) {
+                    best = Some((start, len));
+                }
+            }
+        }
+
+        let Some((mut start, mut len)) = best else {
+            pos += 1;
+            continue;
+        };
+
+        // Grow the match backwards into bytes that would otherwise be inserted literally.
+        let mut back = 0;
+        while back < pos - pending_start
+            && back < start
+            && base[start - back - 1] == target[pos - back - 1]
+        {
+            back += 1;
+        }
+        start -= back;
+        len += back;
+        let match_pos = pos - back;
+
+        flush_insert(&mut out, &target[pending_start..match_pos]);
+        write_copy(&mut out, start, len);
+        pos = match_pos + len;
+        pending_start = pos;
+
+        if out.len() >= limit {
+            return None;
+        }
+    }
+    flush_insert(&mut out, &target[pending_start..]);
+
+    (out.len() < limit).then_some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Text that shares most of its lines with `base`, with some changed, dropped and added.
+    fn edited(base: &[u8]) -> Vec<u8> {
+        let mut target = b"a new first line\n".to_vec();
+        for (i, line) in base.split_inclusive(|&b| b == b'\n').enumerate() {
+            match i % 10 {
+                3 => target.extend_from_slice(b"changed\n"),
+                7 => {}
+                _ => target.extend_from_slice(line),
+            }
+        }
+        target
+    }
+
+    fn numbered_lines(count: usize) -> Vec<u8> {
+        (0..count).flat_map(|i| format!("line number {i}\n").into_bytes()).collect()
+    }
+
+    #[test]
+    fn created_deltas_apply_back_to_the_target() {
+        let base = numbered_lines(5000);
+        let target = edited(&base);
+        let delta = create(&base, &target, usize::MAX).expect("similar objects delta");
+        assert!(delta.len() < target.len() / 2, "delta of {} bytes", delta.len());
+        assert_eq!(apply(&base, &delta).unwrap(), target);
+    }
+
+    #[test]
+    fn deltas_of_unrelated_or_empty_objects_still_apply() {
+        let base = numbered_lines(100);
+        for target in [Vec::new(), b"nothing in common".to_vec(), base.repeat(3)] {
+            if let Some(delta) = create(&base, &target, usize::MAX) {
+                assert_eq!(apply(&base, &delta).unwrap(), target);
+            }
+        }
+    }
+
+    #[test]
+    fn deltas_over_the_limit_are_given_up() {
+        let base = numbered_lines(100);
+        let target = b"nothing in common with the base at all".to_vec();
+        assert_eq!(create(&base, &target, 10), None);
+    }
+
+    #[test]
+    fn copy_without_size_bytes_copies_64k() {
+        let base: Vec<u8> = (0..0x20000).map(|i| i as u8).collect();
+        // Sizes 0x20000 and 0x10000, then a copy from offset 0x100 with no size bytes
+        let delta = [0x80, 0x80, 0x08, 0x80, 0x80, 0x04, 0x82, 0x01];
+        assert_eq!(apply(&base, &delta).unwrap(), &base[0x100..0x10100]);
+    }
+
+    #[test]
+    fn malformed_deltas_are_errors() {
+        let base = b"0123456789".to_vec();
+        let cases: [&[u8]; 6] = [
+            // Base size doesn't match
+            &[5, 1, 1, b'x'],
+            // Reserved instruction
+            &[10, 1, 0],
+            // Copy past the end of the base
+            &[10, 4, 0x91, 8, 4],
+            // Insert runs past the end of the delta
+            &[10, 3, 3, b'x'],
+            // Result size doesn't match
+            &[10, 2, 1, b'x'],
+            // Header cut short
+            &[0x80],
+        ];
+        for delta in cases {
+            assert!(apply(&base, delta).is_err(), "{delta:?}");
+        }
+    }
+}
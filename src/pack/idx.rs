@@ -0,0 +1,223 @@
+use crate::objects::{Format, ObjectId};
+use anyhow::Context;
+use std::path::Path;
+
+// Header of a version 2 pack index: "\377tOc" followed by the version number.
+const IDX_MAGIC: [u8; 4] = [0xff, b't', b'O', b'c'];
+
+// Size of the fan-out table: 256 big-endian u32 counters.
+const FANOUT_LEN: usize = 256 * 4;
+
+// A parsed `.idx` (version 2) file.
+//
+// The file is kept as raw bytes and the tables are sliced on demand, so opening an index is
+// a single read no matter how many objects the pack holds.
+//
+// Layout after the 8-byte header:
+//   fan-out table  256 * u32
+//   object names   N * 20 bytes (32 for SHA-256), sorted
+//   crc32 values   N * u32
+//   offsets        N * u32 (MSB set: index into the 64-bit table)
+//   large offsets  M * u64
+//   pack checksum  20 bytes (32 for SHA-256)
+//   idx checksum   20 bytes (32 for SHA-256)
+pub(crate) struct PackIndex {
+    data: Vec<u8>,
+    count: usize,
+    format: Format,
+}
+
+impl PackIndex {
+    pub(crate) fn open(path: &Path) -> anyhow::Result<PackIndex> {
+        let data = std::fs::read(path).with_context(|| format!("read {}", path.display()))?;
+        PackIndex::parse(data, Format::current()?).with_context(|| format!("parse {}", path.display()))
+    }
+
+    pub(crate) fn parse(data: Vec<u8>, format: Format) -> anyhow::Result<PackIndex> {
+        let len = format.len();
+        anyhow::ensure!(
+            data.len() >= 8 + FANOUT_LEN + 2 * len && data[..4] == IDX_MAGIC,
+            "not a version 2 pack index"
+        );
+        let version = u32::from_be_bytes(data[4..8].try_into().expect("slice is 4 bytes"));
+        anyhow::ensure!(version == 2, "unsupported pack index version {version}");
+
+        // The last fan-out entry is the total number of objects in the pack.
+        let count = u32::from_be_bytes(
+            data[8 + FANOUT_LEN - 4..8 + FANOUT_LEN]
+                .try_into()
+                .expect("slice is 4 bytes"),
+        ) as usize;
+        let min_len = 8 + FANOUT_LEN + count * (len + 4 + 4) + 2 * len;
+        anyhow::ensure!(data.len() >= min_len, "pack index is truncated");
+
+        // Whatever is left before the checksums is the table of large offsets, which every
+        // offset pointing into it must be within
+        let offsets = 8 + FANOUT_LEN + count * (len + 4);
+        let large_count = (data.len() - min_len) / 8;
+        for small in data[offsets..offsets + count * 4].chunks_exact(4) {
+            let small = u32::from_be_bytes(small.try_into().expect("chunk is 4 bytes"));
+            anyhow::ensure!(
+                small & 0x8000_0000 == 0 || ((small & 0x7fff_ffff) as usize) < large_count,
+                "pack index has a large offset beyond its table"
+            );
+        }
+
+        Ok(PackIndex { data, count, format })
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.count
+    }
+
+    fn fanout(&self, byte: u8) -> usize {
+        let at = 8 + byte as usize * 4;
+        u32::from_be_bytes(self.data[at..at + 4].try_into().expect("slice is 4 bytes")) as usize
+    }
+
+    // The object name stored at position `i` of the sorted name table.
+    pub(crate) fn id(&self, i: usize) -> ObjectId {
+        let at = 8 + FANOUT_LEN + i * self.format.len();
+        ObjectId::read(&self.data[at..], self.format).expect("name table is in bounds")
+    }
+
+    pub(crate) fn ids(&self) -> impl Iterator<Item = ObjectId> + '_ {
+        (0..self.count).map(|i| self.id(i))
+    }
+
+    // The pack offset of the object stored at position `i`.
+    pub(crate) fn offset(&self, i: usize) -> u64 {
+        let offsets = 8 + FANOUT_LEN + self.count * (self.format.len() + 4);
+        let at = offsets + i * 4;
+        let small = u32::from_be_bytes(self.data[at..at + 4].try_into().expect("slice is 4 bytes"));
+        if small & 0x8000_0000 == 0 {
+            return small as u64;
+        }
+        // Offsets beyond 2GiB live in a separate table of 64-bit values.
+        let large = offsets + self.count * 4 + (small & 0x7fff_ffff) as usize * 8;
+        u64::from_be_bytes(self.data[large..large + 8].try_into().expect("slice is 8 bytes"))
+    }
+
+    // Positions in the sorted name table holding objects whose name starts with `first_byte`.
+    fn range(&self, first_byte: u8) -> std::ops::Range<usize> {
+        let start = if first_byte == 0 { 0 } else { self.fanout(first_byte - 1) };
+        start..self.fanout(first_byte)
+    }
+
+    pub(crate) fn position(&self, id: &ObjectId) -> Option<usize> {
+        let range = self.range(id.as_bytes()[0]);
+        let (mut lo, mut hi) = (range.start, range.end);
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            match self.id(mid).cmp(id) {
+                std::cmp::Ordering::Equal => return Some(mid),
+                std::cmp::Ordering::Less => lo = mid + 1,
+                std::cmp::Ordering::Greater => hi = mid,
+            }
+        }
+        None
+    }
+
+    // Every object whose hex name starts with `prefix`.
+    pub(crate) fn find_prefix(&self, prefix: &str) -> Vec<ObjectId> {
+        let range = match prefix.get(..2).and_then(|byte| u8::from_str_radix(byte, 16).ok()) {
+            Some(first_byte) => self.range(first_byte),
+            None => 0..self.count,
+        };
+        range
+            .map(|i| self.id(i))
+            .filter(|id| id.to_string().starts_with(prefix))
+            .collect()
+    }
+
+    pub(crate) fn find(&self, id: &ObjectId) -> Option<u64> {
+        self.position(id).map(|i| self.offset(i))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pack::write::{write_index, IndexEntry};
+
+    fn id(format: Format, first: u8, last: u8) -> ObjectId {
+        let mut bytes = vec![0x5a; format.len()];
+        bytes[0] = first;
+        bytes[format.len() - 1] = last;
+        ObjectId::from_bytes(&bytes).unwrap()
+    }
+
+    // An index of objects spread over the fan-out table, one of them past 4 GiB in the pack.
+    fn index(format: Format) -> (PackIndex, Vec<IndexEntry>) {
+        let mut entries = vec![
+            IndexEntry { id: id(format, 0xff, 1), offset: 12, crc: 0 },
+            IndexEntry { id: id(format, 0x00, 2), offset: 300, crc: 0 },
+            IndexEntry { id: id(format, 0x7f, 3), offset: 5 << 32, crc: 0 },
+            IndexEntry { id: id(format, 0x7f, 1), offset: 0x7fff_ffff, crc: 0 },
+        ];
+        let mut out = Vec::new();
+        write_index(&mut entries, &id(format, 0xee, 0xee), &mut out).unwrap();
+        (PackIndex::parse(out, format).unwrap(), entries)
+    }
+
+    #[test]
+    fn written_indexes_find_every_object() {
+        for format in [Format::Sha1, Format::Sha256] {
+            let (index, entries) = index(format);
+            assert_eq!(index.len(), 4);
+            // Sorted by id
+            let ids: Vec<ObjectId> = index.ids().collect();
+            assert_eq!(ids, entries.iter().map(|entry| entry.id).collect::<Vec<_>>());
+            assert!(ids.windows(2).all(|pair| pair[0] < pair[1]));
+            for entry in &entries {
+                assert_eq!(index.find(&entry.id), Some(entry.offset), "{format} {}", entry.id);
+            }
+            assert_eq!(index.find(&id(format, 0x7f, 2)), None);
+            assert_eq!(index.find(&id(format, 0x80, 1)), None);
+        }
+    }
+
+    #[test]
+    fn prefixes_find_all_matches() {
+        let (index, _) = index(Format::Sha1);
+        assert_eq!(index.find_prefix("7f5a").len(), 2);
+        assert_eq!(index.find_prefix(&id(Format::Sha1, 0xff, 1).to_string()[..12]).len(), 1);
+        assert!(index.find_prefix("01").is_empty());
+        assert_eq!(index.find_prefix("").len(), 4);
+    }
+
+    #[test]
+    fn damaged_indexes_are_rejected() {
+        let mut entries = vec![IndexEntry { id: id(Format::Sha1, 1, 1), offset: 12, crc: 0 }];
+        let mut out = Vec::new();
+        write_index(&mut entries, &id(Format::Sha1, 2, 2), &mut out).unwrap();
+
+        assert!(PackIndex::parse(out[..out.len() - 30].to_vec(), Format::Sha1).is_err());
+        let mut version = out.clone();
+        version[7] = 3;
+        assert!(PackIndex::parse(version, Format::Sha1).is_err());
+        let mut magic = out.clone();
+        magic[0] = b'P';
+        assert!(PackIndex::parse(magic, Format::Sha1).is_err());
+        // A SHA-1 index is too short to hold the same objects with SHA-256 ids
+        assert!(PackIndex::parse(out, Format::Sha256).is_err());
+    }
+
+    #[test]
+    fn large_offsets_must_be_in_their_table() {
+        let mut entries = vec![IndexEntry { id: id(Format::Sha1, 1, 1), offset: 5 << 32, crc: 0 }];
+        let mut out = Vec::new();
+        write_index(&mut entries, &id(Format::Sha1, 2, 2), &mut out).unwrap();
+        let small = 8 + FANOUT_LEN + 20 + 4;
+        assert_eq!(out[small..small + 4], [0x80, 0, 0, 0]);
+
+        let mut beyond = out.clone();
+        beyond[small + 3] = 1;
+        assert!(PackIndex::parse(beyond, Format::Sha1).is_err());
+        // Without the table at all
+        let mut missing = out[..small + 4].to_vec();
+        missing.extend_from_slice(&out[out.len() - 40..]);
+        assert!(PackIndex::parse(missing, Format::Sha1).is_err());
+        assert_eq!(PackIndex::parse(out, Format::Sha1).unwrap().offset(0), 5 << 32);
+    }
+}
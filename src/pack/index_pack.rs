@@ -0,0 +1,191 @@
+use super::write::{whole_entry, IndexEntry};
+use super::{delta, install, read_entry_header, temporary_paths, write_index, EntryKind};
+use crate::objects::{self, Format, Kind, Object, ObjectId};
+use anyhow::Context;
+use flate2::bufread::ZlibDecoder;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, Read};
+use std::path::PathBuf;
+use std::rc::Rc;
+
+// An entry of a pack being indexed, with its data still deltified.
+struct Entry {
+    offset: u64,
+    // Where the next entry starts
+    end: usize,
+    kind: EntryKind,
+    data: Vec<u8>,
+}
+
+// An object whose contents are known, and which deltas may be based on.
+struct Resolved {
+    // Its entry in the pack; None for a base the pack leaves out (a thin pack)
+    entry: Option<usize>,
+    id: ObjectId,
+    kind: Kind,
+    data: Rc<[u8]>,
+}
+
+// Store the pack read from `reader` (as sent by upload-pack or receive-pack) in
+// .git/objects/pack, working out the name of every object in it to build its index, like
+// `git index-pack`. Returns the path of the new pack, or None if it held no objects. Nothing
+// past the end of the pack is read, so the reader may go on with whatever follows.
+//
+// Deltas against objects that are not in the pack (a thin pack) are resolved against the
+// objects of the repository, and those bases are added to the pack so it stands on its own.
+pub(crate) fn index_pack(reader: impl BufRead) -> anyhow::Result<Option<PathBuf>> {
+    let format = Format::current()?;
+    let mut reader = Recorder { inner: reader, pack: Vec::new() };
+    let mut header = [0; 12];
+    reader.read_exact(&mut header).context("read pack header")?;
+    anyhow::ensure!(&header[..4] == b"PACK", "received data is not a pack");
+    let version = u32::from_be_bytes(header[4..8].try_into().expect("slice is 4 bytes"));
+    anyhow::ensure!(version == 2 || version == 3, "pack has unsupported version {version}");
+    let count = u32::from_be_bytes(header[8..12].try_into().expect("slice is 4 bytes")) as usize;
+
+    // Inflate every entry, noting where it starts and ends
+    let mut entries = Vec::with_capacity(count);
+    for _ in 0..count {
+        let offset = reader.pack.len() as u64;
+        let (kind, size) = read_entry_header(&mut reader, offset, format).context("pack is truncated")?;
+        let mut data = Vec::with_capacity(size as usize);
+        ZlibDecoder::new(&mut reader)
+            .read_to_end(&mut data)
+            .with_context(|| format!("inflate pack entry at offset {offset}"))?;
+        anyhow::ensure!(
+            data.len() as u64 == size,
+            "pack entry at offset {offset} was not the expected size (expected: {size}, actual: {})",
+            data.len()
+        );
+        entries.push(Entry { offset, end: reader.pack.len(), kind, data });
+    }
+    let mut pack = reader.pack;
+    let mut trailer = vec![0; format.len()];
+    reader.inner.read_exact(&mut trailer).context("pack is truncated")?;
+    let checksum = checksum_of(&pack, format);
+    anyhow::ensure!(checksum.as_bytes() == trailer, "pack is corrupt (checksum mismatch)");
+    if count == 0 {
+        return Ok(None);
+    }
+
+    // Deltas hang off their bases: resolve the whole objects first, then everything that
+    // depends on them, depth first so only one chain is held in memory at a time
+    let mut ofs_children: HashMap<u64, Vec<usize>> = HashMap::new();
+    let mut ref_children: HashMap<ObjectId, Vec<usize>> = HashMap::new();
+    let mut resolved: Vec<Resolved> = Vec::new();
+    let mut ids: Vec<Option<ObjectId>> = vec![None; count];
+    // The bases of a thin pack, which it gets completed with
+    let mut external = Vec::new();
+    for (i, entry) in entries.iter_mut().enumerate() {
+        match entry.kind {
+            EntryKind::Base(kind) => {
+                let data: Rc<[u8]> = std::mem::take(&mut entry.data).into();
+                let id = objects::id_of(kind, &data, format);
+                ids[i] = Some(id);
+                resolved.push(Resolved { entry: Some(i), id, kind, data });
+            }
+            EntryKind::OfsDelta(base) => ofs_children.entry(base).or_default().push(i),
+            EntryKind::RefDelta(base) => ref_children.entry(base).or_default().push(i),
+        }
+    }
+
+    loop {
+        while let Some(Resolved { entry, id, kind, data }) = resolved.pop() {
+            let mut children = ref_children.remove(&id).unwrap_or_default();
+            if let Some(i) = entry {
+                children.extend(ofs_children.remove(&entries[i].offset).unwrap_or_default());
+            }
+            for child in children {
+                let delta_data = std::mem::take(&mut entries[child].data);
+                let data: Rc<[u8]> = delta::apply(&data, &delta_data)
+                    .with_context(|| format!("apply delta at offset {}", entries[child].offset))?
+                    .into();
+                let id = objects::id_of(kind, &data, format);
+                ids[child] = Some(id);
+                resolved.push(Resolved { entry: Some(child), id, kind, data });
+            }
+        }
+
+        // What is left are deltas against objects the pack doesn't have
+        let Some(&base) = ref_children.keys().next() else {
+            break;
+        };
+        let object = Object::read(&base.to_string()).with_context(|| {
+            format!("pack has {} unresolved deltas", ref_children.values().map(Vec::len).sum::<usize>())
+        })?;
+        let kind = object.kind;
+        let data: Rc<[u8]> = object.into_bytes()?.into();
+        external.push((base, kind, data.clone()));
+        resolved.push(Resolved { entry: None, id: base, kind, data });
+    }
+    anyhow::ensure!(
+        ofs_children.is_empty(),
+        "pack has {} deltas with a base that isn't an entry",
+        ofs_children.values().map(Vec::len).sum::<usize>()
+    );
+
+    let mut index: Vec<IndexEntry> = entries
+        .iter()
+        .zip(ids)
+        .map(|(entry, id)| IndexEntry {
+            id: id.expect("every entry is resolved"),
+            offset: entry.offset,
+            crc: crc32fast::hash(&pack[entry.offset as usize..entry.end]),
+        })
+        .collect();
+
+    let mut checksum = checksum;
+    if !external.is_empty() {
+        for (id, kind, data) in &external {
+            let offset = pack.len();
+            pack.extend_from_slice(&whole_entry(*kind, data)?);
+            index.push(IndexEntry { id: *id, offset: offset as u64, crc: crc32fast::hash(&pack[offset..]) });
+        }
+        let count = (count + external.len()) as u32;
+        pack[8..12].copy_from_slice(&count.to_be_bytes());
+        checksum = checksum_of(&pack, format);
+    }
+    pack.extend_from_slice(checksum.as_bytes());
+
+    let (tmp_pack, tmp_idx) = temporary_paths()?;
+    std::fs::write(&tmp_pack, &pack).context("write temporary pack file")?;
+    let file = File::create(&tmp_idx).context("create temporary index file")?;
+    write_index(&mut index, &checksum, file).context("write pack index")?;
+    Ok(Some(install(&tmp_pack, &tmp_idx, &checksum)?))
+}
+
+fn checksum_of(pack: &[u8], format: Format) -> ObjectId {
+    let mut hasher = format.hasher();
+    hasher.update(pack);
+    hasher.finalize()
+}
+
+// Passes reads through, keeping a copy of every byte consumed: the pack as read so far.
+struct Recorder<R> {
+    inner: R,
+    pack: Vec<u8>,
+}
+
+impl<R: BufRead> Read for Recorder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let available = self.fill_buf()?;
+        let n = available.len().min(buf.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl<R: BufRead> BufRead for Recorder<R> {
+    fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        if let Ok(buf) = self.inner.fill_buf() {
+            self.pack.extend_from_slice(&buf[..amt]);
+        }
+        self.inner.consume(amt);
+    }
+}
@@ -0,0 +1,301 @@
+use super::{OBJ_BLOB, OBJ_COMMIT, OBJ_OFS_DELTA, OBJ_REF_DELTA, OBJ_TAG, OBJ_TREE};
+use super::delta;
+use crate::objects::{Format, Hasher, Kind, ObjectId};
+use anyhow::Context;
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use std::io::Write;
+
+// An object to be stored in a pack.
+pub(crate) struct PackObject {
+    pub(crate) id: ObjectId,
+    pub(crate) kind: Kind,
+    pub(crate) data: Vec<u8>,
+}
+
+// Where an object ended up in a written pack, as needed to build its index.
+pub(crate) struct IndexEntry {
+    pub(crate) id: ObjectId,
+    pub(crate) offset: u64,
+    pub(crate) crc: u32,
+}
+
+pub(crate) struct WrittenPack {
+    pub(crate) checksum: ObjectId,
+    pub(crate) entries: Vec<IndexEntry>,
+    pub(crate) deltas: usize,
+}
+
+// How hard to look for delta bases.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct DeltaOptions {
+    // Number of preceding objects to try as a base for each object.
+    pub(crate) window: usize,
+    // Maximum length of a delta chain.
+    pub(crate) depth: usize,
+}
+
+impl Default for DeltaOptions {
+    fn default() -> Self {
+        // Same defaults as `git repack`.
+        DeltaOptions { window: 10, depth: 50 }
+    }
+}
+
+fn type_code(kind: Kind) -> u8 {
+    match kind {
+        Kind::Commit => OBJ_COMMIT,
+        Kind::Tree => OBJ_TREE,
+        Kind::Blob => OBJ_BLOB,
+        Kind::Tag => OBJ_TAG,
+    }
+}
+
+// Keeps track of everything written so far: pack checksum, offset and per-entry CRC.
+struct PackStream<W> {
+    writer: W,
+    hasher: Hasher,
+    crc: crc32fast::Hasher,
+    offset: u64,
+}
+
+impl<W: Write> Write for PackStream<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.writer.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.crc.update(&buf[..n]);
+        self.offset += n as u64;
+        Ok(n)
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+fn entry_header(type_code: u8, size: usize) -> Vec<u8> {
+    let mut header = Vec::with_capacity(10);
+    let mut byte = (type_code << 4) | (size & 0x0f) as u8;
+    let mut rest = size >> 4;
+    while rest != 0 {
+        header.push(byte | 0x80);
+        byte = (rest & 0x7f) as u8;
+        rest >>= 7;
+    }
+    header.push(byte);
+    header
+}
+
+fn ofs_delta_distance(mut distance: u64) -> Vec<u8> {
+    // Inverse of the reader: every continuation byte implies an extra +1.
+    let mut bytes = vec![(distance & 0x7f) as u8];
+    distance >>= 7;
+    while distance != 0 {
+        distance -= 1;
+        bytes.push(0x80 | (distance & 0x7f) as u8);
+        distance >>= 7;
+    }
+    bytes.reverse();
+    bytes
+}
+
+// An entry holding `data` whole, as added to complete a thin pack.
+pub(super) fn whole_entry(kind: Kind, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut entry = entry_header(type_code(kind), data.len());
+    let mut z = ZlibEncoder::new(&mut entry, Compression::default());
+    z.write_all(data).context("compress pack entry")?;
+    z.finish().context("compress pack entry")?;
+    Ok(entry)
+}
+
+// For every object, pick the base (an index into `objects`) it is best stored as a delta of.
+// Objects from `count` on are only there to serve as bases and get no delta of their own.
+fn find_deltas(
+    objects: &[&PackObject],
+    count: usize,
+    order: &[usize],
+    options: DeltaOptions,
+) -> Vec<Option<(usize, Vec<u8>)>> {
+    let mut deltas: Vec<Option<(usize, Vec<u8>)>> = (0..objects.len()).map(|_| None).collect();
+    let mut depth = vec![0usize; objects.len()];
+
+    for (n, &i) in order.iter().enumerate() {
+        let target = objects[i];
+        // Tiny objects are cheaper to store whole.
+        if i >= count || target.data.len() < 64 || options.window == 0 {
+            continue;
+        }
+        // A delta only pays off when it is clearly smaller than the object itself.
+        let mut limit = target.data.len() / 2;
+        let mut best = None;
+        // Bases the other end has aren't written, so those further on in the order (smaller
+        // ones, typically the older versions) will do as well
+        let external = order[n + 1..].iter().filter(|&&j| j >= count).take(options.window);
+        for &j in order[n.saturating_sub(options.window)..n].iter().rev().chain(external) {
+            let base = objects[j];
+            if base.kind != target.kind || depth[j] >= options.depth {
+                continue;
+            }
+            if let Some(delta) = delta::create(&base.data, &target.data, limit) {
+                limit = delta.len();
+                best = Some((j, delta));
+            }
+        }
+        if let Some((j, delta)) = best {
+            depth[i] = depth[j] + 1;
+            deltas[i] = Some((j, delta));
+        }
+    }
+    deltas
+}
+
+// Write `objects` as a version 2 pack, deltifying them against each other as allowed by
+// `options`. Objects may also be stored as deltas of `bases`, which the receiving end already
+// has; such a "thin" pack must be completed with those bases before it can be used.
+pub(crate) fn write_pack(
+    objects: &[PackObject],
+    bases: &[PackObject],
+    options: DeltaOptions,
+    writer: impl Write,
+) -> anyhow::Result<WrittenPack> {
+    let all: Vec<&PackObject> = objects.iter().chain(bases).collect();
+    // Similar objects are most likely of the same kind and of similar size; try the larger
+    // ones first so the smaller (often older) versions become deltas of them.
+    let mut order: Vec<usize> = (0..all.len()).collect();
+    order.sort_by(|&a, &b| {
+        let (a, b) = (all[a], all[b]);
+        type_code(a.kind)
+            .cmp(&type_code(b.kind))
+            .then(b.data.len().cmp(&a.data.len()))
+    });
+    let deltas = find_deltas(&all, objects.len(), &order, options);
+
+    let mut stream = PackStream {
+        writer,
+        hasher: Format::current()?.hasher(),
+        crc: crc32fast::Hasher::new(),
+        offset: 0,
+    };
+    stream.write_all(b"PACK")?;
+    stream.write_all(&2u32.to_be_bytes())?;
+    stream.write_all(&(objects.len() as u32).to_be_bytes())?;
+
+    // OFS_DELTA entries may only point backwards, so emit every base before its deltas.
+    let mut offsets: Vec<Option<u64>> = vec![None; objects.len()];
+    let mut entries = Vec::with_capacity(objects.len());
+    let mut delta_count = 0;
+    let mut stack = Vec::new();
+    for &root in order.iter().filter(|&&i| i < objects.len()) {
+        // Walk up the delta chain to the first object that still has to be written.
+        let mut i = root;
+        loop {
+            if i >= objects.len() || offsets[i].is_some() {
+                break;
+            }
+            stack.push(i);
+            match &deltas[i] {
+                Some((base, _)) => i = *base,
+                None => break,
+            }
+        }
+
+        while let Some(i) = stack.pop() {
+            let object = &objects[i];
+            let offset = stream.offset;
+            stream.crc = crc32fast::Hasher::new();
+
+            let payload = match &deltas[i] {
+                // Bases that aren't in the pack can only be referred to by their id
+                Some((base, delta)) if *base >= objects.len() => {
+                    stream.write_all(&entry_header(OBJ_REF_DELTA, delta.len()))?;
+                    stream.write_all(all[*base].id.as_bytes())?;
+                    delta_count += 1;
+                    &delta[..]
+                }
+                Some((base, delta)) => {
+                    let base_offset = offsets[*base].expect("bases are written before their deltas");
+                    stream.write_all(&entry_header(OBJ_OFS_DELTA, delta.len()))?;
+                    stream.write_all(&ofs_delta_distance(offset - base_offset))?;
+                    delta_count += 1;
+                    &delta[..]
+                }
+                None => {
+                    stream.write_all(&entry_header(type_code(object.kind), object.data.len()))?;
+                    &object.data[..]
+                }
+            };
+            let mut z = ZlibEncoder::new(&mut stream, Compression::default());
+            z.write_all(payload).context("compress pack entry")?;
+            z.finish().context("compress pack entry")?;
+
+            offsets[i] = Some(offset);
+            entries.push(IndexEntry {
+                id: object.id,
+                offset,
+                crc: stream.crc.clone().finalize(),
+            });
+        }
+    }
+
+    let checksum = stream.hasher.clone().finalize();
+    stream.writer.write_all(checksum.as_bytes())?;
+    stream.writer.flush()?;
+
+    Ok(WrittenPack {
+        checksum,
+        entries,
+        deltas: delta_count,
+    })
+}
+
+// Write the version 2 `.idx` for a pack, returning the index checksum.
+pub(crate) fn write_index(
+    entries: &mut [IndexEntry],
+    pack_checksum: &ObjectId,
+    writer: impl Write,
+) -> anyhow::Result<ObjectId> {
+    entries.sort_unstable_by_key(|entry| entry.id);
+
+    let mut out = Vec::new();
+    out.extend_from_slice(&[0xff, b't', b'O', b'c']);
+    out.extend_from_slice(&2u32.to_be_bytes());
+
+    let mut fanout = [0u32; 256];
+    for entry in entries.iter() {
+        fanout[entry.id.as_bytes()[0] as usize] += 1;
+    }
+    let mut total = 0;
+    for count in fanout {
+        total += count;
+        out.extend_from_slice(&total.to_be_bytes());
+    }
+
+    for entry in entries.iter() {
+        out.extend_from_slice(entry.id.as_bytes());
+    }
+    for entry in entries.iter() {
+        out.extend_from_slice(&entry.crc.to_be_bytes());
+    }
+    let mut large = Vec::new();
+    for entry in entries.iter() {
+        if entry.offset < 0x8000_0000 {
+            out.extend_from_slice(&(entry.offset as u32).to_be_bytes());
+        } else {
+            out.extend_from_slice(&(0x8000_0000 | large.len() as u32).to_be_bytes());
+            large.push(entry.offset);
+        }
+    }
+    for offset in large {
+        out.extend_from_slice(&offset.to_be_bytes());
+    }
+    out.extend_from_slice(pack_checksum.as_bytes());
+
+    // The index is checksummed with the same hash function as the pack
+    let mut hasher = pack_checksum.format().hasher();
+    hasher.update(&out);
+    let checksum = hasher.finalize();
+    out.extend_from_slice(checksum.as_bytes());
+
+    let mut writer = writer;
+    writer.write_all(&out).context("write pack index")?;
+    Ok(checksum)
+}
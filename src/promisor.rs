@@ -0,0 +1,66 @@
+use crate::config::Config;
+use crate::objects;
+use crate::remote::Remote;
+use crate::transport::{Limits, UploadPack};
+use anyhow::Context;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// A partial clone leaves objects (like the blobs of old commits) on its "promisor" remote,
+// one with remote.<name>.promisor set (or named by extensions.partialClone, as older
+// versions of git have it), and fetches them from there once they are needed.
+
+// Set while objects are being fetched: whatever that needs and doesn't find is just missing.
+static FETCHING: AtomicBool = AtomicBool::new(false);
+
+// Fetch `hashes` from the promisor remote. Returns false if this isn't a partial clone.
+pub(crate) fn fetch(hashes: &[String]) -> anyhow::Result<bool> {
+    if hashes.is_empty() || FETCHING.load(Ordering::SeqCst) {
+        return Ok(false);
+    }
+    let config = Config::load()?;
+    let Some(name) = remote_name(&config)? else {
+        return Ok(false);
+    };
+    let remote = Remote::get(&config, &name)?;
+    let upload_pack = remote.upload_pack.as_deref().unwrap_or("git-upload-pack");
+
+    FETCHING.store(true, Ordering::SeqCst);
+    let result = UploadPack::connect(&remote.url, upload_pack).and_then(|mut connection| {
+        // Trees come with everything below them, but for the blobs
+        let limits = Limits { filter: Some("blob:none".to_string()), ..Limits::default() };
+        connection.fetch(hashes, &[], &limits)
+    });
+    FETCHING.store(false, Ordering::SeqCst);
+    result.with_context(|| format!("fetch missing objects from promisor remote '{name}'"))?;
+    Ok(true)
+}
+
+// Fetch those of `hashes` that aren't here in one go, rather than each when it is read.
+pub(crate) fn prefetch(hashes: impl IntoIterator<Item = String>) -> anyhow::Result<()> {
+    if remote_name(&Config::load()?)?.is_none() {
+        return Ok(());
+    }
+    let mut missing = Vec::new();
+    for hash in hashes {
+        if !objects::exists(&hash)? {
+            missing.push(hash);
+        }
+    }
+    missing.sort();
+    missing.dedup();
+    fetch(&missing)?;
+    Ok(())
+}
+
+// The remote to fetch missing objects from; None if this isn't a partial clone.
+fn remote_name(config: &Config) -> anyhow::Result<Option<String>> {
+    if let Some(name) = config.get("extensions.partialclone") {
+        return Ok(Some(name.to_string()));
+    }
+    for name in Remote::names(config) {
+        if config.get_bool(&format!("remote.{name}.promisor"))? == Some(true) {
+            return Ok(Some(name));
+        }
+    }
+    Ok(None)
+}
@@ -0,0 +1,447 @@
+use crate::lockfile::Lockfile;
+use crate::objects::{Format, Signature};
+use crate::repository;
+use anyhow::Context;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+
+// What a ref file contains: either an object hash or a pointer to another ref.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum RefValue {
+    Direct(String),
+    Symbolic(String),
+}
+
+// One line of a reflog: "<old> <new> <signature>\t<message>"
+#[derive(Debug, Clone)]
+pub(crate) struct ReflogEntry {
+    pub(crate) old: String,
+    pub(crate) new: String,
+}
+
+// Symbolic refs may point at symbolic refs; git gives up after this many hops.
+const MAX_SYMREF_DEPTH: usize = 5;
+
+// The hash used by git for "no object", e.g. as the old value of a newly created ref: all
+// zeros, as long as the repository's object ids.
+pub(crate) fn null_hash() -> anyhow::Result<String> {
+    Ok(Format::current()?.null().to_string())
+}
+
+// Whether `hash` is the null hash (of either object format).
+pub(crate) fn is_null(hash: &str) -> bool {
+    !hash.is_empty() && hash.bytes().all(|b| b == b'0')
+}
+
+// Read a single ref without following symbolic refs, from its loose file or packed-refs.
+pub(crate) fn read_raw(name: &str) -> anyhow::Result<Option<RefValue>> {
+    match fs::read_to_string(repository::git_dir().join(name)) {
+        Ok(contents) => {
+            let contents = contents.trim_end();
+            return Ok(Some(match contents.strip_prefix("ref: ") {
+                Some(target) => RefValue::Symbolic(target.to_string()),
+                // FETCH_HEAD carries extra information after the hash of its first line
+                None => RefValue::Direct(
+                    contents.split_whitespace().next().unwrap_or_default().to_string(),
+                ),
+            }));
+        }
+        // A directory (e.g. "refs/heads" itself) is not a ref either
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound || repository::git_dir().join(name).is_dir() => {}
+        Err(e) => return Err(e).with_context(|| format!("read ref {name}")),
+    }
+    Ok(packed_refs()?
+        .into_iter()
+        .find(|(packed, _)| packed == name)
+        .map(|(_, hash)| RefValue::Direct(hash)))
+}
+
+// Follow a ref (through any symbolic refs) down to the hash it points at.
+pub(crate) fn resolve(name: &str) -> anyhow::Result<Option<String>> {
+    let mut name = name.to_string();
+    for _ in 0..MAX_SYMREF_DEPTH {
+        match read_raw(&name)? {
+            Some(RefValue::Direct(hash)) => return Ok(Some(hash)),
+            Some(RefValue::Symbolic(target)) => name = target,
+            None => return Ok(None),
+        }
+    }
+    anyhow::bail!("symbolic ref {name} is nested too deeply")
+}
+
+// The ref a symbolic ref (like HEAD) ultimately points at, whether or not it exists yet.
+// Returns None when HEAD is detached.
+pub(crate) fn symbolic_target(name: &str) -> anyhow::Result<Option<String>> {
+    let mut name = name.to_string();
+    let mut target = None;
+    for _ in 0..MAX_SYMREF_DEPTH {
+        match read_raw(&name)? {
+            Some(RefValue::Symbolic(next)) => {
+                target = Some(next.clone());
+                name = next;
+            }
+            _ => return Ok(target),
+        }
+    }
+    anyhow::bail!("symbolic ref {name} is nested too deeply")
+}
+
+// Every ref recorded in .git/packed-refs, as (name, hash) pairs.
+pub(crate) fn packed_refs() -> anyhow::Result<Vec<(String, String)>> {
+    let packed = match fs::read_to_string(repository::git_dir().join("packed-refs")) {
+        Ok(packed) => packed,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).context("read .git/packed-refs"),
+    };
+    Ok(packed
+        .lines()
+        // Skip the header comment and the "^<hash>" peeled lines
+        .filter(|line| !line.starts_with('#') && !line.starts_with('^'))
+        .filter_map(|line| line.split_once(' '))
+        .map(|(hash, name)| (name.to_string(), hash.to_string()))
+        .collect())
+}
+
+fn loose_refs(dir: &Path, prefix: &str, refs: &mut Vec<(String, String)>) -> anyhow::Result<()> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e).with_context(|| format!("list {}", dir.display())),
+    };
+    for entry in entries {
+        let entry = entry.with_context(|| format!("read entry of {}", dir.display()))?;
+        let name = format!("{prefix}{}", entry.file_name().to_string_lossy());
+        // The lockfile of an update in progress (or one that crashed) isn't a ref
+        if name.ends_with(".lock") {
+            continue;
+        }
+        if entry.file_type()?.is_dir() {
+            loose_refs(&entry.path(), &format!("{name}/"), refs)?;
+        } else if let Some(hash) = resolve(&name)? {
+            refs.push((name, hash));
+        }
+    }
+    Ok(())
+}
+
+// All refs under `prefix` (e.g. "refs/tags/"), loose and packed, sorted by name.
+pub(crate) fn list(prefix: &str) -> anyhow::Result<Vec<(String, String)>> {
+    let mut refs = Vec::new();
+    loose_refs(&repository::git_dir().join(prefix), prefix, &mut refs)?;
+    // Loose refs take precedence over packed ones with the same name
+    for (name, hash) in packed_refs()? {
+        if name.starts_with(prefix) && !refs.iter().any(|(loose, _)| *loose == name) {
+            refs.push((name, hash));
+        }
+    }
+    refs.sort();
+    Ok(refs)
+}
+
+// Expand a short name the way git does ("main" -> "refs/heads/main"), returning the first
+// full ref name that exists.
+pub(crate) fn dwim(name: &str) -> anyhow::Result<Option<String>> {
+    let candidates = [
+        name.to_string(),
+        format!("refs/{name}"),
+        format!("refs/tags/{name}"),
+        format!("refs/heads/{name}"),
+        format!("refs/remotes/{name}"),
+        format!("refs/remotes/{name}/HEAD"),
+    ];
+    for candidate in candidates {
+        // Only HEAD-like names (HEAD, FETCH_HEAD, ...) are looked up outside of refs/
+        if !candidate.starts_with("refs/") && !candidate.bytes().all(|b| b.is_ascii_uppercase() || b == b'_') {
+            continue;
+        }
+        if resolve(&candidate)?.is_some() {
+            return Ok(Some(candidate));
+        }
+    }
+    Ok(None)
+}
+
+// The entries of a ref's reflog, oldest first.
+pub(crate) fn reflog(name: &str) -> anyhow::Result<Vec<ReflogEntry>> {
+    let log = match fs::read_to_string(repository::git_dir().join("logs").join(name)) {
+        Ok(log) => log,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).with_context(|| format!("read reflog of {name}")),
+    };
+    Ok(log
+        .lines()
+        .filter_map(|line| {
+            let mut parts = line.splitn(3, ' ');
+            Some(ReflogEntry {
+                old: parts.next()?.to_string(),
+                new: parts.next()?.to_string(),
+            })
+        })
+        .collect())
+}
+
+// Refuse names git itself would reject (see git-check-ref-format).
+pub(crate) fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('-')
+        && !name.ends_with('/')
+        && !name.ends_with('.')
+        && !name.contains("..")
+        && !name.contains("@{")
+        && !name.contains("//")
+        && name != "@"
+        && !name.split('/').any(|part| part.starts_with('.') || part.ends_with(".lock"))
+        && !name
+            .chars()
+            .any(|c| c.is_ascii_control() || " ~^:?*[\\".contains(c))
+}
+
+// Map `name` from the source side of a refspec like "+refs/heads/*:refs/remotes/origin/*"
+// to its destination side, if the refspec covers it.
+pub(crate) fn map_refspec(refspec: &str, name: &str) -> Option<String> {
+    let (src, dst) = refspec.trim_start_matches('+').split_once(':')?;
+    match (src.split_once('*'), dst.split_once('*')) {
+        (Some((prefix, suffix)), Some((dst_prefix, dst_suffix))) => {
+            let matched = name.strip_prefix(prefix)?.strip_suffix(suffix)?;
+            Some(format!("{dst_prefix}{matched}{dst_suffix}"))
+        }
+        (None, None) if src == name => Some(dst.to_string()),
+        _ => None,
+    }
+}
+
+// Point `name` at `new`, following symbolic refs (so updating HEAD moves the current branch).
+// With `expected` set, the update only happens if the ref currently has that value, where
+// the null hash means "must not exist yet". The change is recorded in the reflog with `message`.
+pub(crate) fn update(name: &str, new: &str, expected: Option<&str>, message: &str) -> anyhow::Result<()> {
+    let target = symbolic_target(name)?.unwrap_or_else(|| name.to_string());
+    write_ref(&target, new, expected, message)
+}
+
+// Like `update`, but replaces `name` itself even if it is a symbolic ref (detaching HEAD).
+pub(crate) fn update_no_deref(name: &str, new: &str, message: &str) -> anyhow::Result<()> {
+    write_ref(name, new, None, message)
+}
+
+fn write_ref(target: &str, new: &str, expected: Option<&str>, message: &str) -> anyhow::Result<()> {
+    // Hold the lock while checking the old value, so nobody can sneak in between
+    let mut lock = Lockfile::acquire(repository::git_dir().join(target))?;
+    let old = resolve(target)?;
+    check_expected(target, old.as_deref(), expected)?;
+    writeln!(lock, "{new}").with_context(|| format!("write ref {target}"))?;
+    lock.commit()?;
+    log_update(target, old.as_deref(), new, message)
+}
+
+// Make sure a ref (locked by the caller) has the `expected` value, if there is one; the null
+// hash expects it not to exist.
+fn check_expected(target: &str, old: Option<&str>, expected: Option<&str>) -> anyhow::Result<()> {
+    let Some(expected) = expected else {
+        return Ok(());
+    };
+    let matches = match old {
+        Some(old) => old == expected,
+        None => is_null(expected),
+    };
+    anyhow::ensure!(
+        matches,
+        "cannot lock ref '{target}': is at {} but expected {expected}",
+        old.map_or(null_hash()?, str::to_string)
+    );
+    Ok(())
+}
+
+// The move is logged for the ref itself and, when it is the current branch, for HEAD.
+fn log_update(target: &str, old: Option<&str>, new: &str, message: &str) -> anyhow::Result<()> {
+    let null = null_hash()?;
+    let old = old.unwrap_or(&null);
+    append_reflog(target, old, new, message)?;
+    if target != "HEAD" && symbolic_target("HEAD")?.as_deref() == Some(target) {
+        append_reflog("HEAD", old, new, message)?;
+    }
+    Ok(())
+}
+
+// Update several refs as one: each (name, new, expected) is like `update` with `expected`
+// set, the null hash as `new` deleting the ref. Every ref is locked and checked before any
+// of them changes, so either all of them are updated or, if one can't be, none is.
+pub(crate) fn update_all(updates: &[(String, String, String)], message: &str) -> anyhow::Result<()> {
+    let mut locked = Vec::new();
+    for (name, new, expected) in updates {
+        let target = symbolic_target(name)?.unwrap_or_else(|| name.clone());
+        let mut lock = Lockfile::acquire(repository::git_dir().join(&target))?;
+        let old = resolve(&target)?;
+        check_expected(&target, old.as_deref(), Some(expected))?;
+        if is_null(new) {
+            anyhow::ensure!(old.is_some(), "ref {target} does not exist");
+        } else {
+            writeln!(lock, "{new}").with_context(|| format!("write ref {target}"))?;
+        }
+        locked.push((target, lock, old, new));
+    }
+
+    // Deleted refs leave packed-refs first, the one step that may still fail
+    let packed = packed_refs()?;
+    let unpacked: Vec<&str> = locked
+        .iter()
+        .filter(|(target, _, _, new)| is_null(new) && packed.iter().any(|(name, _)| name == target))
+        .map(|(target, ..)| target.as_str())
+        .collect();
+    if !unpacked.is_empty() {
+        remove_packed_refs(&unpacked)?;
+    }
+    for (target, lock, old, new) in locked {
+        if is_null(new) {
+            remove_loose(&target)?;
+            drop(lock);
+            forget(&target)?;
+        } else {
+            lock.commit()?;
+            log_update(&target, old.as_deref(), new, message)?;
+        }
+    }
+    Ok(())
+}
+
+// Make `name` (e.g. HEAD) a symbolic ref pointing at `target`. With a message, the switch is
+// recorded in the reflog of `name`.
+pub(crate) fn set_symbolic(name: &str, target: &str, message: Option<&str>) -> anyhow::Result<()> {
+    let mut lock = Lockfile::acquire(repository::git_dir().join(name))?;
+    let old = resolve(name)?;
+    writeln!(lock, "ref: {target}").with_context(|| format!("write ref {name}"))?;
+    lock.commit()?;
+
+    if let Some(message) = message {
+        let null = null_hash()?;
+        let old = old.as_deref().unwrap_or(&null);
+        let new = resolve(target)?;
+        append_reflog(name, old, new.as_deref().unwrap_or(&null), message)?;
+    }
+    Ok(())
+}
+
+// Give a ref a new name, taking its reflog along.
+pub(crate) fn rename(old: &str, new: &str, message: &str) -> anyhow::Result<()> {
+    let hash = resolve(old)?.ok_or_else(|| anyhow::anyhow!("ref {old} does not exist"))?;
+    anyhow::ensure!(resolve(new)?.is_none(), "ref {new} already exists");
+
+    let old_log = repository::git_dir().join("logs").join(old);
+    let new_log = repository::git_dir().join("logs").join(new);
+    // Park the log out of the way while the old ref goes, in case the new name is below it
+    let parked = repository::git_dir().join("logs").join(format!("{old}.renaming"));
+    let moved_log = old_log.exists();
+    if moved_log {
+        fs::rename(&old_log, &parked).with_context(|| format!("move reflog of {old}"))?;
+    }
+    delete(old, Some(&hash))?;
+    if moved_log {
+        if let Some(parent) = new_log.parent() {
+            fs::create_dir_all(parent).with_context(|| format!("create {}", parent.display()))?;
+        }
+        fs::rename(&parked, &new_log).with_context(|| format!("move reflog of {old} to {new}"))?;
+    }
+    write_ref(new, &hash, Some(&null_hash()?), message)
+}
+
+// Remove a ref, both its loose file and its packed-refs line, along with its reflog.
+pub(crate) fn delete(name: &str, expected: Option<&str>) -> anyhow::Result<()> {
+    let path = repository::git_dir().join(name);
+    let lock = Lockfile::acquire(&path)?;
+    let old = resolve(name)?.ok_or_else(|| anyhow::anyhow!("ref {name} does not exist"))?;
+    if let Some(expected) = expected {
+        anyhow::ensure!(old == expected, "cannot lock ref '{name}': is at {old} but expected {expected}");
+    }
+
+    if packed_refs()?.iter().any(|(packed, _)| packed == name) {
+        remove_packed_refs(&[name])?;
+    }
+    remove_loose(name)?;
+    drop(lock);
+    forget(name)
+}
+
+// Remove the file of a loose ref, if there is one.
+fn remove_loose(name: &str) -> anyhow::Result<()> {
+    let path = repository::git_dir().join(name);
+    match fs::remove_file(&path) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e).with_context(|| format!("remove {}", path.display())),
+    }
+}
+
+// Remove what is left of a deleted ref: its reflog and the directories it leaves empty.
+fn forget(name: &str) -> anyhow::Result<()> {
+    let log = repository::git_dir().join("logs").join(name);
+    match fs::remove_file(&log) {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e).with_context(|| format!("remove {}", log.display())),
+    }
+
+    // Leave no empty directories below refs/<kind>/ behind, so a ref with the directory's
+    // name can be created
+    for base in [repository::git_dir().to_path_buf(), repository::git_dir().join("logs")] {
+        let mut dir = Path::new(name).parent();
+        while let Some(parent) = dir.filter(|dir| dir.components().count() > 2) {
+            if fs::remove_dir(base.join(parent)).is_err() {
+                break;
+            }
+            dir = parent.parent();
+        }
+    }
+    Ok(())
+}
+
+// Rewrite .git/packed-refs without `names`. Every other line stays as it was, the header and
+// the "^<hash>" lines of peeled tags included, since git trusts the header's "peeled
+// fully-peeled" to mean that every tag has its peeled line.
+fn remove_packed_refs(names: &[&str]) -> anyhow::Result<()> {
+    let mut lock = Lockfile::acquire(repository::git_dir().join("packed-refs"))?;
+    let packed = fs::read_to_string(repository::git_dir().join("packed-refs")).context("read .git/packed-refs")?;
+    let mut removing = false;
+    for line in packed.lines() {
+        // A peeled line belongs to the ref before it
+        if line.starts_with('^') {
+            if !removing {
+                writeln!(lock, "{line}")?;
+            }
+            continue;
+        }
+        removing = line.split_once(' ').is_some_and(|(_, packed)| names.contains(&packed));
+        if !removing {
+            writeln!(lock, "{line}")?;
+        }
+    }
+    lock.commit()
+}
+
+// Whether updates of `name` get logged, like git's default core.logAllRefUpdates=true.
+fn logs_updates(name: &str) -> bool {
+    name == "HEAD"
+        || ["refs/heads/", "refs/remotes/", "refs/notes/"]
+            .iter()
+            .any(|prefix| name.starts_with(prefix))
+        || repository::git_dir().join("logs").join(name).exists()
+}
+
+fn append_reflog(name: &str, old: &str, new: &str, message: &str) -> anyhow::Result<()> {
+    if !logs_updates(name) {
+        return Ok(());
+    }
+    let path = repository::git_dir().join("logs").join(name);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).with_context(|| format!("create {}", parent.display()))?;
+    }
+    let mut log = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .with_context(|| format!("open reflog {}", path.display()))?;
+    // One line per update, written in a single call so concurrent appends don't interleave
+    let message = message.lines().next().unwrap_or_default();
+    let line = format!("{old} {new} {}\t{message}\n", Signature::reflog_committer());
+    log.write_all(line.as_bytes())
+        .with_context(|| format!("write reflog {}", path.display()))
+}
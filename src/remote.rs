@@ -0,0 +1,91 @@
+use crate::config::Config;
+use crate::refs;
+
+// A repository to fetch from and push to: a remote configured as remote.<name>.*, or one
+// given by its URL.
+#[derive(Debug, Clone)]
+pub(crate) struct Remote {
+    // None for a URL given directly
+    pub(crate) name: Option<String>,
+    pub(crate) url: String,
+    // remote.<name>.pushurl, where pushes go instead of `url`
+    pub(crate) push_url: Option<String>,
+    // remote.<name>.fetch refspecs, e.g. "+refs/heads/*:refs/remotes/origin/*"
+    pub(crate) fetch: Vec<String>,
+    // remote.<name>.uploadpack, the command that serves it if it is local
+    pub(crate) upload_pack: Option<String>,
+    // remote.<name>.tagOpt: "--no-tags" or "--tags" instead of following tags
+    pub(crate) tag_opt: Option<String>,
+    // remote.<name>.prune
+    pub(crate) prune: Option<bool>,
+    // remote.<name>.partialCloneFilter of a promisor remote: what fetches from it leave out
+    pub(crate) filter: Option<String>,
+}
+
+impl Remote {
+    // The remote called `name`, or else the repository at the URL or path `name`.
+    pub(crate) fn get(config: &Config, name: &str) -> anyhow::Result<Remote> {
+        if let Some(url) = config.get(&format!("remote.{name}.url")) {
+            return Ok(Remote {
+                name: Some(name.to_string()),
+                url: url.to_string(),
+                push_url: config.get(&format!("remote.{name}.pushurl")).map(str::to_string),
+                fetch: config
+                    .get_all(&format!("remote.{name}.fetch"))
+                    .into_iter()
+                    .map(str::to_string)
+                    .collect(),
+                upload_pack: config.get(&format!("remote.{name}.uploadpack")).map(str::to_string),
+                tag_opt: config.get(&format!("remote.{name}.tagOpt")).map(str::to_string),
+                prune: config.get_bool(&format!("remote.{name}.prune"))?,
+                filter: match config.get_bool(&format!("remote.{name}.promisor"))? {
+                    Some(true) => config.get(&format!("remote.{name}.partialclonefilter")).map(str::to_string),
+                    _ => None,
+                },
+            });
+        }
+        // Remote names can't hold these, so it must be meant as a URL or path
+        anyhow::ensure!(
+            name.contains(['/', ':', '.']),
+            "'{name}' does not appear to be a git repository"
+        );
+        Ok(Remote {
+            name: None,
+            url: name.to_string(),
+            push_url: None,
+            fetch: Vec::new(),
+            upload_pack: None,
+            tag_opt: None,
+            prune: None,
+            filter: None,
+        })
+    }
+
+    // The URL pushes go to.
+    pub(crate) fn push_url(&self) -> &str {
+        self.push_url.as_deref().unwrap_or(&self.url)
+    }
+
+    // The names of the configured remotes, in the order they first appear.
+    pub(crate) fn names(config: &Config) -> Vec<String> {
+        let mut names: Vec<String> = Vec::new();
+        for entry in &config.entries {
+            // "remote.<name>.<key>"; "remote.pushdefault" and the like have no name
+            let Some((name, _)) = entry.key.strip_prefix("remote.").and_then(|rest| rest.rsplit_once('.')) else {
+                continue;
+            };
+            if !names.iter().any(|known| known == name) {
+                names.push(name.to_string());
+            }
+        }
+        names
+    }
+
+    // The remote-tracking ref that keeps a copy of the remote's ref `name`, if any.
+    pub(crate) fn tracking_ref(&self, name: &str) -> Option<String> {
+        self.fetch
+            .iter()
+            .filter(|refspec| !refspec.starts_with('^'))
+            .find_map(|refspec| refs::map_refspec(refspec, name))
+    }
+}
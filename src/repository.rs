@@ -0,0 +1,22 @@
+use std::path::Path;
+
+// Where the repository in the current directory keeps its objects, refs and config: .git, or
+// the directory itself for a bare repository (one without a working tree, like the ones
+// `git init --bare` makes to be pushed to).
+pub(crate) fn git_dir() -> &'static Path {
+    if is_bare() {
+        Path::new(".")
+    } else {
+        Path::new(".git")
+    }
+}
+
+// Whether the current directory is a bare repository.
+pub(crate) fn is_bare() -> bool {
+    !Path::new(".git").exists() && is_git_dir(Path::new("."))
+}
+
+// Whether `dir` has what a .git directory has: objects, refs and HEAD.
+pub(crate) fn is_git_dir(dir: &Path) -> bool {
+    dir.join("objects").is_dir() && dir.join("refs").is_dir() && dir.join("HEAD").is_file()
+}
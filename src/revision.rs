@@ -0,0 +1,410 @@
+use crate::index::Index;
+use crate::objects::{self, Commit, Format, Kind, Object, Tag, Tree};
+use crate::{refs, shallow};
+use anyhow::Context;
+use std::collections::{HashMap, HashSet};
+
+// One step applied after the base name of a revision.
+#[derive(Debug, PartialEq, Eq)]
+enum Step {
+    // rev^N: the Nth parent (rev^0 is the commit itself)
+    Parent(usize),
+    // rev~N: the Nth generation ancestor, following first parents
+    Ancestor(usize),
+    // rev^{type}: peel until an object of that type is reached (rev^{} peels tags only)
+    Peel(String),
+}
+
+// Resolve a revision as understood by git (see gitrevisions(7)) to a full object hash.
+//
+// Supported forms:
+//   <hash>, <unique hash prefix>     full or abbreviated object names
+//   HEAD, <branch>, <tag>, <ref>     names looked up like git does (refs/, refs/tags/, ...)
+//   <rev>@{N}, @{N}, @               reflog entries; "@" alone means HEAD
+//   <rev>^, <rev>^N, <rev>~, <rev>~N parents and first-parent ancestors
+//   <rev>^{type}, <rev>^{}           peeling of tags (and commits, to their tree)
+//   <rev>:<path>                     the object at <path> in the tree of <rev>
+//   :<path>, :<N>:<path>             the blob staged for <path> (at stage N) in the index
+pub(crate) fn resolve(spec: &str) -> anyhow::Result<String> {
+    anyhow::ensure!(!spec.is_empty(), "empty revision");
+
+    if let Some((rev, path)) = split_path(spec) {
+        if rev.is_empty() {
+            return lookup_index(path).with_context(|| format!("resolve '{spec}'"));
+        }
+        let tree = peel(&resolve(rev)?, Kind::Tree)?;
+        return lookup_path(&tree, path).with_context(|| format!("resolve '{spec}'"));
+    }
+
+    let (base, steps) = split_steps(spec)?;
+    let mut hash = resolve_base(base)?;
+    for step in steps {
+        hash = match step {
+            Step::Parent(0) => peel(&hash, Kind::Commit)?,
+            Step::Parent(n) => {
+                let commit = read_commit(&peel(&hash, Kind::Commit)?)?;
+                commit
+                    .parents
+                    .get(n - 1)
+                    .cloned()
+                    .ok_or_else(|| anyhow::anyhow!("'{spec}': commit {hash} has no parent {n}"))?
+            }
+            Step::Ancestor(n) => {
+                for _ in 0..n {
+                    let commit = read_commit(&peel(&hash, Kind::Commit)?)?;
+                    hash = commit
+                        .parents
+                        .first()
+                        .cloned()
+                        .ok_or_else(|| anyhow::anyhow!("'{spec}': commit {hash} has no parent"))?;
+                }
+                hash
+            }
+            Step::Peel(kind) => match kind.as_str() {
+                "" => peel_tags(&hash)?,
+                "object" => {
+                    Object::read(&hash).with_context(|| format!("'{spec}': no object {hash}"))?;
+                    hash
+                }
+                kind => peel(&hash, kind.parse()?)?,
+            },
+        };
+    }
+    Ok(hash)
+}
+
+// Split "<rev>:<path>" at the first colon outside of "@{...}".
+fn split_path(spec: &str) -> Option<(&str, &str)> {
+    let mut depth = 0;
+    for (i, c) in spec.char_indices() {
+        match c {
+            '{' => depth += 1,
+            '}' => depth -= 1,
+            ':' if depth == 0 => return Some((&spec[..i], &spec[i + 1..])),
+            _ => {}
+        }
+    }
+    None
+}
+
+// Separate the base name from the trailing ^ and ~ steps.
+fn split_steps(spec: &str) -> anyhow::Result<(&str, Vec<Step>)> {
+    let mut depth = 0;
+    let start = spec
+        .char_indices()
+        .find(|&(_, c)| {
+            match c {
+                '{' => depth += 1,
+                '}' => depth -= 1,
+                _ => {}
+            }
+            depth == 0 && (c == '^' || c == '~')
+        })
+        .map(|(i, _)| i)
+        .unwrap_or(spec.len());
+    let (base, mut rest) = spec.split_at(start);
+
+    let mut steps = Vec::new();
+    while let Some(op) = rest.chars().next() {
+        rest = &rest[1..];
+        if op == '^' && rest.starts_with('{') {
+            let end = rest
+                .find('}')
+                .ok_or_else(|| anyhow::anyhow!("unterminated '^{{' in '{spec}'"))?;
+            steps.push(Step::Peel(rest[1..end].to_string()));
+            rest = &rest[end + 1..];
+            continue;
+        }
+
+        let digits = rest.bytes().take_while(|b| b.is_ascii_digit()).count();
+        let n = if digits == 0 {
+            1
+        } else {
+            rest[..digits]
+                .parse()
+                .with_context(|| format!("invalid number in '{spec}'"))?
+        };
+        rest = &rest[digits..];
+        match op {
+            '^' => steps.push(Step::Parent(n)),
+            '~' => steps.push(Step::Ancestor(n)),
+            _ => anyhow::bail!("unexpected '{op}' in '{spec}'"),
+        }
+    }
+    Ok((base, steps))
+}
+
+fn resolve_base(base: &str) -> anyhow::Result<String> {
+    if base == "@" {
+        return resolve_base("HEAD");
+    }
+
+    // <ref>@{N}: the Nth prior value of a ref, from its reflog
+    if let Some(at) = base.find("@{") {
+        anyhow::ensure!(base.ends_with('}'), "unterminated '@{{' in '{base}'");
+        let name = &base[..at];
+        let selector = &base[at + 2..base.len() - 1];
+        let n: usize = selector
+            .parse()
+            .map_err(|_| anyhow::anyhow!("unsupported reflog selector '@{{{selector}}}'"))?;
+
+        // A bare @{N} refers to the current branch
+        let full_name = if name.is_empty() {
+            refs::symbolic_target("HEAD")?.unwrap_or_else(|| "HEAD".to_string())
+        } else {
+            refs::dwim(name)?.ok_or_else(|| anyhow::anyhow!("unknown revision '{name}'"))?
+        };
+        return reflog_entry(&full_name, n);
+    }
+
+    // Full hashes are taken as they are, even if no such object exists (like git)
+    let hex_len = Format::current()?.hex_len();
+    if base.len() == hex_len && base.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Ok(base.to_ascii_lowercase());
+    }
+
+    if let Some(full_name) = refs::dwim(base)? {
+        return refs::resolve(&full_name)?
+            .ok_or_else(|| anyhow::anyhow!("ref {full_name} vanished while resolving it"));
+    }
+
+    // Abbreviated hashes must be at least 4 characters long and unique
+    if base.len() >= 4 && base.len() < hex_len && base.bytes().all(|b| b.is_ascii_hexdigit()) {
+        let matches = objects::find_by_prefix(base)?;
+        match matches.len() {
+            0 => {}
+            1 => return Ok(matches[0].to_string()),
+            _ => {
+                let mut candidates = String::new();
+                for id in matches {
+                    let hash = id.to_string();
+                    let kind = Object::read(&hash).map(|o| o.kind.to_string()).unwrap_or_default();
+                    candidates.push_str(&format!("\n  {} {kind}", &hash[..base.len().max(7)]));
+                }
+                anyhow::bail!("short object ID {base} is ambiguous\nThe candidates are:{candidates}");
+            }
+        }
+    }
+
+    anyhow::bail!("unknown revision '{base}'")
+}
+
+fn reflog_entry(name: &str, n: usize) -> anyhow::Result<String> {
+    let log = refs::reflog(name)?;
+    anyhow::ensure!(!log.is_empty(), "no reflog for '{name}'");
+    // @{0} is the newest entry; one past the oldest is where the ref started out
+    if n < log.len() {
+        Ok(log[log.len() - 1 - n].new.clone())
+    } else if n == log.len() && !refs::is_null(&log[0].old) {
+        Ok(log[0].old.clone())
+    } else {
+        anyhow::bail!("log for '{name}' only has {} entries", log.len())
+    }
+}
+
+pub(crate) fn read_commit(hash: &str) -> anyhow::Result<Commit> {
+    let data = Object::read(hash)
+        .with_context(|| format!("read commit {hash}"))?
+        .into_bytes()?;
+    let mut commit = Commit::parse(&data).with_context(|| format!("parse commit {hash}"))?;
+    // The history of a shallow repository ends where its parents are missing
+    if shallow::contains(hash)? {
+        commit.parents.clear();
+    }
+    Ok(commit)
+}
+
+// Whether the commit `ancestor` can be reached from `descendant` by following parents
+// (a commit counts as its own ancestor).
+pub(crate) fn is_ancestor(ancestor: &str, descendant: &str) -> anyhow::Result<bool> {
+    let mut seen = HashSet::new();
+    let mut pending = vec![descendant.to_string()];
+    while let Some(hash) = pending.pop() {
+        if hash == ancestor {
+            return Ok(true);
+        }
+        if seen.insert(hash.clone()) {
+            pending.extend(read_commit(&hash)?.parents);
+        }
+    }
+    Ok(false)
+}
+
+// How many commits `a` has that `b` doesn't, and the other way around.
+pub(crate) fn ahead_behind(a: &str, b: &str) -> anyhow::Result<(usize, usize)> {
+    let from_a = reachable(a)?;
+    let from_b = reachable(b)?;
+    Ok((from_a.difference(&from_b).count(), from_b.difference(&from_a).count()))
+}
+
+// Every commit reachable from `commit`, itself included.
+pub(crate) fn reachable(commit: &str) -> anyhow::Result<HashSet<String>> {
+    let mut seen = HashSet::new();
+    let mut pending = vec![commit.to_string()];
+    while let Some(hash) = pending.pop() {
+        if seen.insert(hash.clone()) {
+            pending.extend(read_commit(&hash)?.parents);
+        }
+    }
+    Ok(seen)
+}
+
+// The best common ancestors of the commits `one` (taken together) and `other`: commits
+// reachable from both that aren't an ancestor of another such commit. There is usually one,
+// but criss-cross merges leave several. Newest (by committer date) first.
+pub(crate) fn merge_bases(one: &[String], other: &[String]) -> anyhow::Result<Vec<String>> {
+    // Parents and committer dates of everything either side reaches
+    let mut commits: HashMap<String, (Vec<String>, i64)> = HashMap::new();
+    let from_one = ancestry(one, &mut commits)?;
+    let from_other = ancestry(other, &mut commits)?;
+    let common: HashSet<&String> = from_one.intersection(&from_other).collect();
+
+    // Anything reachable from the parents of a common ancestor is one too, but not a best one
+    let mut redundant = HashSet::new();
+    let mut pending: Vec<&String> = common.iter().flat_map(|hash| &commits[*hash].0).collect();
+    while let Some(hash) = pending.pop() {
+        if redundant.insert(hash) {
+            pending.extend(&commits[hash].0);
+        }
+    }
+
+    let mut bases: Vec<String> = common
+        .into_iter()
+        .filter(|hash| !redundant.contains(hash))
+        .cloned()
+        .collect();
+    bases.sort_by(|a, b| commits[b].1.cmp(&commits[a].1).then(a.cmp(b)));
+    Ok(bases)
+}
+
+// Every commit reachable from `starts`, recording their parents and dates in `commits`.
+fn ancestry(starts: &[String], commits: &mut HashMap<String, (Vec<String>, i64)>) -> anyhow::Result<HashSet<String>> {
+    let mut seen = HashSet::new();
+    let mut pending = starts.to_vec();
+    while let Some(hash) = pending.pop() {
+        if !seen.insert(hash.clone()) {
+            continue;
+        }
+        if !commits.contains_key(&hash) {
+            let commit = read_commit(&hash)?;
+            commits.insert(hash.clone(), (commit.parents, commit.committer.time));
+        }
+        pending.extend(commits[&hash].0.iter().cloned());
+    }
+    Ok(seen)
+}
+
+// Follow tags until something that isn't a tag is reached.
+pub(crate) fn peel_tags(hash: &str) -> anyhow::Result<String> {
+    let mut hash = hash.to_string();
+    loop {
+        let object = Object::read(&hash).with_context(|| format!("read object {hash}"))?;
+        if object.kind != Kind::Tag {
+            return Ok(hash);
+        }
+        hash = Tag::parse(&object.into_bytes()?)?.object;
+    }
+}
+
+// Dereference tags (and commits, to get at their tree) until an object of `kind` is found.
+pub(crate) fn peel(hash: &str, kind: Kind) -> anyhow::Result<String> {
+    let mut current = hash.to_string();
+    loop {
+        let object = Object::read(&current).with_context(|| format!("read object {current}"))?;
+        if object.kind == kind {
+            return Ok(current);
+        }
+        current = match object.kind {
+            Kind::Tag => Tag::parse(&object.into_bytes()?)?.object,
+            Kind::Commit if kind == Kind::Tree => Commit::parse(&object.into_bytes()?)?.tree,
+            _ => anyhow::bail!("{hash} cannot be resolved to a {kind} (it is a {})", object.kind),
+        };
+    }
+}
+
+// Walk `path` down from the tree `tree` and return the hash of the object found there.
+fn lookup_path(tree: &str, path: &str) -> anyhow::Result<String> {
+    let mut hash = tree.to_string();
+    for component in path.split('/').filter(|c| !c.is_empty()) {
+        let object = Object::read(&hash).with_context(|| format!("read object {hash}"))?;
+        anyhow::ensure!(object.kind == Kind::Tree, "'{component}' is not inside a directory");
+        let tree = Tree::parse(&object.into_bytes()?)?;
+        let entry = tree
+            .entries
+            .into_iter()
+            .find(|entry| entry.name == component)
+            .ok_or_else(|| anyhow::anyhow!("path '{path}' does not exist"))?;
+        hash = entry.hash.to_string();
+    }
+    Ok(hash)
+}
+
+// Find the blob staged for "<path>" or "<stage>:<path>".
+fn lookup_index(path: &str) -> anyhow::Result<String> {
+    let (stage, path) = match path.split_once(':') {
+        Some((stage @ ("0" | "1" | "2" | "3"), path)) => (stage.parse::<u8>()?, path),
+        _ => (0, path),
+    };
+    let index = Index::load()?;
+    index
+        .entries
+        .iter()
+        .find(|entry| entry.path == path && entry.stage() == stage)
+        .map(|entry| entry.hash.to_string())
+        .ok_or_else(|| anyhow::anyhow!("path '{path}' is not in the index at stage {stage}"))
+}
+
+// The shortest prefix of `hash` (at least `min` characters) that names only this object.
+pub(crate) fn abbreviate(hash: &str, min: usize) -> anyhow::Result<String> {
+    for len in min.clamp(4, hash.len())..hash.len() {
+        if objects::find_by_prefix(&hash[..len])?.len() <= 1 {
+            return Ok(hash[..len].to_string());
+        }
+    }
+    Ok(hash.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paths_are_split_off_at_the_first_colon() {
+        assert_eq!(split_path("HEAD:src/lib.rs"), Some(("HEAD", "src/lib.rs")));
+        assert_eq!(split_path(":README"), Some(("", "README")));
+        assert_eq!(split_path(":2:README"), Some(("", "2:README")));
+        assert_eq!(split_path("main~2:a:b"), Some(("main~2", "a:b")));
+        // Not inside a reflog selector
+        assert_eq!(split_path("main@{1}:a"), Some(("main@{1}", "a")));
+        assert_eq!(split_path("main^{tree}"), None);
+        assert_eq!(split_path("main"), None);
+    }
+
+    #[test]
+    fn steps_are_read_after_the_name() {
+        let cases: &[(&str, &str, &[Step])] = &[
+            ("HEAD", "HEAD", &[]),
+            ("HEAD^", "HEAD", &[Step::Parent(1)]),
+            ("HEAD^0", "HEAD", &[Step::Parent(0)]),
+            ("HEAD~", "HEAD", &[Step::Ancestor(1)]),
+            ("main^2~3", "main", &[Step::Parent(2), Step::Ancestor(3)]),
+            ("main~~^", "main", &[Step::Ancestor(1), Step::Ancestor(1), Step::Parent(1)]),
+            ("v1^{}", "v1", &[Step::Peel(String::new())]),
+            ("v1^{commit}^{tree}", "v1", &[Step::Peel("commit".to_string()), Step::Peel("tree".to_string())]),
+            ("main@{1}~2", "main@{1}", &[Step::Ancestor(2)]),
+            ("@{2}^", "@{2}", &[Step::Parent(1)]),
+        ];
+        for &(spec, base, steps) in cases {
+            let (found, found_steps) = split_steps(spec).unwrap();
+            assert_eq!((found, found_steps.as_slice()), (base, steps), "{spec}");
+        }
+    }
+
+    #[test]
+    fn malformed_revisions_are_rejected() {
+        for spec in ["HEAD^{tree", "HEAD~99999999999999999999999"] {
+            assert!(split_steps(spec).is_err(), "{spec}");
+        }
+        assert!(resolve("").is_err());
+    }
+}
@@ -0,0 +1,62 @@
+use crate::repository;
+use crate::transport::pktline;
+use anyhow::Context;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+
+mod http;
+pub(crate) mod receive_pack;
+pub(crate) mod upload_pack;
+
+pub(crate) use http::serve_http;
+
+// The protocol version a client asked for in $GIT_PROTOCOL or the Git-Protocol header, a
+// colon-separated list of parameters like "version=2". Anything but 2 means v0.
+pub(crate) fn protocol_version(requested: Option<&str>) -> u8 {
+    let requested = requested.unwrap_or_default();
+    if requested.split(':').any(|parameter| parameter == "version=2") {
+        2
+    } else {
+        0
+    }
+}
+
+// Change to the repository at `dir`, which is either a working tree, its .git directory or a
+// bare repository. Like git, "<dir>.git" is tried too.
+pub(crate) fn enter_repository(dir: &Path) -> anyhow::Result<()> {
+    let mut with_suffix = dir.as_os_str().to_owned();
+    with_suffix.push(".git");
+    for candidate in [dir.to_path_buf(), PathBuf::from(with_suffix)] {
+        let enter = if candidate.join(".git/objects").is_dir() {
+            candidate
+        } else if candidate.file_name().is_some_and(|name| name == ".git") && candidate.join("objects").is_dir() {
+            match candidate.parent() {
+                Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+                _ => PathBuf::from("."),
+            }
+        } else if repository::is_git_dir(&candidate) {
+            candidate
+        } else {
+            continue;
+        };
+        return std::env::set_current_dir(&enter).with_context(|| format!("change to {}", enter.display()));
+    }
+    anyhow::bail!("'{}' does not appear to be a git repository", dir.display())
+}
+
+// Whether the client has sent everything it is going to, which between requests is fine.
+fn at_end(input: &mut impl BufRead) -> io::Result<bool> {
+    Ok(input.fill_buf()?.is_empty())
+}
+
+// Send `data` on a band of a side-band-64k stream (1 for data, 2 for progress and messages,
+// 3 for a fatal error), in packets as large as allowed.
+fn send_sideband(output: &mut impl Write, band: u8, data: &[u8]) -> io::Result<()> {
+    for chunk in data.chunks(pktline::MAX_DATA - 1) {
+        let mut packet = Vec::with_capacity(chunk.len() + 1);
+        packet.push(band);
+        packet.extend_from_slice(chunk);
+        pktline::write(output, &packet)?;
+    }
+    Ok(())
+}
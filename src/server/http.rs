@@ -0,0 +1,327 @@
+use super::{enter_repository, protocol_version, receive_pack, upload_pack};
+use crate::config::Config;
+use crate::transport::{pktline, Service};
+use anyhow::Context;
+use flate2::bufread::GzDecoder;
+use std::fmt;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::{Mutex, PoisonError};
+use std::time::Duration;
+
+// The most a request body may hold (after decompressing it), so a client can't have the
+// server allocate whatever it claims to send.
+const MAX_BODY: usize = 512 * 1024 * 1024;
+// The longest request line or header, and the most headers, a request may have.
+const MAX_LINE: u64 = 8 * 1024;
+const MAX_HEADERS: usize = 100;
+// How long a connection may go without sending (or taking) anything before it is dropped.
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+// Held while serving a repository, which means changing the current directory of the whole
+// process to it.
+static REPOSITORY: Mutex<()> = Mutex::new(());
+
+// The error for a request whose body is larger than MAX_BODY, answered with 413.
+#[derive(Debug)]
+struct TooLarge;
+
+impl fmt::Display for TooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "request body is larger than {MAX_BODY} bytes")
+    }
+}
+
+impl std::error::Error for TooLarge {}
+
+// A request as far as the smart HTTP protocol cares.
+struct Request {
+    method: String,
+    // The path, still with the query string
+    target: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Request {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+struct Response {
+    status: u16,
+    content_type: String,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn text(status: u16, message: &str) -> Response {
+        Response { status, content_type: "text/plain".to_string(), body: format!("{message}\n").into_bytes() }
+    }
+}
+
+// Serve the repositories below `root` over smart HTTP, like `git http-backend` behind a web
+// server: http://<address>/<path>/info/refs and the POST endpoints next to it, for the
+// repository at <root>/<path>. Every connection (one request each) has a thread of its own,
+// though repositories are served one request at a time. Pushing needs http.receivepack set in
+// the repository, as there is no authentication.
+pub(crate) fn serve_http(address: &str, root: &Path) -> anyhow::Result<()> {
+    let root = root.canonicalize().with_context(|| format!("find {}", root.display()))?;
+    let listener = TcpListener::bind(address).with_context(|| format!("listen on {address}"))?;
+    eprintln!("Serving {} on http://{}/", root.display(), listener.local_addr()?);
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("error: accept connection: {e}");
+                continue;
+            }
+        };
+        let root = root.clone();
+        std::thread::spawn(move || {
+            if let Err(e) = handle(stream, &root) {
+                eprintln!("error: {e:#}");
+            }
+        });
+    }
+    Ok(())
+}
+
+fn handle(mut stream: TcpStream, root: &Path) -> anyhow::Result<()> {
+    // A client that stops halfway must not hold on to its thread forever
+    stream.set_read_timeout(Some(TIMEOUT)).context("set read timeout")?;
+    stream.set_write_timeout(Some(TIMEOUT)).context("set write timeout")?;
+    let mut reader = BufReader::new(stream.try_clone().context("clone connection")?);
+    let response = match read_request(&mut reader, &mut stream) {
+        Ok(request) => {
+            let response = {
+                let _serving = REPOSITORY.lock().unwrap_or_else(PoisonError::into_inner);
+                let response = respond(&request, root);
+                std::env::set_current_dir(root).with_context(|| format!("change to {}", root.display()))?;
+                response
+            };
+            eprintln!("\"{} {}\" {}", request.method, request.target, response.status);
+            response
+        }
+        Err(e) if e.is::<TooLarge>() => Response::text(413, &format!("{e:#}")),
+        Err(e) => Response::text(400, &format!("{e:#}")),
+    };
+
+    let reason = match response.status {
+        200 => "OK",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Payload Too Large",
+        _ => "Internal Server Error",
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {} {reason}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n",
+        response.status,
+        response.content_type,
+        response.body.len()
+    )?;
+    stream.write_all(&response.body)?;
+    stream.flush().context("send response")
+}
+
+fn read_request(reader: &mut impl BufRead, stream: &mut TcpStream) -> anyhow::Result<Request> {
+    let mut line = String::new();
+    read_line(reader, &mut line).context("read request line")?;
+    let mut words = line.split_whitespace();
+    let (Some(method), Some(target)) = (words.next(), words.next()) else {
+        anyhow::bail!("bad request line '{}'", line.trim_end());
+    };
+    let (method, target) = (method.to_string(), target.to_string());
+
+    let mut headers = Vec::new();
+    loop {
+        line.clear();
+        read_line(reader, &mut line).context("read request header")?;
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        anyhow::ensure!(headers.len() < MAX_HEADERS, "more than {MAX_HEADERS} request headers");
+        let Some((key, value)) = header.split_once(':') else {
+            anyhow::bail!("bad request header '{header}'");
+        };
+        headers.push((key.trim().to_string(), value.trim().to_string()));
+    }
+    let mut request = Request { method, target, headers, body: Vec::new() };
+
+    let length = match request.header("Content-Length") {
+        Some(length) => Some(length.parse::<usize>().with_context(|| format!("bad Content-Length '{length}'"))?),
+        None => None,
+    };
+    // Before the client goes on to send it
+    if length.is_some_and(|length| length > MAX_BODY) {
+        return Err(TooLarge.into());
+    }
+    if request.header("Expect").is_some_and(|expect| expect.eq_ignore_ascii_case("100-continue")) {
+        stream.write_all(b"HTTP/1.1 100 Continue\r\n\r\n")?;
+    }
+    let mut body = Vec::new();
+    if request.header("Transfer-Encoding").is_some_and(|encoding| encoding.eq_ignore_ascii_case("chunked")) {
+        // Chunks of "<hex size>\r\n<data>\r\n", up to one of size 0 and the trailers
+        loop {
+            line.clear();
+            read_line(reader, &mut line).context("read chunk size")?;
+            let size = line.trim_end().split(';').next().unwrap_or_default();
+            let size = usize::from_str_radix(size, 16).with_context(|| format!("bad chunk size '{size}'"))?;
+            if size == 0 {
+                loop {
+                    line.clear();
+                    if read_line(reader, &mut line).context("read trailer")? <= 2 {
+                        break;
+                    }
+                }
+                break;
+            }
+            if body.len().saturating_add(size) > MAX_BODY {
+                return Err(TooLarge.into());
+            }
+            read_body(reader, size, &mut body).context("read chunk")?;
+            let mut end = [0; 2];
+            reader.read_exact(&mut end).context("read chunk")?;
+        }
+    } else if let Some(length) = length {
+        read_body(reader, length, &mut body).context("read request body")?;
+    }
+    if request.header("Content-Encoding").is_some_and(|encoding| encoding.eq_ignore_ascii_case("gzip")) {
+        let mut decoded = Vec::new();
+        GzDecoder::new(&body[..])
+            .take(MAX_BODY as u64 + 1)
+            .read_to_end(&mut decoded)
+            .context("decompress request body")?;
+        if decoded.len() > MAX_BODY {
+            return Err(TooLarge.into());
+        }
+        body = decoded;
+    }
+    request.body = body;
+    Ok(request)
+}
+
+// Read a line of at most MAX_LINE bytes into `line`, returning its length (0 at the end).
+fn read_line(reader: &mut impl BufRead, line: &mut String) -> anyhow::Result<usize> {
+    let read = reader.take(MAX_LINE).read_line(line)?;
+    anyhow::ensure!(read < MAX_LINE as usize || line.ends_with('\n'), "line longer than {MAX_LINE} bytes");
+    Ok(read)
+}
+
+// Append `length` bytes of the body to `body`, as they arrive rather than all at once.
+fn read_body(reader: &mut impl BufRead, length: usize, body: &mut Vec<u8>) -> anyhow::Result<()> {
+    let read = reader.take(length as u64).read_to_end(body)?;
+    anyhow::ensure!(read == length, "body ends after {read} of {length} bytes");
+    Ok(())
+}
+
+fn respond(request: &Request, root: &Path) -> Response {
+    let (path, query) = request.target.split_once('?').unwrap_or((&request.target, ""));
+    let path = decode_path(path);
+    let (repository, service) = if let Some(repository) = path.strip_suffix("/info/refs") {
+        let service = query.split('&').find_map(|parameter| parameter.strip_prefix("service="));
+        match service {
+            Some("git-upload-pack") => (repository, Service::UploadPack),
+            Some("git-receive-pack") => (repository, Service::ReceivePack),
+            // The dumb protocol, which would need the files themselves
+            _ => return Response::text(403, "only the smart HTTP protocol is supported"),
+        }
+    } else if let Some(repository) = path.strip_suffix("/git-upload-pack") {
+        (repository, Service::UploadPack)
+    } else if let Some(repository) = path.strip_suffix("/git-receive-pack") {
+        (repository, Service::ReceivePack)
+    } else {
+        return Response::text(404, "Not Found");
+    };
+    let advertisement = path.ends_with("/info/refs");
+    let expected_method = if advertisement { "GET" } else { "POST" };
+    if request.method != expected_method {
+        return Response::text(405, "Method Not Allowed");
+    }
+
+    let repository = repository.trim_matches('/');
+    if repository.split('/').any(|component| component == "..") {
+        return Response::text(404, "Not Found");
+    }
+    if enter_repository(&root.join(repository)).is_err() {
+        return Response::text(404, "Repository not found");
+    }
+    if service == Service::ReceivePack {
+        let enabled = Config::load().and_then(|config| config.get_bool("http.receivepack"));
+        match enabled {
+            Ok(Some(true)) => {}
+            Ok(_) => return Response::text(403, "Pushing is not enabled (set http.receivepack)"),
+            Err(e) => return Response::text(500, &format!("{e:#}")),
+        }
+    }
+
+    let name = service.name();
+    let version = match service {
+        Service::UploadPack => protocol_version(request.header("Git-Protocol")),
+        Service::ReceivePack => 0,
+    };
+    let mut body = Vec::new();
+    let (content_type, result) = if advertisement {
+        let result = (|| {
+            // v2 goes straight to the capabilities
+            if version != 2 {
+                pktline::write_line(&mut body, &format!("# service={name}"))?;
+                pktline::flush(&mut body)?;
+            }
+            match service {
+                Service::UploadPack => upload_pack::advertise(version, &mut body),
+                Service::ReceivePack => receive_pack::advertise(&mut body),
+            }
+        })();
+        (format!("application/x-{name}-advertisement"), result)
+    } else {
+        let mut input = &request.body[..];
+        let result = match service {
+            Service::UploadPack => upload_pack::serve(version, true, &mut input, &mut body),
+            Service::ReceivePack => receive_pack::serve(&mut input, &mut body),
+        };
+        (format!("application/x-{name}-result"), result)
+    };
+    match result {
+        Ok(()) => Response { status: 200, content_type, body },
+        Err(e) => {
+            eprintln!("error: {e:#}");
+            // What was sent so far may explain the failure to the client (an ERR packet)
+            if body.is_empty() {
+                Response::text(500, &format!("{e:#}"))
+            } else {
+                Response { status: 200, content_type, body }
+            }
+        }
+    }
+}
+
+// Undo the percent-encoding of a URL path.
+fn decode_path(path: &str) -> String {
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes.get(i + 1..i + 3).and_then(|hex| hex::decode(hex).ok());
+        match (bytes[i], escaped) {
+            (b'%', Some(byte)) => {
+                decoded.extend(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
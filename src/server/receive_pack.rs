@@ -0,0 +1,339 @@
+use super::{at_end, send_sideband};
+use crate::checkout::{self, Operation};
+use crate::config::Config;
+use crate::index::Index;
+use crate::objects::{Kind, Object, ObjectId, Tree};
+use crate::pack;
+use crate::objects::Format;
+use crate::refs;
+use crate::repository;
+use crate::revision;
+use crate::transport::{pktline, AGENT};
+use crate::worktree;
+use anyhow::Context;
+use std::collections::BTreeMap;
+use std::io::{BufRead, Write};
+
+const CAPABILITIES: &[&str] = &["report-status", "delete-refs", "side-band-64k", "quiet", "atomic", "ofs-delta"];
+
+// Open a session with the refs (no HEAD: it isn't something to push to), the capabilities on
+// the first line. Only protocol v0 exists for pushing.
+pub(crate) fn advertise(output: &mut impl Write) -> anyhow::Result<()> {
+    let mut capabilities: Vec<String> = CAPABILITIES.iter().map(|capability| capability.to_string()).collect();
+    capabilities.push(format!("object-format={}", Format::current()?));
+    capabilities.push(format!("agent={AGENT}"));
+
+    let mut lines: Vec<String> = refs::list("refs/")?.into_iter().map(|(name, hash)| format!("{hash} {name}")).collect();
+    if lines.is_empty() {
+        lines.push(format!("{} capabilities^{{}}", refs::null_hash()?));
+    }
+    lines[0] = format!("{}\0{}", lines[0], capabilities.join(" "));
+    for line in lines {
+        pktline::write_line(output, &line)?;
+    }
+    pktline::flush(output)?;
+    output.flush()?;
+    Ok(())
+}
+
+// A ref the client wants changed.
+struct Command {
+    name: String,
+    old: String,
+    new: String,
+    // Why it can't be, if it can't
+    error: Option<String>,
+    // The checked out branch, whose working tree is to follow (receive.denyCurrentBranch
+    // set to updateInstead)
+    update_worktree: bool,
+}
+
+// What a receive.deny* setting has done with a change it covers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Deny {
+    Ignore,
+    Warn,
+    Refuse,
+    UpdateInstead,
+}
+
+// Take the "<old> <new> <ref>" commands (the first with the capabilities the client uses)
+// and, unless they only delete, the pack after them. The refs are updated if the checks
+// allow, and the outcome reported as "unpack ok" and an "ok <ref>" or "ng <ref> <reason>"
+// line for each.
+pub(crate) fn serve(input: &mut impl BufRead, output: &mut impl Write) -> anyhow::Result<()> {
+    if at_end(input)? {
+        return Ok(());
+    }
+    let mut commands = Vec::new();
+    let mut capabilities = Vec::new();
+    while let Some(line) = pktline::read_line(input)? {
+        let line = match line.split_once('\0') {
+            Some((line, requested)) if commands.is_empty() => {
+                capabilities = requested.split(' ').map(str::to_string).collect();
+                line.to_string()
+            }
+            _ => line,
+        };
+        let mut words = line.splitn(3, ' ');
+        let (Some(old), Some(new), Some(name)) = (words.next(), words.next(), words.next()) else {
+            anyhow::bail!("protocol error: expected old/new/ref, got '{line}'");
+        };
+        commands.push(Command {
+            name: name.to_string(),
+            old: old.to_string(),
+            new: new.to_string(),
+            error: None,
+            update_worktree: false,
+        });
+    }
+    if commands.is_empty() {
+        return Ok(());
+    }
+    let has = |name: &str| capabilities.iter().any(|capability| capability == name);
+    let sideband = has("side-band-64k");
+    let mut messages = Vec::new();
+
+    let unpack_error = if commands.iter().any(|command| !refs::is_null(&command.new)) {
+        match pack::index_pack(&mut *input) {
+            Ok(_) => None,
+            Err(e) => Some(format!("{e:#}")),
+        }
+    } else {
+        None
+    };
+    match &unpack_error {
+        Some(_) => {
+            for command in &mut commands {
+                command.error = Some("unpacker error".to_string());
+            }
+        }
+        None => {
+            let config = Config::load()?;
+            // What the refs lead to already is known to be complete
+            let existing: Vec<String> = refs::list("refs/")?.into_iter().map(|(_, hash)| hash).collect();
+            for command in &mut commands {
+                command.error = check(command, &config, &existing, &mut messages)?;
+            }
+            if has("atomic") {
+                update_atomically(&mut commands, &mut messages);
+            } else {
+                for command in commands.iter_mut().filter(|command| command.error.is_none()) {
+                    if let Err(e) = update(command) {
+                        messages.push(format!("error: {e:#}"));
+                        command.error = Some("failed to update ref".to_string());
+                    }
+                }
+            }
+        }
+    }
+
+    let mut report = Vec::new();
+    pktline::write_line(&mut report, &format!("unpack {}", unpack_error.as_deref().unwrap_or("ok")))?;
+    for command in &commands {
+        match &command.error {
+            None => pktline::write_line(&mut report, &format!("ok {}", command.name))?,
+            Some(error) => pktline::write_line(&mut report, &format!("ng {} {error}", command.name))?,
+        }
+    }
+    pktline::flush(&mut report)?;
+
+    // Messages go where the client shows them as "remote: ..."
+    for message in messages {
+        if sideband {
+            send_sideband(output, 2, format!("{message}\n").as_bytes())?;
+        } else {
+            eprintln!("{message}");
+        }
+    }
+    if has("report-status") {
+        if sideband {
+            send_sideband(output, 1, &report)?;
+        } else {
+            output.write_all(&report)?;
+        }
+    }
+    if sideband {
+        pktline::flush(output)?;
+    }
+    output.flush()?;
+    Ok(())
+}
+
+// Update a ref on its own, and the working tree first if it follows the ref.
+fn update(command: &Command) -> anyhow::Result<()> {
+    if command.update_worktree {
+        update_worktree(&command.old, &command.new)?;
+    }
+    if refs::is_null(&command.new) {
+        refs::delete(&command.name, Some(&command.old))
+    } else {
+        refs::update(&command.name, &command.new, Some(&command.old), "push")
+    }
+}
+
+// Update every ref or, if one of them can't be (refused by the checks or failing now), none:
+// the refs are all locked and checked before any of them changes.
+fn update_atomically(commands: &mut [Command], messages: &mut Vec<String>) {
+    if commands.iter().any(|command| command.error.is_some()) {
+        for command in commands.iter_mut() {
+            command.error.get_or_insert_with(|| "atomic push failure".to_string());
+        }
+        return;
+    }
+    let result = match commands.iter().find(|command| command.update_worktree) {
+        Some(command) => update_worktree(&command.old, &command.new),
+        None => Ok(()),
+    };
+    let result = result.and_then(|()| {
+        let updates: Vec<(String, String, String)> = commands
+            .iter()
+            .map(|command| (command.name.clone(), command.new.clone(), command.old.clone()))
+            .collect();
+        refs::update_all(&updates, "push")
+    });
+    if let Err(e) = result {
+        messages.push(format!("error: {e:#}"));
+        for command in commands.iter_mut() {
+            command.error = Some("atomic transaction failed".to_string());
+        }
+    }
+}
+
+// Bring the working tree and index of the checked out branch from `old` to `new`, which
+// (like git's receive.denyCurrentBranch=updateInstead) is refused unless both match HEAD.
+fn update_worktree(old: &str, new: &str) -> anyhow::Result<()> {
+    let mut index = Index::load()?;
+    for entry in &index.entries {
+        if entry.stage() != 0 || worktree::is_modified(entry, index.mtime)? {
+            anyhow::bail!("Working directory has unstaged changes");
+        }
+    }
+    let old_tree = revision::peel(old, Kind::Tree)?;
+    let staged: BTreeMap<String, (u32, ObjectId)> =
+        index.entries.iter().map(|entry| (entry.path.clone(), (entry.mode, entry.hash))).collect();
+    if staged != Tree::flatten(&old_tree)? {
+        anyhow::bail!("Working directory has staged changes");
+    }
+    let new_tree = revision::peel(new, Kind::Tree)?;
+    checkout::switch_trees(&mut index, Some(&old_tree), &new_tree, false, Operation::Checkout)
+        .context("Could not update working tree to new HEAD")?;
+    index.write()
+}
+
+// Why `command` may not go ahead, if there is a reason, like `git receive-pack`: only refs
+// under refs/ may change, everything the new value leads to must be there (down to what the
+// `existing` ref values lead to), and receive.denyCurrentBranch, receive.denyDeleteCurrent,
+// receive.denyDeletes and receive.denyNonFastForwards are honored. A setting with a value
+// that makes no sense refuses the changes it covers.
+fn check(
+    command: &mut Command,
+    config: &Config,
+    existing: &[String],
+    messages: &mut Vec<String>,
+) -> anyhow::Result<Option<String>> {
+    let name = &command.name;
+    if !name.starts_with("refs/") || !refs::valid_name(name) {
+        messages.push(format!("error: refusing to create funny ref '{name}' remotely"));
+        return Ok(Some("funny refname".to_string()));
+    }
+    let current = match refs::resolve(name)? {
+        Some(current) => current,
+        None => refs::null_hash()?,
+    };
+    if command.old != current {
+        messages.push(format!(
+            "error: cannot lock ref '{name}': is at {current} but expected {}",
+            command.old
+        ));
+        return Ok(Some("failed to update ref".to_string()));
+    }
+    // A bare repository has no working tree to get out of step with its branch
+    let checked_out = !repository::is_bare() && refs::symbolic_target("HEAD")?.as_deref() == Some(name.as_str());
+
+    if refs::is_null(&command.new) {
+        if denies(config, "receive.denyDeletes", messages) {
+            messages.push(format!("error: denying ref deletion for {name}"));
+            return Ok(Some("deletion prohibited".to_string()));
+        }
+        if checked_out {
+            match deny(config, "receive.denyDeleteCurrent", Deny::Refuse, messages) {
+                Deny::Ignore => {}
+                Deny::Warn => messages.push("warning: deleting the current branch".to_string()),
+                Deny::Refuse | Deny::UpdateInstead => {
+                    messages.push(format!("error: refusing to delete the current branch: {name}"));
+                    return Ok(Some("deletion of the current branch prohibited".to_string()));
+                }
+            }
+        }
+        return Ok(None);
+    }
+
+    // A pack may name a tip without the objects below it, which would leave the repository
+    // corrupt; walking the new history reads every object it needs
+    if let Err(e) = pack::collect(std::slice::from_ref(&command.new), existing, false, &pack::Omit::default()) {
+        messages.push(format!("error: {e:#}"));
+        return Ok(Some("missing necessary objects".to_string()));
+    }
+    let object = Object::read(&command.new)?;
+    if checked_out {
+        match deny(config, "receive.denyCurrentBranch", Deny::Refuse, messages) {
+            Deny::Ignore => {}
+            Deny::Warn => messages.push("warning: updating the current branch".to_string()),
+            Deny::UpdateInstead => command.update_worktree = true,
+            Deny::Refuse => {
+                messages.push(format!("error: refusing to update checked out branch: {name}"));
+                return Ok(Some("branch is currently checked out".to_string()));
+            }
+        }
+    }
+    if !refs::is_null(&command.old)
+        && object.kind == Kind::Commit
+        && denies(config, "receive.denyNonFastForwards", messages)
+        && !revision::is_ancestor(&command.old, &command.new)?
+    {
+        messages.push(format!("error: denying non-fast-forward {name} (you should pull first)"));
+        return Ok(Some("non-fast-forward".to_string()));
+    }
+    Ok(None)
+}
+
+// What the receive.deny* setting `key` does: "ignore", "warn", "refuse" or "updateInstead" in
+// any case, or a boolean for refusing or not, `unset` if it isn't there. A value that is none
+// of these is reported and refuses.
+fn deny(config: &Config, key: &str, unset: Deny, messages: &mut Vec<String>) -> Deny {
+    let Some(value) = config.get(key) else {
+        return unset;
+    };
+    for (name, deny) in [
+        ("ignore", Deny::Ignore),
+        ("warn", Deny::Warn),
+        ("refuse", Deny::Refuse),
+        ("updateInstead", Deny::UpdateInstead),
+    ] {
+        if value.eq_ignore_ascii_case(name) {
+            return deny;
+        }
+    }
+    match config.get_bool(key) {
+        Ok(Some(true)) => Deny::Refuse,
+        Ok(Some(false)) => Deny::Ignore,
+        Ok(None) => unset,
+        Err(e) => {
+            messages.push(format!("error: {e:#}"));
+            Deny::Refuse
+        }
+    }
+}
+
+// Whether the boolean receive.deny* setting `key` is on; a value that isn't a boolean is
+// reported and refuses.
+fn denies(config: &Config, key: &str, messages: &mut Vec<String>) -> bool {
+    match config.get_bool(key) {
+        Ok(value) => value.unwrap_or(false),
+        Err(e) => {
+            messages.push(format!("error: {e:#}"));
+            true
+        }
+    }
+}
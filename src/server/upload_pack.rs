@@ -0,0 +1,463 @@
+use super::{at_end, send_sideband};
+use crate::objects::{self, Format, Kind, Object};
+use crate::pack::{self, DeltaOptions, Omit, PackObject};
+use crate::refs;
+use crate::transport::{pktline, AGENT};
+use crate::{revision, shallow};
+use anyhow::Context;
+use std::collections::{HashSet, VecDeque};
+use std::io::{BufRead, Write};
+
+// What protocol v0 clients may ask for in their first want line.
+const CAPABILITIES: &[&str] =
+    &["multi_ack", "multi_ack_detailed", "side-band-64k", "thin-pack", "ofs-delta", "include-tag", "no-progress"];
+
+// Open a session: for v0 the refs (HEAD first, annotated tags followed by what they peel to)
+// with the capabilities on the first line, for v2 just the capabilities.
+pub(crate) fn advertise(version: u8, output: &mut impl Write) -> anyhow::Result<()> {
+    let object_format = format!("object-format={}", Format::current()?);
+    if version == 2 {
+        pktline::write_line(output, "version 2")?;
+        for capability in [&format!("agent={AGENT}"), "ls-refs=unborn", "fetch=shallow filter", &object_format] {
+            pktline::write_line(output, capability)?;
+        }
+        pktline::flush(output)?;
+        output.flush()?;
+        return Ok(());
+    }
+
+    let mut capabilities: Vec<String> = CAPABILITIES.iter().map(|capability| capability.to_string()).collect();
+    if let Some(target) = refs::symbolic_target("HEAD")? {
+        capabilities.push(format!("symref=HEAD:{target}"));
+    }
+    capabilities.push(object_format);
+    capabilities.push(format!("agent={AGENT}"));
+
+    let mut lines = Vec::new();
+    if let Some(head) = refs::resolve("HEAD")? {
+        lines.push(format!("{head} HEAD"));
+    }
+    for (name, hash) in refs::list("refs/")? {
+        lines.push(format!("{hash} {name}"));
+        if let Some(peeled) = peeled(&hash)? {
+            lines.push(format!("{peeled} {name}^{{}}"));
+        }
+    }
+    // Without any refs, a made-up one carries the capabilities
+    if lines.is_empty() {
+        lines.push(format!("{} capabilities^{{}}", refs::null_hash()?));
+    }
+    lines[0] = format!("{}\0{}", lines[0], capabilities.join(" "));
+    for line in lines {
+        pktline::write_line(output, &line)?;
+    }
+    pktline::flush(output)?;
+    output.flush()?;
+    Ok(())
+}
+
+// Answer what the client asks for after the advertisement; with `stateless` (smart HTTP)
+// there is a single request, and the client repeats what it has learned in every one.
+pub(crate) fn serve(
+    version: u8,
+    stateless: bool,
+    input: &mut impl BufRead,
+    output: &mut impl Write,
+) -> anyhow::Result<()> {
+    if version == 2 {
+        serve_v2(stateless, input, output)
+    } else {
+        serve_v0(stateless, input, output)
+    }
+}
+
+// Protocol v0: "want <hash>" lines (the first with the capabilities the client uses) and a
+// flush, then rounds of "have <hash>" lines ending in a flush, until "done". Every have we
+// know of is acknowledged, and once the client is done the pack follows.
+fn serve_v0(stateless: bool, input: &mut impl BufRead, output: &mut impl Write) -> anyhow::Result<()> {
+    // A client that wants nothing (like ls-remote) hangs up right away
+    if at_end(input)? {
+        return Ok(());
+    }
+    let mut wants = Vec::new();
+    let mut capabilities = Vec::new();
+    while let Some(line) = pktline::read_line(input)? {
+        let Some(rest) = line.strip_prefix("want ") else {
+            anyhow::bail!("protocol error: expected want, got '{line}'");
+        };
+        let mut words = rest.split(' ');
+        let want = words.next().unwrap_or_default();
+        if wants.is_empty() {
+            capabilities = words.map(str::to_string).collect();
+        }
+        check_want(want, output)?;
+        wants.push(want.to_string());
+    }
+    if wants.is_empty() {
+        return Ok(());
+    }
+    let has = |name: &str| capabilities.iter().any(|capability| capability == name);
+    let multi_ack = if has("multi_ack_detailed") {
+        2
+    } else if has("multi_ack") {
+        1
+    } else {
+        0
+    };
+
+    let mut common: Vec<String> = Vec::new();
+    loop {
+        let line = match pktline::read(input)? {
+            pktline::Packet::Flush => {
+                if common.is_empty() || multi_ack > 0 {
+                    pktline::write_line(output, "NAK")?;
+                }
+                output.flush()?;
+                if stateless {
+                    return Ok(());
+                }
+                continue;
+            }
+            packet => packet.text().unwrap_or_default().to_string(),
+        };
+        if line == "done" {
+            match common.last() {
+                Some(last) if multi_ack > 0 => pktline::write_line(output, &format!("ACK {last}"))?,
+                Some(_) => {}
+                None => pktline::write_line(output, "NAK")?,
+            }
+            break;
+        }
+        let Some(have) = line.strip_prefix("have ") else {
+            anyhow::bail!("protocol error: expected have or done, got '{line}'");
+        };
+        if objects::exists(have)? && !common.iter().any(|known| known == have) {
+            common.push(have.to_string());
+            match multi_ack {
+                2 => pktline::write_line(output, &format!("ACK {have} common"))?,
+                1 => pktline::write_line(output, &format!("ACK {have} continue"))?,
+                _ if common.len() == 1 => pktline::write_line(output, &format!("ACK {have}"))?,
+                _ => {}
+            }
+        }
+    }
+
+    let (data, _) = build_pack(&wants, &common, has("thin-pack"), has("include-tag"), &Omit::default())?;
+    if has("side-band-64k") {
+        send_sideband(output, 1, &data)?;
+        pktline::flush(output)?;
+    } else {
+        output.write_all(&data)?;
+    }
+    output.flush()?;
+    Ok(())
+}
+
+// Protocol v2: "command=<name>", capabilities, a delim and the arguments, ending in a flush.
+// A flush on its own ends the session.
+fn serve_v2(stateless: bool, input: &mut impl BufRead, output: &mut impl Write) -> anyhow::Result<()> {
+    loop {
+        if at_end(input)? {
+            return Ok(());
+        }
+        let command = match pktline::read(input)? {
+            pktline::Packet::Flush => return Ok(()),
+            packet => packet.text().unwrap_or_default().to_string(),
+        };
+        let Some(command) = command.strip_prefix("command=") else {
+            anyhow::bail!("protocol error: expected a command, got '{command}'");
+        };
+        // The capabilities the client uses (agent, object-format) make no difference here
+        let mut args = Vec::new();
+        let mut in_args = false;
+        loop {
+            match pktline::read(input)? {
+                pktline::Packet::Delim => in_args = true,
+                pktline::Packet::Flush => break,
+                packet if in_args => args.push(packet.text().unwrap_or_default().to_string()),
+                _ => {}
+            }
+        }
+        match command {
+            "ls-refs" => ls_refs(&args, output)?,
+            "fetch" => fetch(&args, output)?,
+            _ => anyhow::bail!("unknown command '{command}'"),
+        }
+        output.flush()?;
+        if stateless {
+            return Ok(());
+        }
+    }
+}
+
+// "<hash> <name> [symref-target:<target>] [peeled:<hash>]" for HEAD and every ref, or just
+// those starting with one of the "ref-prefix" arguments.
+fn ls_refs(args: &[String], output: &mut impl Write) -> anyhow::Result<()> {
+    let mut symrefs = false;
+    let mut peel = false;
+    let mut unborn = false;
+    let mut prefixes = Vec::new();
+    for arg in args {
+        match arg.as_str() {
+            "symrefs" => symrefs = true,
+            "peel" => peel = true,
+            "unborn" => unborn = true,
+            _ => match arg.strip_prefix("ref-prefix ") {
+                Some(prefix) => prefixes.push(prefix),
+                None => anyhow::bail!("unexpected line: '{arg}'"),
+            },
+        }
+    }
+
+    let mut listed = Vec::new();
+    if let Some(head) = refs::resolve("HEAD")? {
+        listed.push(("HEAD".to_string(), Some(head)));
+    } else if unborn {
+        listed.push(("HEAD".to_string(), None));
+    }
+    listed.extend(refs::list("refs/")?.into_iter().map(|(name, hash)| (name, Some(hash))));
+    for (name, hash) in listed {
+        if !prefixes.is_empty() && !prefixes.iter().any(|prefix| name.starts_with(prefix)) {
+            continue;
+        }
+        let mut line = format!("{} {name}", hash.as_deref().unwrap_or("unborn"));
+        if symrefs || hash.is_none() {
+            if let Some(target) = refs::symbolic_target(&name)? {
+                line.push_str(&format!(" symref-target:{target}"));
+            }
+        }
+        if let (true, Some(hash)) = (peel, &hash) {
+            if let Some(peeled) = peeled(hash)? {
+                line.push_str(&format!(" peeled:{peeled}"));
+            }
+        }
+        pktline::write_line(output, &line)?;
+    }
+    pktline::flush(output)?;
+    Ok(())
+}
+
+// How far back the history a client asks for goes, if not all the way.
+#[derive(Debug, Default)]
+struct Deepen {
+    depth: Option<u32>,
+    // The depth counts from the client's shallow commits rather than from what it wants
+    relative: bool,
+    since: Option<i64>,
+    exclude: Vec<String>,
+}
+
+// Acknowledge the haves we know of, and unless the client wants to keep negotiating, send
+// the pack in a "packfile" section. A client that is or is to become shallow learns which
+// commits it gets without their parents first, in a "shallow-info" section.
+fn fetch(args: &[String], output: &mut impl Write) -> anyhow::Result<()> {
+    let mut wants = Vec::new();
+    let mut common = Vec::new();
+    let mut done = false;
+    let mut thin = false;
+    let mut include_tag = false;
+    let mut client_shallow = HashSet::new();
+    let mut deepen = Deepen::default();
+    let mut filter = None;
+    for arg in args {
+        match arg.as_str() {
+            "done" => done = true,
+            "thin-pack" => thin = true,
+            "include-tag" => include_tag = true,
+            "ofs-delta" | "no-progress" => {}
+            "deepen-relative" => deepen.relative = true,
+            _ => {
+                if let Some(want) = arg.strip_prefix("want ") {
+                    check_want(want, output)?;
+                    wants.push(want.to_string());
+                } else if let Some(have) = arg.strip_prefix("have ") {
+                    if objects::exists(have)? {
+                        common.push(have.to_string());
+                    }
+                } else if let Some(commit) = arg.strip_prefix("shallow ") {
+                    client_shallow.insert(commit.to_string());
+                } else if let Some(depth) = arg.strip_prefix("deepen ") {
+                    let depth = depth.parse().ok().filter(|&depth| depth > 0);
+                    deepen.depth = Some(depth.with_context(|| format!("bad depth in '{arg}'"))?);
+                } else if let Some(since) = arg.strip_prefix("deepen-since ") {
+                    deepen.since = Some(since.parse().with_context(|| format!("bad time in '{arg}'"))?);
+                } else if let Some(rev) = arg.strip_prefix("deepen-not ") {
+                    deepen.exclude.push(rev.to_string());
+                } else if let Some(spec) = arg.strip_prefix("filter ") {
+                    filter = Some(spec.parse()?);
+                } else {
+                    anyhow::bail!("unexpected line: '{arg}'");
+                }
+            }
+        }
+    }
+
+    if !done {
+        pktline::write_line(output, "acknowledgments")?;
+        if common.is_empty() {
+            pktline::write_line(output, "NAK")?;
+        }
+        for have in &common {
+            pktline::write_line(output, &format!("ACK {have}"))?;
+        }
+        // Anything in common is enough to go on with
+        if common.is_empty() {
+            pktline::flush(output)?;
+            return Ok(());
+        }
+        pktline::write_line(output, "ready")?;
+        pktline::delim(output)?;
+    }
+
+    // The client has its shallow commits, but nothing of their history
+    let mut omit = Omit { shallow_haves: client_shallow.clone(), shallow: HashSet::new(), filter };
+    let mut lines = Vec::new();
+    let mut unshallow = Vec::new();
+    let deepening = deepen.depth.is_some() || deepen.since.is_some() || !deepen.exclude.is_empty();
+    if deepening {
+        (omit.shallow, unshallow) = shallow_boundary(&wants, &client_shallow, &deepen)?;
+        let new_shallow = omit.shallow.iter().filter(|commit| !client_shallow.contains(*commit));
+        lines.extend(new_shallow.map(|commit| format!("shallow {commit}")));
+        for commit in &unshallow {
+            // The client gets the history it lacked of these
+            lines.push(format!("unshallow {commit}"));
+            wants.extend(revision::read_commit(commit)?.parents);
+        }
+    }
+    common.extend(client_shallow.iter().filter(|commit| !unshallow.contains(*commit)).cloned());
+    common.extend(unshallow);
+
+    let (data, commits) = build_pack(&wants, &common, thin, include_tag, &omit)?;
+    // Where our own history stops, the client's does as well
+    for commit in commits {
+        let line = format!("shallow {commit}");
+        if shallow::contains(&commit)? && !client_shallow.contains(&commit) && !lines.contains(&line) {
+            lines.push(line);
+        }
+    }
+    if deepening || !lines.is_empty() {
+        pktline::write_line(output, "shallow-info")?;
+        for line in &lines {
+            pktline::write_line(output, line)?;
+        }
+        pktline::delim(output)?;
+    }
+    pktline::write_line(output, "packfile")?;
+    send_sideband(output, 1, &data)?;
+    pktline::flush(output)?;
+    Ok(())
+}
+
+// Where the history of `wants` is cut off for a client asking to deepen it: the commits
+// that come without their parents, and those of the client's `shallow` commits that come
+// with them now.
+fn shallow_boundary(
+    wants: &[String],
+    shallow: &HashSet<String>,
+    deepen: &Deepen,
+) -> anyhow::Result<(HashSet<String>, Vec<String>)> {
+    let mut excluded = HashSet::new();
+    for rev in &deepen.exclude {
+        let Some(name) = refs::dwim(rev)? else {
+            anyhow::bail!("git upload-pack: deepen-not is not a ref: {rev}");
+        };
+        let hash = refs::resolve(&name)?.with_context(|| format!("resolve {name}"))?;
+        excluded.extend(revision::reachable(&revision::peel(&hash, Kind::Commit)?)?);
+    }
+
+    // Breadth first, so every commit is reached at its smallest depth first. Deepening by
+    // n relative to the client's shallow commits gives each of them n more generations, and
+    // leaves history that doesn't go through them uncut, as git does.
+    let (starts, limit) = match deepen.depth {
+        Some(depth) if deepen.relative => (shallow.iter().cloned().collect(), Some(depth + 1)),
+        depth => (wants.to_vec(), depth),
+    };
+    let mut pending: VecDeque<(String, u32)> = VecDeque::new();
+    for start in starts {
+        if let Ok(commit) = revision::peel(&start, Kind::Commit) {
+            pending.push_back((commit, 1));
+        }
+    }
+    let mut included = HashSet::new();
+    let mut boundary = HashSet::new();
+    while let Some((hash, depth)) = pending.pop_front() {
+        if !included.insert(hash.clone()) {
+            continue;
+        }
+        let commit = revision::read_commit(&hash)?;
+        let mut cut = limit.is_some_and(|limit| depth >= limit);
+        for parent in &commit.parents {
+            cut |= excluded.contains(parent);
+            if let Some(since) = deepen.since {
+                cut |= revision::read_commit(parent)?.committer.time < since;
+            }
+        }
+        if cut && !commit.parents.is_empty() {
+            boundary.insert(hash);
+            continue;
+        }
+        pending.extend(commit.parents.into_iter().map(|parent| (parent, depth + 1)));
+    }
+    let unshallow = shallow
+        .iter()
+        .filter(|commit| included.contains(*commit) && !boundary.contains(*commit))
+        .cloned()
+        .collect();
+    Ok((boundary, unshallow))
+}
+
+// Clients may only ask for objects we have; this is no place to probe for others.
+fn check_want(want: &str, output: &mut impl Write) -> anyhow::Result<()> {
+    if !objects::exists(want)? {
+        let message = format!("upload-pack: not our ref {want}");
+        pktline::write_line(output, &format!("ERR {message}"))?;
+        output.flush()?;
+        anyhow::bail!(message);
+    }
+    Ok(())
+}
+
+// The pack with everything `wants` need that `common` doesn't have, short of what `omit`
+// leaves out, and with `include_tag` the annotated tags of anything in it. Also returns the
+// commits in it.
+fn build_pack(
+    wants: &[String],
+    common: &[String],
+    thin: bool,
+    include_tag: bool,
+    omit: &Omit,
+) -> anyhow::Result<(Vec<u8>, Vec<String>)> {
+    let mut selection = pack::collect(wants, common, thin, omit).context("find objects to send")?;
+    let sent: HashSet<String> = selection.objects.iter().map(|object| object.id.to_string()).collect();
+    if include_tag {
+        for (_, hash) in refs::list("refs/tags/")? {
+            if sent.contains(&hash) || common.contains(&hash) {
+                continue;
+            }
+            let Some(peeled) = peeled(&hash)? else { continue };
+            if sent.contains(&peeled) {
+                let data = Object::read(&hash)?.into_bytes()?;
+                let id = hash.parse().context("decode tag hash")?;
+                selection.objects.push(PackObject { id, kind: Kind::Tag, data });
+            }
+        }
+    }
+    let commits = selection
+        .objects
+        .iter()
+        .filter(|object| object.kind == Kind::Commit)
+        .map(|object| object.id.to_string())
+        .collect();
+    let mut data = Vec::new();
+    pack::write_pack(&selection.objects, &selection.bases, DeltaOptions::default(), &mut data)
+        .context("write pack")?;
+    Ok((data, commits))
+}
+
+// What an annotated tag points at in the end; None for anything else.
+fn peeled(hash: &str) -> anyhow::Result<Option<String>> {
+    if Object::read(hash)?.kind != Kind::Tag {
+        return Ok(None);
+    }
+    Ok(Some(revision::peel_tags(hash)?))
+}
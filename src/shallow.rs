@@ -0,0 +1,68 @@
+use crate::lockfile::Lockfile;
+use crate::repository;
+use anyhow::Context;
+use std::collections::BTreeSet;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+// A shallow repository has only part of its history: .git/shallow lists the commits whose
+// parents it doesn't have, one hash per line. History stops at them, as if they were root
+// commits.
+
+// .git/shallow as last read or written, keyed by the directory it was found in.
+static SHALLOW: Mutex<Option<(PathBuf, Arc<BTreeSet<String>>)>> = Mutex::new(None);
+
+// The commits of the repository whose parents are missing; empty if it isn't shallow.
+pub(crate) fn commits() -> anyhow::Result<Arc<BTreeSet<String>>> {
+    let dir = std::env::current_dir().context("get current directory")?;
+    let mut cache = SHALLOW.lock().expect("shallow cache poisoned");
+    if let Some((cached_dir, commits)) = cache.as_ref() {
+        if *cached_dir == dir {
+            return Ok(commits.clone());
+        }
+    }
+    let commits: BTreeSet<String> = match std::fs::read_to_string(repository::git_dir().join("shallow")) {
+        Ok(text) => text.lines().filter(|line| !line.is_empty()).map(str::to_string).collect(),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeSet::new(),
+        Err(e) => return Err(e).context("read .git/shallow"),
+    };
+    let commits = Arc::new(commits);
+    *cache = Some((dir, commits.clone()));
+    Ok(commits)
+}
+
+// Whether history stops at `commit`.
+pub(crate) fn contains(commit: &str) -> anyhow::Result<bool> {
+    Ok(commits()?.contains(commit))
+}
+
+// Record that the parents of `added` are missing, and those of `removed` no longer are.
+pub(crate) fn update(added: &[String], removed: &[String]) -> anyhow::Result<()> {
+    if added.is_empty() && removed.is_empty() {
+        return Ok(());
+    }
+    let mut commits = (*commits()?).clone();
+    commits.extend(added.iter().cloned());
+    for commit in removed {
+        commits.remove(commit);
+    }
+
+    if commits.is_empty() {
+        // All of history is here again
+        match std::fs::remove_file(repository::git_dir().join("shallow")) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e).context("remove .git/shallow"),
+        }
+    } else {
+        let mut lock = Lockfile::acquire(repository::git_dir().join("shallow"))?;
+        for commit in &commits {
+            writeln!(lock, "{commit}").context("write .git/shallow")?;
+        }
+        lock.commit()?;
+    }
+    let dir = std::env::current_dir().context("get current directory")?;
+    *SHALLOW.lock().expect("shallow cache poisoned") = Some((dir, Arc::new(commits)));
+    Ok(())
+}
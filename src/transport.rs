@@ -0,0 +1,180 @@
+use anyhow::Context;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::PathBuf;
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+
+pub(crate) mod pktline;
+mod receive_pack;
+mod upload_pack;
+
+pub(crate) use receive_pack::{ReceivePack, RefUpdate};
+pub(crate) use upload_pack::{Limits, RemoteRef, UploadPack};
+
+// How we introduce ourselves to servers. Some smart HTTP hosts only speak the protocol to
+// user agents that start with "git/".
+pub(crate) const AGENT: &str = concat!("git-like-vcs/", env!("CARGO_PKG_VERSION"));
+
+// Where a repository lives, as given by a URL or path.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Location {
+    // A path, or a file:// URL
+    Local(PathBuf),
+    // An http:// or https:// URL, without a trailing slash
+    Http(String),
+}
+
+impl Location {
+    pub(crate) fn parse(url: &str) -> anyhow::Result<Location> {
+        if url.starts_with("http://") || url.starts_with("https://") {
+            return Ok(Location::Http(url.trim_end_matches('/').to_string()));
+        }
+        if let Some(path) = url.strip_prefix("file://") {
+            return Ok(Location::Local(PathBuf::from(path)));
+        }
+        if let Some((scheme, _)) = url.split_once("://") {
+            anyhow::bail!("unsupported URL scheme '{scheme}' in {url}");
+        }
+        Ok(Location::Local(PathBuf::from(url)))
+    }
+}
+
+// The program on the remote end: upload-pack sends objects, receive-pack takes them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Service {
+    UploadPack,
+    ReceivePack,
+}
+
+impl Service {
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Service::UploadPack => "git-upload-pack",
+            Service::ReceivePack => "git-receive-pack",
+        }
+    }
+}
+
+// The connection to a service, which starts with the service advertising its refs or
+// capabilities. Requests are pkt-line messages, each with a response to read.
+enum Channel {
+    // The service running on this machine, talking over its stdin and stdout
+    Process { child: Child, stdin: Option<ChildStdin>, stdout: BufReader<ChildStdout> },
+    // Smart HTTP: the advertisement comes from GET info/refs, and every request is a POST
+    // whose response replaces the previous one
+    Http { url: String, agent: ureq::Agent, service: Service, response: Box<dyn BufRead + Send> },
+}
+
+impl Channel {
+    // Start `service` for the repository at `url`. Local repositories are served by running
+    // `program` (a shell command) with the repository path as its argument.
+    fn open(url: &str, service: Service, program: &str) -> anyhow::Result<Channel> {
+        // Only upload-pack speaks protocol v2
+        let protocol = (service == Service::UploadPack).then_some("version=2");
+        match Location::parse(url)? {
+            Location::Local(path) => {
+                let mut command = Command::new("sh");
+                command.arg("-c").arg(format!("{program} \"$@\"")).arg(program).arg(&path);
+                if let Some(protocol) = protocol {
+                    command.env("GIT_PROTOCOL", protocol);
+                }
+                let mut child = command
+                    .stdin(Stdio::piped())
+                    .stdout(Stdio::piped())
+                    .spawn()
+                    .with_context(|| format!("run {program}"))?;
+                let stdin = child.stdin.take();
+                let stdout = BufReader::new(child.stdout.take().expect("stdout is piped"));
+                Ok(Channel::Process { child, stdin, stdout })
+            }
+            Location::Http(url) => {
+                let name = service.name();
+                let agent = ureq::AgentBuilder::new().user_agent(&format!("git/2.0 ({AGENT})")).build();
+                let mut request = agent.get(&format!("{url}/info/refs?service={name}"));
+                if let Some(protocol) = protocol {
+                    request = request.set("Git-Protocol", protocol);
+                }
+                let response = request.call().map_err(|e| http_error(e, &url))?;
+                anyhow::ensure!(
+                    response.content_type() == format!("application/x-{name}-advertisement"),
+                    "{url}/info/refs not valid: is this a git repository? (only smart HTTP is supported)"
+                );
+
+                // A protocol v0 advertisement starts off with "# service=<name>" and a flush,
+                // while v2 gets right to it
+                let mut response = BufReader::new(response.into_reader());
+                let first = pktline::read(&mut response)?;
+                if first.text() == Some(&format!("# service={name}")) {
+                    anyhow::ensure!(
+                        pktline::read(&mut response)? == pktline::Packet::Flush,
+                        "protocol error: bad smart HTTP service announcement"
+                    );
+                    return Ok(Channel::Http { url, agent, service, response: Box::new(response) });
+                }
+                let mut replayed = Vec::new();
+                match first {
+                    pktline::Packet::Data(data) => pktline::write(&mut replayed, &data)?,
+                    _ => anyhow::bail!("protocol error: bad smart HTTP service announcement"),
+                }
+                let response = Box::new(io::Cursor::new(replayed).chain(response));
+                Ok(Channel::Http { url, agent, service, response })
+            }
+        }
+    }
+
+    // Where the advertisement, and then the response to the last request, is read from.
+    fn reader(&mut self) -> &mut dyn BufRead {
+        match self {
+            Channel::Process { stdout, .. } => stdout,
+            Channel::Http { response, .. } => response,
+        }
+    }
+
+    fn send(&mut self, message: &[u8]) -> anyhow::Result<()> {
+        match self {
+            Channel::Process { stdin, .. } => {
+                let stdin = stdin.as_mut().context("the remote end hung up")?;
+                stdin
+                    .write_all(message)
+                    .and_then(|()| stdin.flush())
+                    .context("the remote end hung up unexpectedly")
+            }
+            Channel::Http { url, agent, service, response } => {
+                let name = service.name();
+                let mut request = agent
+                    .post(&format!("{url}/{name}"))
+                    .set("Content-Type", &format!("application/x-{name}-request"))
+                    .set("Accept", &format!("application/x-{name}-result"));
+                if *service == Service::UploadPack {
+                    request = request.set("Git-Protocol", "version=2");
+                }
+                let reply = request.send_bytes(message).map_err(|e| http_error(e, url))?;
+                *response = Box::new(BufReader::new(reply.into_reader()));
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Drop for Channel {
+    fn drop(&mut self) {
+        // A flush tells the services we're done, and they exit once they see the end of
+        // their input
+        if let Channel::Process { child, stdin, .. } = self {
+            if let Some(mut stdin) = stdin.take() {
+                let _ = pktline::flush(&mut stdin);
+            }
+            let _ = child.wait();
+        }
+    }
+}
+
+fn http_error(error: ureq::Error, url: &str) -> anyhow::Error {
+    match error {
+        ureq::Error::Status(404, _) => anyhow::anyhow!("repository '{url}/' not found"),
+        ureq::Error::Status(401 | 403, _) => anyhow::anyhow!("Authentication failed for '{url}/'"),
+        ureq::Error::Status(code, response) => {
+            anyhow::anyhow!("unable to access '{url}/': The requested URL returned error: {code} {}", response.status_text())
+        }
+        ureq::Error::Transport(e) => anyhow::anyhow!("unable to access '{url}/': {e}"),
+    }
+}
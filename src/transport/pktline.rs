@@ -0,0 +1,192 @@
+use anyhow::Context;
+use std::io::{self, Read, Write};
+
+// The largest payload of a pkt-line: 65520 bytes in all, less the 4-byte length prefix.
+pub(crate) const MAX_DATA: usize = 65516;
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Packet {
+    Data(Vec<u8>),
+    // "0000": the end of a message
+    Flush,
+    // "0001": separates the sections of a protocol v2 message
+    Delim,
+    // "0002": the end of a protocol v2 response over a stateless transport (HTTP)
+    ResponseEnd,
+}
+
+impl Packet {
+    // The payload as a line of text, without its trailing newline; None for special packets.
+    pub(crate) fn text(&self) -> Option<&str> {
+        match self {
+            Packet::Data(data) => {
+                let data = data.strip_suffix(b"\n").unwrap_or(data);
+                Some(std::str::from_utf8(data).unwrap_or_default())
+            }
+            _ => None,
+        }
+    }
+}
+
+pub(crate) fn read(reader: &mut (impl Read + ?Sized)) -> anyhow::Result<Packet> {
+    let mut len = [0; 4];
+    reader
+        .read_exact(&mut len)
+        .context("the remote end hung up unexpectedly")?;
+    let len = std::str::from_utf8(&len)
+        .ok()
+        .and_then(|len| usize::from_str_radix(len, 16).ok())
+        .ok_or_else(|| anyhow::anyhow!("protocol error: bad line length character: {}", String::from_utf8_lossy(&len)))?;
+    Ok(match len {
+        0 => Packet::Flush,
+        1 => Packet::Delim,
+        2 => Packet::ResponseEnd,
+        3 => anyhow::bail!("protocol error: bad line length {len}"),
+        _ => {
+            let mut data = vec![0; len - 4];
+            reader
+                .read_exact(&mut data)
+                .context("the remote end hung up unexpectedly")?;
+            Packet::Data(data)
+        }
+    })
+}
+
+// Read a line of text, or None at a flush or delim packet.
+pub(crate) fn read_line(reader: &mut (impl Read + ?Sized)) -> anyhow::Result<Option<String>> {
+    Ok(read(reader)?.text().map(str::to_string))
+}
+
+pub(crate) fn write(writer: &mut impl Write, data: &[u8]) -> io::Result<()> {
+    assert!(data.len() <= MAX_DATA, "pkt-line payload too long");
+    write!(writer, "{:04x}", data.len() + 4)?;
+    writer.write_all(data)
+}
+
+pub(crate) fn write_line(writer: &mut impl Write, line: &str) -> io::Result<()> {
+    write(writer, format!("{line}\n").as_bytes())
+}
+
+pub(crate) fn flush(writer: &mut impl Write) -> io::Result<()> {
+    writer.write_all(b"0000")
+}
+
+pub(crate) fn delim(writer: &mut impl Write) -> io::Result<()> {
+    writer.write_all(b"0001")
+}
+
+// The data sent on band 1 of a side-band-64k stream, which is what a pack arrives in.
+// Progress messages on band 2 are shown on stderr as "remote: ..." lines, and an error on
+// band 3 fails the read. The stream ends at a flush packet.
+pub(crate) struct Sideband<R> {
+    reader: R,
+    data: Vec<u8>,
+    pos: usize,
+    done: bool,
+    // Whether the next progress output starts a new line, which gets the "remote: " prefix
+    line_start: bool,
+}
+
+impl<R: Read> Sideband<R> {
+    pub(crate) fn new(reader: R) -> Sideband<R> {
+        Sideband { reader, data: Vec::new(), pos: 0, done: false, line_start: true }
+    }
+
+    fn progress(&mut self, message: &[u8]) {
+        let mut stderr = io::stderr().lock();
+        // Progress meters redraw their line with '\r', so both end a line
+        for part in message.split_inclusive(|&b| b == b'\n' || b == b'\r') {
+            if self.line_start {
+                let _ = stderr.write_all(b"remote: ");
+            }
+            let _ = stderr.write_all(part);
+            self.line_start = part.ends_with(b"\n") || part.ends_with(b"\r");
+        }
+    }
+}
+
+impl<R: Read> Read for Sideband<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.data.len() && !self.done {
+            let packet = read(&mut self.reader).map_err(|e| io::Error::other(format!("{e:#}")))?;
+            match packet {
+                Packet::Data(data) => match data.split_first() {
+                    Some((1, payload)) => {
+                        self.data = payload.to_vec();
+                        self.pos = 0;
+                    }
+                    Some((2, message)) => self.progress(message),
+                    Some((3, message)) => {
+                        let message = String::from_utf8_lossy(message);
+                        return Err(io::Error::other(format!("remote error: {}", message.trim_end())));
+                    }
+                    _ => return Err(io::Error::other("protocol error: bad band in side-band stream")),
+                },
+                _ => self.done = true,
+            }
+        }
+        let n = buf.len().min(self.data.len() - self.pos);
+        buf[..n].copy_from_slice(&self.data[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lines_and_special_packets_round_trip() {
+        let mut out = Vec::new();
+        write_line(&mut out, "want abc").unwrap();
+        delim(&mut out).unwrap();
+        write(&mut out, b"").unwrap();
+        flush(&mut out).unwrap();
+        out.extend_from_slice(b"0002");
+        assert_eq!(&out[..13], b"000dwant abc\n");
+
+        let mut input = &out[..];
+        assert_eq!(read_line(&mut input).unwrap().as_deref(), Some("want abc"));
+        assert_eq!(read(&mut input).unwrap(), Packet::Delim);
+        assert_eq!(read(&mut input).unwrap(), Packet::Data(Vec::new()));
+        assert_eq!(read(&mut input).unwrap(), Packet::Flush);
+        assert_eq!(read(&mut input).unwrap(), Packet::ResponseEnd);
+        assert!(read(&mut input).is_err());
+    }
+
+    #[test]
+    fn bad_lengths_are_protocol_errors() {
+        for bad in [&b"0003"[..], b"zzzz", b"0009abc"] {
+            assert!(read(&mut &bad[..]).is_err(), "{}", String::from_utf8_lossy(bad));
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "pkt-line payload too long")]
+    fn oversized_payloads_are_refused() {
+        write(&mut Vec::new(), &[0; MAX_DATA + 1]).unwrap();
+    }
+
+    #[test]
+    fn sideband_keeps_band_one_and_stops_at_flush() {
+        let mut stream = Vec::new();
+        write(&mut stream, b"\x01PACK").unwrap();
+        write(&mut stream, b"\x02counting objects\n").unwrap();
+        write(&mut stream, b"\x01data").unwrap();
+        flush(&mut stream).unwrap();
+        write(&mut stream, b"\x01after").unwrap();
+
+        let mut data = Vec::new();
+        Sideband::new(&stream[..]).read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"PACKdata");
+    }
+
+    #[test]
+    fn sideband_error_band_fails_the_read() {
+        let mut stream = Vec::new();
+        write(&mut stream, b"\x03access denied\n").unwrap();
+        let error = Sideband::new(&stream[..]).read_to_end(&mut Vec::new()).unwrap_err();
+        assert_eq!(error.to_string(), "remote error: access denied");
+    }
+}
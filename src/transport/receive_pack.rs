@@ -0,0 +1,176 @@
+use super::pktline::{self, Sideband};
+use super::{Channel, Service, AGENT};
+use crate::objects::Format;
+use crate::refs;
+use anyhow::Context;
+use std::io::{self, IsTerminal, Read};
+
+// A change to make to a ref on the remote. `old` is the null hash for a ref to create and
+// `new` the null hash for one to delete.
+#[derive(Debug, Clone)]
+pub(crate) struct RefUpdate {
+    pub(crate) name: String,
+    pub(crate) old: String,
+    pub(crate) new: String,
+}
+
+// A session with receive-pack on a remote (protocol v0, which is all it speaks), for
+// updating its refs.
+pub(crate) struct ReceivePack {
+    channel: Channel,
+    // The refs the remote has, as (name, hash)
+    pub(crate) refs: Vec<(String, String)>,
+    // Objects the remote has besides its refs (".have" lines, from alternates)
+    pub(crate) extra_haves: Vec<String>,
+    capabilities: Vec<String>,
+}
+
+impl ReceivePack {
+    // Connect to the repository at `url`, running `program` for local ones.
+    pub(crate) fn connect(url: &str, program: &str) -> anyhow::Result<ReceivePack> {
+        let mut channel = Channel::open(url, Service::ReceivePack, program)?;
+        let reader = channel.reader();
+        let mut refs = Vec::new();
+        let mut extra_haves = Vec::new();
+        let mut capabilities = Vec::new();
+
+        // "<hash> <name>\0<capabilities>" first (with a made-up "capabilities^{}" ref if
+        // there are no refs), then "<hash> <name>" lines
+        let mut first = true;
+        loop {
+            let packet = pktline::read(reader).context("could not read from remote repository")?;
+            let Some(line) = packet.text() else { break };
+            if first && line.starts_with("version ") {
+                continue;
+            }
+            let line = match line.split_once('\0') {
+                Some((line, advertised)) if first => {
+                    capabilities = advertised.split(' ').map(str::to_string).collect();
+                    line
+                }
+                _ => line,
+            };
+            first = false;
+            let Some((hash, name)) = line.split_once(' ') else {
+                anyhow::bail!("protocol error: bad ref advertisement line '{line}'");
+            };
+            match name {
+                "capabilities^{}" => {}
+                ".have" => extra_haves.push(hash.to_string()),
+                _ => refs.push((name.to_string(), hash.to_string())),
+            }
+        }
+        Ok(ReceivePack { channel, refs, extra_haves, capabilities })
+    }
+
+    // The value a capability was advertised with ("" if it has none), if it was.
+    pub(crate) fn capability(&self, name: &str) -> Option<&str> {
+        self.capabilities.iter().find_map(|capability| match capability.split_once('=') {
+            Some((key, value)) if key == name => Some(value),
+            None if capability == name => Some(""),
+            _ => None,
+        })
+    }
+
+    // The hash function the remote names its objects with: SHA-1 unless it says otherwise.
+    pub(crate) fn object_format(&self) -> anyhow::Result<Format> {
+        self.capability("object-format").map_or(Ok(Format::Sha1), str::parse)
+    }
+
+    // Send the ref updates, and the pack with the objects they need unless they are all
+    // deletions. Returns what became of each ref: Ok, or the reason the remote gave for
+    // refusing it.
+    pub(crate) fn push(
+        &mut self,
+        updates: &[RefUpdate],
+        pack: &[u8],
+        atomic: bool,
+    ) -> anyhow::Result<Vec<(String, Result<(), String>)>> {
+        let (ours, theirs) = (Format::current()?, self.object_format()?);
+        anyhow::ensure!(ours == theirs, "mismatched algorithms: client {ours}; server {theirs}");
+        let mut wanted = Vec::new();
+        for capability in ["report-status", "side-band-64k"] {
+            if self.capability(capability).is_some() {
+                wanted.push(capability.to_string());
+            }
+        }
+        // Progress is only worth showing to a person
+        if self.capability("quiet").is_some() && !io::stderr().is_terminal() {
+            wanted.push("quiet".to_string());
+        }
+        if atomic {
+            anyhow::ensure!(self.capability("atomic").is_some(), "the receiving end does not support --atomic push");
+            wanted.push("atomic".to_string());
+        }
+        if let Some(format) = self.capability("object-format") {
+            wanted.push(format!("object-format={format}"));
+        }
+        if self.capability("agent").is_some() {
+            wanted.push(format!("agent={AGENT}"));
+        }
+        if updates.iter().any(|update| refs::is_null(&update.new)) {
+            anyhow::ensure!(self.capability("delete-refs").is_some(), "the receiving end does not support deleting refs");
+        }
+
+        let mut message = Vec::new();
+        for (i, update) in updates.iter().enumerate() {
+            let mut line = format!("{} {} {}", update.old, update.new, update.name);
+            if i == 0 {
+                line.push('\0');
+                line.push_str(&wanted.join(" "));
+            }
+            pktline::write(&mut message, line.as_bytes())?;
+        }
+        pktline::flush(&mut message)?;
+        if updates.iter().any(|update| !refs::is_null(&update.new)) {
+            message.extend_from_slice(pack);
+        }
+        self.channel.send(&message)?;
+
+        let mut results: Vec<(String, Result<(), String>)> =
+            updates.iter().map(|update| (update.name.clone(), Ok(()))).collect();
+        if self.capability("report-status").is_none() {
+            return Ok(results);
+        }
+        let sideband = self.capability("side-band-64k").is_some();
+        let reader = self.channel.reader();
+        let mut report: Box<dyn Read + '_> = if sideband {
+            Box::new(Sideband::new(reader))
+        } else {
+            Box::new(reader)
+        };
+
+        // "unpack ok" (or the error), then "ok <ref>" or "ng <ref> <reason>" for every ref
+        let unpack = pktline::read_line(&mut report)?.unwrap_or_default();
+        let unpack_error = match unpack.strip_prefix("unpack ") {
+            Some("ok") => None,
+            Some(error) => Some(error.to_string()),
+            None => anyhow::bail!("protocol error: expected 'unpack' status, got '{unpack}'"),
+        };
+        while let Some(line) = pktline::read_line(&mut report)? {
+            let (name, result) = if let Some(name) = line.strip_prefix("ok ") {
+                (name, Ok(()))
+            } else if let Some(rest) = line.strip_prefix("ng ") {
+                let (name, reason) = rest.split_once(' ').unwrap_or((rest, "failed"));
+                (name, Err(reason.to_string()))
+            } else {
+                anyhow::bail!("protocol error: bad report-status line '{line}'");
+            };
+            if let Some(slot) = results.iter_mut().find(|(slot, _)| slot == name) {
+                slot.1 = result;
+            }
+        }
+        // The side-band stream ends with a flush of its own
+        if sideband {
+            io::copy(&mut report, &mut io::sink()).context("read the rest of the report")?;
+        }
+
+        if let Some(error) = unpack_error {
+            eprintln!("error: remote unpack failed: {error}");
+            for (_, result) in &mut results {
+                *result = Err("unpacker error".to_string());
+            }
+        }
+        Ok(results)
+    }
+}
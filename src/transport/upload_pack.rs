@@ -0,0 +1,330 @@
+use super::pktline::{self, Packet, Sideband};
+use super::{Channel, Service, AGENT};
+use crate::objects::{Format, Kind};
+use crate::{pack, revision, shallow};
+use anyhow::Context;
+use std::collections::{BinaryHeap, HashSet};
+use std::io::{self, BufReader, IsTerminal};
+use std::path::PathBuf;
+
+// A ref the remote has, as listed by ls-refs.
+#[derive(Debug, Clone)]
+pub(crate) struct RemoteRef {
+    pub(crate) name: String,
+    // None for an unborn HEAD (a remote without commits)
+    pub(crate) hash: Option<String>,
+    // What a symbolic ref (HEAD) points at
+    pub(crate) symref_target: Option<String>,
+    // What an annotated tag points at
+    pub(crate) peeled: Option<String>,
+}
+
+// A protocol v2 session with upload-pack on a remote, for listing its refs and fetching
+// objects from it.
+pub(crate) struct UploadPack {
+    channel: Channel,
+    // The capability advertisement: "ls-refs=unborn", "fetch=shallow filter", "agent=...", ...
+    capabilities: Vec<String>,
+}
+
+impl UploadPack {
+    // Connect to the repository at `url`, running `program` for local ones.
+    pub(crate) fn connect(url: &str, program: &str) -> anyhow::Result<UploadPack> {
+        let mut channel = Channel::open(url, Service::UploadPack, program)?;
+        let reader = channel.reader();
+        let first = pktline::read(reader).context("could not read from remote repository")?;
+        anyhow::ensure!(
+            first.text() == Some("version 2"),
+            "the remote doesn't speak protocol version 2 (it answered '{}')",
+            first.text().unwrap_or_default()
+        );
+        let mut capabilities = Vec::new();
+        while let Some(line) = pktline::read_line(reader)? {
+            capabilities.push(line);
+        }
+        Ok(UploadPack { channel, capabilities })
+    }
+
+    // The value a capability was advertised with ("" if it has none), if it was.
+    pub(crate) fn capability(&self, name: &str) -> Option<&str> {
+        self.capabilities.iter().find_map(|capability| match capability.split_once('=') {
+            Some((key, value)) if key == name => Some(value),
+            None if capability == name => Some(""),
+            _ => None,
+        })
+    }
+
+    // The hash function the remote names its objects with: SHA-1 unless it says otherwise.
+    pub(crate) fn object_format(&self) -> anyhow::Result<Format> {
+        self.capability("object-format").map_or(Ok(Format::Sha1), str::parse)
+    }
+
+    // Whether a command supports a feature, e.g. ("fetch", "shallow").
+    pub(crate) fn supports(&self, command: &str, feature: &str) -> bool {
+        self.capability(command)
+            .is_some_and(|features| features.split(' ').any(|f| f == feature))
+    }
+
+    // Send a command with its arguments; the response is then read from the channel.
+    fn request(&mut self, command: &str, args: &[String]) -> anyhow::Result<()> {
+        let mut message = Vec::new();
+        pktline::write_line(&mut message, &format!("command={command}"))?;
+        if self.capability("agent").is_some() {
+            pktline::write_line(&mut message, &format!("agent={AGENT}"))?;
+        }
+        if let Some(format) = self.capability("object-format") {
+            pktline::write_line(&mut message, &format!("object-format={format}"))?;
+        }
+        pktline::delim(&mut message)?;
+        for arg in args {
+            pktline::write_line(&mut message, arg)?;
+        }
+        pktline::flush(&mut message)?;
+        self.channel.send(&message)
+    }
+
+    // The refs whose names start with one of `prefixes`.
+    pub(crate) fn ls_refs(&mut self, prefixes: &[&str]) -> anyhow::Result<Vec<RemoteRef>> {
+        let mut args = vec!["symrefs".to_string(), "peel".to_string()];
+        if self.supports("ls-refs", "unborn") {
+            args.push("unborn".to_string());
+        }
+        args.extend(prefixes.iter().map(|prefix| format!("ref-prefix {prefix}")));
+        self.request("ls-refs", &args)?;
+
+        let response = self.channel.reader();
+        let mut refs = Vec::new();
+        // "<hash> <name> [symref-target:<target>] [peeled:<hash>]", or "unborn <name> ..."
+        while let Some(line) = pktline::read_line(response)? {
+            let mut words = line.split(' ');
+            let (Some(hash), Some(name)) = (words.next(), words.next()) else {
+                anyhow::bail!("protocol error: bad ls-refs line '{line}'");
+            };
+            let mut remote = RemoteRef {
+                name: name.to_string(),
+                hash: (hash != "unborn").then(|| hash.to_string()),
+                symref_target: None,
+                peeled: None,
+            };
+            for attribute in words {
+                if let Some(target) = attribute.strip_prefix("symref-target:") {
+                    remote.symref_target = Some(target.to_string());
+                } else if let Some(peeled) = attribute.strip_prefix("peeled:") {
+                    remote.peeled = Some(peeled.to_string());
+                }
+            }
+            refs.push(remote);
+        }
+        Ok(refs)
+    }
+
+    // Fetch the objects needed for `wants` into a new pack, returning its path (None if
+    // there was nothing to send). The history of the local commits `tips` is offered in
+    // rounds of "have" lines, newest first, so that the remote can leave out what it shares
+    // with us. With `limits`, less than all of it comes: .git/shallow is updated with where
+    // history now stops, and a filtered pack is marked as coming from a promisor remote.
+    pub(crate) fn fetch(
+        &mut self,
+        wants: &[String],
+        tips: &[String],
+        limits: &Limits,
+    ) -> anyhow::Result<Option<PathBuf>> {
+        let (ours, theirs) = (Format::current()?, self.object_format()?);
+        anyhow::ensure!(ours == theirs, "mismatched algorithms: client {ours}; server {theirs}");
+        let mut options = vec!["thin-pack".to_string(), "ofs-delta".to_string(), "include-tag".to_string()];
+        if !io::stderr().is_terminal() {
+            options.push("no-progress".to_string());
+        }
+        // The remote must know where our history stops, or it would take us for having more
+        let shallow = shallow::commits()?;
+        let deepen = limits.depth.is_some() || limits.since.is_some() || !limits.exclude.is_empty();
+        if !shallow.is_empty() || deepen {
+            anyhow::ensure!(self.supports("fetch", "shallow"), "Server does not support shallow clients");
+        }
+        options.extend(shallow.iter().map(|commit| format!("shallow {commit}")));
+        options.extend(limits.depth.map(|depth| format!("deepen {depth}")));
+        options.extend(limits.since.map(|since| format!("deepen-since {since}")));
+        options.extend(limits.exclude.iter().map(|rev| format!("deepen-not {rev}")));
+        let mut filtered = false;
+        if let Some(filter) = &limits.filter {
+            if self.supports("fetch", "filter") {
+                options.push(format!("filter {filter}"));
+                filtered = true;
+            } else {
+                eprintln!("warning: filtering not recognized by server, ignoring");
+            }
+        }
+        let mut haves = Haves::new(tips)?;
+        let mut common: Vec<String> = Vec::new();
+        // How many haves were sent since the last one the remote knew
+        let mut in_vain = 0;
+        loop {
+            let batch: Vec<String> = haves.by_ref().take(HAVES_PER_ROUND).collect::<anyhow::Result<_>>()?;
+            let done = batch.is_empty() || in_vain >= MAX_IN_VAIN;
+            let mut args = options.clone();
+            args.extend(wants.iter().map(|want| format!("want {want}")));
+            args.extend(common.iter().chain(&batch).map(|have| format!("have {have}")));
+            if done {
+                args.push("done".to_string());
+            }
+            self.request("fetch", &args)?;
+            let response = self.channel.reader();
+
+            if !done {
+                // "acknowledgments": NAK, or an ACK for every have the remote has, and "ready"
+                // once it has found enough to go on with
+                anyhow::ensure!(
+                    pktline::read_line(response)?.as_deref() == Some("acknowledgments"),
+                    "protocol error: expected acknowledgments"
+                );
+                let mut ready = false;
+                let end = loop {
+                    match pktline::read(response)? {
+                        Packet::Data(data) => {
+                            let line = String::from_utf8_lossy(&data);
+                            let line = line.trim_end();
+                            if let Some(hash) = line.strip_prefix("ACK ") {
+                                haves.mark_common(hash)?;
+                                common.push(hash.to_string());
+                                in_vain = 0;
+                            } else if line == "ready" {
+                                ready = true;
+                            }
+                        }
+                        end => break end,
+                    }
+                };
+                in_vain += batch.len();
+                match (ready, end) {
+                    (true, Packet::Delim) => {}
+                    (false, Packet::Flush) => continue,
+                    _ => anyhow::bail!("protocol error: bad acknowledgments section"),
+                }
+            }
+
+            // Sections until the packfile one: shallow-info, wanted-refs, ...
+            let response = self.channel.reader();
+            let mut added = Vec::new();
+            let mut removed = Vec::new();
+            let path = loop {
+                let header = match pktline::read(response)? {
+                    Packet::Data(data) => String::from_utf8_lossy(&data).trim_end().to_string(),
+                    _ => break None,
+                };
+                if header == "packfile" {
+                    break pack::index_pack(BufReader::new(Sideband::new(response))).context("index received pack")?;
+                }
+                let end = loop {
+                    match pktline::read(response)? {
+                        Packet::Data(data) if header == "shallow-info" => {
+                            let line = String::from_utf8_lossy(&data);
+                            let line = line.trim_end();
+                            if let Some(commit) = line.strip_prefix("shallow ") {
+                                added.push(commit.to_string());
+                            } else if let Some(commit) = line.strip_prefix("unshallow ") {
+                                removed.push(commit.to_string());
+                            }
+                        }
+                        Packet::Data(_) => {}
+                        end => break end,
+                    }
+                };
+                if end != Packet::Delim {
+                    break None;
+                }
+            };
+            shallow::update(&added, &removed)?;
+            if let (Some(path), true) = (&path, filtered) {
+                // What the pack refers to but doesn't hold can be had from the same place
+                let marker = path.with_extension("promisor");
+                std::fs::write(&marker, "").with_context(|| format!("write {}", marker.display()))?;
+            }
+            return Ok(path);
+        }
+    }
+}
+
+// How much less than everything to fetch. Depth, date and excluded revisions make for a
+// shallow history; a filter ("blob:none", "blob:limit=<n>" or "tree:<depth>") leaves out
+// objects, for a partial clone.
+#[derive(Debug, Default)]
+pub(crate) struct Limits {
+    pub(crate) depth: Option<u32>,
+    // Committer time (seconds since the epoch) before which history is left out
+    pub(crate) since: Option<i64>,
+    // Revisions of the remote whose history is left out
+    pub(crate) exclude: Vec<String>,
+    pub(crate) filter: Option<String>,
+}
+
+// How many haves go in a round of negotiation, and how many may go unacknowledged before we
+// give up looking for more in common (as git does).
+const HAVES_PER_ROUND: usize = 32;
+const MAX_IN_VAIN: usize = 256;
+
+// The local commits to offer as haves, newest first. Once the remote knows a commit, its
+// ancestors go without saying, so they aren't offered.
+struct Haves {
+    // Commits still to go, by committer date
+    queue: BinaryHeap<(i64, String)>,
+    seen: HashSet<String>,
+    common: HashSet<String>,
+}
+
+impl Haves {
+    fn new(tips: &[String]) -> anyhow::Result<Haves> {
+        let mut haves = Haves { queue: BinaryHeap::new(), seen: HashSet::new(), common: HashSet::new() };
+        for tip in tips {
+            // Tags offer what they point at; trees and blobs make no history to walk
+            let Ok(commit) = revision::peel(tip, Kind::Commit) else { continue };
+            haves.push(commit)?;
+        }
+        Ok(haves)
+    }
+
+    fn push(&mut self, hash: String) -> anyhow::Result<()> {
+        if self.seen.insert(hash.clone()) {
+            let time = revision::read_commit(&hash)?.committer.time;
+            self.queue.push((time, hash));
+        }
+        Ok(())
+    }
+
+    // Note that the remote has `hash`, and with it all of its history.
+    fn mark_common(&mut self, hash: &str) -> anyhow::Result<()> {
+        if self.common.insert(hash.to_string()) {
+            if let Ok(commit) = revision::read_commit(hash) {
+                self.common.extend(commit.parents);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Iterator for Haves {
+    type Item = anyhow::Result<String>;
+
+    fn next(&mut self) -> Option<anyhow::Result<String>> {
+        // Everything left is history the remote shares with us
+        while self.queue.iter().any(|(_, hash)| !self.common.contains(hash)) {
+            let (_, hash) = self.queue.pop()?;
+            let parents = match revision::read_commit(&hash) {
+                Ok(commit) => commit.parents,
+                Err(e) => return Some(Err(e)),
+            };
+            let common = self.common.contains(&hash);
+            for parent in parents {
+                if common {
+                    self.common.insert(parent.clone());
+                }
+                if let Err(e) = self.push(parent) {
+                    return Some(Err(e));
+                }
+            }
+            if !common {
+                return Some(Ok(hash));
+            }
+        }
+        None
+    }
+}
@@ -0,0 +1,198 @@
+use crate::ignore::IgnoreRules;
+use crate::index::{self, IndexEntry};
+use crate::objects::{Kind, Object, ObjectId};
+use anyhow::Context;
+use std::ffi::OsStr;
+use std::fs::{self, OpenOptions};
+use std::io::Cursor;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Component, Path};
+
+// Turn a path given on the command line into a path relative to the repository root
+// ("" for the root itself), rejecting anything outside of it.
+pub(crate) fn repo_path(path: &Path) -> anyhow::Result<String> {
+    let mut parts = Vec::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::Normal(part) => parts.push(
+                part.to_str()
+                    .ok_or_else(|| anyhow::anyhow!("path {} is not valid UTF-8", path.display()))?,
+            ),
+            Component::ParentDir if parts.pop().is_some() => {}
+            _ => anyhow::bail!("'{}' is outside repository", path.display()),
+        }
+    }
+    Ok(parts.join("/"))
+}
+
+// Every file (and symlink) below `dir`, as paths relative to the repository root, sorted.
+// `prefix` is the path of `dir` itself ("" for the root). The .git directory is skipped, and
+// so is everything matched by `ignore` (without descending into ignored directories).
+pub(crate) fn files(dir: &Path, prefix: &str, mut ignore: Option<&mut IgnoreRules>) -> anyhow::Result<Vec<String>> {
+    let mut out = Vec::new();
+    walk(dir, prefix, &mut ignore, &mut out)?;
+    out.sort();
+    Ok(out)
+}
+
+fn walk(
+    dir: &Path,
+    prefix: &str,
+    ignore: &mut Option<&mut IgnoreRules>,
+    out: &mut Vec<String>,
+) -> anyhow::Result<()> {
+    let entries = fs::read_dir(dir).with_context(|| format!("failed to read directory {}", dir.display()))?;
+    for entry in entries {
+        let entry = entry.with_context(|| format!("failed to read entry in {}", dir.display()))?;
+        let name = entry.file_name();
+        if name == ".git" {
+            continue;
+        }
+        let name = name
+            .to_str()
+            .ok_or_else(|| anyhow::anyhow!("file name {:?} is not valid UTF-8", entry.path()))?;
+        let path = if prefix.is_empty() {
+            name.to_string()
+        } else {
+            format!("{prefix}/{name}")
+        };
+
+        // Don't follow symlinks to directories: the link itself is what gets tracked
+        let file_type = entry.file_type().with_context(|| format!("stat {path}"))?;
+        if let Some(rules) = ignore {
+            if rules.is_ignored(&path, file_type.is_dir())? {
+                continue;
+            }
+        }
+        if file_type.is_dir() {
+            walk(&entry.path(), &path, ignore, out)?;
+        } else {
+            out.push(path);
+        }
+    }
+    Ok(())
+}
+
+// Hash a working tree file as a blob, optionally writing it to .git/objects. Symlinks are
+// stored as a blob holding the link target, like git does.
+pub(crate) fn hash_file(path: &Path, meta: &fs::Metadata, write: bool) -> anyhow::Result<ObjectId> {
+    if meta.file_type().is_symlink() {
+        let target = fs::read_link(path).with_context(|| format!("read link {}", path.display()))?;
+        let target = target.as_os_str().as_bytes().to_vec();
+        let object = Object {
+            kind: Kind::Blob,
+            size: target.len() as u64,
+            reader: Cursor::new(target),
+        };
+        return if write {
+            object.write_to_objects()
+        } else {
+            object.write(std::io::sink())
+        };
+    }
+
+    let object = Object::blob_from_file(path)?;
+    if write {
+        object.write_to_objects()
+    } else {
+        object.write(std::io::sink())
+    }
+}
+
+// Whether the working tree file of an index entry differs from what is staged (including
+// being deleted). Files whose stat data matches the entry aren't read at all.
+pub(crate) fn is_modified(entry: &IndexEntry, index_mtime: Option<(u32, u32)>) -> anyhow::Result<bool> {
+    // Submodules are directories holding another repository; their content isn't ours to check
+    if entry.mode == 0o160000 {
+        return Ok(false);
+    }
+    let meta = match fs::symlink_metadata(&entry.path) {
+        Ok(meta) => meta,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(true),
+        Err(e) => return Err(e).with_context(|| format!("stat {}", entry.path)),
+    };
+    if entry.matches_stat(&meta, index_mtime) {
+        return Ok(false);
+    }
+    if meta.is_dir() || index::mode_from_metadata(&meta) != entry.mode {
+        return Ok(true);
+    }
+    Ok(hash_file(Path::new(&entry.path), &meta, false)? != entry.hash)
+}
+
+// Write the blob `hash` to `path` as a file with the given tree mode (regular, executable or
+// symlink), replacing whatever is there, and return an index entry for the result.
+pub(crate) fn checkout_file(path: &str, mode: u32, hash: ObjectId) -> anyhow::Result<IndexEntry> {
+    match fs::symlink_metadata(path) {
+        // An empty directory may be in the way (anything else in it must be dealt with first)
+        Ok(meta) if meta.is_dir() && mode != 0o160000 => {
+            fs::remove_dir(path).with_context(|| format!("remove directory {path} to make room for a file"))?
+        }
+        Ok(meta) if !meta.is_dir() => fs::remove_file(path).with_context(|| format!("remove {path}"))?,
+        _ => {}
+    }
+    if let Some(parent) = Path::new(path).parent().filter(|parent| !parent.as_os_str().is_empty()) {
+        if fs::create_dir_all(parent).is_err() {
+            // A file stands where a directory must go, which has been checked to be expendable
+            for ancestor in parent.ancestors().filter(|a| !a.as_os_str().is_empty()) {
+                if fs::symlink_metadata(ancestor).is_ok_and(|meta| !meta.is_dir()) {
+                    fs::remove_file(ancestor).with_context(|| format!("remove {}", ancestor.display()))?;
+                }
+            }
+            fs::create_dir_all(parent).with_context(|| format!("create {}", parent.display()))?;
+        }
+    }
+
+    let hex = hash.to_string();
+    match mode {
+        // A submodule is checked out as an empty directory
+        0o160000 => fs::create_dir_all(path).with_context(|| format!("create {path}"))?,
+        0o120000 => {
+            let target = Object::read(&hex)
+                .with_context(|| format!("read blob {hex} of {path}"))?
+                .into_bytes()?;
+            std::os::unix::fs::symlink(OsStr::from_bytes(&target), path)
+                .with_context(|| format!("create symlink {path}"))?;
+        }
+        _ => {
+            let mut object = Object::read(&hex).with_context(|| format!("read blob {hex} of {path}"))?;
+            anyhow::ensure!(object.kind == Kind::Blob, "{path} points to a {}, not a blob", object.kind);
+            // The umask decides the final permissions, like for any other new file
+            let mut file = OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .mode(if mode == 0o100755 { 0o777 } else { 0o666 })
+                .open(path)
+                .with_context(|| format!("create {path}"))?;
+            std::io::copy(&mut object.reader, &mut file).with_context(|| format!("write {path}"))?;
+        }
+    }
+
+    let meta = fs::symlink_metadata(path).with_context(|| format!("stat {path}"))?;
+    let mut entry = IndexEntry::from_metadata(path.to_string(), &meta, hash);
+    entry.mode = mode;
+    Ok(entry)
+}
+
+// Delete a working tree file, along with the directories this leaves empty, like git does.
+pub(crate) fn remove_file(path: &str) -> anyhow::Result<()> {
+    match fs::remove_file(path) {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        // A submodule's directory
+        Err(_) if Path::new(path).is_dir() => {
+            let _ = fs::remove_dir(path);
+        }
+        Err(e) => return Err(e).with_context(|| format!("remove {path}")),
+    }
+    let mut dir = Path::new(path).parent();
+    while let Some(parent) = dir.filter(|d| !d.as_os_str().is_empty()) {
+        if fs::remove_dir(parent).is_err() {
+            break;
+        }
+        dir = parent.parent();
+    }
+    Ok(())
+}
@@ -0,0 +1,137 @@
+mod common;
+
+use common::{arg, git, sample_repository, subdir, vcs, Server, BIN};
+use std::path::Path;
+
+// Check that `clone` is a complete, checked out copy of `source`.
+fn assert_cloned(source: &Path, clone: &Path) {
+    git(clone, &["fsck", "--strict", "--no-progress"]);
+    assert_eq!(git(clone, &["rev-parse", "HEAD"]), git(source, &["rev-parse", "main"]));
+    assert_eq!(git(clone, &["symbolic-ref", "HEAD"]), "refs/heads/main");
+    assert_eq!(git(clone, &["rev-parse", "origin/topic"]), git(source, &["rev-parse", "topic"]));
+    assert_eq!(git(clone, &["rev-parse", "v1"]), git(source, &["rev-parse", "v1"]));
+    assert_eq!(git(clone, &["status", "--porcelain"]), "");
+    assert_eq!(std::fs::read_to_string(clone.join("src/lib.rs")).unwrap(), "pub fn two() -> u32 {\n    2\n}\n");
+}
+
+#[test]
+fn clone_through_upload_pack() {
+    let temp = tempfile::tempdir().unwrap();
+    let source = subdir(temp.path(), "source");
+    sample_repository(&source);
+
+    vcs(temp.path(), &["clone", "-u", &format!("{BIN} upload-pack"), arg(&source), "ours"]);
+    assert_cloned(&source, &temp.path().join("ours"));
+
+    vcs(temp.path(), &["clone", &format!("file://{}", arg(&source)), "with-git"]);
+    assert_cloned(&source, &temp.path().join("with-git"));
+
+    git(temp.path(), &["clone", "-q", "-u", &format!("{BIN} upload-pack"), arg(&source), "by-git"]);
+    assert_cloned(&source, &temp.path().join("by-git"));
+}
+
+#[test]
+fn shallow_clone_through_upload_pack() {
+    let temp = tempfile::tempdir().unwrap();
+    let source = subdir(temp.path(), "source");
+    sample_repository(&source);
+
+    let url = format!("file://{}", arg(&source));
+    vcs(temp.path(), &["clone", "-u", &format!("{BIN} upload-pack"), "--depth", "1", &url, "shallow"]);
+    let clone = temp.path().join("shallow");
+    git(&clone, &["fsck", "--no-progress"]);
+    assert_eq!(git(&clone, &["rev-list", "--count", "HEAD"]), "1");
+    assert_eq!(git(&clone, &["rev-parse", "HEAD"]), git(&source, &["rev-parse", "main"]));
+}
+
+#[test]
+fn deepen_a_shallow_clone_relative_to_its_boundary() {
+    let temp = tempfile::tempdir().unwrap();
+    let source = subdir(temp.path(), "source");
+    git(&source, &["init", "-q", "-b", "main", "."]);
+    for i in 1..=5 {
+        std::fs::write(source.join("file"), format!("{i}\n")).unwrap();
+        git(&source, &["add", "file"]);
+        git(&source, &["commit", "-q", "-m", &format!("commit {i}")]);
+    }
+
+    let upload_pack = format!("{BIN} upload-pack");
+    let url = format!("file://{}", arg(&source));
+    git(temp.path(), &["clone", "-q", "--depth", "1", "-u", &upload_pack, &url, "shallow"]);
+    let clone = temp.path().join("shallow");
+    assert_eq!(git(&clone, &["rev-list", "--count", "HEAD"]), "1");
+
+    git(&clone, &["fetch", "-q", "--deepen=2", "--upload-pack", &upload_pack]);
+    assert_eq!(git(&clone, &["rev-list", "--count", "HEAD"]), "3");
+    assert_eq!(std::fs::read_to_string(clone.join(".git/shallow")).unwrap().trim(), git(&source, &["rev-parse", "main~2"]));
+
+    // New commits come in whole, and the boundary moves on by one
+    std::fs::write(source.join("file"), "6\n").unwrap();
+    git(&source, &["commit", "-q", "-a", "-m", "commit 6"]);
+    git(&clone, &["fetch", "-q", "--deepen=1", "--upload-pack", &upload_pack, "origin"]);
+    assert_eq!(git(&clone, &["rev-list", "--count", "origin/main"]), "5");
+
+    git(&clone, &["fetch", "-q", "--deepen=5", "--upload-pack", &upload_pack]);
+    assert_eq!(git(&clone, &["rev-list", "--count", "origin/main"]), "6");
+    assert!(!clone.join(".git/shallow").exists());
+    git(&clone, &["fsck", "--strict", "--no-progress"]);
+}
+
+#[test]
+fn clone_over_http() {
+    let temp = tempfile::tempdir().unwrap();
+    let root = subdir(temp.path(), "root");
+    let source = subdir(&root, "project");
+    sample_repository(&source);
+    let server = Server::start(&root);
+    let url = format!("{}project", server.url);
+
+    vcs(temp.path(), &["clone", &url, "ours"]);
+    assert_cloned(&source, &temp.path().join("ours"));
+
+    git(temp.path(), &["clone", "-q", &url, "by-git"]);
+    assert_cloned(&source, &temp.path().join("by-git"));
+}
+
+#[test]
+fn clone_of_a_bare_repository_over_http() {
+    let temp = tempfile::tempdir().unwrap();
+    let root = subdir(temp.path(), "root");
+    let work = subdir(temp.path(), "work");
+    sample_repository(&work);
+    git(&root, &["clone", "-q", "--bare", arg(&work), "project.git"]);
+    let server = Server::start(&root);
+
+    vcs(temp.path(), &["clone", &format!("{}project.git", server.url), "ours"]);
+    let clone = temp.path().join("ours");
+    git(&clone, &["fsck", "--strict", "--no-progress"]);
+    assert_eq!(git(&clone, &["rev-parse", "HEAD"]), git(&work, &["rev-parse", "main"]));
+}
+
+#[test]
+fn tree_depth_filter_keeps_objects_also_found_higher_up() {
+    let temp = tempfile::tempdir().unwrap();
+    let source = subdir(temp.path(), "source");
+    git(&source, &["init", "-q", "-b", "main", "."]);
+    git(&source, &["config", "uploadpack.allowFilter", "true"]);
+    std::fs::write(source.join("README"), "hello\n").unwrap();
+    git(&source, &["add", "."]);
+    git(&source, &["commit", "-q", "-m", "first"]);
+    // On a branch that isn't checked out, so nothing gets fetched later: the same blob at the
+    // top and in sub/, which is walked first, and a blob only in sub/
+    git(&source, &["switch", "-q", "-c", "other"]);
+    std::fs::create_dir(source.join("sub")).unwrap();
+    std::fs::write(source.join("b"), "shared\n").unwrap();
+    std::fs::write(source.join("sub/x"), "shared\n").unwrap();
+    std::fs::write(source.join("sub/y"), "deep only\n").unwrap();
+    git(&source, &["add", "."]);
+    git(&source, &["commit", "-q", "-m", "second"]);
+    git(&source, &["switch", "-q", "main"]);
+
+    let url = format!("file://{}", arg(&source));
+    vcs(temp.path(), &["clone", "-u", &format!("{BIN} upload-pack"), "--filter=tree:2", &url, "partial"]);
+    let clone = temp.path().join("partial");
+    let missing = git(&clone, &["rev-list", "--objects", "--all", "--missing=print"]);
+    let missing: Vec<&str> = missing.lines().filter_map(|line| line.strip_prefix('?')).collect();
+    assert_eq!(missing, [git(&source, &["rev-parse", "other:sub/y"])]);
+}
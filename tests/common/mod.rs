@@ -0,0 +1,127 @@
+// Helpers shared by the integration tests, which run the built binary and git side by side on
+// repositories in temporary directories.
+#![allow(dead_code)]
+
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Output, Stdio};
+
+pub const BIN: &str = env!("CARGO_BIN_EXE_git-like-vcs");
+
+// A command in `dir` with a fixed identity and dates, and no user or system config.
+fn command(program: &str, dir: &Path) -> Command {
+    let mut command = Command::new(program);
+    command
+        .current_dir(dir)
+        .env("GIT_CONFIG_NOSYSTEM", "1")
+        .env("GIT_CONFIG_GLOBAL", "/dev/null")
+        .env("HOME", dir)
+        .env("GIT_AUTHOR_NAME", "Tester")
+        .env("GIT_AUTHOR_EMAIL", "t@example.com")
+        .env("GIT_AUTHOR_DATE", "1700000000 +0000")
+        .env("GIT_COMMITTER_NAME", "Tester")
+        .env("GIT_COMMITTER_EMAIL", "t@example.com")
+        .env("GIT_COMMITTER_DATE", "1700000000 +0000");
+    command
+}
+
+fn run(program: &str, dir: &Path, args: &[&str]) -> Output {
+    command(program, dir).args(args).output().unwrap_or_else(|e| panic!("run {program}: {e}"))
+}
+
+fn expect_success(program: &str, args: &[&str], output: Output) -> String {
+    assert!(
+        output.status.success(),
+        "{program} {} failed:\n{}{}",
+        args.join(" "),
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout).unwrap().trim_end().to_string()
+}
+
+// Run git in `dir`, failing the test if it fails; its output, trimmed.
+pub fn git(dir: &Path, args: &[&str]) -> String {
+    expect_success("git", args, run("git", dir, args))
+}
+
+// Run the binary in `dir`, failing the test if it fails; its output, trimmed.
+pub fn vcs(dir: &Path, args: &[&str]) -> String {
+    expect_success(BIN, args, run(BIN, dir, args))
+}
+
+// Run git in `dir`, whether it succeeds or not.
+pub fn git_output(dir: &Path, args: &[&str]) -> Output {
+    run("git", dir, args)
+}
+
+// Run the binary in `dir`, whether it succeeds or not.
+pub fn vcs_output(dir: &Path, args: &[&str]) -> Output {
+    run(BIN, dir, args)
+}
+
+// Make a repository at `dir` with git: a few commits on main, a second branch and a tag.
+pub fn sample_repository(dir: &Path) {
+    git(dir, &["init", "-q", "-b", "main", "."]);
+    std::fs::write(dir.join("README"), "hello\n").unwrap();
+    std::fs::create_dir(dir.join("src")).unwrap();
+    std::fs::write(dir.join("src/lib.rs"), "pub fn one() -> u32 {\n    1\n}\n").unwrap();
+    git(dir, &["add", "."]);
+    git(dir, &["commit", "-q", "-m", "first"]);
+    git(dir, &["branch", "topic"]);
+    std::fs::write(dir.join("README"), "hello\nworld\n").unwrap();
+    git(dir, &["commit", "-q", "-a", "-m", "second"]);
+    git(dir, &["tag", "-a", "-m", "release", "v1"]);
+    std::fs::write(dir.join("src/lib.rs"), "pub fn two() -> u32 {\n    2\n}\n").unwrap();
+    git(dir, &["commit", "-q", "-a", "-m", "third"]);
+}
+
+// `serve --http` on a free port of 127.0.0.1, killed when dropped.
+pub struct Server {
+    child: Child,
+    pub url: String,
+}
+
+impl Server {
+    pub fn start(root: &Path) -> Server {
+        let mut child = Command::new(BIN)
+            .args(["serve", "--http", "127.0.0.1:0"])
+            .arg(root)
+            .stderr(Stdio::piped())
+            .spawn()
+            .expect("start the server");
+        // "Serving <root> on http://<address>/"
+        let mut stderr = BufReader::new(child.stderr.take().unwrap());
+        let mut line = String::new();
+        stderr.read_line(&mut line).unwrap();
+        let url = match line.trim_end().rsplit_once(" on ") {
+            Some((_, url)) => url.to_string(),
+            None => {
+                let _ = child.kill();
+                panic!("unexpected server output: {line}");
+            }
+        };
+        // Keep reading what it logs, so that it never writes to a closed pipe.
+        std::thread::spawn(move || std::io::copy(&mut stderr, &mut std::io::sink()));
+        Server { child, url }
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+// A path as a string argument.
+pub fn arg(path: &Path) -> &str {
+    path.to_str().unwrap()
+}
+
+// A directory next to others in a temporary directory.
+pub fn subdir(root: &Path, name: &str) -> PathBuf {
+    let dir = root.join(name);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
@@ -0,0 +1,28 @@
+mod common;
+
+use common::{git, BIN};
+use std::process::{Command, Stdio};
+
+#[test]
+fn a_reader_that_goes_away_is_no_error() {
+    let temp = tempfile::tempdir().unwrap();
+    let dir = temp.path();
+    git(dir, &["init", "-q", "-b", "main", "."]);
+    for i in 0..100 {
+        std::fs::write(dir.join(format!("file{i}")), "x\n").unwrap();
+    }
+    git(dir, &["add", "."]);
+
+    // Like a `head` that is done at once: the pipe is closed before anything is read from it
+    let mut child = Command::new(BIN)
+        .args(["ls-files", "--stage"])
+        .current_dir(dir)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    drop(child.stdout.take());
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "");
+}
@@ -0,0 +1,248 @@
+mod common;
+
+use common::{arg, git, sample_repository, subdir, vcs_output, BIN};
+use std::path::{Path, PathBuf};
+use std::process::Output;
+use tempfile::TempDir;
+
+// A working repository made with git and an empty bare repository to push it to.
+struct Setup {
+    _temp: TempDir,
+    work: PathBuf,
+    bare: PathBuf,
+    receive_pack: String,
+}
+
+impl Setup {
+    fn new(receive_pack: &str) -> Setup {
+        let temp = tempfile::tempdir().unwrap();
+        let work = subdir(temp.path(), "work");
+        sample_repository(&work);
+        let bare = temp.path().join("remote.git");
+        git(temp.path(), &["init", "-q", "--bare", "-b", "main", arg(&bare)]);
+        Setup { _temp: temp, work, bare, receive_pack: receive_pack.to_string() }
+    }
+
+    fn push(&self, args: &[&str]) -> Output {
+        let mut all = vec!["push", "--receive-pack", &self.receive_pack];
+        all.extend_from_slice(args);
+        vcs_output(&self.work, &all)
+    }
+
+    fn push_ok(&self, args: &[&str]) {
+        let output = self.push(args);
+        assert!(output.status.success(), "push {args:?} failed:\n{}", String::from_utf8_lossy(&output.stderr));
+    }
+
+    // The push fails, and says why.
+    fn push_rejected(&self, args: &[&str], reason: &str) {
+        let output = self.push(args);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(!output.status.success(), "push {args:?} succeeded:\n{stderr}");
+        assert!(stderr.contains(reason), "push {args:?} didn't say '{reason}':\n{stderr}");
+    }
+
+    // The refs of the remote, as "<hash> <name>" lines.
+    fn remote_refs(&self) -> String {
+        let output = common::git_output(&self.bare, &["show-ref"]);
+        String::from_utf8(output.stdout).unwrap()
+    }
+
+    fn remote(&self, name: &str) -> String {
+        git(&self.bare, &["rev-parse", "--verify", name])
+    }
+
+    fn local(&self, name: &str) -> String {
+        git(&self.work, &["rev-parse", "--verify", name])
+    }
+
+    fn remote_url(&self) -> &str {
+        arg(&self.bare)
+    }
+}
+
+// Make a commit on the current branch of `dir`.
+fn commit(dir: &Path, file: &str, message: &str) {
+    std::fs::write(dir.join(file), format!("{message}\n")).unwrap();
+    git(dir, &["add", file]);
+    git(dir, &["commit", "-q", "-m", message]);
+}
+
+fn new_branch(receive_pack: &str) {
+    let setup = Setup::new(receive_pack);
+    setup.push_ok(&[setup.remote_url(), "main"]);
+    assert_eq!(setup.remote("main"), setup.local("main"));
+    setup.push_ok(&[setup.remote_url(), "topic:refs/heads/feature"]);
+    assert_eq!(setup.remote("feature"), setup.local("topic"));
+    git(&setup.bare, &["fsck", "--strict", "--no-progress"]);
+}
+
+fn fast_forward(receive_pack: &str) {
+    let setup = Setup::new(receive_pack);
+    setup.push_ok(&[setup.remote_url(), "main"]);
+    commit(&setup.work, "NEWS", "fourth");
+    commit(&setup.work, "NEWS", "fifth");
+    setup.push_ok(&[setup.remote_url(), "main"]);
+    assert_eq!(setup.remote("main"), setup.local("main"));
+    git(&setup.bare, &["fsck", "--strict", "--no-progress"]);
+}
+
+fn non_fast_forward_is_rejected(receive_pack: &str) {
+    let setup = Setup::new(receive_pack);
+    setup.push_ok(&[setup.remote_url(), "main"]);
+    let pushed = setup.local("main");
+    git(&setup.work, &["reset", "-q", "--hard", "HEAD~1"]);
+    commit(&setup.work, "NEWS", "rewritten");
+
+    setup.push_rejected(&[setup.remote_url(), "main"], "non-fast-forward");
+    assert_eq!(setup.remote("main"), pushed);
+
+    setup.push_ok(&["--force", setup.remote_url(), "main"]);
+    assert_eq!(setup.remote("main"), setup.local("main"));
+}
+
+fn force_with_lease_with_a_stale_expectation(receive_pack: &str) {
+    let setup = Setup::new(receive_pack);
+    setup.push_ok(&[setup.remote_url(), "main"]);
+    let expected = setup.local("main");
+    // Someone else moves the branch on
+    commit(&setup.work, "NEWS", "theirs");
+    setup.push_ok(&[setup.remote_url(), "main"]);
+    let theirs = setup.local("main");
+    git(&setup.work, &["reset", "-q", "--hard", &expected]);
+    commit(&setup.work, "NEWS", "ours");
+
+    let lease = format!("--force-with-lease=main:{expected}");
+    setup.push_rejected(&[&lease, setup.remote_url(), "main"], "stale info");
+    assert_eq!(setup.remote("main"), theirs);
+
+    let lease = format!("--force-with-lease=main:{theirs}");
+    setup.push_ok(&[&lease, setup.remote_url(), "main"]);
+    assert_eq!(setup.remote("main"), setup.local("main"));
+}
+
+fn atomic_push_with_a_failing_ref(receive_pack: &str) {
+    let setup = Setup::new(receive_pack);
+    setup.push_ok(&[setup.remote_url(), "main", "topic"]);
+    let before = setup.remote_refs();
+
+    // Refused here: main no longer fast-forwards
+    git(&setup.work, &["reset", "-q", "--hard", "HEAD~1"]);
+    git(&setup.work, &["branch", "-q", "extra", "topic"]);
+    setup.push_rejected(&["--atomic", setup.remote_url(), "main", "extra"], "atomic push failed");
+    assert_eq!(setup.remote_refs(), before);
+
+    // Refused by the remote: it doesn't allow deletions
+    git(&setup.bare, &["config", "receive.denyDeletes", "true"]);
+    setup.push_rejected(&["--atomic", setup.remote_url(), ":topic", "extra"], "deletion prohibited");
+    assert_eq!(setup.remote_refs(), before);
+
+    setup.push_ok(&["--atomic", setup.remote_url(), "extra"]);
+    assert_eq!(setup.remote("extra"), setup.local("extra"));
+}
+
+fn atomic_push_with_a_locked_ref(receive_pack: &str) {
+    let setup = Setup::new(receive_pack);
+    setup.push_ok(&[setup.remote_url(), "main", "topic"]);
+    let before = setup.remote_refs();
+    commit(&setup.work, "NEWS", "fourth");
+    git(&setup.work, &["branch", "-q", "-f", "topic", "main"]);
+
+    // Both refs pass the checks, but only main can be locked
+    let lock = setup.bare.join("refs/heads/topic.lock");
+    std::fs::write(&lock, "").unwrap();
+    setup.push_rejected(&["--atomic", setup.remote_url(), "main", "topic"], "topic");
+    assert_eq!(setup.remote_refs(), before);
+
+    std::fs::remove_file(&lock).unwrap();
+    setup.push_ok(&["--atomic", setup.remote_url(), "main", "topic"]);
+    assert_eq!(setup.remote("main"), setup.local("main"));
+    assert_eq!(setup.remote("topic"), setup.local("main"));
+}
+
+fn update_instead_updates_a_clean_working_tree(receive_pack: &str) {
+    let setup = Setup::new(receive_pack);
+    let deploy = setup.work.parent().unwrap().join("deploy");
+    git(&setup.work, &["clone", "-q", ".", arg(&deploy)]);
+    git(&deploy, &["config", "receive.denyCurrentBranch", "updateInstead"]);
+
+    commit(&setup.work, "NEWS", "fourth");
+    setup.push_ok(&[arg(&deploy), "main"]);
+    assert_eq!(git(&deploy, &["rev-parse", "HEAD"]), setup.local("main"));
+    assert_eq!(std::fs::read_to_string(deploy.join("NEWS")).unwrap(), "fourth\n");
+    assert_eq!(git(&deploy, &["status", "--porcelain"]), "");
+
+    // Local changes there are not overwritten
+    let pushed = setup.local("main");
+    std::fs::write(deploy.join("NEWS"), "local\n").unwrap();
+    commit(&setup.work, "NEWS", "fifth");
+    setup.push_rejected(&[arg(&deploy), "main"], "Working directory has unstaged changes");
+    assert_eq!(git(&deploy, &["rev-parse", "HEAD"]), pushed);
+    assert_eq!(std::fs::read_to_string(deploy.join("NEWS")).unwrap(), "local\n");
+}
+
+// The same pushes against git's receive-pack and the built-in one.
+macro_rules! push_tests {
+    ($module:ident, $receive_pack:expr) => {
+        mod $module {
+            #[test]
+            fn new_branch() {
+                super::new_branch(&$receive_pack);
+            }
+
+            #[test]
+            fn fast_forward() {
+                super::fast_forward(&$receive_pack);
+            }
+
+            #[test]
+            fn non_fast_forward_is_rejected() {
+                super::non_fast_forward_is_rejected(&$receive_pack);
+            }
+
+            #[test]
+            fn force_with_lease_with_a_stale_expectation() {
+                super::force_with_lease_with_a_stale_expectation(&$receive_pack);
+            }
+
+            #[test]
+            fn atomic_push_with_a_failing_ref() {
+                super::atomic_push_with_a_failing_ref(&$receive_pack);
+            }
+
+            #[test]
+            fn atomic_push_with_a_locked_ref() {
+                super::atomic_push_with_a_locked_ref(&$receive_pack);
+            }
+
+            #[test]
+            fn update_instead_updates_a_clean_working_tree() {
+                super::update_instead_updates_a_clean_working_tree(&$receive_pack);
+            }
+        }
+    };
+}
+
+fn built_in() -> String {
+    format!("{BIN} receive-pack")
+}
+
+push_tests!(git_receive_pack, "git-receive-pack");
+push_tests!(built_in_receive_pack, super::built_in());
+
+// git's receive-pack gives up on the whole push here.
+#[test]
+fn a_bad_deny_setting_refuses_only_the_refs_it_covers() {
+    let setup = Setup::new(&built_in());
+    let deploy = setup.work.parent().unwrap().join("deploy");
+    git(&setup.work, &["clone", "-q", ".", arg(&deploy)]);
+    git(&deploy, &["config", "receive.denyCurrentBranch", "sometimes"]);
+
+    commit(&setup.work, "NEWS", "fourth");
+    let output = setup.push(&[arg(&deploy), "main", "main:refs/heads/other"]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!output.status.success(), "push succeeded:\n{stderr}");
+    assert!(stderr.contains("bad boolean config value 'sometimes'"), "the setting isn't reported:\n{stderr}");
+    assert_ne!(git(&deploy, &["rev-parse", "main"]), setup.local("main"));
+    assert_eq!(git(&deploy, &["rev-parse", "other"]), setup.local("main"));
+}
@@ -0,0 +1,38 @@
+mod common;
+
+use common::{git, sample_repository, vcs};
+use std::fs;
+
+#[test]
+fn deleting_a_packed_ref_keeps_peeled_tags() {
+    let temp = tempfile::tempdir().unwrap();
+    let dir = temp.path();
+    sample_repository(dir);
+    git(dir, &["branch", "gone"]);
+    git(dir, &["pack-refs", "--all"]);
+    let peeled = git(dir, &["show-ref", "-d", "v1"]);
+    assert!(peeled.contains("refs/tags/v1^{}"), "{peeled}");
+
+    vcs(dir, &["branch", "-d", "gone"]);
+    assert_eq!(git(dir, &["show-ref", "-d", "v1"]), peeled);
+    assert!(git(dir, &["ls-remote", "."]).contains("refs/tags/v1^{}"));
+    let packed = fs::read_to_string(dir.join(".git/packed-refs")).unwrap();
+    assert!(!packed.contains("refs/heads/gone"), "{packed}");
+    assert!(packed.contains("refs/heads/topic"), "{packed}");
+
+    vcs(dir, &["branch", "-m", "topic", "renamed"]);
+    assert_eq!(git(dir, &["show-ref", "-d", "v1"]), peeled);
+    git(dir, &["fsck", "--no-progress"]);
+}
+
+#[test]
+fn lockfiles_are_not_refs() {
+    let temp = tempfile::tempdir().unwrap();
+    let dir = temp.path();
+    sample_repository(dir);
+    fs::copy(dir.join(".git/refs/heads/topic"), dir.join(".git/refs/heads/topic.lock")).unwrap();
+
+    let branches = vcs(dir, &["branch"]);
+    assert!(!branches.contains("lock"), "{branches}");
+    assert_eq!(branches.lines().count(), 2, "{branches}");
+}
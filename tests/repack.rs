@@ -0,0 +1,55 @@
+mod common;
+
+use common::{git, sample_repository, vcs};
+use std::path::Path;
+
+// Every object in the repository, as git lists them.
+fn all_objects(dir: &Path) -> Vec<String> {
+    let list = git(dir, &["cat-file", "--batch-all-objects", "--batch-check=%(objectname)"]);
+    list.lines().map(str::to_string).collect()
+}
+
+fn assert_readable(dir: &Path, objects: &[String]) {
+    git(dir, &["fsck", "--strict", "--no-progress"]);
+    for object in objects {
+        vcs(dir, &["cat-file", "-e", object]);
+        git(dir, &["cat-file", "-p", object]);
+    }
+    assert_eq!(all_objects(dir), objects);
+}
+
+#[test]
+fn repacking_twice_keeps_every_object() {
+    let temp = tempfile::tempdir().unwrap();
+    let dir = temp.path();
+    sample_repository(dir);
+    let objects = all_objects(dir);
+
+    vcs(dir, &["repack", "-a", "-d"]);
+    assert_readable(dir, &objects);
+    let packs = std::fs::read_dir(dir.join(".git/objects/pack")).unwrap().count();
+
+    // A second run packs the same objects into a pack of the same name
+    vcs(dir, &["repack", "-a", "-d"]);
+    assert_readable(dir, &objects);
+    assert_eq!(std::fs::read_dir(dir.join(".git/objects/pack")).unwrap().count(), packs);
+}
+
+#[test]
+fn repacking_leaves_kept_packs_alone() {
+    let temp = tempfile::tempdir().unwrap();
+    let dir = temp.path();
+    sample_repository(dir);
+    git(dir, &["repack", "-q", "-a", "-d"]);
+    let pack_dir = dir.join(".git/objects/pack");
+    let kept = std::fs::read_dir(&pack_dir).unwrap().next().unwrap().unwrap().path().with_extension("keep");
+    std::fs::write(&kept, "").unwrap();
+    std::fs::write(dir.join("NEWS"), "news\n").unwrap();
+    git(dir, &["add", "NEWS"]);
+    git(dir, &["commit", "-q", "-m", "fourth"]);
+    let objects = all_objects(dir);
+
+    vcs(dir, &["repack", "-a", "-d"]);
+    assert_readable(dir, &objects);
+    assert!(kept.with_extension("pack").exists());
+}
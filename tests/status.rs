@@ -0,0 +1,68 @@
+mod common;
+
+use common::{git, git_output, vcs};
+use std::fs;
+use std::path::Path;
+
+// A staged rename with further changes, a rename in the working tree to a file added with
+// "add -N", and a plain modification.
+fn renamed_files(dir: &Path) {
+    git(dir, &["init", "-q", "-b", "main", "."]);
+    let lines = |count: usize| (1..=count).map(|i| format!("line {i}\n")).collect::<String>();
+    fs::write(dir.join("big"), lines(200)).unwrap();
+    fs::write(dir.join("other"), lines(100)).unwrap();
+    fs::write(dir.join("keep"), "x\n").unwrap();
+    git(dir, &["add", "."]);
+    git(dir, &["commit", "-q", "-m", "one"]);
+
+    git(dir, &["mv", "big", "big2"]);
+    fs::write(dir.join("big2"), lines(200) + "extra\n").unwrap();
+    fs::rename(dir.join("other"), dir.join("other2")).unwrap();
+    git(dir, &["add", "-N", "other2"]);
+    fs::write(dir.join("keep"), "x\ny\n").unwrap();
+}
+
+// git's long format, without the hints this one leaves out.
+fn git_long_status(dir: &Path) -> String {
+    let status = git(dir, &["status"]);
+    status.lines().filter(|line| !line.starts_with("  (use ")).collect::<Vec<_>>().join("\n")
+}
+
+#[test]
+fn renames_are_shown_like_git_shows_them() {
+    let temp = tempfile::tempdir().unwrap();
+    let dir = temp.path();
+    renamed_files(dir);
+
+    let short = vcs(dir, &["status", "--short"]);
+    assert_eq!(short, "RM big -> big2\n M keep\n R other -> other2");
+    assert_eq!(short, git(dir, &["status", "--short"]));
+
+    let long = vcs(dir, &["status"]);
+    assert!(long.contains("\trenamed:    big -> big2"), "{long}");
+    assert!(long.contains("\trenamed:    other -> other2"), "{long}");
+    assert_eq!(long, git_long_status(dir));
+
+    let v2 = vcs(dir, &["status", "--porcelain=v2"]);
+    assert!(v2.lines().next().unwrap().starts_with("2 RM N... "), "{v2}");
+    assert!(v2.contains(" R100 big2\tbig\n"), "{v2}");
+    assert!(v2.ends_with(" R100 other2\tother"), "{v2}");
+    assert_eq!(v2, git(dir, &["status", "--porcelain=v2"]));
+}
+
+#[test]
+fn renames_can_be_turned_off() {
+    let temp = tempfile::tempdir().unwrap();
+    let dir = temp.path();
+    renamed_files(dir);
+
+    let expected = "D  big\nAM big2\n M keep\n D other\n A other2";
+    assert_eq!(vcs(dir, &["status", "--short", "--no-renames"]), expected);
+    git(dir, &["config", "status.renames", "false"]);
+    assert_eq!(vcs(dir, &["status", "--short"]), expected);
+    let output = git_output(dir, &["status", "--short"]);
+    assert_eq!(String::from_utf8(output.stdout).unwrap().trim_end(), expected);
+
+    // Unless asked for explicitly
+    assert!(vcs(dir, &["status", "--short", "--find-renames"]).starts_with("RM big -> big2\n"));
+}
@@ -0,0 +1,58 @@
+mod common;
+
+use common::{git, vcs, vcs_output};
+use std::fs;
+use std::path::Path;
+
+// main has README; other changes README and adds dir/sub, where main's worktree has an
+// untracked file named dir.
+fn blocked_switch(dir: &Path) {
+    git(dir, &["init", "-q", "-b", "main", "."]);
+    fs::write(dir.join("README"), "main\n").unwrap();
+    fs::write(dir.join("a-first"), "first\n").unwrap();
+    git(dir, &["add", "."]);
+    git(dir, &["commit", "-q", "-m", "main"]);
+    git(dir, &["switch", "-q", "-c", "other"]);
+    fs::write(dir.join("README"), "other\n").unwrap();
+    fs::remove_file(dir.join("a-first")).unwrap();
+    fs::create_dir(dir.join("dir")).unwrap();
+    fs::write(dir.join("dir/sub"), "sub\n").unwrap();
+    git(dir, &["add", "-A"]);
+    git(dir, &["commit", "-q", "-m", "other"]);
+    git(dir, &["switch", "-q", "main"]);
+    fs::write(dir.join("dir"), "untracked\n").unwrap();
+}
+
+#[test]
+fn refused_switch_changes_nothing() {
+    let temp = tempfile::tempdir().unwrap();
+    let dir = temp.path();
+    blocked_switch(dir);
+    let index = fs::read(dir.join(".git/index")).unwrap();
+
+    let output = vcs_output(dir, &["switch", "other"]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("untracked working tree files would be overwritten"), "{stderr}");
+    assert!(stderr.contains("\tdir\n"), "{stderr}");
+
+    assert_eq!(git(dir, &["symbolic-ref", "HEAD"]), "refs/heads/main");
+    assert_eq!(fs::read(dir.join(".git/index")).unwrap(), index);
+    assert_eq!(fs::read_to_string(dir.join("README")).unwrap(), "main\n");
+    assert_eq!(fs::read_to_string(dir.join("a-first")).unwrap(), "first\n");
+    assert_eq!(fs::read_to_string(dir.join("dir")).unwrap(), "untracked\n");
+    assert_eq!(git(dir, &["status", "--porcelain"]), "?? dir");
+}
+
+#[test]
+fn forced_switch_replaces_the_untracked_file() {
+    let temp = tempfile::tempdir().unwrap();
+    let dir = temp.path();
+    blocked_switch(dir);
+
+    vcs(dir, &["switch", "-f", "other"]);
+    assert_eq!(git(dir, &["symbolic-ref", "HEAD"]), "refs/heads/other");
+    assert_eq!(fs::read_to_string(dir.join("dir/sub")).unwrap(), "sub\n");
+    assert!(!dir.join("a-first").exists());
+    assert_eq!(git(dir, &["status", "--porcelain"]), "");
+}